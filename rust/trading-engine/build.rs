@@ -0,0 +1,11 @@
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/trading.proto");
+        let file_descriptors = protox::compile(["proto/trading.proto"], ["proto"])
+            .expect("Failed to parse proto/trading.proto");
+        tonic_prost_build::configure()
+            .compile_fds(file_descriptors)
+            .expect("Failed to generate gRPC code");
+    }
+}
@@ -1,13 +1,32 @@
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, NaiveTime, Utc, Weekday};
+use chrono_tz::Tz;
 use rand::prelude::*;
-use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::io::{self, Write};
+use std::ops::Bound::{Excluded, Unbounded};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Instant;
 use uuid::Uuid;
 
+// Emits a tracing event when the `tracing` feature is on. Without it the
+// call expands to nothing, so its arguments are never evaluated.
+macro_rules! trace_event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)+);
+    };
+}
+
 // ===== DATA STRUCTURES =====
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum Side {
     Buy,
     Sell,
@@ -22,10 +41,168 @@ impl fmt::Display for Side {
     }
 }
 
+// Fixed-point quantity with eight decimal places, so fractional shares and
+// crypto lots match exactly. Signed so it can also hold net positions. The
+// operators saturate at the i64 bounds instead of wrapping or panicking; use
+// the checked_ methods where an out-of-range result must be rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct Quantity(i64);
+
+impl Quantity {
+    pub const SCALE: i64 = 100_000_000;
+    pub const ZERO: Quantity = Quantity(0);
+
+    pub const MAX: Quantity = Quantity(i64::MAX);
+
+    // Whole units, saturating past roughly 92 billion
+    pub fn from_units(units: i64) -> Self {
+        Quantity(units.saturating_mul(Self::SCALE))
+    }
+
+    pub fn checked_from_units(units: i64) -> Option<Self> {
+        units.checked_mul(Self::SCALE).map(Quantity)
+    }
+
+    pub fn from_raw(raw: i64) -> Self {
+        Quantity(raw)
+    }
+
+    pub fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub fn to_f64(self) -> f64 {
+        self.0 as f64 / Self::SCALE as f64
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    // Difference floored at zero, for draining a quantity against fills
+    pub fn saturating_sub(self, other: Quantity) -> Self {
+        Quantity(self.0.saturating_sub(other.0).max(0))
+    }
+
+    pub fn checked_add(self, other: Quantity) -> Option<Self> {
+        self.0.checked_add(other.0).map(Quantity)
+    }
+
+    pub fn checked_sub(self, other: Quantity) -> Option<Self> {
+        self.0.checked_sub(other.0).map(Quantity)
+    }
+
+    // Scales by a whole factor, such as a spread leg ratio
+    pub fn checked_mul(self, factor: i64) -> Option<Self> {
+        self.0.checked_mul(factor).map(Quantity)
+    }
+
+    pub fn abs(self) -> Self {
+        Quantity(self.0.saturating_abs())
+    }
+
+    // Whether this is a whole number of `step` increments
+    pub fn is_multiple_of(self, step: Quantity) -> bool {
+        step.0 > 0 && self.0 % step.0 == 0
+    }
+}
+
+impl From<u32> for Quantity {
+    fn from(units: u32) -> Self {
+        Quantity::from_units(units as i64)
+    }
+}
+
+impl std::ops::Add for Quantity {
+    type Output = Quantity;
+    fn add(self, other: Quantity) -> Quantity {
+        Quantity(self.0.saturating_add(other.0))
+    }
+}
+
+impl std::ops::Sub for Quantity {
+    type Output = Quantity;
+    fn sub(self, other: Quantity) -> Quantity {
+        Quantity(self.0.saturating_sub(other.0))
+    }
+}
+
+impl std::ops::Neg for Quantity {
+    type Output = Quantity;
+    fn neg(self) -> Quantity {
+        Quantity(self.0.saturating_neg())
+    }
+}
+
+impl std::ops::AddAssign for Quantity {
+    fn add_assign(&mut self, other: Quantity) {
+        *self = *self + other;
+    }
+}
+
+impl std::ops::SubAssign for Quantity {
+    fn sub_assign(&mut self, other: Quantity) {
+        *self = *self - other;
+    }
+}
+
+impl std::iter::Sum for Quantity {
+    fn sum<I: Iterator<Item = Quantity>>(iter: I) -> Quantity {
+        iter.fold(Quantity::ZERO, |total, q| total + q)
+    }
+}
+
+impl fmt::Display for Quantity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs() / Self::SCALE as u64;
+        let fraction = self.0.unsigned_abs() % Self::SCALE as u64;
+        let text = if fraction == 0 {
+            format!("{}{}", sign, units)
+        } else {
+            let digits = format!("{:08}", fraction);
+            format!("{}{}.{}", sign, units, digits.trim_end_matches('0'))
+        };
+        f.pad(&text)
+    }
+}
+
+impl std::str::FromStr for Quantity {
+    type Err = String;
+
+    // Parses a plain decimal such as "10" or "0.5"; more than eight decimal
+    // places is rejected rather than rounded
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid quantity: {}", text);
+        let (negative, digits) = match text.trim().strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, text.trim()),
+        };
+        let (units, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if units.is_empty() && fraction.is_empty() || fraction.len() > 8 {
+            return Err(invalid());
+        }
+        if !units.chars().chain(fraction.chars()).all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+
+        let units: i64 = if units.is_empty() { 0 } else { units.parse().map_err(|_| invalid())? };
+        let fraction: i64 = format!("{:0<8}", fraction).parse().map_err(|_| invalid())?;
+        let raw = units
+            .checked_mul(Self::SCALE)
+            .and_then(|raw| raw.checked_add(fraction))
+            .ok_or_else(invalid)?;
+        Ok(Quantity(if negative { -raw } else { raw }))
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub enum OrderType {
     Market,
     Limit,
+    MarketOnClose, // Held until the closing auction
+    Stop,          // Becomes a market order once the stop price trades
 }
 
 impl fmt::Display for OrderType {
@@ -33,19 +210,50 @@ impl fmt::Display for OrderType {
         match self {
             OrderType::Market => write!(f, "MARKET"),
             OrderType::Limit => write!(f, "LIMIT"),
+            OrderType::MarketOnClose => write!(f, "MOC"),
+            OrderType::Stop => write!(f, "STOP"),
         }
     }
 }
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Order {
     id: String,
     symbol: String,
     side: Side,
     order_type: OrderType,
-    quantity: u32,
-    price: Option<f64>,
+    quantity: Quantity,
+    price: Option<f64>, // None for market orders; any finite value is a legal limit
     timestamp: DateTime<Utc>,
+    last_look_reject_prob: Option<f64>, // Chance a resting order declines a fill (simulation only)
+    account: Option<String>,
+    client_order_id: Option<String>, // Caller's own id, unique per account, to dedupe retries
+    stop_price: Option<f64>,
+    sequence: u64, // Assigned by the order book on acceptance
+    // monotonic_nanos at acceptance. For latency only; queue ties go by sequence.
+    accepted_nanos: u64,
+    hidden: bool,  // Rests and matches but is left out of the public book view
+    expires_at: Option<DateTime<Utc>>, // Good-till-date; None means good-till-cancel
+    not_before: Option<DateTime<Utc>>, // Good-after-time; held inactive until then
+    immediate_or_cancel: bool, // Cancel any unfilled remainder instead of resting it
+    received_at: Option<DateTime<Utc>>, // Simulated exchange receipt time under order latency
+    quote_currency: Option<String>, // Currency of the prices; None means the book's own
+    fx_conversion: Option<FxConversion>, // Set when prices were converted on acceptance
+    improved_book: bool, // Set a new best price for its side when it came to rest
+    protected: bool,     // Quoting obligation, e.g. a designated market maker's; not cancellable
+    tags: Vec<(String, String)>, // Caller metadata, e.g. a strategy id; copied onto its fills
+    max_fill_per_interaction: Option<Quantity>, // Most a resting order fills before yielding
+}
+
+// Record of an order's prices being converted into its book's quote currency
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct FxConversion {
+    currency: String, // Currency the order was submitted in
+    rate: f64,        // Book quote currency per unit of `currency`
+    original_price: Option<f64>,
+    original_stop_price: Option<f64>,
 }
 
 impl Order {
@@ -53,7 +261,7 @@ impl Order {
         symbol: String,
         side: Side,
         order_type: OrderType,
-        quantity: u32,
+        quantity: Quantity,
         price: Option<f64>,
     ) -> Self {
         Order {
@@ -64,8 +272,137 @@ impl Order {
             quantity,
             price,
             timestamp: Utc::now(),
+            last_look_reject_prob: None,
+            account: None,
+            client_order_id: None,
+            stop_price: None,
+            sequence: 0,
+            accepted_nanos: 0,
+            hidden: false,
+            expires_at: None,
+            not_before: None,
+            immediate_or_cancel: false,
+            received_at: None,
+            quote_currency: None,
+            fx_conversion: None,
+            improved_book: false,
+            protected: false,
+            tags: Vec::new(),
+            max_fill_per_interaction: None,
         }
     }
+
+    pub fn immediate_or_cancel(mut self) -> Self {
+        self.immediate_or_cancel = true;
+        self
+    }
+
+    // Whether an unfilled remainder joins the book. A marketable limit order
+    // sweeps up to its limit and by default rests the rest at that limit;
+    // IOC limits and all market orders drop it instead.
+    fn rests(&self) -> bool {
+        self.order_type == OrderType::Limit && !self.immediate_or_cancel
+    }
+
+    // Most the order fills in one turn with `left` still unfilled
+    fn turn(&self, left: Quantity) -> Quantity {
+        self.max_fill_per_interaction.map_or(left, |cap| cap.min(left))
+    }
+
+    pub fn good_after(mut self, not_before: DateTime<Utc>) -> Self {
+        self.not_before = Some(not_before);
+        self
+    }
+
+    fn is_scheduled(&self, now: DateTime<Utc>) -> bool {
+        self.not_before.is_some_and(|not_before| not_before > now)
+    }
+
+    pub fn good_till(mut self, expires_at: DateTime<Utc>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // What a book digest records of a working order
+    fn digest_entry(&self) -> (&str, Quantity, Option<u64>, u64) {
+        (&self.id, self.quantity, self.price.map(f64::to_bits), self.sequence)
+    }
+
+    pub fn quoted_in(mut self, currency: &str) -> Self {
+        self.quote_currency = Some(currency.to_string());
+        self
+    }
+
+    // While resting, fill at most `max_fill` per turn against an incoming
+    // order before the rest of the level gets a turn. Batch auctions and the
+    // close give turns the same way.
+    pub fn with_max_fill_per_interaction(mut self, max_fill: Quantity) -> Self {
+        self.max_fill_per_interaction = Some(max_fill);
+        self
+    }
+
+    // Setting a key again replaces its value
+    pub fn with_tag(mut self, key: &str, value: &str) -> Self {
+        self.tags.retain(|(k, _)| k != key);
+        self.tags.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    // Whether the order set a new best price for its side when it came to rest
+    pub fn improved_book(&self) -> bool {
+        self.improved_book
+    }
+
+    pub fn accepted_nanos(&self) -> u64 {
+        self.accepted_nanos
+    }
+
+    pub fn protected(mut self) -> Self {
+        self.protected = true;
+        self
+    }
+
+    pub fn hidden(mut self) -> Self {
+        self.hidden = true;
+        self
+    }
+
+    pub fn new_with_client_id(
+        symbol: String,
+        side: Side,
+        order_type: OrderType,
+        quantity: Quantity,
+        price: Option<f64>,
+        client_order_id: &str,
+    ) -> Self {
+        let mut order = Order::new(symbol, side, order_type, quantity, price);
+        order.client_order_id = Some(client_order_id.to_string());
+        order
+    }
+
+    pub fn new_stop(symbol: String, side: Side, quantity: Quantity, stop_price: f64) -> Self {
+        let mut order = Order::new(symbol, side, OrderType::Stop, quantity, None);
+        order.stop_price = Some(stop_price);
+        order
+    }
+
+    pub fn with_account(mut self, account: &str) -> Self {
+        self.account = Some(account.to_string());
+        self
+    }
+
+    pub fn with_last_look(mut self, reject_prob: f64) -> Self {
+        self.last_look_reject_prob = Some(reject_prob);
+        self
+    }
 }
 
 impl fmt::Display for Order {
@@ -84,15 +421,55 @@ impl fmt::Display for Order {
     }
 }
 
+// Price move from the symbol's previous print
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum TickDirection {
+    Uptick,
+    Downtick,
+    #[default]
+    Zero, // Unchanged, and the first trade of a book
+}
+
+impl TickDirection {
+    fn between(previous: Option<f64>, price: f64) -> Self {
+        match previous {
+            Some(previous) if price > previous => TickDirection::Uptick,
+            Some(previous) if price < previous => TickDirection::Downtick,
+            _ => TickDirection::Zero,
+        }
+    }
+}
+
+impl fmt::Display for TickDirection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TickDirection::Uptick => write!(f, "UP"),
+            TickDirection::Downtick => write!(f, "DOWN"),
+            TickDirection::Zero => write!(f, "ZERO"),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
 pub struct Trade {
     id: String,
     symbol: String,
     buyer_order_id: String,
     seller_order_id: String,
-    quantity: u32,
+    buyer_account: Option<String>,
+    seller_account: Option<String>,
+    aggressor: Option<Side>, // Side of the incoming order; None for auction prints
+    quantity: Quantity,
     price: f64,
     timestamp: DateTime<Utc>,
+    publish_time: DateTime<Utc>, // When the print appears on the public tape
+    tick_direction: TickDirection,
+    mid_at_trade: f64, // Mid of the published quote the trade executed against
+    executed_nanos: u64, // monotonic_nanos at execution; less an order's accepted_nanos is latency
+    buyer_tags: Vec<(String, String)>, // Passed through from the buy order
+    seller_tags: Vec<(String, String)>, // Passed through from the sell order
 }
 
 impl fmt::Display for Trade {
@@ -105,520 +482,11174 @@ impl fmt::Display for Trade {
     }
 }
 
+impl Trade {
+    // Whether two prints describe the same execution. Trade and order ids are
+    // random per run, so replays are compared on the economics; timestamps
+    // only when asked, since they match only if both runs used a SimClock.
+    fn same_execution(&self, other: &Trade, compare_timestamps: bool) -> bool {
+        self.symbol == other.symbol
+            && self.buyer_account == other.buyer_account
+            && self.seller_account == other.seller_account
+            && self.aggressor == other.aggressor
+            && self.quantity == other.quantity
+            && self.price == other.price
+            && (!compare_timestamps || self.timestamp == other.timestamp)
+    }
+
+    pub fn executed_nanos(&self) -> u64 {
+        self.executed_nanos
+    }
+}
+
+// First point where a replayed trade stream departs from the recorded one.
+// A missing side means one stream ended before the other.
 #[derive(Debug, Clone)]
-pub struct MarketData {
-    symbol: String,
-    bid: f64,
-    ask: f64,
-    last_price: f64,
-    timestamp: DateTime<Utc>,
+pub struct Divergence {
+    index: usize,
+    expected: Option<Trade>,
+    actual: Option<Trade>,
 }
 
-impl fmt::Display for MarketData {
+impl fmt::Display for Divergence {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |trade: &Option<Trade>| match trade {
+            Some(t) => format!("{} x {} @ {:.2} at {}", t.symbol, t.quantity, t.price, t.timestamp),
+            None => "end of stream".to_string(),
+        };
         write!(
             f,
-            "{}: Bid: {:.2}, Ask: {:.2}, Last: {:.2}",
-            self.symbol, self.bid, self.ask, self.last_price
+            "Divergence at trade {}: expected {}, got {}",
+            self.index,
+            describe(&self.expected),
+            describe(&self.actual)
         )
     }
 }
 
-// ===== ORDER BOOK =====
+impl Divergence {
+    pub fn index(&self) -> usize {
+        self.index
+    }
 
-#[derive(Debug)]
-pub struct OrderBook {
-    symbol: String,
-    bids: VecDeque<Order>, // Sorted in descending order by price
-    asks: VecDeque<Order>, // Sorted in ascending order by price
-    trades: Vec<Trade>,
-    market_data: MarketData,
-}
+    pub fn expected(&self) -> Option<&Trade> {
+        self.expected.as_ref()
+    }
 
-impl OrderBook {
-    pub fn new(symbol: String, initial_price: f64) -> Self {
-        OrderBook {
-            symbol: symbol.clone(),
-            bids: VecDeque::new(),
-            asks: VecDeque::new(),
-            trades: Vec::new(),
-            market_data: MarketData {
-                symbol,
-                bid: initial_price * 0.99,
-                ask: initial_price * 1.01,
-                last_price: initial_price,
-                timestamp: Utc::now(),
-            },
-        }
+    pub fn actual(&self) -> Option<&Trade> {
+        self.actual.as_ref()
     }
+}
 
-    pub fn add_order(&mut self, mut order: Order) -> Vec<Trade> {
-        // For market orders, set the price to ensure best execution
-        if order.order_type == OrderType::Market {
-            match order.side {
-                Side::Buy => order.price = Some(f64::MAX), // Will match with the lowest ask
-                Side::Sell => order.price = Some(0.0),     // Will match with the highest bid
-            }
-        }
+// Compares a replayed session's trades against the recorded ones in sequence,
+// returning the first mismatch or None when the streams are identical
+pub fn compare_trade_streams(expected: &[Trade], actual: &[Trade]) -> Option<Divergence> {
+    first_divergence(expected, actual, false)
+}
 
-        let mut trades = Vec::new();
+// As compare_trade_streams, but execution times must match too. Only useful
+// when both sessions ran on a SimClock with the same start.
+pub fn compare_trade_streams_timed(expected: &[Trade], actual: &[Trade]) -> Option<Divergence> {
+    first_divergence(expected, actual, true)
+}
 
-        // Try to match the order
-        match order.side {
-            Side::Buy => {
-                while order.quantity > 0 && !self.asks.is_empty() {
-                    if let Some(ask) = self.asks.front() {
-                        // For limit orders, check if the price is acceptable
-                        if order.order_type == OrderType::Limit 
-                            && order.price.unwrap() < ask.price.unwrap() {
-                            break;
-                        }
-
-                        let trade_quantity = std::cmp::min(order.quantity, ask.quantity);
-                        let trade_price = ask.price.unwrap();
-
-                        // Create a trade
-                        let trade = Trade {
-                            id: Uuid::new_v4().to_string(),
-                            symbol: self.symbol.clone(),
-                            buyer_order_id: order.id.clone(),
-                            seller_order_id: ask.id.clone(),
-                            quantity: trade_quantity,
-                            price: trade_price,
-                            timestamp: Utc::now(),
-                        };
-
-                        trades.push(trade.clone());
-                        self.trades.push(trade);
-
-                        // Update market data
-                        self.market_data.last_price = trade_price;
-                        self.market_data.timestamp = Utc::now();
-
-                        // Update order quantity
-                        order.quantity -= trade_quantity;
-
-                        // Update the ask
-                        let mut ask = self.asks.pop_front().unwrap();
-                        ask.quantity -= trade_quantity;
-
-                        // If the ask still has quantity, put it back
-                        if ask.quantity > 0 {
-                            self.asks.push_front(ask);
-                        }
-                    }
-                }
+fn first_divergence(expected: &[Trade], actual: &[Trade], timed: bool) -> Option<Divergence> {
+    let len = expected.len().max(actual.len());
+    (0..len).find_map(|index| {
+        let (e, a) = (expected.get(index), actual.get(index));
+        match (e, a) {
+            (Some(e), Some(a)) if e.same_execution(a, timed) => None,
+            _ => Some(Divergence {
+                index,
+                expected: e.cloned(),
+                actual: a.cloned(),
+            }),
+        }
+    })
+}
 
-                // If the order is not fully filled, add it to the book
-                if order.quantity > 0 && order.order_type == OrderType::Limit {
-                    self.insert_bid(order);
-                    self.update_market_data();
-                }
-            }
-            Side::Sell => {
-                while order.quantity > 0 && !self.bids.is_empty() {
-                    if let Some(bid) = self.bids.front() {
-                        // For limit orders, check if the price is acceptable
-                        if order.order_type == OrderType::Limit 
-                            && order.price.unwrap() > bid.price.unwrap() {
-                            break;
-                        }
-
-                        let trade_quantity = std::cmp::min(order.quantity, bid.quantity);
-                        let trade_price = bid.price.unwrap();
-
-                        // Create a trade
-                        let trade = Trade {
-                            id: Uuid::new_v4().to_string(),
-                            symbol: self.symbol.clone(),
-                            buyer_order_id: bid.id.clone(),
-                            seller_order_id: order.id.clone(),
-                            quantity: trade_quantity,
-                            price: trade_price,
-                            timestamp: Utc::now(),
-                        };
-
-                        trades.push(trade.clone());
-                        self.trades.push(trade);
-
-                        // Update market data
-                        self.market_data.last_price = trade_price;
-                        self.market_data.timestamp = Utc::now();
-
-                        // Update order quantity
-                        order.quantity -= trade_quantity;
-
-                        // Update the bid
-                        let mut bid = self.bids.pop_front().unwrap();
-                        bid.quantity -= trade_quantity;
-
-                        // If the bid still has quantity, put it back
-                        if bid.quantity > 0 {
-                            self.bids.push_front(bid);
-                        }
-                    }
-                }
+// Aggregated (price, quantity) for one level of the book
+pub type PriceLevel = (f64, Quantity);
 
-                // If the order is not fully filled, add it to the book
-                if order.quantity > 0 && order.order_type == OrderType::Limit {
-                    self.insert_ask(order);
-                    self.update_market_data();
-                }
-            }
-        }
+#[derive(Debug, Clone)]
+pub struct MarketData {
+    symbol: String,
+    bid: f64,
+    ask: f64,
+    bid_size: Quantity, // Total resting at the best bid; zero when the side is empty
+    ask_size: Quantity,
+    last_price: f64,
+    mark_price: f64, // Valuation price from the book's MarkPriceMethod
+    timestamp: DateTime<Utc>,
+}
 
-        trades
+impl MarketData {
+    // Time since the quote last changed
+    pub fn age(&self, now: DateTime<Utc>) -> chrono::Duration {
+        now - self.timestamp
     }
 
-    fn insert_bid(&mut self, order: Order) {
-        let price = order.price.unwrap();
-        let mut idx = 0;
+    pub fn is_stale(&self, now: DateTime<Utc>, max_age: chrono::Duration) -> bool {
+        self.age(now) > max_age
+    }
 
-        // Find the position to insert (descending order by price)
-        while idx < self.bids.len() && self.bids[idx].price.unwrap() > price {
-            idx += 1;
+    // Quoted spread relative to the mid, in basis points. None while either
+    // side is empty or the mid is not positive, where the ratio means nothing.
+    pub fn spread_bps(&self) -> Option<f64> {
+        if self.bid_size.is_zero() || self.ask_size.is_zero() {
+            return None;
         }
+        let mid = (self.bid + self.ask) / 2.0;
+        (mid > 0.0).then(|| (self.ask - self.bid) / mid * 10_000.0)
+    }
+}
 
-        self.bids.insert(idx, order);
+impl fmt::Display for MarketData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: Bid: {:.2} x {}, Ask: {:.2} x {}, Last: {:.2}, Mark: {:.2}",
+            self.symbol,
+            self.bid,
+            self.bid_size,
+            self.ask,
+            self.ask_size,
+            self.last_price,
+            self.mark_price
+        )
     }
+}
 
-    fn insert_ask(&mut self, order: Order) {
-        let price = order.price.unwrap();
-        let mut idx = 0;
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub enum OrderError {
+    MarketNotFound(String),
+    MarketClosed(String),
+    OrderNotFound(String),
+    DuplicateClientOrderId { account: String, client_order_id: String },
+    OrderProtected(String),
+    TradeNotFound(String),
+    VenueNotFound(String),
+    InvalidSnapshot(String),
+    SymbolMismatch { expected: String, actual: String },
+    TradeThrough { venue: String, price: f64 },
+    Throttled { account: String, ratio: f64, max_ratio: f64 },
+    NoBorrowAvailable { symbol: String, requested: Quantity, available: Quantity },
+    InvalidQuantity { quantity: Quantity, step: Quantity },
+    QuantityTooLarge { symbol: String, quantity: Quantity, max_quantity: Quantity },
+    FxRateUnavailable { from: String, to: String },
+    OffTickPrice { price: f64, tick_size: f64 },
+    InvalidPrice(f64),
+    InvalidTickSize(f64),
+    InvalidCapacity(usize),
+    UnencodableSymbol(String),
+    MarketHalted { symbol: String, imbalance: Quantity },
+    MinRestTimeNotMet { order_id: String, remaining: chrono::Duration },
+    SpreadRejected(String),
+    PositionLimitExceeded {
+        account: String,
+        symbol: String,
+        exposure: Quantity,
+        max_position: Quantity,
+    },
+}
 
-        // Find the position to insert (ascending order by price)
-        while idx < self.asks.len() && self.asks[idx].price.unwrap() < price {
-            idx += 1;
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OrderError::MarketNotFound(symbol) => write!(f, "Market {} not found", symbol),
+            OrderError::MarketClosed(symbol) => write!(f, "Market {} is closed", symbol),
+            OrderError::OrderNotFound(order_id) => write!(f, "Order {} not found", order_id),
+            OrderError::DuplicateClientOrderId {
+                account,
+                client_order_id,
+            } => write!(
+                f,
+                "Client order id {} was already used by account {}",
+                client_order_id, account
+            ),
+            OrderError::OrderProtected(order_id) => {
+                write!(f, "Order {} is protected from cancellation", order_id)
+            }
+            OrderError::TradeNotFound(trade_id) => write!(f, "Trade {} not found", trade_id),
+            OrderError::VenueNotFound(venue) => write!(f, "Venue {} not found", venue),
+            OrderError::InvalidSnapshot(reason) => write!(f, "Invalid book snapshot: {}", reason),
+            OrderError::SymbolMismatch { expected, actual } => write!(
+                f,
+                "Order symbol {} does not match venue group {}",
+                actual, expected
+            ),
+            OrderError::TradeThrough { venue, price } => write!(
+                f,
+                "Order would trade through better price {:.2} on venue {}",
+                price, venue
+            ),
+            OrderError::Throttled {
+                account,
+                ratio,
+                max_ratio,
+            } => write!(
+                f,
+                "Account {} throttled: order-to-trade ratio {:.2} exceeds {:.2}",
+                account, ratio, max_ratio
+            ),
+            OrderError::NoBorrowAvailable {
+                symbol,
+                requested,
+                available,
+            } => write!(
+                f,
+                "No borrow available for {}: requested {}, available {}",
+                symbol, requested, available
+            ),
+            OrderError::InvalidQuantity { quantity, step } => write!(
+                f,
+                "Quantity {} is not a positive multiple of the step {}",
+                quantity, step
+            ),
+            OrderError::QuantityTooLarge {
+                symbol,
+                quantity,
+                max_quantity,
+            } => write!(
+                f,
+                "Order quantity {} exceeds the maximum {} for {}",
+                quantity, max_quantity, symbol
+            ),
+            OrderError::FxRateUnavailable { from, to } => {
+                write!(f, "No FX rate available from {} to {}", from, to)
+            }
+            OrderError::MarketHalted { symbol, imbalance } => write!(
+                f,
+                "Market {} is halted on an order imbalance of {}",
+                symbol, imbalance
+            ),
+            OrderError::SpreadRejected(reason) => write!(f, "Spread order rejected: {}", reason),
+            OrderError::MinRestTimeNotMet { order_id, remaining } => write!(
+                f,
+                "Order {} cannot be cancelled for another {}ms",
+                order_id,
+                remaining.num_milliseconds()
+            ),
+            OrderError::OffTickPrice { price, tick_size } => {
+                write!(f, "Price {} is not a multiple of the tick size {}", price, tick_size)
+            }
+            OrderError::InvalidPrice(price) => write!(f, "Price {} is not accepted", price),
+            OrderError::InvalidTickSize(tick_size) => {
+                write!(f, "Tick size {} must be positive and finite", tick_size)
+            }
+            OrderError::InvalidCapacity(capacity) => {
+                write!(f, "Capacity {} must be at least one", capacity)
+            }
+            OrderError::UnencodableSymbol(symbol) => {
+                write!(f, "Symbol {} does not fit in 8 ASCII bytes", symbol)
+            }
+            OrderError::PositionLimitExceeded {
+                account,
+                symbol,
+                exposure,
+                max_position,
+            } => write!(
+                f,
+                "Account {} position limit on {} exceeded: exposure {} above {}",
+                account, symbol, exposure, max_position
+            ),
         }
+    }
+}
+
+// ===== CLOCK =====
 
-        self.asks.insert(idx, order);
+// Time source for books and the engine. Everything that stamps or compares
+// times goes through a clock so backtests can replay history on SimClock.
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
     }
+}
 
-    fn update_market_data(&mut self) {
-        if !self.bids.is_empty() {
-            self.market_data.bid = self.bids[0].price.unwrap();
-        }
-        if !self.asks.is_empty() {
-            self.market_data.ask = self.asks[0].price.unwrap();
+// Nanoseconds since the first call in this process, strictly increasing
+// across all threads. Unlike the wall clock it never steps backwards and
+// tells apart events inside the same microsecond. It follows real time even
+// when books run on SimClock, so it suits latency measurement, not replay.
+pub fn monotonic_nanos() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let elapsed = START.get_or_init(Instant::now).elapsed().as_nanos() as u64;
+    let previous = LAST
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(elapsed.max(last + 1)))
+        .unwrap();
+    elapsed.max(previous + 1)
+}
+
+// Manually driven clock. Clones share the same time, so a handle kept by the
+// caller can advance the engine it was installed in.
+#[derive(Debug, Clone)]
+pub struct SimClock {
+    now: Arc<RwLock<DateTime<Utc>>>,
+}
+
+impl SimClock {
+    pub fn new(start: DateTime<Utc>) -> Self {
+        SimClock {
+            now: Arc::new(RwLock::new(start)),
         }
-        self.market_data.timestamp = Utc::now();
     }
 
-    pub fn get_market_data(&self) -> MarketData {
-        self.market_data.clone()
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.write().unwrap() = now;
     }
 
-    pub fn get_orders(&self) -> (Vec<Order>, Vec<Order>) {
-        (
-            self.bids.iter().cloned().collect(),
-            self.asks.iter().cloned().collect(),
-        )
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.write().unwrap() += by;
     }
+}
 
-    pub fn get_trades(&self) -> Vec<Trade> {
-        self.trades.clone()
+impl Clock for SimClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.read().unwrap()
     }
 }
 
-// ===== TRADING ENGINE =====
+// ===== TRADING SESSIONS =====
 
-pub struct TradingEngine {
-    order_books: HashMap<String, OrderBook>,
+// Regular trading hours expressed in the exchange's local timezone.
+// Converting through chrono-tz means the UTC open/close shift with DST.
+#[derive(Debug, Clone)]
+pub struct SessionSchedule {
+    timezone: Tz,
+    open: NaiveTime,
+    close: NaiveTime,
+    trading_days: Vec<Weekday>,
 }
 
-impl TradingEngine {
-    pub fn new() -> Self {
-        TradingEngine {
-            order_books: HashMap::new(),
+impl SessionSchedule {
+    pub fn new(timezone: Tz, open: NaiveTime, close: NaiveTime) -> Self {
+        SessionSchedule {
+            timezone,
+            open,
+            close,
+            trading_days: vec![
+                Weekday::Mon,
+                Weekday::Tue,
+                Weekday::Wed,
+                Weekday::Thu,
+                Weekday::Fri,
+            ],
         }
     }
 
-    pub fn create_market(&mut self, symbol: &str, initial_price: f64) {
-        let order_book = OrderBook::new(symbol.to_string(), initial_price);
-        self.order_books.insert(symbol.to_string(), order_book);
+    pub fn with_trading_days(mut self, trading_days: Vec<Weekday>) -> Self {
+        self.trading_days = trading_days;
+        self
     }
 
-    pub fn place_order(&mut self, order: Order) -> Result<Vec<Trade>, String> {
-        if let Some(order_book) = self.order_books.get_mut(&order.symbol) {
-            Ok(order_book.add_order(order))
-        } else {
-            Err(format!("Market {} not found", order.symbol))
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        if !self.trading_days.contains(&local.weekday()) {
+            return false;
         }
-    }
 
-    pub fn get_market_data(&self, symbol: &str) -> Option<MarketData> {
-        self.order_books.get(symbol).map(|ob| ob.get_market_data())
+        let time = local.time();
+        time >= self.open && time < self.close
     }
+}
 
-    pub fn get_orders(&self, symbol: &str) -> Option<(Vec<Order>, Vec<Order>)> {
-        self.order_books.get(symbol).map(|ob| ob.get_orders())
-    }
+// ===== ORDER BOOK =====
 
-    pub fn get_trades(&self, symbol: &str) -> Option<Vec<Trade>> {
-        self.order_books.get(symbol).map(|ob| ob.get_trades())
-    }
+// How orders at the same price are queued. Orders are placed in the queue
+// by this key when they rest; price always takes precedence. The rank is
+// fixed on arrival: under size priority an order that is partly filled or
+// reduced keeps its place rather than dropping behind larger orders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PriorityModel {
+    #[default]
+    TimeOnly,
+    DisplayedBeforeHidden, // Displayed orders ahead of hidden ones, time within each
+    SizePriority,          // Larger resting quantity first, time between equal sizes
+}
 
-    pub fn get_symbols(&self) -> Vec<String> {
-        self.order_books.keys().cloned().collect()
+// How an incoming order's quantity is split across the orders resting at a price
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchingAlgorithm {
+    #[default]
+    Fifo,    // Fill resting orders one at a time in queue order
+    ProRata, // Split each level in proportion to resting size
+}
+
+impl MatchingAlgorithm {
+    pub fn policy(self) -> Arc<dyn AllocationPolicy> {
+        match self {
+            MatchingAlgorithm::Fifo => Arc::new(FifoPolicy),
+            MatchingAlgorithm::ProRata => Arc::new(ProRataPolicy),
+        }
     }
+}
 
-    // Generate mock market data
-    pub fn populate_with_mock_data(&mut self) {
-        // Create some markets
-        let symbols = vec!["AAPL", "GOOGL", "MSFT", "AMZN", "TSLA"];
-        let prices = vec![150.0, 2800.0, 300.0, 3500.0, 750.0];
+// Decides who gets filled at one price level. `level` holds the resting
+// orders eligible to trade, in queue order, after last look has excused any
+// makers declining the fill. The result says how much of `incoming` each
+// receives, in the order their trades should print. The book caps each
+// share at what both sides have left and skips orders not in `level`.
+pub trait AllocationPolicy: fmt::Debug + Send + Sync {
+    fn allocate(&self, level: &[&Order], incoming: Quantity) -> Vec<(String, Quantity)>;
+}
 
-        for (&symbol, &price) in symbols.iter().zip(prices.iter()) {
-            self.create_market(symbol, price);
-            self.generate_mock_orders(symbol, price);
+// Fills resting orders one at a time in queue order. An order with a
+// per-interaction cap fills up to the cap and then yields to the orders
+// behind it; the level is walked round-robin until the incoming order or the
+// level runs out, so a capped order may appear more than once.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FifoPolicy;
+
+impl FifoPolicy {
+    // The allocation as positions in `level`. Auctions and the close use it
+    // directly so capped orders take turns there too.
+    fn turns(level: &[&Order], incoming: Quantity) -> Vec<(usize, Quantity)> {
+        let mut remaining = incoming;
+        let mut unfilled: Vec<Quantity> = level.iter().map(|o| o.quantity).collect();
+        let mut turns = Vec::new();
+        while !remaining.is_zero() {
+            let before = remaining;
+            for (i, (order, left)) in level.iter().zip(unfilled.iter_mut()).enumerate() {
+                if remaining.is_zero() {
+                    break;
+                }
+                let quantity = remaining.min(order.turn(*left));
+                if quantity > Quantity::ZERO {
+                    turns.push((i, quantity));
+                    remaining -= quantity;
+                    *left -= quantity;
+                }
+            }
+            // A pass that fills nothing means the level is used up
+            if remaining == before {
+                break;
+            }
         }
+        turns
     }
+}
 
-    fn generate_mock_orders(&mut self, symbol: &str, price: f64) {
-        let mut rng = rand::thread_rng();
-        
-        // Generate some buy orders
-        for _ in 0..10 {
-            let price_offset = rng.gen_range(-0.05..0.0);
-            let order_price = price * (1.0 + price_offset);
-            let quantity = rng.gen_range(10..100);
-            
-            let order = Order::new(
-                symbol.to_string(),
-                Side::Buy,
-                OrderType::Limit,
-                quantity,
-                Some(order_price),
-            );
-            
-            let _ = self.place_order(order);
+impl AllocationPolicy for FifoPolicy {
+    fn allocate(&self, level: &[&Order], incoming: Quantity) -> Vec<(String, Quantity)> {
+        Self::turns(level, incoming)
+            .into_iter()
+            .map(|(i, quantity)| (level[i].id.clone(), quantity))
+            .collect()
+    }
+}
+
+// Splits the fill in proportion to resting size, rounding every share down.
+// The indivisible remainder is then handed out one raw unit at a time to the
+// largest resting order first, with equal sizes going to the earlier
+// sequence, cycling until the remainder is used up. An order with a
+// per-interaction cap takes at most the cap per round and its excess is
+// split over the others; rounds repeat while the incoming order has quantity
+// left, so a capped order may appear more than once.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProRataPolicy;
+
+impl ProRataPolicy {
+    // One round: `fill` shared in proportion to `weights`, no order taking
+    // more than its `turns` entry. `fill` must not exceed the turns' total.
+    fn split(level: &[&Order], weights: &[i128], turns: &[i128], fill: i128) -> Vec<i128> {
+        let mut shares = vec![0; level.len()];
+        let mut open: Vec<usize> = (0..level.len()).filter(|&i| turns[i] > 0).collect();
+        let mut unassigned = fill;
+        // Orders whose share reaches their turn are settled at the turn and
+        // the rest re-split among the others
+        loop {
+            let weight: i128 = open.iter().map(|&i| weights[i]).sum();
+            if weight == 0 {
+                break;
+            }
+            let (full, partial): (Vec<usize>, Vec<usize>) = open
+                .iter()
+                .partition(|&&i| unassigned * weights[i] / weight >= turns[i]);
+            if full.is_empty() {
+                let split = unassigned;
+                for &i in &partial {
+                    shares[i] = split * weights[i] / weight;
+                    unassigned -= shares[i];
+                }
+                break;
+            }
+            for &i in &full {
+                shares[i] = turns[i];
+                unassigned -= turns[i];
+            }
+            open = partial;
         }
-        
-        // Generate some sell orders
-        for _ in 0..10 {
-            let price_offset = rng.gen_range(0.0..0.05);
-            let order_price = price * (1.0 + price_offset);
-            let quantity = rng.gen_range(10..100);
-            
-            let order = Order::new(
-                symbol.to_string(),
-                Side::Sell,
-                OrderType::Limit,
-                quantity,
-                Some(order_price),
-            );
-            
-            let _ = self.place_order(order);
+
+        let mut ranked: Vec<usize> = (0..level.len()).collect();
+        ranked.sort_by(|&a, &b| {
+            let (a, b) = (level[a], level[b]);
+            b.quantity.cmp(&a.quantity).then(a.sequence.cmp(&b.sequence))
+        });
+        while unassigned > 0 {
+            for &r in &ranked {
+                if unassigned > 0 && shares[r] < turns[r] {
+                    shares[r] += 1;
+                    unassigned -= 1;
+                }
+            }
         }
+        shares
     }
 }
 
-// ===== CLI =====
+impl AllocationPolicy for ProRataPolicy {
+    fn allocate(&self, level: &[&Order], incoming: Quantity) -> Vec<(String, Quantity)> {
+        // Allocation works in raw units, so the remainder is handed out in the
+        // smallest representable quantity rather than whole shares
+        let mut unfilled: Vec<i128> = level.iter().map(|o| o.quantity.raw() as i128).collect();
+        let mut remaining = incoming.raw() as i128;
+        let mut allocations = Vec::new();
+        while remaining > 0 {
+            let turns: Vec<i128> = level
+                .iter()
+                .zip(&unfilled)
+                .map(|(o, &left)| o.turn(Quantity::from_raw(left as i64)).raw() as i128)
+                .collect();
+            let fill = remaining.min(turns.iter().sum());
+            if fill == 0 {
+                break;
+            }
+            let shares = Self::split(level, &unfilled, &turns, fill);
+            for (i, share) in shares.into_iter().enumerate().filter(|(_, s)| *s > 0) {
+                allocations.push((level[i].id.clone(), Quantity::from_raw(share as i64)));
+                unfilled[i] -= share;
+            }
+            remaining -= fill;
+        }
+        allocations
+    }
+}
 
-fn print_menu() {
-    println!("\n=== TRADING SYSTEM CLI ===");
-    println!("1. View available markets");
-    println!("2. View market data");
-    println!("3. View order book");
-    println!("4. View recent trades");
-    println!("5. Place limit order");
-    println!("6. Place market order");
-    println!("7. Generate more mock data");
-    println!("8. Exit");
-    print!("Select an option: ");
-    io::stdout().flush().unwrap();
+// Decides whether a resting order with a last-look window declines a fill.
+// Only consulted for makers that carry a rejection probability; injected so
+// a simulation can replay on a seeded generator or script the decisions.
+pub trait LastLook: fmt::Debug + Send + Sync {
+    fn rejects(&mut self, maker: &Order, reject_prob: f64) -> bool;
 }
 
-fn read_line() -> String {
-    let mut input = String::new();
-    io::stdin().read_line(&mut input).expect("Failed to read input");
-    input.trim().to_string()
+// Rolls each maker's rejection probability on its own generator
+#[derive(Debug)]
+pub struct RandomLastLook {
+    rng: StdRng,
 }
 
-fn view_markets(engine: &TradingEngine) {
-    println!("\n=== AVAILABLE MARKETS ===");
-    for symbol in engine.get_symbols() {
-        if let Some(market_data) = engine.get_market_data(&symbol) {
-            println!("{}", market_data);
-        } else {
-            println!("{}: No market data available", symbol);
+impl RandomLastLook {
+    pub fn new(seed: u64) -> Self {
+        RandomLastLook {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn from_entropy() -> Self {
+        RandomLastLook {
+            rng: StdRng::from_entropy(),
         }
     }
 }
 
-fn view_market_data(engine: &TradingEngine) {
-    print!("Enter symbol: ");
-    io::stdout().flush().unwrap();
-    let symbol = read_line();
-    
-    if let Some(market_data) = engine.get_market_data(&symbol) {
-        println!("\n=== MARKET DATA FOR {} ===", symbol);
-        println!("{}", market_data);
-    } else {
-        println!("Market {} not found", symbol);
+impl LastLook for RandomLastLook {
+    fn rejects(&mut self, _maker: &Order, reject_prob: f64) -> bool {
+        self.rng.gen_bool(reject_prob.clamp(0.0, 1.0))
     }
 }
 
-fn view_order_book(engine: &TradingEngine) {
-    print!("Enter symbol: ");
-    io::stdout().flush().unwrap();
-    let symbol = read_line();
-    
-    if let Some((bids, asks)) = engine.get_orders(&symbol) {
-        println!("\n=== ORDER BOOK FOR {} ===", symbol);
-        
-        println!("BIDS:");
-        for bid in bids {
-            println!("  {}", bid);
-        }
-        
-        println!("ASKS:");
-        for ask in asks {
-            println!("  {}", ask);
+// Whether orders match on arrival or accumulate for a periodic uniform-price
+// auction. Batch boundaries fall on multiples of the interval since the epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MatchingMode {
+    #[default]
+    Continuous,
+    BatchAuction(chrono::Duration),
+}
+
+// How the mark price used for valuation is derived. An external reference
+// price, when one is set, overrides every method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MarkPriceMethod {
+    Last,
+    #[default]
+    Mid,                              // Falls back to last when a side is empty
+    TimeWeightedMid(chrono::Duration), // Mid averaged over the trailing window
+}
+
+// Price a crossing order fills at. Real venues use the resting order's
+// price; the taker's own limit gives a worst-case fill for simulations.
+// Market orders have no limit and always fill at the maker price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FillPriceSide {
+    #[default]
+    MakerPrice,
+    TakerPrice,
+}
+
+impl FillPriceSide {
+    fn price(&self, taker: &Order, maker_price: f64) -> f64 {
+        match self {
+            FillPriceSide::MakerPrice => maker_price,
+            FillPriceSide::TakerPrice => taker.price.unwrap_or(maker_price),
         }
+    }
+}
+
+// Which way an off-tick execution price is moved onto the tick grid. Prints
+// without an aggressor, such as auction fills, always round to nearest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TradeRounding {
+    #[default]
+    Nearest,
+    TowardMaker, // Price improvement goes to the resting side
+    TowardTaker, // Price improvement goes to the aggressor
+}
+
+// Tiered tick regime keyed on price magnitude, for markets created without
+// an explicit tick size
+pub fn auto_tick_size(price: f64) -> f64 {
+    match price.abs() {
+        p if p >= 1000.0 => 0.1,
+        p if p >= 1.0 => 0.01,
+        _ => 0.0001,
+    }
+}
+
+fn is_on_tick(price: f64, tick_size: f64) -> bool {
+    let ticks = price / tick_size;
+    (ticks - ticks.round()).abs() < 1e-9
+}
+
+// Moves an off-grid price to the next tick up or down
+fn round_to_tick(price: f64, tick_size: f64, up: bool) -> f64 {
+    let ticks = price / tick_size;
+    if is_on_tick(price, tick_size) {
+        ticks.round() * tick_size
+    } else if up {
+        ticks.ceil() * tick_size
     } else {
-        println!("Market {} not found", symbol);
+        ticks.floor() * tick_size
     }
 }
 
-fn view_trades(engine: &TradingEngine) {
-    print!("Enter symbol: ");
-    io::stdout().flush().unwrap();
-    let symbol = read_line();
-    
-    if let Some(trades) = engine.get_trades(&symbol) {
-        println!("\n=== RECENT TRADES FOR {} ===", symbol);
-        
-        if trades.is_empty() {
-            println!("No trades yet");
+impl TradeRounding {
+    fn apply(&self, price: f64, tick_size: f64, aggressor: Option<Side>) -> f64 {
+        let ticks = price / tick_size;
+        let nearest = ticks.round();
+        // Absorb float noise so prices already on the grid stay put
+        if is_on_tick(price, tick_size) {
+            return nearest * tick_size;
+        }
+        // A buying taker pays more when the maker is favoured
+        let round_up = match (self, aggressor) {
+            (TradeRounding::Nearest, _) | (_, None) => return nearest * tick_size,
+            (TradeRounding::TowardMaker, Some(side)) => side == Side::Buy,
+            (TradeRounding::TowardTaker, Some(side)) => side == Side::Sell,
+        };
+        if round_up {
+            ticks.ceil() * tick_size
         } else {
-            for trade in trades.iter().rev().take(10) {
-                println!("{}", trade);
-            }
+            ticks.floor() * tick_size
         }
-    } else {
-        println!("Market {} not found", symbol);
     }
 }
 
-fn place_limit_order(engine: &mut TradingEngine) {
-    print!("Enter symbol: ");
-    io::stdout().flush().unwrap();
-    let symbol = read_line();
-    
-    print!("Side (buy/sell): ");
-    io::stdout().flush().unwrap();
-    let side_input = read_line().to_lowercase();
-    let side = if side_input == "buy" { Side::Buy } else { Side::Sell };
-    
-    print!("Quantity: ");
-    io::stdout().flush().unwrap();
-    let quantity: u32 = read_line().parse().unwrap_or(0);
-    
-    print!("Limit price: ");
-    io::stdout().flush().unwrap();
-    let price: f64 = read_line().parse().unwrap_or(0.0);
-    
-    if quantity == 0 || price == 0.0 {
-        println!("Invalid quantity or price");
-        return;
+impl PriorityModel {
+    // Whether an incoming order should queue ahead of a resting one at the same price
+    fn ranks_ahead(&self, incoming: &Order, resting: &Order) -> bool {
+        match self {
+            PriorityModel::TimeOnly => false,
+            PriorityModel::DisplayedBeforeHidden => !incoming.hidden && resting.hidden,
+            PriorityModel::SizePriority => incoming.quantity > resting.quantity,
+        }
     }
-    
-    let order = Order::new(
-        symbol,
-        side,
-        OrderType::Limit,
-        quantity,
-        Some(price),
-    );
-    
-    match engine.place_order(order.clone()) {
-        Ok(trades) => {
-            println!("Order placed: {}", order);
-            if !trades.is_empty() {
-                println!("Trades executed:");
-                for trade in trades {
-                    println!("  {}", trade);
-                }
-            }
+}
+
+// Large prints are kept off the public tape for `delay` after execution
+#[derive(Debug, Clone, Copy)]
+pub struct DeferredPublication {
+    min_quantity: Quantity,
+    delay: chrono::Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct BookConfig {
+    priority_model: PriorityModel,
+    deferred_publication: Option<DeferredPublication>,
+    contract_multiplier: f64, // Units of the underlying per quantity unit; 1 for equities
+    matching_algorithm: MatchingAlgorithm,
+    quantity_step: Quantity, // Minimum increment for order sizes; one whole unit by default
+    max_orders_per_side: Option<usize>, // Resting order cap per side; None is unlimited
+    matching_mode: MatchingMode,
+    mark_price_method: MarkPriceMethod,
+    fill_price_side: FillPriceSide,
+    quote_currency: String, // Currency prices are held in; other currencies are converted
+    imbalance_halt_threshold: Option<Quantity>, // Resting buy/sell gap that halts the market
+    market_order_collar_pct: Option<f64>, // Market orders stop this fraction past the last price
+    max_order_quantity: Option<Quantity>, // Fat-finger cap on a single order; None is unlimited
+    min_rest_time: Option<chrono::Duration>, // Anti-flicker: time an order must rest before cancel
+    tick_size: Option<f64>, // Price grid for orders and trade prints; None allows any price
+    trade_rounding: TradeRounding,
+    reject_zero_price: bool, // Treat a 0.0 limit as a client error; zero is legal by default
+}
+
+impl Default for BookConfig {
+    fn default() -> Self {
+        BookConfig {
+            priority_model: PriorityModel::default(),
+            deferred_publication: None,
+            contract_multiplier: 1.0,
+            matching_algorithm: MatchingAlgorithm::default(),
+            quantity_step: Quantity::from_units(1),
+            max_orders_per_side: None,
+            matching_mode: MatchingMode::default(),
+            mark_price_method: MarkPriceMethod::default(),
+            fill_price_side: FillPriceSide::default(),
+            quote_currency: "USD".to_string(),
+            imbalance_halt_threshold: None,
+            market_order_collar_pct: None,
+            max_order_quantity: None,
+            min_rest_time: None,
+            tick_size: None,
+            trade_rounding: TradeRounding::default(),
+            reject_zero_price: false,
         }
-        Err(e) => println!("Error: {}", e),
     }
 }
 
-fn place_market_order(engine: &mut TradingEngine) {
-    print!("Enter symbol: ");
-    io::stdout().flush().unwrap();
-    let symbol = read_line();
-    
-    print!("Side (buy/sell): ");
-    io::stdout().flush().unwrap();
-    let side_input = read_line().to_lowercase();
-    let side = if side_input == "buy" { Side::Buy } else { Side::Sell };
-    
-    print!("Quantity: ");
-    io::stdout().flush().unwrap();
-    let quantity: u32 = read_line().parse().unwrap_or(0);
-    
-    if quantity == 0 {
-        println!("Invalid quantity");
-        return;
+impl BookConfig {
+    pub fn with_imbalance_halt(mut self, threshold: Quantity) -> Self {
+        self.imbalance_halt_threshold = Some(threshold);
+        self
     }
-    
-    let order = Order::new(
-        symbol,
-        side,
-        OrderType::Market,
-        quantity,
-        None,
-    );
-    
-    match engine.place_order(order.clone()) {
-        Ok(trades) => {
-            println!("Order placed: {}", order);
-            if !trades.is_empty() {
-                println!("Trades executed:");
-                for trade in trades {
-                    println!("  {}", trade);
-                }
-            } else {
-                println!("No trades executed. No matching orders in the book.");
-            }
+
+    // e.g. 0.05 keeps market orders within 5% of the last price; the rest is cancelled
+    pub fn with_market_order_collar(mut self, collar_pct: f64) -> Self {
+        self.market_order_collar_pct = Some(collar_pct);
+        self
+    }
+
+    pub fn with_fill_price_side(mut self, fill_price_side: FillPriceSide) -> Self {
+        self.fill_price_side = fill_price_side;
+        self
+    }
+
+    pub fn with_quote_currency(mut self, quote_currency: &str) -> Self {
+        self.quote_currency = quote_currency.to_string();
+        self
+    }
+
+    pub fn with_min_rest_time(mut self, min_rest_time: chrono::Duration) -> Self {
+        self.min_rest_time = Some(min_rest_time);
+        self
+    }
+
+    pub fn with_max_order_quantity(mut self, max_order_quantity: Quantity) -> Self {
+        self.max_order_quantity = Some(max_order_quantity);
+        self
+    }
+
+    // For feeds where an unset price field arrives as 0.0 rather than being absent
+    pub fn with_zero_price_rejected(mut self) -> Self {
+        self.reject_zero_price = true;
+        self
+    }
+
+    // Prices are divided by the tick size, so zero, negative and NaN are refused
+    pub fn with_tick_size(
+        mut self,
+        tick_size: f64,
+        rounding: TradeRounding,
+    ) -> Result<Self, OrderError> {
+        if !(tick_size > 0.0 && tick_size.is_finite()) {
+            return Err(OrderError::InvalidTickSize(tick_size));
         }
-        Err(e) => println!("Error: {}", e),
+        self.tick_size = Some(tick_size);
+        self.trade_rounding = rounding;
+        Ok(self)
+    }
+
+    pub fn with_mark_price_method(mut self, mark_price_method: MarkPriceMethod) -> Self {
+        self.mark_price_method = mark_price_method;
+        self
+    }
+
+    pub fn with_matching_mode(mut self, matching_mode: MatchingMode) -> Self {
+        self.matching_mode = matching_mode;
+        self
+    }
+
+    pub fn with_max_orders_per_side(mut self, max_orders: usize) -> Self {
+        self.max_orders_per_side = Some(max_orders);
+        self
+    }
+
+    pub fn with_quantity_step(mut self, quantity_step: Quantity) -> Self {
+        self.quantity_step = quantity_step;
+        self
+    }
+
+    pub fn with_matching_algorithm(mut self, matching_algorithm: MatchingAlgorithm) -> Self {
+        self.matching_algorithm = matching_algorithm;
+        self
+    }
+
+    pub fn with_contract_multiplier(mut self, contract_multiplier: f64) -> Self {
+        self.contract_multiplier = contract_multiplier;
+        self
+    }
+
+    pub fn with_deferred_publication(
+        mut self,
+        min_quantity: Quantity,
+        delay: chrono::Duration,
+    ) -> Self {
+        self.deferred_publication = Some(DeferredPublication {
+            min_quantity,
+            delay,
+        });
+        self
+    }
+
+    pub fn with_priority_model(mut self, priority_model: PriorityModel) -> Self {
+        self.priority_model = priority_model;
+        self
     }
 }
 
-fn generate_more_mock_data(engine: &mut TradingEngine) {
-    for symbol in engine.get_symbols() {
-        if let Some(market_data) = engine.get_market_data(&symbol) {
-            engine.generate_mock_orders(&symbol, market_data.last_price);
-        }
+// Resource use of one book. Byte counts are estimates: struct sizes plus
+// the heap text each order and trade owns, ignoring allocator overhead.
+#[derive(Debug, Clone, Default)]
+pub struct BookMemoryStats {
+    bid_orders: usize,
+    ask_orders: usize,
+    parked_orders: usize, // Stops, MOC, scheduled, batch and evicted orders off the book
+    trades: usize,
+    session_history: usize,
+    estimated_bytes: usize,
+}
+
+impl BookMemoryStats {
+    pub fn resting_orders(&self) -> usize {
+        self.bid_orders + self.ask_orders
+    }
+
+    pub fn session_history(&self) -> usize {
+        self.session_history
+    }
+
+    pub fn estimated_bytes(&self) -> usize {
+        self.estimated_bytes
     }
-    println!("Generated additional mock orders for all markets");
 }
 
-fn main() {
-    let mut engine = TradingEngine::new();
-    engine.populate_with_mock_data();
-    
-    loop {
-        print_menu();
-        let choice = read_line();
-        
-        match choice.as_str() {
-            "1" => view_markets(&engine),
-            "2" => view_market_data(&engine),
-            "3" => view_order_book(&engine),
-            "4" => view_trades(&engine),
-            "5" => place_limit_order(&mut engine),
-            "6" => place_market_order(&mut engine),
-            "7" => generate_more_mock_data(&mut engine),
-            "8" => {
-                println!("Exiting...");
+// Engine-wide totals with the per-book breakdown, sorted by symbol
+#[derive(Debug, Clone, Default)]
+pub struct MemoryReport {
+    books: Vec<(String, BookMemoryStats)>,
+    resting_orders: usize,
+    parked_orders: usize,
+    trades: usize,
+    estimated_bytes: usize,
+}
+
+// Where the batch auction would clear if it ran now
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuctionState {
+    price: f64,
+    matched_quantity: Quantity,
+    imbalance: Quantity, // Unmatched demand minus supply at the price; negative is a sell surplus
+}
+
+// Trading summary for one session of a book
+#[derive(Debug, Clone)]
+pub struct SessionStats {
+    started_at: DateTime<Utc>,
+    ended_at: Option<DateTime<Utc>>, // None while the session is live
+    trade_count: usize,
+    volume: Quantity,
+    high: Option<f64>,
+    low: Option<f64>,
+    vwap: Option<f64>,
+}
+
+impl SessionStats {
+    pub fn started_at(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    pub fn ended_at(&self) -> Option<DateTime<Utc>> {
+        self.ended_at
+    }
+
+    pub fn trade_count(&self) -> usize {
+        self.trade_count
+    }
+
+    pub fn volume(&self) -> Quantity {
+        self.volume
+    }
+
+    pub fn high(&self) -> Option<f64> {
+        self.high
+    }
+
+    pub fn low(&self) -> Option<f64> {
+        self.low
+    }
+
+    pub fn vwap(&self) -> Option<f64> {
+        self.vwap
+    }
+}
+
+// Outcome of OrderBook::process_time_events
+#[derive(Debug, Clone, Default)]
+pub struct TimeEvents {
+    expired: Vec<Order>,
+    trades: Vec<Trade>, // Fills from orders that activated
+}
+
+impl TimeEvents {
+    pub fn expired(&self) -> &[Order] {
+        &self.expired
+    }
+
+    pub fn trades(&self) -> &[Trade] {
+        &self.trades
+    }
+}
+
+// Price with a total order so it can key a heap or a map. Negative zero is
+// folded into zero so the two land on the same key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PriceKey(f64);
+
+impl PriceKey {
+    fn new(price: f64) -> Self {
+        PriceKey(price + 0.0)
+    }
+}
+
+impl Eq for PriceKey {}
+
+impl PartialOrd for PriceKey {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PriceKey {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Order-insensitive digest of a collection: entry hashes are summed, so an
+// entry is added or taken back out in O(1) as the collection changes
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct SetDigest(u64);
+
+impl SetDigest {
+    fn entry_hash(entry: impl Hash) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        entry.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn add(&mut self, entry: impl Hash) {
+        self.0 = self.0.wrapping_add(Self::entry_hash(entry));
+    }
+
+    fn remove(&mut self, entry: impl Hash) {
+        self.0 = self.0.wrapping_sub(Self::entry_hash(entry));
+    }
+
+    // Swaps the entry for `key` from its old value to its new one. Zero
+    // values are left out, so an unset key and one set back to zero agree.
+    fn replace<V: Hash + Default + PartialEq>(&mut self, key: impl Hash, old: V, new: V) {
+        if old != V::default() {
+            self.remove((&key, old));
+        }
+        if new != V::default() {
+            self.add((&key, new));
+        }
+    }
+}
+
+// Pending stops held by acceptance sequence, with a heap per direction so a
+// price move only visits the stops it fires. Buy stops sit in a min-heap on
+// stop price, sell stops in a max-heap. Removing a stop leaves its heap entry
+// behind; stale entries are skipped when popped and compacted away once
+// they outnumber the live stops.
+#[derive(Debug, Default)]
+struct StopIndex {
+    orders: BTreeMap<u64, Order>, // sequence -> stop
+    sequences: HashMap<String, u64>, // order id -> sequence
+    buys: BinaryHeap<Reverse<(PriceKey, u64)>>,
+    sells: BinaryHeap<(PriceKey, Reverse<u64>)>,
+    digest: SetDigest,
+}
+
+impl StopIndex {
+    fn push(&mut self, stop: Order) {
+        let price = PriceKey::new(stop.stop_price.unwrap());
+        match stop.side {
+            Side::Buy => self.buys.push(Reverse((price, stop.sequence))),
+            Side::Sell => self.sells.push((price, Reverse(stop.sequence))),
+        }
+        self.digest.add(stop.digest_entry());
+        self.sequences.insert(stop.id.clone(), stop.sequence);
+        self.orders.insert(stop.sequence, stop);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.orders.is_empty()
+    }
+
+    // In acceptance order
+    fn iter(&self) -> impl Iterator<Item = &Order> {
+        self.orders.values()
+    }
+
+    // Empties the index, returning the stops in acceptance order
+    fn drain(&mut self) -> Vec<Order> {
+        self.buys.clear();
+        self.sells.clear();
+        self.sequences.clear();
+        self.digest = SetDigest::default();
+        std::mem::take(&mut self.orders).into_values().collect()
+    }
+
+    fn take(&mut self, sequence: u64) -> Option<Order> {
+        let stop = self.orders.remove(&sequence)?;
+        self.sequences.remove(&stop.id);
+        self.digest.remove(stop.digest_entry());
+        Some(stop)
+    }
+
+    fn remove(&mut self, order_id: &str) -> Option<Order> {
+        let sequence = *self.sequences.get(order_id)?;
+        let removed = self.take(sequence);
+        self.compact();
+        removed
+    }
+
+    fn remove_where(&mut self, mut predicate: impl FnMut(&Order) -> bool) -> Vec<Order> {
+        let sequences: Vec<u64> =
+            self.orders.values().filter(|o| predicate(o)).map(|o| o.sequence).collect();
+        let removed = sequences.iter().filter_map(|&seq| self.take(seq)).collect();
+        self.compact();
+        removed
+    }
+
+    // Removes the stops whose trigger lies within [low, high] in activation
+    // order: buys by ascending stop price, then sells by descending stop
+    // price, ties by sequence
+    fn take_triggered(&mut self, low: f64, high: f64) -> Vec<Order> {
+        let mut triggered = Vec::new();
+        while let Some(&Reverse((price, sequence))) = self.buys.peek() {
+            if price.0 > high {
                 break;
             }
-            _ => println!("Invalid option"),
+            self.buys.pop();
+            triggered.extend(self.take(sequence));
+        }
+        while let Some(&(price, Reverse(sequence))) = self.sells.peek() {
+            if price.0 < low {
+                break;
+            }
+            self.sells.pop();
+            triggered.extend(self.take(sequence));
+        }
+        triggered
+    }
+
+    fn compact(&mut self) {
+        if self.buys.len() + self.sells.len() <= 2 * self.orders.len() {
+            return;
+        }
+        let orders = &self.orders;
+        self.buys.retain(|Reverse((_, sequence))| orders.contains_key(sequence));
+        self.sells.retain(|(_, Reverse(sequence))| orders.contains_key(sequence));
+    }
+}
+
+// One slot of an order side's arena: a resting order and its neighbours in
+// the queue at its price
+#[derive(Debug)]
+struct Slot {
+    order: Order,
+    prev: Option<usize>,
+    next: Option<usize>,
+}
+
+// Front and back slots of a price level's queue
+#[derive(Debug, Clone, Copy)]
+struct Level {
+    head: usize,
+    tail: usize,
+}
+
+// Resting orders on one side of the book. Orders live in an arena whose
+// freed slots are reused, and each price level is a doubly linked queue
+// threaded through the slots, so a fill or cancel unlinks its order in place
+// rather than shifting everything queued behind it. Levels are keyed best
+// first (bid prices are negated) and an id index finds an order's slot
+// directly. Order ids are unique, being generated when an order is created.
+#[derive(Debug)]
+struct OrderSide {
+    side: Side,
+    slots: Vec<Option<Slot>>,
+    free: Vec<usize>,
+    levels: BTreeMap<PriceKey, Level>,
+    index: HashMap<String, usize>,
+    digest: SetDigest, // Kept up to date as orders come, change and go
+}
+
+impl OrderSide {
+    fn new(side: Side) -> Self {
+        OrderSide {
+            side,
+            slots: Vec::new(),
+            free: Vec::new(),
+            levels: BTreeMap::new(),
+            index: HashMap::new(),
+            digest: SetDigest::default(),
+        }
+    }
+
+    fn key(&self, price: f64) -> PriceKey {
+        match self.side {
+            Side::Buy => PriceKey::new(-price),
+            Side::Sell => PriceKey::new(price),
+        }
+    }
+
+    fn slot(&self, idx: usize) -> &Slot {
+        self.slots[idx].as_ref().expect("linked slot is live")
+    }
+
+    fn slot_mut(&mut self, idx: usize) -> &mut Slot {
+        self.slots[idx].as_mut().expect("linked slot is live")
+    }
+
+    fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    // Slots of a queue from `from` to its back
+    fn queue(&self, from: usize) -> impl Iterator<Item = usize> + '_ {
+        std::iter::successors(Some(from), move |&idx| self.slot(idx).next)
+    }
+
+    // In priority order: best level first, queue order within a level
+    fn iter(&self) -> impl Iterator<Item = &Order> {
+        self.levels
+            .values()
+            .flat_map(move |level| self.queue(level.head))
+            .map(move |idx| &self.slot(idx).order)
+    }
+
+    fn front(&self) -> Option<&Order> {
+        let level = self.levels.values().next()?;
+        Some(&self.slot(level.head).order)
+    }
+
+    fn best_price(&self) -> Option<f64> {
+        self.front().and_then(|o| o.price)
+    }
+
+    // Best level that shows any quantity, with the size it shows. Levels
+    // holding only hidden orders are skipped.
+    fn best_displayed(&self) -> Option<(f64, Quantity)> {
+        self.levels.values().find_map(|level| {
+            let shown: Quantity = self
+                .queue(level.head)
+                .map(|idx| self.order_at(idx))
+                .filter(|o| !o.hidden)
+                .map(|o| o.quantity)
+                .sum();
+            let price = self.order_at(level.head).price?;
+            (!shown.is_zero()).then_some((price, shown))
+        })
+    }
+
+    // Price of the first level behind `after` in priority order, or of the
+    // best level when `after` is None. `after` need not still have orders.
+    fn next_level(&self, after: Option<f64>) -> Option<f64> {
+        let mut levels = match after {
+            Some(price) => self.levels.range((Excluded(self.key(price)), Unbounded)),
+            None => self.levels.range(..),
+        };
+        levels.next().and_then(|(_, level)| self.slot(level.head).order.price)
+    }
+
+    // Slots of the orders resting at `price`, in queue order
+    fn level_slots(&self, price: f64) -> Vec<usize> {
+        match self.levels.get(&self.key(price)) {
+            Some(level) => self.queue(level.head).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn level(&self, price: f64) -> impl Iterator<Item = &Order> {
+        let level = self.levels.get(&self.key(price));
+        level
+            .into_iter()
+            .flat_map(move |level| self.queue(level.head))
+            .map(move |idx| &self.slot(idx).order)
+    }
+
+    fn order_at(&self, idx: usize) -> &Order {
+        &self.slot(idx).order
+    }
+
+    fn slot_of(&self, order_id: &str) -> Option<usize> {
+        self.index.get(order_id).copied()
+    }
+
+    fn get(&self, order_id: &str) -> Option<&Order> {
+        self.slot_of(order_id).map(|idx| self.order_at(idx))
+    }
+
+    fn contains(&self, order_id: &str) -> bool {
+        self.index.contains_key(order_id)
+    }
+
+    // Changes an order in place. The change must keep its price and leave
+    // some quantity; fills go through fill_at so empty orders leave the queue.
+    fn update<R>(&mut self, order_id: &str, change: impl FnOnce(&mut Order) -> R) -> Option<R> {
+        let idx = self.slot_of(order_id)?;
+        let order = &mut self.slots[idx].as_mut().expect("linked slot is live").order;
+        self.digest.remove(order.digest_entry());
+        let result = change(order);
+        self.digest.add(order.digest_entry());
+        Some(result)
+    }
+
+    // Takes `quantity` off the order in a slot, unlinking it once nothing is
+    // left. Returns the order if it was filled out.
+    fn fill_at(&mut self, idx: usize, quantity: Quantity) -> Option<Order> {
+        let order = &mut self.slots[idx].as_mut().expect("linked slot is live").order;
+        self.digest.remove(order.digest_entry());
+        order.quantity -= quantity;
+        self.digest.add(order.digest_entry());
+        if order.quantity > Quantity::ZERO {
+            return None;
+        }
+        Some(self.unlink(idx))
+    }
+
+    fn fill_front(&mut self, quantity: Quantity) -> Option<Order> {
+        let head = self.levels.values().next()?.head;
+        self.fill_at(head, quantity)
+    }
+
+    // Where `quantity` would go walking down from the best level: slots and
+    // amounts in fill order, capped orders taking turns within their level
+    // as under FifoPolicy. Falls short if the side runs out.
+    fn fill_plan(&self, quantity: Quantity) -> Vec<(usize, Quantity)> {
+        let mut plan = Vec::new();
+        let mut remaining = quantity;
+        let mut previous_level = None;
+        while !remaining.is_zero() {
+            let Some(price) = self.next_level(previous_level) else {
+                break;
+            };
+            previous_level = Some(price);
+            let slots = self.level_slots(price);
+            let level: Vec<&Order> = slots.iter().map(|&idx| self.order_at(idx)).collect();
+            for (i, quantity) in FifoPolicy::turns(&level, remaining) {
+                plan.push((slots[i], quantity));
+                remaining -= quantity;
+            }
+        }
+        plan
+    }
+
+    // Queues a priced order at its level. Under time priority it joins the
+    // back; other models let it pass the resting orders it ranks ahead of.
+    fn insert(&mut self, order: Order, priority_model: PriorityModel) {
+        let key = self.key(order.price.expect("resting orders are priced"));
+        let ahead_of = self
+            .levels
+            .get(&key)
+            .filter(|_| priority_model != PriorityModel::TimeOnly)
+            .and_then(|level| {
+                self.queue(level.head)
+                    .find(|&idx| priority_model.ranks_ahead(&order, &self.slot(idx).order))
+            });
+
+        let id = order.id.clone();
+        self.digest.add(order.digest_entry());
+        let slot = Slot {
+            order,
+            prev: None,
+            next: None,
+        };
+        let idx = match self.free.pop() {
+            Some(idx) => {
+                self.slots[idx] = Some(slot);
+                idx
+            }
+            None => {
+                self.slots.push(Some(slot));
+                self.slots.len() - 1
+            }
+        };
+        self.index.insert(id, idx);
+
+        match (self.levels.get(&key).copied(), ahead_of) {
+            (None, _) => {
+                self.levels.insert(key, Level { head: idx, tail: idx });
+            }
+            (Some(level), None) => {
+                self.slot_mut(level.tail).next = Some(idx);
+                self.slot_mut(idx).prev = Some(level.tail);
+                self.levels.insert(key, Level { tail: idx, ..level });
+            }
+            (Some(level), Some(behind)) => {
+                let prev = self.slot(behind).prev;
+                self.slot_mut(idx).prev = prev;
+                self.slot_mut(idx).next = Some(behind);
+                self.slot_mut(behind).prev = Some(idx);
+                match prev {
+                    Some(prev) => self.slot_mut(prev).next = Some(idx),
+                    None => {
+                        self.levels.insert(key, Level { head: idx, ..level });
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove(&mut self, order_id: &str) -> Option<Order> {
+        let idx = self.slot_of(order_id)?;
+        Some(self.unlink(idx))
+    }
+
+    // Takes an order out of its queue and frees its slot, dropping the level
+    // once it is empty
+    fn unlink(&mut self, idx: usize) -> Order {
+        let slot = self.slots[idx].take().expect("linked slot is live");
+        self.free.push(idx);
+        self.index.remove(&slot.order.id);
+        self.digest.remove(slot.order.digest_entry());
+
+        let key = self.key(slot.order.price.expect("resting orders are priced"));
+        if let Some(prev) = slot.prev {
+            self.slot_mut(prev).next = slot.next;
+        }
+        if let Some(next) = slot.next {
+            self.slot_mut(next).prev = slot.prev;
+        }
+        match (slot.prev, slot.next) {
+            (None, None) => {
+                self.levels.remove(&key);
+            }
+            (None, Some(next)) => self.levels.get_mut(&key).expect("linked level").head = next,
+            (Some(prev), None) => self.levels.get_mut(&key).expect("linked level").tail = prev,
+            (Some(_), Some(_)) => {}
+        }
+        slot.order
+    }
+
+    // The order furthest from the touch: last in time at the worst level
+    fn pop_back(&mut self) -> Option<Order> {
+        let tail = self.levels.values().next_back()?.tail;
+        Some(self.unlink(tail))
+    }
+
+    // Removes the orders matching `predicate`, returned in priority order
+    fn remove_where(&mut self, mut predicate: impl FnMut(&Order) -> bool) -> Vec<Order> {
+        let matching: Vec<usize> = self
+            .levels
+            .values()
+            .flat_map(|level| self.queue(level.head))
+            .filter(|&idx| predicate(&self.slot(idx).order))
+            .collect();
+        matching.into_iter().map(|idx| self.unlink(idx)).collect()
+    }
+
+    // Empties the side, returning its orders in priority order
+    fn drain(&mut self) -> Vec<Order> {
+        let order: Vec<usize> =
+            self.levels.values().flat_map(|level| self.queue(level.head)).collect();
+        let mut slots = std::mem::take(&mut self.slots);
+        self.free.clear();
+        self.levels.clear();
+        self.index.clear();
+        self.digest = SetDigest::default();
+        order.into_iter().filter_map(|idx| slots[idx].take()).map(|slot| slot.order).collect()
+    }
+}
+
+#[derive(Debug)]
+pub struct OrderBook {
+    symbol: String,
+    bids: OrderSide,
+    asks: OrderSide,
+    trades: Vec<Trade>,
+    trades_by_order: HashMap<String, Vec<usize>>, // Order id to its trades' positions in `trades`
+    market_data: MarketData,
+    session: Option<SessionSchedule>, // None means the market never closes
+    borrow_available: Option<Quantity>, // Shares available to borrow; None means no locate required
+    reference_price: Option<f64>,     // Externally supplied price, used for the mark when set
+    moc_orders: Vec<Order>,           // Market-on-close orders awaiting the closing auction
+    closing_price: Option<f64>,
+    initial_price: f64, // Last price to fall back on when no trades remain
+    last_price: f64,    // Last traded price, including prints still deferred from the tape
+    session_start: usize, // Index into trades where the live session begins
+    session_started_at: DateTime<Utc>,
+    session_history: Vec<SessionStats>, // Archived sessions, oldest first
+    mid_samples: VecDeque<(DateTime<Utc>, f64)>, // Mid after each book change, for the TWAP mark
+    quote_mids: Vec<(DateTime<Utc>, f64)>, // Every change of the published quote mid, oldest first
+    pending_stops: StopIndex,
+    scheduled_orders: Vec<Order>, // Good-after-time orders awaiting activation
+    evicted_orders: Vec<Order>,   // Removed by the depth cap, not yet collected
+    watched: HashSet<String>,     // Orders to report once they stop working
+    finished: Vec<(String, Quantity)>, // Watched orders that stopped working, and unfilled size
+    batch_orders: Vec<Order>,     // Orders waiting for the next batch auction
+    batch_deadline: Option<DateTime<Utc>>, // Boundary of the batch currently collecting
+    halted_imbalance: Option<Quantity>, // Imbalance that tripped the halt; None while trading
+    next_sequence: u64,
+    config: BookConfig,
+    allocation: Arc<dyn AllocationPolicy>, // From the config's matching algorithm unless replaced
+    last_look: Box<dyn LastLook>, // Entropy-seeded unless replaced
+    clock: Arc<dyn Clock>,
+}
+
+impl OrderBook {
+    pub fn new(symbol: String, initial_price: f64) -> Self {
+        Self::with_config(symbol, initial_price, BookConfig::default())
+    }
+
+    pub fn with_config(symbol: String, initial_price: f64, config: BookConfig) -> Self {
+        // Seed a 1% quote either side; prices may be negative, so the spread
+        // is taken from the magnitude to keep the bid below the ask
+        let half_spread = initial_price.abs() * 0.01;
+        OrderBook {
+            symbol: symbol.clone(),
+            bids: OrderSide::new(Side::Buy),
+            asks: OrderSide::new(Side::Sell),
+            trades: Vec::new(),
+            trades_by_order: HashMap::new(),
+            market_data: MarketData {
+                symbol,
+                bid: initial_price - half_spread,
+                ask: initial_price + half_spread,
+                bid_size: Quantity::ZERO,
+                ask_size: Quantity::ZERO,
+                last_price: initial_price,
+                mark_price: initial_price,
+                timestamp: SystemClock.now(),
+            },
+            session: None,
+            borrow_available: None,
+            reference_price: None,
+            moc_orders: Vec::new(),
+            closing_price: None,
+            initial_price,
+            last_price: initial_price,
+            session_start: 0,
+            session_started_at: SystemClock.now(),
+            session_history: Vec::new(),
+            mid_samples: VecDeque::new(),
+            quote_mids: Vec::new(),
+            pending_stops: StopIndex::default(),
+            scheduled_orders: Vec::new(),
+            evicted_orders: Vec::new(),
+            watched: HashSet::new(),
+            finished: Vec::new(),
+            batch_orders: Vec::new(),
+            batch_deadline: None,
+            halted_imbalance: None,
+            next_sequence: 1,
+            allocation: config.matching_algorithm.policy(),
+            last_look: Box::new(RandomLastLook::from_entropy()),
+            config,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    // Swaps in a custom allocation step for continuous matching
+    pub fn set_allocation_policy(&mut self, policy: Arc<dyn AllocationPolicy>) {
+        self.allocation = policy;
+    }
+
+    pub fn set_last_look(&mut self, last_look: Box<dyn LastLook>) {
+        self.last_look = last_look;
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+        self.market_data.timestamp = self.clock.now();
+        self.session_started_at = self.clock.now();
+    }
+
+    pub fn memory_stats(&self) -> BookMemoryStats {
+        let tag_bytes = |tags: &[(String, String)]| {
+            tags.iter()
+                .map(|(k, v)| std::mem::size_of::<(String, String)>() + k.capacity() + v.capacity())
+                .sum::<usize>()
+        };
+        let order_bytes = |o: &Order| {
+            std::mem::size_of::<Order>()
+                + o.id.capacity()
+                + o.symbol.capacity()
+                + o.account.as_ref().map_or(0, |a| a.capacity())
+                + tag_bytes(&o.tags)
+        };
+        let trade_bytes = |t: &Trade| {
+            std::mem::size_of::<Trade>()
+                + t.id.capacity()
+                + t.symbol.capacity()
+                + t.buyer_order_id.capacity()
+                + t.seller_order_id.capacity()
+                + t.buyer_account.as_ref().map_or(0, |a| a.capacity())
+                + t.seller_account.as_ref().map_or(0, |a| a.capacity())
+                + tag_bytes(&t.buyer_tags)
+                + tag_bytes(&t.seller_tags)
+        };
+
+        let parked: Vec<&Order> = self
+            .pending_stops
+            .iter()
+            .chain(&self.moc_orders)
+            .chain(&self.scheduled_orders)
+            .chain(&self.batch_orders)
+            .chain(&self.evicted_orders)
+            .collect();
+        let estimated_bytes = std::mem::size_of::<OrderBook>()
+            + self.bids.iter().chain(self.asks.iter()).map(order_bytes).sum::<usize>()
+            + parked.iter().map(|o| order_bytes(o)).sum::<usize>()
+            + self.trades.iter().map(trade_bytes).sum::<usize>()
+            + self.session_history.len() * std::mem::size_of::<SessionStats>()
+            + (self.mid_samples.len() + self.quote_mids.len())
+                * std::mem::size_of::<(DateTime<Utc>, f64)>();
+
+        BookMemoryStats {
+            bid_orders: self.bids.len(),
+            ask_orders: self.asks.len(),
+            parked_orders: parked.len(),
+            trades: self.trades.len(),
+            session_history: self.session_history.len(),
+            estimated_bytes,
+        }
+    }
+
+    // Statistics for trades since the last roll_session that are on the
+    // tape. A print still deferred when the session rolls is left out.
+    pub fn session_stats(&self) -> SessionStats {
+        let now = self.clock.now();
+        let trades: Vec<&Trade> = self.trades[self.session_start..]
+            .iter()
+            .filter(|t| t.publish_time <= now)
+            .collect();
+        let volume: Quantity = trades.iter().map(|t| t.quantity).sum();
+        let value: f64 = trades.iter().map(|t| t.price * t.quantity.to_f64()).sum();
+
+        SessionStats {
+            started_at: self.session_started_at,
+            ended_at: None,
+            trade_count: trades.len(),
+            volume,
+            high: trades.iter().map(|t| t.price).reduce(f64::max),
+            low: trades.iter().map(|t| t.price).reduce(f64::min),
+            vwap: (!volume.is_zero()).then(|| value / volume.to_f64()),
+        }
+    }
+
+    // Traded volume per price bucket over the whole trade history, lowest
+    // bucket first. Buckets are aligned to multiples of `bucket_size` and
+    // keyed by their lower edge; a price on an edge belongs to the bucket it
+    // opens, so the top of the range never spills into an empty bucket.
+    pub fn volume_profile(&self, bucket_size: f64) -> Vec<(f64, Quantity)> {
+        if bucket_size <= 0.0 {
+            return Vec::new();
+        }
+
+        let mut buckets: BTreeMap<i64, Quantity> = BTreeMap::new();
+        for trade in &self.trades {
+            // Nudge up so a price on an edge is not lost to binary rounding
+            let bucket = (trade.price / bucket_size + 1e-9).floor() as i64;
+            *buckets.entry(bucket).or_default() += trade.quantity;
+        }
+        buckets
+            .into_iter()
+            .map(|(bucket, volume)| (bucket as f64 * bucket_size, volume))
+            .collect()
+    }
+
+    // Standard deviation of log returns between consecutive prints over the
+    // last `window` trades, per trade. None with fewer than two trades in the
+    // window, or when a price is not positive and has no log return.
+    pub fn realized_volatility(&self, window: usize) -> Option<f64> {
+        let prices: Vec<f64> = self.trades[self.trades.len().saturating_sub(window)..]
+            .iter()
+            .map(|t| t.price)
+            .collect();
+        if prices.len() < 2 || prices.iter().any(|&p| p <= 0.0) {
+            return None;
+        }
+
+        let returns: Vec<f64> = prices.windows(2).map(|w| (w[1] / w[0]).ln()).collect();
+        let n = returns.len() as f64;
+        let mean = returns.iter().sum::<f64>() / n;
+        let variance = returns.iter().map(|r| (r - mean).powi(2)).sum::<f64>() / n;
+        Some(variance.sqrt())
+    }
+
+    // realized_volatility scaled by the square root of the number of
+    // trade-to-trade periods in a year
+    pub fn annualized_volatility(&self, window: usize, periods_per_year: f64) -> Option<f64> {
+        self.realized_volatility(window).map(|vol| vol * periods_per_year.sqrt())
+    }
+
+    // Lower edge of the busiest volume profile bucket (the lowest on a tie)
+    pub fn point_of_control(&self, bucket_size: f64) -> Option<f64> {
+        self.volume_profile(bucket_size)
+            .into_iter()
+            .rev()
+            .max_by_key(|(_, volume)| *volume)
+            .map(|(price, _)| price)
+    }
+
+    // Archives the live session's statistics and starts a fresh session.
+    // Resting orders, including GTC ones, and the trade history are kept.
+    pub fn roll_session(&mut self) -> SessionStats {
+        let now = self.clock.now();
+        let mut stats = self.session_stats();
+        stats.ended_at = Some(now);
+        self.session_history.push(stats.clone());
+
+        self.session_start = self.trades.len();
+        self.session_started_at = now;
+        stats
+    }
+
+    pub fn session_history(&self) -> &[SessionStats] {
+        &self.session_history
+    }
+
+    pub fn set_session_schedule(&mut self, schedule: Option<SessionSchedule>) {
+        self.session = schedule;
+    }
+
+    pub fn is_open(&self, now: DateTime<Utc>) -> bool {
+        self.session.as_ref().is_none_or(|s| s.is_open(now))
+    }
+
+    // Resting buy quantity minus resting sell quantity
+    pub fn order_imbalance(&self) -> Quantity {
+        let bought: Quantity = self.bids.iter().map(|o| o.quantity).sum();
+        let sold: Quantity = self.asks.iter().map(|o| o.quantity).sum();
+        bought - sold
+    }
+
+    // Halts the market once the resting imbalance exceeds the configured
+    // threshold. The halt holds until resume is called, however the book
+    // changes in the meantime.
+    pub fn check_imbalance_halt(&mut self) -> Option<Quantity> {
+        if self.halted_imbalance.is_none() {
+            let threshold = self.config.imbalance_halt_threshold?;
+            let imbalance = self.order_imbalance();
+            if imbalance.abs() > threshold {
+                self.halted_imbalance = Some(imbalance);
+            }
         }
+        self.halted_imbalance
+    }
+
+    pub fn halted_imbalance(&self) -> Option<Quantity> {
+        self.halted_imbalance
+    }
+
+    pub fn resume(&mut self) {
+        self.halted_imbalance = None;
     }
-}
\ No newline at end of file
+
+    // Trades are returned in execution order, which is also the order they are
+    // appended to the book's trade history. A sweep fills best price first, so
+    // a buy's fills have non-decreasing prices and a sell's non-increasing,
+    // with time priority within a level. Fills from stops the sweep triggers
+    // follow the sweep's own fills.
+    // Orders are stamped with the book's clock on arrival. Resting orders whose
+    // good-till time has passed are expired first, and an order that arrives
+    // already expired is discarded, as is one with a NaN or infinite price.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip_all, fields(symbol = %self.symbol, order_id = %order.id))
+    )]
+    pub fn add_order(&mut self, mut order: Order) -> Vec<Trade> {
+        let now = self.clock.now();
+        self.expire_orders(now);
+        let unpriceable = [order.price, order.stop_price].iter().flatten().any(|p| !p.is_finite());
+        if order.is_expired(now) || unpriceable {
+            self.finish(&order.id, order.quantity);
+            return Vec::new();
+        }
+
+        order.timestamp = now;
+        order.accepted_nanos = monotonic_nanos();
+        order.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        trace_event!(
+            INFO,
+            side = %order.side,
+            order_type = %order.order_type,
+            quantity = %order.quantity,
+            price = ?order.price,
+            sequence = order.sequence,
+            "order accepted"
+        );
+
+        // Good-after-time orders are accepted now but only enter the book
+        // once process_time_events reaches their activation time
+        if order.is_scheduled(now) {
+            self.scheduled_orders.push(order);
+            return Vec::new();
+        }
+
+        // Market-on-close orders only participate in the closing auction
+        if order.order_type == OrderType::MarketOnClose {
+            self.moc_orders.push(order);
+            return Vec::new();
+        }
+
+        // Stops wait for their trigger unless the last price is already through it
+        if order.order_type == OrderType::Stop {
+            let last_price = self.last_price;
+            if !Self::stop_triggered(&order, last_price, last_price) {
+                self.pending_stops.push(order);
+                return Vec::new();
+            }
+            order.order_type = OrderType::Market;
+        }
+
+        if let MatchingMode::BatchAuction(_) = self.config.matching_mode {
+            self.enqueue_batch(order, now);
+            return Vec::new();
+        }
+
+        let mut trades = self.match_order(order);
+        self.activate_stops(&mut trades, 0);
+        trades
+    }
+
+    // Holds an order for the batch that closes at the next interval boundary
+    fn enqueue_batch(&mut self, order: Order, now: DateTime<Utc>) {
+        if let MatchingMode::BatchAuction(interval) = self.config.matching_mode {
+            let step = interval.num_microseconds().unwrap_or(i64::MAX).max(1);
+            let boundary = (now.timestamp_micros().div_euclid(step) + 1).saturating_mul(step);
+            let deadline = DateTime::from_timestamp_micros(boundary).unwrap_or(now);
+            self.batch_deadline.get_or_insert(deadline);
+        }
+        self.batch_orders.push(order);
+    }
+
+    // Whether the collecting batch has reached its boundary and can clear
+    pub fn batch_due(&self, now: DateTime<Utc>) -> bool {
+        self.batch_deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    // Limit price an order is willing to trade at; None for market orders
+    fn limit_of(order: &Order) -> Option<f64> {
+        match order.order_type {
+            OrderType::Limit => order.price,
+            _ => None,
+        }
+    }
+
+    // Clears the collected batch once its boundary has passed. The batch and
+    // the resting book trade at a single price: the one that maximises
+    // executed quantity, then minimises the leftover imbalance, then sits
+    // closest to the last price (lower price on an exact tie). The last price
+    // is itself a candidate so a batch of market orders can still clear.
+    // Fills go in price priority, then queue order. Limit remainders rest while
+    // IOC and market remainders are cancelled. Auction prints carry no aggressor.
+    pub fn run_auction(&mut self, now: DateTime<Utc>) -> Vec<Trade> {
+        match self.batch_deadline {
+            Some(deadline) if now >= deadline => self.batch_deadline = None,
+            _ => return Vec::new(),
+        }
+
+        let (batch_buys, batch_sells): (Vec<Order>, Vec<Order>) =
+            std::mem::take(&mut self.batch_orders)
+                .into_iter()
+                .partition(|o| o.side == Side::Buy);
+        let batch_ids: HashSet<String> =
+            batch_buys.iter().chain(&batch_sells).map(|o| o.id.clone()).collect();
+        let mut buys: Vec<Order> = self.bids.drain().into_iter().chain(batch_buys).collect();
+        let mut sells: Vec<Order> = self.asks.drain().into_iter().chain(batch_sells).collect();
+
+        // Stable sorts keep the book's queue order within a price, with the
+        // batch behind orders that were already resting
+        buys.sort_by(|a, b| match (Self::limit_of(a), Self::limit_of(b)) {
+            (Some(a), Some(b)) => b.total_cmp(&a),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        });
+        sells.sort_by(|a, b| match (Self::limit_of(a), Self::limit_of(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (a, b) => a.is_some().cmp(&b.is_some()),
+        });
+
+        let best = Self::clearing_state(&buys, &sells, self.last_price);
+
+        let mut trades = Vec::new();
+        if let Some(AuctionState {
+            price,
+            matched_quantity: volume,
+            ..
+        }) = best
+        {
+            let mut buy_fills = Self::auction_fills(&buys, volume);
+            let mut sell_fills = Self::auction_fills(&sells, volume);
+            let (mut i, mut j) = (0, 0);
+            while let (Some(&(b, buy_left)), Some(&(s, sell_left))) =
+                (buy_fills.get(i), sell_fills.get(j))
+            {
+                let quantity = buy_left.min(sell_left);
+                let (buy, sell) = (&buys[b], &sells[s]);
+                let trade = Self::make_trade(&self.symbol, buy, sell, None, quantity, price);
+                trades.push(self.record_trade(trade));
+
+                buys[b].quantity -= quantity;
+                sells[s].quantity -= quantity;
+                buy_fills[i].1 -= quantity;
+                sell_fills[j].1 -= quantity;
+                if buy_fills[i].1.is_zero() {
+                    i += 1;
+                }
+                if sell_fills[j].1.is_zero() {
+                    j += 1;
+                }
+            }
+        }
+
+        // Orders that were already resting go back as they were, keeping their
+        // improved_book flag. Batch remainders then arrive behind them, best
+        // first, and are flagged and held to the depth cap like any arrival.
+        let (arriving, restored): (Vec<Order>, Vec<Order>) = buys
+            .into_iter()
+            .chain(sells)
+            .partition(|o| batch_ids.contains(&o.id));
+        for order in restored.into_iter().chain(arriving) {
+            if order.quantity.is_zero() || !order.rests() {
+                self.finish(&order.id, order.quantity);
+            } else if batch_ids.contains(&order.id) {
+                self.insert_resting(order);
+            } else {
+                self.restore_resting(order);
+            }
+        }
+        self.update_market_data();
+
+        // Stops set off by the print join the next batch
+        self.activate_stops(&mut trades, 0);
+        trades
+    }
+
+    // How one side of an auction shares the matched volume: positions in
+    // `orders`, already in priority order, and amounts in fill order. Orders
+    // at one limit form a level, where capped orders take turns as under
+    // FifoPolicy.
+    fn auction_fills(orders: &[Order], volume: Quantity) -> Vec<(usize, Quantity)> {
+        let mut fills = Vec::new();
+        let mut remaining = volume;
+        let mut start = 0;
+        while start < orders.len() && !remaining.is_zero() {
+            let limit = Self::limit_of(&orders[start]);
+            let end = start
+                + orders[start..].iter().take_while(|o| Self::limit_of(o) == limit).count();
+            let level: Vec<&Order> = orders[start..end].iter().collect();
+            for (i, quantity) in FifoPolicy::turns(&level, remaining) {
+                fills.push((start + i, quantity));
+                remaining -= quantity;
+            }
+            start = end;
+        }
+        fills
+    }
+
+    // Indicative clearing price, matched quantity and imbalance of the
+    // collecting batch against the resting book, republished as orders
+    // arrive. None while nothing would trade. The closing auction has its
+    // own indication in closing_indicative.
+    pub fn auction_indicative(&self) -> Option<AuctionState> {
+        let (batch_buys, batch_sells): (Vec<&Order>, Vec<&Order>) =
+            self.batch_orders.iter().partition(|o| o.side == Side::Buy);
+        let buys: Vec<&Order> = self.bids.iter().chain(batch_buys).collect();
+        let sells: Vec<&Order> = self.asks.iter().chain(batch_sells).collect();
+        Self::clearing_state(&buys, &sells, self.last_price)
+    }
+
+    // Single-price discovery shared by the batch auction and its indicator
+    fn clearing_state<O: std::borrow::Borrow<Order>>(
+        buys: &[O],
+        sells: &[O],
+        last_price: f64,
+    ) -> Option<AuctionState> {
+        let mut best: Option<AuctionState> = None;
+        let candidates = buys.iter().chain(sells).map(|o| o.borrow()).filter_map(Self::limit_of);
+        for price in candidates.chain(std::iter::once(last_price)) {
+            let demand: Quantity = buys
+                .iter()
+                .map(|o| o.borrow())
+                .filter(|o| Self::limit_of(o).is_none_or(|limit| limit >= price))
+                .map(|o| o.quantity)
+                .sum();
+            let supply: Quantity = sells
+                .iter()
+                .map(|o| o.borrow())
+                .filter(|o| Self::limit_of(o).is_none_or(|limit| limit <= price))
+                .map(|o| o.quantity)
+                .sum();
+            let volume = demand.min(supply);
+            let imbalance = demand - supply;
+            if volume.is_zero() {
+                continue;
+            }
+
+            let better = match &best {
+                None => true,
+                Some(state) => {
+                    let distance = (price - last_price).abs();
+                    let best_distance = (state.price - last_price).abs();
+                    volume
+                        .cmp(&state.matched_quantity)
+                        .then(state.imbalance.abs().cmp(&imbalance.abs()))
+                        .then(best_distance.total_cmp(&distance))
+                        .then(state.price.total_cmp(&price))
+                        .is_gt()
+                }
+            };
+            if better {
+                best = Some(AuctionState {
+                    price,
+                    matched_quantity: volume,
+                    imbalance,
+                });
+            }
+        }
+        best
+    }
+
+    // Removes every order, resting or pending, whose good-till time is at or
+    // before `now`, and returns them in book order
+    pub fn expire_orders(&mut self, now: DateTime<Utc>) -> Vec<Order> {
+        let mut expired = self.bids.remove_where(|o| o.is_expired(now));
+        expired.extend(self.asks.remove_where(|o| o.is_expired(now)));
+        expired.extend(self.pending_stops.remove_where(|o| o.is_expired(now)));
+        for queue in [
+            &mut self.moc_orders,
+            &mut self.scheduled_orders,
+            &mut self.batch_orders,
+        ] {
+            let (dead, live) = queue.drain(..).partition(|o: &Order| o.is_expired(now));
+            *queue = live;
+            expired.extend(dead);
+        }
+
+        for order in &expired {
+            self.finish(&order.id, order.quantity);
+        }
+        if !expired.is_empty() {
+            self.update_market_data();
+        }
+        expired
+    }
+
+    // Runs everything that is due at `now`: expiry first, then activation of
+    // good-after-time orders in activation-time order (arrival order for ties).
+    // Activated orders are handled like fresh arrivals and may trade at once.
+    pub fn process_time_events(&mut self, now: DateTime<Utc>) -> TimeEvents {
+        let expired = self.expire_orders(now);
+
+        let (mut due, waiting): (Vec<Order>, Vec<Order>) =
+            self.scheduled_orders.drain(..).partition(|o| !o.is_scheduled(now));
+        self.scheduled_orders = waiting;
+        due.sort_by_key(|o| (o.not_before, o.sequence));
+
+        let mut trades = Vec::new();
+        for mut order in due {
+            order.not_before = None;
+            trades.extend(self.add_order(order));
+        }
+
+        TimeEvents { expired, trades }
+    }
+
+    fn stop_triggered(stop: &Order, low: f64, high: f64) -> bool {
+        match stop.side {
+            Side::Buy => high >= stop.stop_price.unwrap(),
+            Side::Sell => low <= stop.stop_price.unwrap(),
+        }
+    }
+
+    // Fires pending stops whose trigger was touched by trades[from..], then
+    // repeats for the trades those stops generate. Stops that trigger together
+    // activate in the order the price path reaches them: buy stops by
+    // ascending stop price, then sell stops by descending stop price, with
+    // ties broken by acceptance sequence.
+    fn activate_stops(&mut self, trades: &mut Vec<Trade>, from: usize) {
+        let mut processed = from;
+
+        while processed < trades.len() && !self.pending_stops.is_empty() {
+            let new_trades = &trades[processed..];
+            let low = new_trades.iter().map(|t| t.price).fold(f64::MAX, f64::min);
+            let high = new_trades.iter().map(|t| t.price).fold(f64::MIN, f64::max);
+            processed = trades.len();
+            self.fire_stops(low, high, trades);
+        }
+    }
+
+    // Activates every pending stop whose trigger lies within [low, high]
+    fn fire_stops(&mut self, low: f64, high: f64, trades: &mut Vec<Trade>) {
+        for mut stop in self.pending_stops.take_triggered(low, high) {
+            stop.order_type = OrderType::Market;
+            if let MatchingMode::BatchAuction(_) = self.config.matching_mode {
+                let now = self.clock.now();
+                self.enqueue_batch(stop, now);
+            } else {
+                trades.extend(self.match_order(stop));
+            }
+        }
+    }
+
+    // External reference price (e.g. a consolidated feed). It feeds the mark
+    // price and triggers stops exactly as a trade at that price would, so
+    // the returned trades are those of any stops it sets off.
+    pub fn set_reference_price(&mut self, price: f64) -> Vec<Trade> {
+        self.reference_price = Some(price);
+        self.update_market_data();
+
+        let mut trades = Vec::new();
+        self.fire_stops(price, price, &mut trades);
+        self.activate_stops(&mut trades, 0);
+        trades
+    }
+
+    pub fn reference_price(&self) -> Option<f64> {
+        self.reference_price
+    }
+
+    // Every working order: resting bids and asks, pending stops, market-on-close
+    // orders awaiting the auction, and scheduled and batched orders
+    fn working_orders(&self) -> impl Iterator<Item = &Order> {
+        self.bids
+            .iter()
+            .chain(self.asks.iter())
+            .chain(self.pending_stops.iter())
+            .chain(self.moc_orders.iter())
+            .chain(self.scheduled_orders.iter())
+            .chain(self.batch_orders.iter())
+    }
+
+    pub fn open_orders(&self, account: &str) -> Vec<Order> {
+        self.working_orders()
+            .filter(|o| o.account.as_deref() == Some(account))
+            .cloned()
+            .collect()
+    }
+
+    // Time left before a resting order may be cancelled under the minimum
+    // rest rule; None once it is free to go or when no rule is set
+    pub fn rest_time_remaining(&self, order_id: &str) -> Option<chrono::Duration> {
+        let min_rest_time = self.config.min_rest_time?;
+        let order = self.bids.get(order_id).or_else(|| self.asks.get(order_id))?;
+        let remaining = order.timestamp + min_rest_time - self.clock.now();
+        (remaining > chrono::Duration::zero()).then_some(remaining)
+    }
+
+    pub fn is_protected(&self, order_id: &str) -> bool {
+        self.working_orders().any(|o| o.id == order_id && o.protected)
+    }
+
+    pub fn get_pending_stops(&self) -> Vec<Order> {
+        self.pending_stops.iter().cloned().collect()
+    }
+
+    // Whether the order may trade at a resting price. Limit orders are bound
+    // by their limit; market orders (including triggered stops) take any level
+    // inside the collar, or any level at all when the book has none.
+    fn crosses(order: &Order, resting_price: f64, collar: Option<f64>) -> bool {
+        let limit = match (order.order_type, order.price) {
+            (OrderType::Limit, Some(limit)) => limit,
+            _ => match collar {
+                Some(collar) => collar,
+                None => return true,
+            },
+        };
+        match order.side {
+            Side::Buy => limit >= resting_price,
+            Side::Sell => limit <= resting_price,
+        }
+    }
+
+    // Furthest price a market order may trade at, fixed from the last price
+    // when the order arrives
+    fn market_collar(&self, side: Side) -> Option<f64> {
+        let pct = self.config.market_order_collar_pct?;
+        let band = self.last_price.abs() * pct;
+        Some(match side {
+            Side::Buy => self.last_price + band,
+            Side::Sell => self.last_price - band,
+        })
+    }
+
+    // Walks the opposite side level by level while the order crosses, letting
+    // the allocation policy split each level among the resting orders. Makers
+    // that reject on last look are left out of the level's allocation.
+    // Market and IOC orders never rest: whatever is left after walking the
+    // book is cancelled, so only plain limit remainders are inserted.
+    fn match_order(&mut self, mut order: Order) -> Vec<Trade> {
+        let mut trades = Vec::new();
+        let collar = self.market_collar(order.side);
+        let mut previous_level = None;
+
+        while order.quantity > Quantity::ZERO {
+            let resting = match order.side {
+                Side::Buy => &mut self.asks,
+                Side::Sell => &mut self.bids,
+            };
+
+            // A level is left behind once visited, even if last look kept
+            // some of its makers in the book
+            let Some(level_price) = resting.next_level(previous_level) else {
+                break;
+            };
+            if !Self::crosses(&order, level_price, collar) {
+                break;
+            }
+            previous_level = Some(level_price);
+
+            let queue = resting.level_slots(level_price);
+            let last_look = self.last_look.as_mut();
+            let eligible: Vec<usize> = queue
+                .iter()
+                .copied()
+                .filter(|&idx| !Self::maker_rejects(last_look, resting.order_at(idx)))
+                .collect();
+            let level: Vec<&Order> = eligible.iter().map(|&idx| resting.order_at(idx)).collect();
+            let allocations = self.allocation.allocate(&level, order.quantity);
+            trace_event!(
+                DEBUG,
+                price = level_price,
+                resting_orders = queue.len(),
+                eligible_orders = eligible.len(),
+                incoming_quantity = %order.quantity,
+                "matching level"
+            );
+
+            let eligible: HashSet<usize> = eligible.into_iter().collect();
+            let mut level_trades = Vec::new();
+            let mut filled_out = Vec::new();
+            for (order_id, quantity) in allocations {
+                let Some(idx) = resting.slot_of(&order_id).filter(|idx| eligible.contains(idx))
+                else {
+                    continue;
+                };
+                let maker = resting.order_at(idx);
+                // A policy decides who fills, but never beyond what either side has left
+                let quantity = quantity.min(maker.quantity).min(order.quantity);
+                if quantity <= Quantity::ZERO {
+                    continue;
+                }
+                let (buyer, seller) = match order.side {
+                    Side::Buy => (&order, maker),
+                    Side::Sell => (maker, &order),
+                };
+                level_trades.push(Self::make_trade(
+                    &self.symbol,
+                    buyer,
+                    seller,
+                    Some(order.side),
+                    quantity,
+                    self.config.fill_price_side.price(&order, level_price),
+                ));
+                filled_out.extend(resting.fill_at(idx, quantity));
+                order.quantity -= quantity;
+            }
+            for maker in filled_out {
+                self.finish(&maker.id, Quantity::ZERO);
+            }
+
+            if !level_trades.is_empty() {
+                self.market_data.timestamp = self.clock.now();
+            }
+            for trade in level_trades {
+                trades.push(self.record_trade(trade));
+            }
+        }
+
+        if order.quantity > Quantity::ZERO && order.rests() {
+            self.insert_resting(order);
+        } else {
+            self.finish(&order.id, order.quantity);
+        }
+
+        // Matching can consume the top of either side, so refresh even if nothing rested
+        self.update_market_data();
+
+        trades
+    }
+
+    // Closing auction: MOC buys and sells cross each other first, and any
+    // imbalance executes against resting limit liquidity. Everything prints
+    // at one closing price - the last limit level the imbalance reaches, or
+    // the last traded price if no limit liquidity is needed. MOC quantity
+    // that cannot be filled is cancelled.
+    pub fn close(&mut self) -> Vec<Trade> {
+        let (mut moc_buys, mut moc_sells): (VecDeque<Order>, VecDeque<Order>) =
+            std::mem::take(&mut self.moc_orders)
+                .into_iter()
+                .partition(|o| o.side == Side::Buy);
+
+        let buy_quantity: Quantity = moc_buys.iter().map(|o| o.quantity).sum();
+        let sell_quantity: Quantity = moc_sells.iter().map(|o| o.quantity).sum();
+        let (close_price, limit_fill) = self.closing_cross(buy_quantity, sell_quantity);
+
+        let mut trades = Vec::new();
+        let mut moc_filled = Vec::new();
+
+        // Cross MOC interest against itself
+        while let (Some(buy), Some(sell)) = (moc_buys.front_mut(), moc_sells.front_mut()) {
+            let quantity = std::cmp::min(buy.quantity, sell.quantity);
+            trades.push(Self::make_trade(&self.symbol, buy, sell, None, quantity, close_price));
+
+            buy.quantity -= quantity;
+            sell.quantity -= quantity;
+            if buy.quantity.is_zero() {
+                moc_filled.extend(moc_buys.pop_front());
+            }
+            if sell.quantity.is_zero() {
+                moc_filled.extend(moc_sells.pop_front());
+            }
+        }
+
+        // Execute the imbalance against limit orders priced at or through the
+        // close, which is exactly the limit fill closing_cross priced
+        let (imbalance, resting) = if moc_buys.is_empty() {
+            (&mut moc_sells, &mut self.bids)
+        } else {
+            (&mut moc_buys, &mut self.asks)
+        };
+        let mut limits_filled = Vec::new();
+        for (idx, mut limit_left) in resting.fill_plan(limit_fill) {
+            while let Some(moc) = imbalance.front_mut().filter(|_| !limit_left.is_zero()) {
+                let quantity = std::cmp::min(moc.quantity, limit_left);
+                let limit = resting.order_at(idx);
+                let (buy, sell) = match moc.side {
+                    Side::Buy => (&*moc, limit),
+                    Side::Sell => (limit, &*moc),
+                };
+                trades.push(Self::make_trade(&self.symbol, buy, sell, None, quantity, close_price));
+
+                moc.quantity -= quantity;
+                limit_left -= quantity;
+                limits_filled.extend(resting.fill_at(idx, quantity));
+                if moc.quantity.is_zero() {
+                    moc_filled.extend(imbalance.pop_front());
+                }
+            }
+        }
+        for limit in limits_filled {
+            self.finish(&limit.id, Quantity::ZERO);
+        }
+
+        // Filled MOC orders are done, and whatever is left of the rest is cancelled
+        for order in moc_filled.iter().chain(&moc_buys).chain(&moc_sells) {
+            self.finish(&order.id, order.quantity);
+        }
+
+        if !trades.is_empty() {
+            trades = trades.into_iter().map(|t| self.record_trade(t)).collect();
+        }
+        self.closing_price = Some(close_price);
+        self.update_market_data();
+
+        trades
+    }
+
+    // Closing price for the given MOC interest, and how much of the MOC
+    // imbalance resting limits fill at it
+    fn closing_cross(&self, buy_quantity: Quantity, sell_quantity: Quantity) -> (f64, Quantity) {
+        // Find the closing price from the limit levels the imbalance sweeps through
+        let (imbalance, resting) = if buy_quantity >= sell_quantity {
+            (buy_quantity - sell_quantity, &self.asks)
+        } else {
+            (sell_quantity - buy_quantity, &self.bids)
+        };
+
+        let mut close_price = self.last_price;
+        let mut remaining = imbalance;
+        for resting_order in resting.iter() {
+            if remaining.is_zero() {
+                break;
+            }
+            close_price = resting_order.price.unwrap();
+            remaining = remaining.saturating_sub(resting_order.quantity);
+        }
+        (close_price, imbalance - remaining)
+    }
+
+    // What close would do if it ran now: the closing price, the MOC quantity
+    // that executes (crossed or filled by resting limits), and the MOC
+    // quantity close would cancel, negative when it is sells. None while no
+    // MOC order would trade.
+    pub fn closing_indicative(&self) -> Option<AuctionState> {
+        let side_total = |side: Side| -> Quantity {
+            self.moc_orders.iter().filter(|o| o.side == side).map(|o| o.quantity).sum()
+        };
+        let (buy_quantity, sell_quantity) = (side_total(Side::Buy), side_total(Side::Sell));
+        let (price, swept) = self.closing_cross(buy_quantity, sell_quantity);
+        let matched_quantity = buy_quantity.min(sell_quantity) + swept;
+        let unfilled = (buy_quantity - sell_quantity).abs() - swept;
+        let imbalance = if buy_quantity >= sell_quantity { unfilled } else { -unfilled };
+        (!matched_quantity.is_zero()).then_some(AuctionState {
+            price,
+            matched_quantity,
+            imbalance,
+        })
+    }
+
+    pub fn closing_price(&self) -> Option<f64> {
+        self.closing_price
+    }
+
+    fn make_trade(
+        symbol: &str,
+        buyer: &Order,
+        seller: &Order,
+        aggressor: Option<Side>,
+        quantity: Quantity,
+        price: f64,
+    ) -> Trade {
+        // Timestamps are filled in from the book's clock by record_trade
+        let now = DateTime::<Utc>::UNIX_EPOCH;
+        Trade {
+            id: Uuid::new_v4().to_string(),
+            symbol: symbol.to_string(),
+            buyer_order_id: buyer.id.clone(),
+            seller_order_id: seller.id.clone(),
+            buyer_account: buyer.account.clone(),
+            seller_account: seller.account.clone(),
+            aggressor,
+            quantity,
+            price,
+            timestamp: now,
+            publish_time: now,
+            tick_direction: TickDirection::Zero,
+            mid_at_trade: price,
+            executed_nanos: 0,
+            buyer_tags: buyer.tags.clone(),
+            seller_tags: seller.tags.clone(),
+        }
+    }
+
+    // Rounds the price onto the tick grid, stamps execution and public release
+    // times, and appends the trade to the history as the new last price
+    fn record_trade(&mut self, mut trade: Trade) -> Trade {
+        if let Some(tick_size) = self.config.tick_size {
+            trade.price = self.config.trade_rounding.apply(trade.price, tick_size, trade.aggressor);
+        }
+        trade.timestamp = self.clock.now();
+        trade.executed_nanos = monotonic_nanos();
+        trade.publish_time = trade.timestamp;
+        // The published quote only moves once matching finishes, so it still
+        // shows what the incoming order saw
+        trade.mid_at_trade = (self.market_data.bid + self.market_data.ask) / 2.0;
+        // Ticks are measured against the tape, which deferred prints have not reached
+        let previous = self.last_published(trade.timestamp).map(|t| t.price);
+        trade.tick_direction = TickDirection::between(previous, trade.price);
+        if let Some(deferral) = &self.config.deferred_publication {
+            if trade.quantity >= deferral.min_quantity {
+                trade.publish_time = trade.timestamp + deferral.delay;
+            }
+        }
+
+        self.last_price = trade.price;
+        trace_event!(
+            INFO,
+            symbol = %trade.symbol,
+            trade_id = %trade.id,
+            buyer_order_id = %trade.buyer_order_id,
+            seller_order_id = %trade.seller_order_id,
+            price = trade.price,
+            quantity = %trade.quantity,
+            "trade"
+        );
+        for order_id in [&trade.buyer_order_id, &trade.seller_order_id] {
+            self.trades_by_order.entry(order_id.clone()).or_default().push(self.trades.len());
+        }
+        self.trades.push(trade.clone());
+        trade
+    }
+
+    // Removes an erroneous trade from the history and recomputes the last
+    // price from what remains. The orders involved are not restored: their
+    // filled quantity stays consumed, as on a real venue.
+    pub fn bust_trade(&mut self, trade_id: &str) -> Result<Trade, OrderError> {
+        let idx = self
+            .trades
+            .iter()
+            .position(|t| t.id == trade_id)
+            .ok_or_else(|| OrderError::TradeNotFound(trade_id.to_string()))?;
+
+        let trade = self.trades.remove(idx);
+        if idx < self.trades.len() {
+            // The print after the busted one now follows a different price
+            let previous = idx.checked_sub(1).map(|i| self.trades[i].price);
+            let price = self.trades[idx].price;
+            self.trades[idx].tick_direction = TickDirection::between(previous, price);
+        }
+        if idx < self.session_start {
+            self.session_start -= 1;
+        }
+        self.reindex_trades();
+        self.last_price = self.trades.last().map_or(self.initial_price, |t| t.price);
+        self.update_market_data();
+        Ok(trade)
+    }
+
+    // Positions shift when a trade is removed; busts are rare enough to rebuild
+    fn reindex_trades(&mut self) {
+        self.trades_by_order.clear();
+        for (idx, trade) in self.trades.iter().enumerate() {
+            for order_id in [&trade.buyer_order_id, &trade.seller_order_id] {
+                self.trades_by_order.entry(order_id.clone()).or_default().push(idx);
+            }
+        }
+    }
+
+    // Every fill of an order, oldest first, whether or not it is published yet
+    pub fn trades_for_order(&self, order_id: &str) -> Vec<Trade> {
+        self.trades_by_order
+            .get(order_id)
+            .map(|positions| positions.iter().map(|&idx| self.trades[idx].clone()).collect())
+            .unwrap_or_default()
+    }
+
+    // Last-look hook: a maker with a rejection probability may decline a fill
+    fn maker_rejects(last_look: &mut dyn LastLook, maker: &Order) -> bool {
+        match maker.last_look_reject_prob {
+            Some(prob) => last_look.rejects(maker, prob),
+            None => false,
+        }
+    }
+
+    pub fn cancel_order(&mut self, order_id: &str) -> Option<Order> {
+        let cancelled = if let Some(bid) = self.bids.remove(order_id) {
+            Some(bid)
+        } else if let Some(ask) = self.asks.remove(order_id) {
+            Some(ask)
+        } else if let Some(stop) = self.pending_stops.remove(order_id) {
+            Some(stop)
+        } else if let Some(idx) = self.moc_orders.iter().position(|o| o.id == order_id) {
+            Some(self.moc_orders.remove(idx))
+        } else if let Some(idx) = self.scheduled_orders.iter().position(|o| o.id == order_id) {
+            Some(self.scheduled_orders.remove(idx))
+        } else if let Some(idx) = self.batch_orders.iter().position(|o| o.id == order_id) {
+            Some(self.batch_orders.remove(idx))
+        } else {
+            None
+        };
+
+        if let Some(order) = &cancelled {
+            trace_event!(INFO, symbol = %self.symbol, order_id, "order cancelled");
+            self.finish(order_id, order.quantity);
+            self.update_market_data();
+        }
+        cancelled
+    }
+
+    // Cancels part of a resting order in place, keeping its time priority.
+    // Reducing by the whole remainder or more cancels the order outright.
+    // A partial cut must be positive and leave a whole number of steps.
+    pub fn reduce_order(
+        &mut self,
+        order_id: &str,
+        reduce_by: Quantity,
+    ) -> Result<Quantity, OrderError> {
+        let step = self.config.quantity_step;
+        if reduce_by <= Quantity::ZERO {
+            return Err(OrderError::InvalidQuantity { quantity: reduce_by, step });
+        }
+        let side = if self.bids.contains(order_id) {
+            &mut self.bids
+        } else {
+            &mut self.asks
+        };
+        let quantity = side
+            .get(order_id)
+            .map(|order| order.quantity)
+            .ok_or_else(|| OrderError::OrderNotFound(order_id.to_string()))?;
+
+        if reduce_by >= quantity {
+            self.cancel_order(order_id);
+            return Ok(Quantity::ZERO);
+        }
+        let remaining = quantity - reduce_by;
+        if !remaining.is_multiple_of(step) {
+            return Err(OrderError::InvalidQuantity { quantity: remaining, step });
+        }
+        side.update(order_id, |order| order.quantity = remaining);
+        self.update_market_data();
+        Ok(remaining)
+    }
+
+    // Puts a limit order straight on the book without matching it, for feeds
+    // that report an add the venue has already decided rests
+    pub fn rest_order(&mut self, mut order: Order) -> Result<(), OrderError> {
+        let price = order.price.ok_or(OrderError::InvalidPrice(f64::NAN))?;
+        if !price.is_finite() {
+            return Err(OrderError::InvalidPrice(price));
+        }
+        order.timestamp = self.clock.now();
+        order.accepted_nanos = monotonic_nanos();
+        order.sequence = self.next_sequence;
+        self.next_sequence += 1;
+        self.insert_resting(order);
+        self.update_market_data();
+        Ok(())
+    }
+
+    // Fills part or all of one resting order at its own price, for feeds that
+    // report executions against a referenced order. Nothing ahead of it in the
+    // queue trades; the other side is an anonymous aggressor.
+    pub fn execute_order(
+        &mut self,
+        order_id: &str,
+        quantity: Quantity,
+    ) -> Result<Trade, OrderError> {
+        let orders = if self.bids.contains(order_id) {
+            &mut self.bids
+        } else {
+            &mut self.asks
+        };
+        let idx = orders
+            .slot_of(order_id)
+            .ok_or_else(|| OrderError::OrderNotFound(order_id.to_string()))?;
+        let resting = orders.order_at(idx).clone();
+        if quantity <= Quantity::ZERO || quantity > resting.quantity {
+            return Err(OrderError::InvalidQuantity {
+                quantity,
+                step: self.config.quantity_step,
+            });
+        }
+
+        let price = resting.price.expect("resting orders are priced");
+        let aggressor = match resting.side {
+            Side::Buy => Side::Sell,
+            Side::Sell => Side::Buy,
+        };
+        let contra = Order::new(self.symbol.clone(), aggressor, OrderType::Limit, quantity, None);
+        let (buyer, seller) = match resting.side {
+            Side::Buy => (&resting, &contra),
+            Side::Sell => (&contra, &resting),
+        };
+        let trade = Self::make_trade(&self.symbol, buyer, seller, Some(aggressor), quantity, price);
+
+        if orders.fill_at(idx, quantity).is_some() {
+            self.finish(order_id, Quantity::ZERO);
+        }
+        let trade = self.record_trade(trade);
+        self.update_market_data();
+        Ok(trade)
+    }
+
+    // Pulls every working order except protected ones
+    pub fn cancel_all(&mut self) -> Vec<Order> {
+        let ids: Vec<String> = self
+            .working_orders()
+            .filter(|o| !o.protected)
+            .map(|o| o.id.clone())
+            .collect();
+        ids.iter().filter_map(|id| self.cancel_order(id)).collect()
+    }
+
+    // Pulls an entire price level, e.g. a market maker withdrawing a quote.
+    // Protected orders at the level stay.
+    pub fn cancel_level(&mut self, side: Side, price: f64) -> Vec<Order> {
+        let orders = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+
+        let withdrawn: Vec<String> =
+            orders.level(price).filter(|o| !o.protected).map(|o| o.id.clone()).collect();
+        let cancelled: Vec<Order> = withdrawn.iter().filter_map(|id| orders.remove(id)).collect();
+
+        for order in &cancelled {
+            self.finish(&order.id, order.quantity);
+        }
+        if !cancelled.is_empty() {
+            self.update_market_data();
+        }
+        cancelled
+    }
+
+    // Hash of everything that makes up the book's state: working orders, the
+    // trade history, last and reference prices and the borrow pool. Resting
+    // and stop orders are kept digested as they change, so only the parked
+    // MOC, scheduled and batch queues are walked here.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.symbol.hash(&mut hasher);
+
+        for digest in [self.bids.digest, self.asks.digest, self.pending_stops.digest] {
+            digest.0.hash(&mut hasher);
+        }
+        for orders in [&self.moc_orders, &self.scheduled_orders, &self.batch_orders] {
+            orders.len().hash(&mut hasher);
+            for order in orders {
+                Self::hash_order(order, &mut hasher);
+            }
+        }
+
+        self.trades.len().hash(&mut hasher);
+        if let Some(trade) = self.trades.last() {
+            trade.id.hash(&mut hasher);
+        }
+        self.last_price.to_bits().hash(&mut hasher);
+        self.reference_price.map(f64::to_bits).hash(&mut hasher);
+        self.borrow_available.hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    fn hash_order(order: &Order, hasher: &mut DefaultHasher) {
+        order.id.hash(hasher);
+        order.quantity.hash(hasher);
+        order.price.map(f64::to_bits).hash(hasher);
+    }
+
+    // Replaces the resting orders and trade history wholesale, e.g. when
+    // bootstrapping from a recovery source. Each side must arrive in priority
+    // order; a snapshot that is unsorted, crossed or for another market is
+    // rejected and the book is left as it was. Pending stop, MOC, scheduled
+    // and batch orders belong to the replaced state and are dropped.
+    pub fn load_snapshot(
+        &mut self,
+        bids: Vec<Order>,
+        asks: Vec<Order>,
+        trades: Vec<Trade>,
+    ) -> Result<(), OrderError> {
+        let symbols = bids.iter().chain(&asks).map(|o| &o.symbol);
+        let mut symbols = symbols.chain(trades.iter().map(|t| &t.symbol));
+        if let Some(other) = symbols.find(|s| **s != self.symbol) {
+            return Err(OrderError::InvalidSnapshot(format!(
+                "entry for {} in a snapshot of {}",
+                other, self.symbol
+            )));
+        }
+        if bids.iter().any(|o| o.side != Side::Buy) || asks.iter().any(|o| o.side != Side::Sell) {
+            return Err(OrderError::InvalidSnapshot(
+                "order on the wrong side of the book".to_string(),
+            ));
+        }
+        let mut ids = HashSet::new();
+        if let Some(order) = bids.iter().chain(&asks).find(|o| !ids.insert(&o.id)) {
+            return Err(OrderError::InvalidSnapshot(format!("duplicate order {}", order.id)));
+        }
+        for (name, orders) in [("bid", &bids), ("ask", &asks)] {
+            Self::check_side(name, orders.iter()).map_err(OrderError::InvalidSnapshot)?;
+        }
+
+        // Sequence in snapshot order so queue priority survives later ties,
+        // and queue each level in the order given
+        let mut sides = [OrderSide::new(Side::Buy), OrderSide::new(Side::Sell)];
+        for (side, orders) in sides.iter_mut().zip([bids, asks]) {
+            for mut order in orders {
+                order.sequence = self.next_sequence;
+                self.next_sequence += 1;
+                side.insert(order, PriorityModel::TimeOnly);
+            }
+        }
+        let [bids, asks] = sides;
+        let previous_bids = std::mem::replace(&mut self.bids, bids);
+        let previous_asks = std::mem::replace(&mut self.asks, asks);
+        if let Err(reason) = self.check_invariants() {
+            self.bids = previous_bids;
+            self.asks = previous_asks;
+            return Err(OrderError::InvalidSnapshot(reason));
+        }
+
+        let mut replaced: Vec<Order> = [previous_bids, previous_asks]
+            .into_iter()
+            .flat_map(|mut side| side.drain())
+            .collect();
+        replaced.extend(self.pending_stops.drain());
+        replaced.append(&mut self.moc_orders);
+        replaced.append(&mut self.scheduled_orders);
+        replaced.append(&mut self.batch_orders);
+        for order in &replaced {
+            self.finish(&order.id, order.quantity);
+        }
+        self.last_price = trades.last().map_or(self.initial_price, |t| t.price);
+        self.trades = trades;
+        self.reindex_trades();
+        self.session_start = 0;
+        self.update_market_data();
+        Ok(())
+    }
+
+    // Structural invariants any storage layout must preserve: price-sorted
+    // sides, no empty or unpriced resting orders and an uncrossed book.
+    pub fn check_invariants(&self) -> Result<(), String> {
+        Self::check_side("bid", self.bids.iter())?;
+        Self::check_side("ask", self.asks.iter())?;
+
+        if self.is_locked_or_crossed() {
+            return Err(format!(
+                "Book is crossed: bid {:.2} >= ask {:.2}",
+                self.bids.best_price().unwrap(),
+                self.asks.best_price().unwrap()
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Orders of one side in priority order: each priced, not empty and no
+    // better than the one ahead of it
+    fn check_side<'a>(name: &str, orders: impl Iterator<Item = &'a Order>) -> Result<(), String> {
+        let mut previous: Option<f64> = None;
+        for order in orders {
+            if order.quantity <= Quantity::ZERO {
+                return Err(format!("Resting {} {} has no quantity", name, order.id));
+            }
+            let Some(price) = order.price else {
+                return Err(format!("Resting {} {} has no price", name, order.id));
+            };
+            let out_of_order = previous.is_some_and(|previous| match order.side {
+                Side::Buy => price > previous,
+                Side::Sell => price < previous,
+            });
+            if out_of_order {
+                let direction = if name == "bid" { "descending" } else { "ascending" };
+                return Err(format!("{}s are not sorted in {} price order", name, direction));
+            }
+            previous = Some(price);
+        }
+        Ok(())
+    }
+
+    // A locked (bid == ask) or crossed (bid > ask) book should never survive
+    // matching; seeing one means something bypassed the matcher. Hidden
+    // orders count, since they match like any other.
+    pub fn is_locked_or_crossed(&self) -> bool {
+        let (bid, ask) = (self.bids.best_price(), self.asks.best_price());
+        matches!((bid, ask), (Some(bid), Some(ask)) if bid >= ask)
+    }
+
+    // Self-heal for a crossed book: trades the overlapping orders against
+    // each other until the book is clean. Each fill prints at the price of
+    // whichever order was accepted first, as if the later one had been
+    // matched on arrival.
+    pub fn uncross(&mut self) -> Vec<Trade> {
+        let mut trades = Vec::new();
+
+        while self.is_locked_or_crossed() {
+            let bid = self.bids.front().unwrap();
+            let ask = self.asks.front().unwrap();
+            // The later of the two orders is the taker
+            let (maker, taker, aggressor) = if bid.sequence < ask.sequence {
+                (bid, ask, Side::Sell)
+            } else {
+                (ask, bid, Side::Buy)
+            };
+            let price = self.config.fill_price_side.price(taker, maker.price.unwrap());
+            let quantity = std::cmp::min(bid.quantity, ask.quantity);
+
+            let trade = Self::make_trade(&self.symbol, bid, ask, Some(aggressor), quantity, price);
+            trades.push(self.record_trade(trade));
+
+            let filled_out = [self.bids.fill_front(quantity), self.asks.fill_front(quantity)];
+            for order in filled_out.into_iter().flatten() {
+                self.finish(&order.id, Quantity::ZERO);
+            }
+        }
+
+        if !trades.is_empty() {
+            self.update_market_data();
+        }
+        trades
+    }
+
+    fn insert_resting(&mut self, mut order: Order) {
+        let price = order.price.unwrap();
+        let side = order.side;
+        let orders = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+        order.improved_book = orders.best_price().is_none_or(|best| match side {
+            Side::Buy => price > best,
+            Side::Sell => price < best,
+        });
+
+        orders.insert(order, self.config.priority_model);
+        self.enforce_side_cap(side);
+    }
+
+    // Puts back an order taken off the book, as it was when it rested
+    fn restore_resting(&mut self, order: Order) {
+        match order.side {
+            Side::Buy => self.bids.insert(order, self.config.priority_model),
+            Side::Sell => self.asks.insert(order, self.config.priority_model),
+        }
+    }
+
+    // Each side is kept in priority order, so the back of the queue is the
+    // order furthest from the touch (and last in time at that price). That is
+    // the one evicted when a side grows past its cap, even if it just arrived.
+    fn enforce_side_cap(&mut self, side: Side) {
+        let max_orders = match self.config.max_orders_per_side {
+            Some(max_orders) => max_orders,
+            None => return,
+        };
+        let orders = match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        };
+
+        let mut evicted = Vec::new();
+        while orders.len() > max_orders {
+            evicted.extend(orders.pop_back());
+        }
+        for order in evicted {
+            self.finish(&order.id, order.quantity);
+            self.evicted_orders.push(order);
+        }
+    }
+
+    // Hands over orders cancelled by the depth cap since the last call
+    pub fn take_evicted(&mut self) -> Vec<Order> {
+        std::mem::take(&mut self.evicted_orders)
+    }
+
+    // Whether the order is resting or parked (stop, MOC, scheduled or batch)
+    fn is_working(&self, order_id: &str) -> bool {
+        self.bids.contains(order_id)
+            || self.asks.contains(order_id)
+            || self.working_orders().any(|o| o.id == order_id)
+    }
+
+    // Asks for the order to be reported by take_finished once it stops
+    // working, however that happens: filled, cancelled, expired or evicted
+    fn watch(&mut self, order_id: &str) {
+        self.watched.insert(order_id.to_string());
+    }
+
+    fn finish(&mut self, order_id: &str, unfilled: Quantity) {
+        if !self.watched.is_empty() && self.watched.remove(order_id) {
+            self.finished.push((order_id.to_string(), unfilled));
+        }
+    }
+
+    // Watched orders that stopped working since the last call, each with the
+    // quantity left unfilled (zero when it filled completely)
+    fn take_finished(&mut self) -> Vec<(String, Quantity)> {
+        std::mem::take(&mut self.finished)
+    }
+
+    fn update_market_data(&mut self) {
+        if let Some(bid) = self.best_bid() {
+            self.market_data.bid = bid;
+        }
+        if let Some(ask) = self.best_ask() {
+            self.market_data.ask = ask;
+        }
+        self.market_data.bid_size = Self::best_level_quantity(&self.bids);
+        self.market_data.ask_size = Self::best_level_quantity(&self.asks);
+        let now = self.clock.now();
+        self.market_data.timestamp = now;
+        let quote_mid = (self.market_data.bid + self.market_data.ask) / 2.0;
+        if self.quote_mids.last().map(|&(_, mid)| mid) != Some(quote_mid) {
+            self.quote_mids.push((now, quote_mid));
+        }
+
+        if let MarkPriceMethod::TimeWeightedMid(window) = self.config.mark_price_method {
+            if let Some(mid) = self.mid() {
+                self.mid_samples.push_back((now, mid));
+            }
+            // Keep the newest sample at or before the window start, since its
+            // mid still applies from the start of the window
+            let window_start = now - window;
+            while self.mid_samples.len() > 1 && self.mid_samples[1].0 <= window_start {
+                self.mid_samples.pop_front();
+            }
+        }
+        self.market_data.mark_price = self.mark_price();
+    }
+
+    pub fn set_mark_price_method(&mut self, method: MarkPriceMethod) {
+        self.config.mark_price_method = method;
+        self.mid_samples.clear();
+        self.update_market_data();
+    }
+
+    // Each sampled mid holds until the next sample (or now), clipped to the window
+    fn time_weighted_mid(&self, window: chrono::Duration) -> Option<f64> {
+        let now = self.clock.now();
+        let window_start = now - window;
+        let mut weighted = 0.0;
+        let mut total = 0.0;
+
+        for (idx, &(at, mid)) in self.mid_samples.iter().enumerate() {
+            let until = self.mid_samples.get(idx + 1).map_or(now, |&(next, _)| next);
+            let from = at.max(window_start);
+            if until > from {
+                let micros = (until - from).num_microseconds().unwrap_or(0) as f64;
+                weighted += mid * micros;
+                total += micros;
+            }
+        }
+
+        if total > 0.0 {
+            Some(weighted / total)
+        } else {
+            self.mid_samples.back().map(|&(_, mid)| mid)
+        }
+    }
+
+    // Quotes come from displayed orders only: hidden orders rest and trade
+    // but never set the bid, ask or their sizes, nor the mids built on them
+    pub fn best_bid(&self) -> Option<f64> {
+        self.bids.best_displayed().map(|(price, _)| price)
+    }
+
+    pub fn best_ask(&self) -> Option<f64> {
+        self.asks.best_displayed().map(|(price, _)| price)
+    }
+
+    // Displayed quantity at the best displayed price on one side of the book
+    fn best_level_quantity(orders: &OrderSide) -> Quantity {
+        orders.best_displayed().map_or(Quantity::ZERO, |(_, shown)| shown)
+    }
+
+    // Effective spread: twice the distance between the trade price and the
+    // quote mid it executed against
+    pub fn effective_spread(&self, trade: &Trade) -> f64 {
+        2.0 * (trade.price - trade.mid_at_trade).abs()
+    }
+
+    // Realized spread: what the liquidity provider kept once the quote had
+    // `interval` to move, signed so a positive value is a gain for the maker.
+    // None for prints without an aggressor or while the interval is still running.
+    pub fn realized_spread(&self, trade: &Trade, interval: chrono::Duration) -> Option<f64> {
+        let direction = match trade.aggressor? {
+            Side::Buy => 1.0,
+            Side::Sell => -1.0,
+        };
+        let at = trade.timestamp + interval;
+        if at > self.clock.now() {
+            return None;
+        }
+        let idx = self.quote_mids.partition_point(|&(time, _)| time <= at);
+        let (_, later_mid) = self.quote_mids[idx.checked_sub(1)?];
+        Some(2.0 * direction * (trade.price - later_mid))
+    }
+
+    pub fn mid(&self) -> Option<f64> {
+        match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => Some((bid + ask) / 2.0),
+            _ => None,
+        }
+    }
+
+    // Microprice: the mid weighted towards the side with less resting size,
+    // since that side is the one more likely to be taken out next.
+    pub fn weighted_mid(&self) -> Option<f64> {
+        let (bid, ask) = match (self.best_bid(), self.best_ask()) {
+            (Some(bid), Some(ask)) => (bid, ask),
+            _ => return None,
+        };
+
+        let bid_qty = Self::best_level_quantity(&self.bids).to_f64();
+        let ask_qty = Self::best_level_quantity(&self.asks).to_f64();
+
+        Some((bid * ask_qty + ask * bid_qty) / (bid_qty + ask_qty))
+    }
+
+    // Depth-weighted fair value over the top `levels` displayed levels:
+    //
+    //   fair = (VWAP_bid * Q_bid + VWAP_ask * Q_ask) / (Q_bid + Q_ask)
+    //
+    // where VWAP_side is the size-weighted price of that side's levels and
+    // Q_side their total size. The estimate leans toward the side with more
+    // depth. A one-sided book gives that side's VWAP; an empty one gives None.
+    pub fn fair_value(&self, levels: usize) -> Option<f64> {
+        let (bids, asks) = self.depth(levels);
+        let side_totals = |side: &[PriceLevel]| {
+            let quantity: f64 = side.iter().map(|(_, q)| q.to_f64()).sum();
+            let value: f64 = side.iter().map(|(p, q)| p * q.to_f64()).sum();
+            (value, quantity)
+        };
+        let (bid_value, bid_qty) = side_totals(&bids);
+        let (ask_value, ask_qty) = side_totals(&asks);
+
+        // Each side's VWAP times its size is just its value, so the weighted
+        // average collapses to total value over total size
+        let total_qty = bid_qty + ask_qty;
+        (total_qty > 0.0).then(|| (bid_value + ask_value) / total_qty)
+    }
+
+    // Price used to value positions: the external reference if any, otherwise
+    // the configured method, falling back to the last trade when the book
+    // cannot supply a mid.
+    pub fn mark_price(&self) -> f64 {
+        let last = self.public_last_price(self.clock.now());
+        self.reference_price.unwrap_or_else(|| match self.config.mark_price_method {
+            MarkPriceMethod::Last => last,
+            MarkPriceMethod::Mid => self.mid().unwrap_or(last),
+            MarkPriceMethod::TimeWeightedMid(window) => self
+                .time_weighted_mid(window)
+                .or_else(|| self.mid())
+                .unwrap_or(last),
+        })
+    }
+
+    // Quantity a buyer (seller) must take to lift the best ask (hit the best
+    // bid) until it sits at or beyond the target: every resting order priced
+    // strictly better than the target. If the book runs out first, this is
+    // the whole opposite side.
+    pub fn quantity_to_reach_price(&self, side: Side, target_price: f64) -> Quantity {
+        match side {
+            Side::Buy => self
+                .asks
+                .iter()
+                .take_while(|ask| ask.price.unwrap() < target_price)
+                .map(|ask| ask.quantity)
+                .sum(),
+            Side::Sell => self
+                .bids
+                .iter()
+                .take_while(|bid| bid.price.unwrap() > target_price)
+                .map(|bid| bid.quantity)
+                .sum(),
+        }
+    }
+
+    // Cash needed to take `quantity` from one side, level by level; None if
+    // the side cannot fill it
+    fn sweep_notional(&self, orders: &OrderSide, quantity: Quantity) -> Option<f64> {
+        let mut remaining = quantity;
+        let mut notional = 0.0;
+
+        for resting in orders.iter() {
+            if remaining.is_zero() {
+                break;
+            }
+            let filled = remaining.min(resting.quantity);
+            notional += self.notional(resting.price.unwrap(), filled);
+            remaining -= filled;
+        }
+
+        remaining.is_zero().then_some(notional)
+    }
+
+    // Liquidity-taking cost of buying `quantity` at the asks and selling it
+    // straight back into the bids, walking as many levels as needed. None if
+    // either side is too thin to absorb the full quantity.
+    pub fn round_trip_cost(&self, quantity: Quantity) -> Option<f64> {
+        let bought = self.sweep_notional(&self.asks, quantity)?;
+        let sold = self.sweep_notional(&self.bids, quantity)?;
+        Some(bought - sold)
+    }
+
+    // Worst price an incoming order would reach while sweeping the opposite side
+    fn sweep_limit_price(
+        &self,
+        side: Side,
+        quantity: Quantity,
+        limit: Option<f64>,
+    ) -> Option<f64> {
+        let resting_orders = match side {
+            Side::Buy => &self.asks,
+            Side::Sell => &self.bids,
+        };
+
+        let mut remaining = quantity;
+        let mut worst_price = None;
+
+        for resting in resting_orders.iter() {
+            if remaining.is_zero() {
+                break;
+            }
+
+            let price = resting.price.unwrap();
+            let acceptable = match (side, limit) {
+                (_, None) => true,
+                (Side::Buy, Some(limit)) => price <= limit,
+                (Side::Sell, Some(limit)) => price >= limit,
+            };
+            if !acceptable {
+                break;
+            }
+
+            worst_price = Some(price);
+            remaining = remaining.saturating_sub(resting.quantity);
+        }
+
+        worst_price
+    }
+
+    // Cheap (bid, ask, last) read straight from the book for hot-path consumers,
+    // avoiding the MarketData clone and its symbol allocation.
+    pub fn top_of_book(&self) -> (Option<f64>, Option<f64>, Option<f64>) {
+        (
+            self.best_bid(),
+            self.best_ask(),
+            Some(self.public_last_price(self.clock.now())),
+        )
+    }
+
+    // The public last price is that of the newest print already on the tape,
+    // so a deferred print only moves it once published, and then only if no
+    // later trade has printed meanwhile
+    pub fn get_market_data(&self) -> MarketData {
+        let mut market_data = self.market_data.clone();
+        market_data.last_price = self.public_last_price(self.clock.now());
+        market_data
+    }
+
+    fn last_published(&self, now: DateTime<Utc>) -> Option<&Trade> {
+        self.trades.iter().rev().find(|trade| trade.publish_time <= now)
+    }
+
+    fn public_last_price(&self, now: DateTime<Utc>) -> f64 {
+        self.last_published(now).map_or(self.initial_price, |t| t.price)
+    }
+
+    // The `n` biggest displayed orders on a side wherever they sit in the
+    // book, largest first; equal sizes keep book priority
+    pub fn largest_orders(&self, side: Side, n: usize) -> Vec<Order> {
+        let orders = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        let mut visible: Vec<&Order> = orders.iter().filter(|o| !o.hidden).collect();
+        visible.sort_by_key(|o| Reverse(o.quantity));
+        visible.into_iter().take(n).cloned().collect()
+    }
+
+    // Visible quantity aggregated per price level, best levels first
+    pub fn depth(&self, levels: usize) -> (Vec<PriceLevel>, Vec<PriceLevel>) {
+        (
+            Self::aggregate_levels(&self.bids, levels),
+            Self::aggregate_levels(&self.asks, levels),
+        )
+    }
+
+    // Visible quantity available up to each level, moving away from the touch
+    pub fn depth_curve(&self, side: Side) -> Vec<PriceLevel> {
+        let orders = match side {
+            Side::Buy => &self.bids,
+            Side::Sell => &self.asks,
+        };
+        let mut cumulative = Quantity::ZERO;
+        Self::aggregate_levels(orders, usize::MAX)
+            .into_iter()
+            .map(|(price, quantity)| {
+                cumulative += quantity;
+                (price, cumulative)
+            })
+            .collect()
+    }
+
+    pub fn depth_snapshot(&self, levels: usize) -> DepthSnapshot {
+        let (bids, asks) = self.depth(levels);
+        DepthSnapshot {
+            symbol: self.symbol.clone(),
+            timestamp: self.clock.now(),
+            bids,
+            asks,
+        }
+    }
+
+    // Full visible depth in the compact binary layout, for hot snapshot paths
+    pub fn snapshot_bytes(&self) -> Result<Vec<u8>, OrderError> {
+        self.depth_snapshot(usize::MAX).to_bytes()
+    }
+
+    fn aggregate_levels(orders: &OrderSide, levels: usize) -> Vec<PriceLevel> {
+        let mut aggregated: Vec<PriceLevel> = Vec::new();
+
+        for order in orders.iter().filter(|o| !o.hidden) {
+            let price = order.price.unwrap();
+            match aggregated.last_mut() {
+                Some((level_price, quantity)) if *level_price == price => {
+                    *quantity += order.quantity;
+                }
+                _ => {
+                    if aggregated.len() == levels {
+                        break;
+                    }
+                    aggregated.push((price, order.quantity));
+                }
+            }
+        }
+
+        aggregated
+    }
+
+    pub fn get_orders(&self) -> (Vec<Order>, Vec<Order>) {
+        (
+            self.bids.iter().filter(|o| !o.hidden).cloned().collect(),
+            self.asks.iter().filter(|o| !o.hidden).cloned().collect(),
+        )
+    }
+
+    // Public tape view: trades whose publication is still deferred are hidden
+    pub fn get_trades(&self) -> Vec<Trade> {
+        self.public_trades(self.clock.now())
+    }
+
+    pub fn public_trades(&self, now: DateTime<Utc>) -> Vec<Trade> {
+        self.trades
+            .iter()
+            .filter(|trade| trade.publish_time <= now)
+            .cloned()
+            .collect()
+    }
+
+    // Cash value of a price and quantity once the contract multiplier is applied
+    pub fn notional(&self, price: f64, quantity: Quantity) -> f64 {
+        price * quantity.to_f64() * self.config.contract_multiplier
+    }
+
+    // Cash value of every trade in the book's history. Each print is summed in
+    // fixed point (prices to eight places, quantities raw) so the total does
+    // not drift over a long session the way a running f64 sum would.
+    pub fn traded_notional(&self) -> f64 {
+        let scale = Quantity::SCALE as i128;
+        let total: i128 = self
+            .trades
+            .iter()
+            .map(|t| (t.price * scale as f64).round() as i128 * t.quantity.raw() as i128)
+            .sum();
+        total as f64 / (scale * scale) as f64 * self.config.contract_multiplier
+    }
+
+    // Every trade regardless of publication, for internal and account use
+    pub fn all_trades(&self) -> Vec<Trade> {
+        self.trades.clone()
+    }
+}
+
+// ===== VENUES =====
+
+// Several venues trading the same symbol, each an engine of its own with
+// one market. Orders are checked against the best quote across all venues so
+// none executes at an inferior price (a simplified trade-through rule), then
+// go through the chosen venue's place_order with its validators, accounts
+// and audit log.
+pub struct VenueGroup {
+    symbol: String,
+    venues: Vec<(String, TradingEngine)>,
+}
+
+impl VenueGroup {
+    pub fn new(symbol: &str) -> Self {
+        VenueGroup {
+            symbol: symbol.to_string(),
+            venues: Vec::new(),
+        }
+    }
+
+    pub fn add_venue(&mut self, venue: &str, initial_price: f64) {
+        self.add_venue_with_params(venue, initial_price, BookConfig::default());
+    }
+
+    pub fn add_venue_with_params(&mut self, venue: &str, initial_price: f64, config: BookConfig) {
+        let mut engine = TradingEngine::new();
+        engine.create_market_with_params(&self.symbol, initial_price, config);
+        self.venues.push((venue.to_string(), engine));
+    }
+
+    pub fn venue(&self, venue: &str) -> Option<&OrderBook> {
+        self.venue_engine(venue).map(|engine| &engine.order_books[&self.symbol])
+    }
+
+    pub fn venue_engine(&self, venue: &str) -> Option<&TradingEngine> {
+        self.venues.iter().find(|(name, _)| name == venue).map(|(_, engine)| engine)
+    }
+
+    // For venue-specific setup such as sessions, clocks or risk limits
+    pub fn venue_engine_mut(&mut self, venue: &str) -> Option<&mut TradingEngine> {
+        self.venues.iter_mut().find(|(name, _)| name == venue).map(|(_, engine)| engine)
+    }
+
+    fn book(&self, idx: usize) -> &OrderBook {
+        &self.venues[idx].1.order_books[&self.symbol]
+    }
+
+    pub fn get_venues(&self) -> Vec<String> {
+        self.venues.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    pub fn place_order(&mut self, venue: &str, order: Order) -> Result<Vec<Trade>, OrderError> {
+        if order.symbol != self.symbol {
+            return Err(OrderError::SymbolMismatch {
+                expected: self.symbol.clone(),
+                actual: order.symbol.clone(),
+            });
+        }
+
+        let idx = self
+            .venues
+            .iter()
+            .position(|(name, _)| name == venue)
+            .ok_or_else(|| OrderError::VenueNotFound(venue.to_string()))?;
+
+        let limit = match order.order_type {
+            OrderType::Limit => order.price,
+            OrderType::Market | OrderType::MarketOnClose | OrderType::Stop => None,
+        };
+
+        let order_book = self.book(idx);
+        if let Some(worst_price) = order_book.sweep_limit_price(order.side, order.quantity, limit) {
+            if let Some((away_venue, away_price)) = self.best_away_price(idx, order.side) {
+                let trades_through = match order.side {
+                    Side::Buy => worst_price > away_price,
+                    Side::Sell => worst_price < away_price,
+                };
+                if trades_through {
+                    return Err(OrderError::TradeThrough {
+                        venue: away_venue,
+                        price: away_price,
+                    });
+                }
+            }
+        }
+
+        self.venues[idx].1.place_order(order)
+    }
+
+    // Sends the order to the venue quoting the best opposite price. Orders
+    // with nothing to trade against anywhere go to the first (primary) venue.
+    pub fn route_order(&mut self, order: Order) -> Result<(String, Vec<Trade>), OrderError> {
+        let mut best: Option<(usize, f64)> = None;
+
+        for i in 0..self.venues.len() {
+            let order_book = self.book(i);
+            let price = match order.side {
+                Side::Buy => order_book.best_ask(),
+                Side::Sell => order_book.best_bid(),
+            };
+
+            if let Some(price) = price {
+                let better = match (best, order.side) {
+                    (None, _) => true,
+                    (Some((_, best_price)), Side::Buy) => price < best_price,
+                    (Some((_, best_price)), Side::Sell) => price > best_price,
+                };
+                if better {
+                    best = Some((i, price));
+                }
+            }
+        }
+
+        let idx = match best {
+            Some((idx, _)) => idx,
+            None if !self.venues.is_empty() => 0,
+            None => return Err(OrderError::VenueNotFound(self.symbol.clone())),
+        };
+
+        let venue = self.venues[idx].0.clone();
+        let trades = self.place_order(&venue, order)?;
+        Ok((venue, trades))
+    }
+
+    // Best opposite-side price on any venue other than the one at `idx`
+    fn best_away_price(&self, idx: usize, side: Side) -> Option<(String, f64)> {
+        let mut best: Option<(String, f64)> = None;
+
+        for (i, (name, _)) in self.venues.iter().enumerate() {
+            if i == idx {
+                continue;
+            }
+            let order_book = self.book(i);
+
+            let price = match side {
+                Side::Buy => order_book.best_ask(),
+                Side::Sell => order_book.best_bid(),
+            };
+
+            if let Some(price) = price {
+                let better = match (&best, side) {
+                    (None, _) => true,
+                    (Some((_, best_price)), Side::Buy) => price < *best_price,
+                    (Some((_, best_price)), Side::Sell) => price > *best_price,
+                };
+                if better {
+                    best = Some((name.clone(), price));
+                }
+            }
+        }
+
+        best
+    }
+}
+
+// ===== FEES =====
+
+#[derive(Debug, Clone, Copy)]
+pub struct VolumeTier {
+    min_volume: f64, // Trailing traded notional needed to reach this tier
+    maker_rate: f64, // Fraction of notional; negative for a rebate
+    taker_rate: f64,
+}
+
+impl VolumeTier {
+    pub fn new(min_volume: f64, maker_rate: f64, taker_rate: f64) -> Self {
+        VolumeTier {
+            min_volume,
+            maker_rate,
+            taker_rate,
+        }
+    }
+}
+
+// Tiered maker/taker pricing keyed on an account's traded notional over
+// the last `lookback_periods` fee periods (including the current one).
+#[derive(Debug, Clone)]
+pub struct VolumeTierSchedule {
+    tiers: Vec<VolumeTier>, // Sorted by ascending min_volume
+    lookback_periods: usize,
+}
+
+impl VolumeTierSchedule {
+    pub fn new(mut tiers: Vec<VolumeTier>) -> Self {
+        tiers.sort_by(|a, b| a.min_volume.total_cmp(&b.min_volume));
+        VolumeTierSchedule {
+            tiers,
+            lookback_periods: 30,
+        }
+    }
+
+    pub fn with_lookback_periods(mut self, lookback_periods: usize) -> Self {
+        self.lookback_periods = lookback_periods.max(1);
+        self
+    }
+
+    // Highest tier the volume qualifies for; accounts below every tier pay nothing
+    pub fn tier_for(&self, volume: f64) -> Option<&VolumeTier> {
+        self.tiers.iter().rev().find(|tier| volume >= tier.min_volume)
+    }
+}
+
+// ===== FX =====
+
+// Source of conversion rates for orders quoted outside their book's currency
+pub trait FxRates: fmt::Debug + Send + Sync {
+    // Units of `to` per unit of `from`
+    fn rate(&self, from: &str, to: &str) -> Option<f64>;
+}
+
+// Fixed rates, with each pair also usable in the inverse direction
+#[derive(Debug, Clone, Default)]
+pub struct StaticFxRates {
+    rates: HashMap<(String, String), f64>,
+}
+
+impl StaticFxRates {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_rate(mut self, from: &str, to: &str, rate: f64) -> Self {
+        self.rates.insert((from.to_string(), to.to_string()), rate);
+        self
+    }
+}
+
+impl FxRates for StaticFxRates {
+    fn rate(&self, from: &str, to: &str) -> Option<f64> {
+        let pair = (from.to_string(), to.to_string());
+        let inverse = (to.to_string(), from.to_string());
+        self.rates
+            .get(&pair)
+            .copied()
+            .or_else(|| self.rates.get(&inverse).map(|rate| 1.0 / rate))
+    }
+}
+
+// ===== AUDIT =====
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    CreateMarket,
+    PlaceOrder,
+    CancelOrder,
+    ReduceOrder,
+    CancelLevel,
+    CloseMarket,
+    ReferencePrice,
+    RepairBook,
+    LoadSnapshot,
+    TimeEvents,
+    Auction,
+    BustTrade,
+    CancelAll,
+    Resume,
+    PlaceOrderUnchecked, // Placed by an account exempt from risk checks
+    RestOrder,           // Feed add rested without matching
+    OcoCancel,           // Cancelled as the other leg of an OCO pair
+    ExecuteOrder,        // Feed execution against one resting order
+    Funding,             // Funding payments settled on a market's open positions
+    BorrowPool,          // Shares available to borrow reset for a market
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuditAction::CreateMarket => write!(f, "CREATE"),
+            AuditAction::PlaceOrder => write!(f, "PLACE"),
+            AuditAction::CancelOrder => write!(f, "CANCEL"),
+            AuditAction::ReduceOrder => write!(f, "REDUCE"),
+            AuditAction::CancelLevel => write!(f, "CANCEL_LEVEL"),
+            AuditAction::CloseMarket => write!(f, "CLOSE"),
+            AuditAction::ReferencePrice => write!(f, "REFERENCE"),
+            AuditAction::RepairBook => write!(f, "REPAIR"),
+            AuditAction::LoadSnapshot => write!(f, "SNAPSHOT"),
+            AuditAction::TimeEvents => write!(f, "TIME"),
+            AuditAction::Auction => write!(f, "AUCTION"),
+            AuditAction::BustTrade => write!(f, "BUST"),
+            AuditAction::CancelAll => write!(f, "CANCEL_ALL"),
+            AuditAction::Resume => write!(f, "RESUME"),
+            AuditAction::PlaceOrderUnchecked => write!(f, "PLACE_UNCHECKED"),
+            AuditAction::RestOrder => write!(f, "REST"),
+            AuditAction::OcoCancel => write!(f, "OCO_CANCEL"),
+            AuditAction::ExecuteOrder => write!(f, "EXECUTE"),
+            AuditAction::Funding => write!(f, "FUNDING"),
+            AuditAction::BorrowPool => write!(f, "BORROW_POOL"),
+        }
+    }
+}
+
+// One state-changing operation. Hashes cover the whole engine, so the
+// after_hash of an entry always equals the before_hash of the next one
+// unless something changed the books without being audited.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    audit_id: u64,
+    action: AuditAction,
+    symbol: String,
+    order_id: Option<String>,
+    before_hash: u64,
+    after_hash: u64,
+    timestamp: DateTime<Utc>,
+}
+
+impl fmt::Display for AuditEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Audit[{}] {}: {} {} {} ({:016x} -> {:016x})",
+            self.audit_id,
+            self.timestamp.format("%Y-%m-%d %H:%M:%S%.6f"),
+            self.action,
+            self.symbol,
+            self.order_id.as_deref().unwrap_or("-"),
+            self.before_hash,
+            self.after_hash
+        )
+    }
+}
+
+// ===== TRADING ENGINE =====
+
+// Per-account message counts used for order-to-trade ratio surveillance
+#[derive(Debug, Clone, Default)]
+pub struct AccountActivity {
+    orders: u64,
+    cancels: u64,
+    trades: u64,
+}
+
+impl AccountActivity {
+    // Messages (placements + cancels) per trade. Accounts with no trades yet
+    // are measured against a single trade so the ratio stays finite.
+    pub fn order_to_trade_ratio(&self) -> f64 {
+        (self.orders + self.cancels) as f64 / self.trades.max(1) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OrderToTradeLimit {
+    max_ratio: f64,
+    min_messages: u64, // Ratio is only enforced once an account has sent this many messages
+}
+
+impl OrderToTradeLimit {
+    pub fn new(max_ratio: f64, min_messages: u64) -> Self {
+        OrderToTradeLimit {
+            max_ratio,
+            min_messages,
+        }
+    }
+}
+
+// Exposure for one symbol, valued at the book's mark price
+#[derive(Debug, Clone)]
+pub struct SymbolRisk {
+    symbol: String,
+    position: Quantity,
+    price: f64,
+    notional: f64, // Signed: negative for shorts
+    unrealized_pnl: f64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PortfolioRisk {
+    account: String,
+    positions: Vec<SymbolRisk>, // Sorted by symbol
+    gross_exposure: f64,
+    net_exposure: f64,
+    unrealized_pnl: f64,
+    largest_concentration: Option<(String, f64)>, // Symbol and its share of gross exposure
+}
+
+impl SymbolRisk {
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn position(&self) -> Quantity {
+        self.position
+    }
+
+    pub fn price(&self) -> f64 {
+        self.price
+    }
+
+    pub fn notional(&self) -> f64 {
+        self.notional
+    }
+}
+
+impl PortfolioRisk {
+    pub fn account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn positions(&self) -> &[SymbolRisk] {
+        &self.positions
+    }
+
+    pub fn gross_exposure(&self) -> f64 {
+        self.gross_exposure
+    }
+
+    pub fn net_exposure(&self) -> f64 {
+        self.net_exposure
+    }
+
+    pub fn unrealized_pnl(&self) -> f64 {
+        self.unrealized_pnl
+    }
+
+    pub fn largest_concentration(&self) -> Option<(&str, f64)> {
+        self.largest_concentration.as_ref().map(|(symbol, share)| (symbol.as_str(), *share))
+    }
+}
+
+// One pre-trade check. Validators run in registration order inside
+// place_order, after the market lookup and before anything is changed, and
+// the first rejection wins. The engine is passed read-only for context.
+pub trait OrderValidator: Send + Sync {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError>;
+
+    // Risk checks are waived for accounts flagged to skip them
+    fn is_risk_check(&self) -> bool {
+        false
+    }
+}
+
+// Rejects orders while the market is outside its trading session
+pub struct SessionValidator;
+
+impl OrderValidator for SessionValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        if !engine.order_books[&order.symbol].is_open(engine.now()) {
+            return Err(OrderError::MarketClosed(order.symbol.clone()));
+        }
+        Ok(())
+    }
+}
+
+// Rejects orders while the market is in an imbalance halt
+pub struct ImbalanceHaltValidator;
+
+impl OrderValidator for ImbalanceHaltValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        if let Some(imbalance) = engine.order_books[&order.symbol].halted_imbalance() {
+            return Err(OrderError::MarketHalted {
+                symbol: order.symbol.clone(),
+                imbalance,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Quantity must be positive and a whole number of the book's quantity step
+pub struct QuantityStepValidator;
+
+impl OrderValidator for QuantityStepValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        let step = engine.order_books[&order.symbol].config.quantity_step;
+        if order.quantity <= Quantity::ZERO || !order.quantity.is_multiple_of(step) {
+            return Err(OrderError::InvalidQuantity {
+                quantity: order.quantity,
+                step,
+            });
+        }
+        Ok(())
+    }
+}
+
+// Rejects any single order larger than the book's fat-finger cap
+pub struct MaxOrderQuantityValidator;
+
+impl OrderValidator for MaxOrderQuantityValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        let book = &engine.order_books[&order.symbol];
+        if let Some(max_quantity) = book.config.max_order_quantity {
+            if order.quantity > max_quantity {
+                return Err(OrderError::QuantityTooLarge {
+                    symbol: order.symbol.clone(),
+                    quantity: order.quantity,
+                    max_quantity,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Limit and stop prices must sit on the book's tick grid, when it has one
+pub struct PriceTickValidator;
+
+impl OrderValidator for PriceTickValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        let Some(tick_size) = engine.order_books[&order.symbol].config.tick_size else {
+            return Ok(());
+        };
+        for price in [order.price, order.stop_price].into_iter().flatten() {
+            if !is_on_tick(price, tick_size) {
+                return Err(OrderError::OffTickPrice { price, tick_size });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Market orders carry no price, so no limit price is reserved for them. NaN and
+// infinities are never accepted; exactly zero only where the book opts out.
+pub struct PriceValidityValidator;
+
+impl OrderValidator for PriceValidityValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        let reject_zero = engine.order_books[&order.symbol].config.reject_zero_price;
+        for price in [order.price, order.stop_price].into_iter().flatten() {
+            if !price.is_finite() || (reject_zero && price == 0.0) {
+                return Err(OrderError::InvalidPrice(price));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Throttles accounts whose order-to-trade ratio is over the engine's limit
+pub struct OrderToTradeValidator;
+
+impl OrderValidator for OrderToTradeValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        if let (Some(account), Some(limit)) = (&order.account, &engine.order_to_trade_limit) {
+            if let Some(activity) = engine.account_activity.get(account) {
+                if activity.orders + activity.cancels >= limit.min_messages
+                    && activity.order_to_trade_ratio() > limit.max_ratio
+                {
+                    return Err(OrderError::Throttled {
+                        account: account.clone(),
+                        ratio: activity.order_to_trade_ratio(),
+                        max_ratio: limit.max_ratio,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_risk_check(&self) -> bool {
+        true
+    }
+}
+
+// Pre-trade position limit. The order is rejected if the account's position
+// could breach the limit in either direction once this order and every open
+// order on the same side fill.
+pub struct PositionLimitValidator;
+
+impl OrderValidator for PositionLimitValidator {
+    fn validate(&self, engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+        let account = match &order.account {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let max_position = match engine
+            .position_limits
+            .get(account)
+            .and_then(|symbols| symbols.get(&order.symbol))
+        {
+            Some(&max_position) => max_position,
+            None => return Ok(()),
+        };
+
+        let open_quantity: Quantity = engine.order_books[&order.symbol]
+            .open_orders(account)
+            .iter()
+            .filter(|open| open.side == order.side)
+            .map(|open| open.quantity)
+            .sum();
+        let added = open_quantity + order.quantity;
+        let position = engine.get_position(account, &order.symbol);
+        let exposure = match order.side {
+            Side::Buy => position + added,
+            Side::Sell => position - added,
+        }
+        .abs();
+
+        if exposure > max_position {
+            return Err(OrderError::PositionLimitExceeded {
+                account: account.clone(),
+                symbol: order.symbol.clone(),
+                exposure,
+                max_position,
+            });
+        }
+        Ok(())
+    }
+
+    fn is_risk_check(&self) -> bool {
+        true
+    }
+}
+
+// Receives every trade the engine applies, whichever operation produced it:
+// orders, stops set off by a reference price, auctions and time events.
+// A print deferred from the public tape is passed on once its publish time
+// has come, by the next engine operation that applies trades, such as
+// process_time_events. A deferred print that is busted first never is.
+pub trait TradeSink: Send + Sync {
+    fn publish(&self, trade: &Trade);
+}
+
+// Consumer end of the bounded trade ring, a fixed-size channel with one
+// producer (the engine) and one consumer. The consumer drains it without
+// touching the engine. When the ring is full the engine never waits: the
+// trade is dropped and counted, and the consumer sees the count as its
+// backpressure signal.
+pub struct TradeRingConsumer {
+    receiver: Receiver<Trade>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl TradeRingConsumer {
+    pub fn try_recv(&self) -> Option<Trade> {
+        self.receiver.try_recv().ok()
+    }
+
+    // Everything currently in the ring, oldest first
+    pub fn drain(&self) -> Vec<Trade> {
+        self.receiver.try_iter().collect()
+    }
+
+    // Trades lost to a full ring since it was created
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+// Result of a submission, as replayed to a client retrying the same key
+pub type OrderOutcome = Result<Vec<Trade>, OrderError>;
+
+// Idempotency keys are per account, so two clients may pick the same one
+type IdempotencyKey = (Option<String>, String);
+
+// Trades of recently accepted submissions, evicting the least recently used
+// key once full. Each use is appended to `recency`, leaving the key's earlier
+// entries stale, so a retry never has to search the queue.
+#[derive(Debug)]
+struct IdempotencyCache {
+    capacity: usize,
+    next_use: u64,
+    accepted: HashMap<IdempotencyKey, (u64, Vec<Trade>)>, // Last use and trades
+    recency: VecDeque<(u64, IdempotencyKey)>,            // Uses, oldest first
+}
+
+impl IdempotencyCache {
+    fn new(capacity: usize) -> Self {
+        IdempotencyCache {
+            capacity,
+            next_use: 0,
+            accepted: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    fn get(&mut self, key: &IdempotencyKey) -> Option<Vec<Trade>> {
+        let (last_use, trades) = self.accepted.get_mut(key)?;
+        *last_use = self.next_use;
+        let trades = trades.clone();
+        self.recency.push_back((self.next_use, key.clone()));
+        self.next_use += 1;
+        self.compact();
+        Some(trades)
+    }
+
+    fn insert(&mut self, key: IdempotencyKey, trades: Vec<Trade>) {
+        self.accepted.insert(key.clone(), (self.next_use, trades));
+        self.recency.push_back((self.next_use, key));
+        self.next_use += 1;
+        while self.accepted.len() > self.capacity {
+            let Some((used, oldest)) = self.recency.pop_front() else {
+                break;
+            };
+            if self.is_last_use(used, &oldest) {
+                self.accepted.remove(&oldest);
+            }
+        }
+        self.compact();
+    }
+
+    fn is_last_use(&self, used: u64, key: &IdempotencyKey) -> bool {
+        self.accepted.get(key).is_some_and(|(last_use, _)| *last_use == used)
+    }
+
+    // Drops stale uses once they outnumber live keys, so each use costs
+    // amortised constant time
+    fn compact(&mut self) {
+        if self.recency.len() <= 2 * self.accepted.len().max(self.capacity) {
+            return;
+        }
+        let recency = std::mem::take(&mut self.recency);
+        self.recency =
+            recency.into_iter().filter(|(used, key)| self.is_last_use(*used, key)).collect();
+    }
+}
+
+struct TradeRingProducer {
+    sender: SyncSender<Trade>,
+    dropped: Arc<AtomicU64>,
+}
+
+// One leg of a spread: `ratio` units of `symbol` per unit of the spread
+#[derive(Debug, Clone)]
+pub struct SpreadLeg {
+    symbol: String,
+    side: Side,
+    ratio: u32,
+}
+
+// A combination that trades all of its legs or none of them. The net price
+// is the cash paid per spread unit: bought legs cost, sold legs pay, so a
+// negative limit demands a credit. It is cash, so each leg counts at its
+// price times its market's contract multiplier. Legs take liquidity
+// immediately; nothing rests.
+#[derive(Debug, Clone)]
+pub struct SpreadOrder {
+    legs: Vec<SpreadLeg>,
+    quantity: Quantity,
+    max_net_price: f64,
+    account: Option<String>,
+}
+
+impl SpreadOrder {
+    pub fn new(quantity: Quantity, max_net_price: f64) -> Self {
+        SpreadOrder {
+            legs: Vec::new(),
+            quantity,
+            max_net_price,
+            account: None,
+        }
+    }
+
+    pub fn with_leg(mut self, symbol: &str, side: Side, ratio: u32) -> Self {
+        self.legs.push(SpreadLeg {
+            symbol: symbol.to_string(),
+            side,
+            ratio,
+        });
+        self
+    }
+
+    pub fn with_account(mut self, account: &str) -> Self {
+        self.account = Some(account.to_string());
+        self
+    }
+}
+
+pub struct TradingEngine {
+    order_books: HashMap<String, OrderBook>,
+    connection_orders: HashMap<u64, Vec<(String, String)>>, // connection id -> (symbol, order id)
+    order_connections: HashMap<String, u64>, // order id -> connection that placed it
+    account_activity: HashMap<String, AccountActivity>,
+    order_to_trade_limit: Option<OrderToTradeLimit>,
+    positions: HashMap<String, HashMap<String, Quantity>>, // account -> symbol -> net quantity
+    borrowed: HashMap<String, HashMap<String, Quantity>>, // account -> symbol -> located borrow
+    borrow_reserved: HashMap<String, (String, Quantity)>, // order id -> (account, unsold borrow)
+    position_limits: HashMap<String, HashMap<String, Quantity>>, // account -> symbol -> max
+    cash: HashMap<String, f64>,
+    account_digest: SetDigest, // Positions, cash, fees paid and borrow of every account
+    fee_schedule: Option<VolumeTierSchedule>,
+    fee_volume: HashMap<String, VecDeque<f64>>, // account -> notional per fee period, newest last
+    fees_paid: HashMap<String, f64>,
+    trade_fees: HashMap<String, Vec<(String, f64)>>, // trade id -> (account, fee), this fee period
+    cost_basis: HashMap<String, HashMap<String, f64>>, // account -> symbol -> open position cost
+    order_latency: HashMap<String, chrono::Duration>, // account -> submit-to-receipt delay
+    fx_rates: Option<Arc<dyn FxRates>>,
+    skip_risk_checks: HashSet<String>, // Privileged accounts, e.g. the venue's own liquidity
+    oco_links: HashMap<String, (String, String)>, // order id -> (symbol, linked order id)
+    oco_cancels: Vec<(String, String)>, // (symbol, order id) of siblings due to be cancelled
+    trade_ring: Option<TradeRingProducer>,
+    trade_sinks: Vec<Box<dyn TradeSink>>,
+    deferred_trades: Vec<Trade>, // Held back from the sinks until their publish time
+    idempotency: IdempotencyCache,
+    client_order_ids: HashMap<String, HashSet<String>>, // account -> client order ids accepted
+    audit_log: Vec<AuditEntry>,
+    clock: Arc<dyn Clock>, // Shared with every book the engine creates
+    validators: Vec<Box<dyn OrderValidator>>,
+}
+
+impl Default for TradingEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TradingEngine {
+    pub fn new() -> Self {
+        TradingEngine {
+            order_books: HashMap::new(),
+            connection_orders: HashMap::new(),
+            order_connections: HashMap::new(),
+            account_activity: HashMap::new(),
+            order_to_trade_limit: None,
+            positions: HashMap::new(),
+            borrowed: HashMap::new(),
+            borrow_reserved: HashMap::new(),
+            position_limits: HashMap::new(),
+            cash: HashMap::new(),
+            account_digest: SetDigest::default(),
+            fee_schedule: None,
+            fee_volume: HashMap::new(),
+            fees_paid: HashMap::new(),
+            trade_fees: HashMap::new(),
+            cost_basis: HashMap::new(),
+            order_latency: HashMap::new(),
+            fx_rates: None,
+            skip_risk_checks: HashSet::new(),
+            oco_links: HashMap::new(),
+            oco_cancels: Vec::new(),
+            trade_ring: None,
+            trade_sinks: Vec::new(),
+            deferred_trades: Vec::new(),
+            idempotency: IdempotencyCache::new(1024),
+            client_order_ids: HashMap::new(),
+            audit_log: Vec::new(),
+            clock: Arc::new(SystemClock),
+            validators: Self::default_validators(),
+        }
+    }
+
+    // The built-in checks, in the order place_order has always applied them
+    pub fn default_validators() -> Vec<Box<dyn OrderValidator>> {
+        vec![
+            Box::new(SessionValidator),
+            Box::new(ImbalanceHaltValidator),
+            Box::new(QuantityStepValidator),
+            Box::new(MaxOrderQuantityValidator),
+            Box::new(PriceValidityValidator),
+            Box::new(PriceTickValidator),
+            Box::new(OrderToTradeValidator),
+            Box::new(PositionLimitValidator),
+        ]
+    }
+
+    // Appends a check that runs after the ones already registered
+    pub fn add_validator(&mut self, validator: Box<dyn OrderValidator>) {
+        self.validators.push(validator);
+    }
+
+    pub fn set_validators(&mut self, validators: Vec<Box<dyn OrderValidator>>) {
+        self.validators = validators;
+    }
+
+    // Installs a time source on the engine and every existing book
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        for order_book in self.order_books.values_mut() {
+            order_book.set_clock(clock.clone());
+        }
+        self.clock = clock;
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        self.clock.now()
+    }
+
+    pub fn create_market(&mut self, symbol: &str, initial_price: f64) {
+        self.create_market_with_params(symbol, initial_price, BookConfig::default());
+    }
+
+    // Creates a market whose tick size follows auto_tick_size for its price
+    pub fn create_market_auto_tick(&mut self, symbol: &str, initial_price: f64) {
+        // auto_tick_size is always a valid tick, so the default is never used
+        let config = BookConfig::default()
+            .with_tick_size(auto_tick_size(initial_price), TradeRounding::default())
+            .unwrap_or_default();
+        self.create_market_with_params(symbol, initial_price, config);
+    }
+
+    pub fn create_market_with_params(
+        &mut self,
+        symbol: &str,
+        initial_price: f64,
+        config: BookConfig,
+    ) {
+        let before_hash = self.state_hash();
+        let mut order_book = OrderBook::with_config(symbol.to_string(), initial_price, config);
+        order_book.set_clock(self.clock.clone());
+        self.order_books.insert(symbol.to_string(), order_book);
+        self.record_audit(AuditAction::CreateMarket, symbol, None, before_hash);
+    }
+
+    // place_order for clients that may retry: a submission whose account
+    // already had an order accepted under the same key gets the original
+    // trades back instead of executing again. Rejections are not remembered,
+    // since a retry after e.g. a throttle or the open may well succeed.
+    // Without a key it is place_order.
+    pub fn place_order_idempotent(
+        &mut self,
+        order: Order,
+        idempotency_key: Option<String>,
+    ) -> OrderOutcome {
+        let Some(key) = idempotency_key else {
+            return self.place_order(order);
+        };
+        let key = (order.account.clone(), key);
+        if let Some(trades) = self.idempotency.get(&key) {
+            return Ok(trades);
+        }
+        let trades = self.place_order(order)?;
+        self.idempotency.insert(key, trades.clone());
+        Ok(trades)
+    }
+
+    // How many recent idempotency keys are remembered. Resizing forgets every key.
+    pub fn set_idempotency_capacity(&mut self, capacity: usize) {
+        self.idempotency = IdempotencyCache::new(capacity);
+    }
+
+    pub fn place_order(&mut self, mut order: Order) -> Result<Vec<Trade>, OrderError> {
+        let symbol = order.symbol.clone();
+        if !self.order_books.contains_key(&symbol) {
+            return Err(OrderError::MarketNotFound(symbol));
+        }
+        // Orders without an account share one namespace of client ids
+        let account = order.account.clone().unwrap_or_default();
+        if let Some(client_order_id) = &order.client_order_id {
+            let seen = self.client_order_ids.get(&account);
+            if seen.is_some_and(|ids| ids.contains(client_order_id)) {
+                return Err(OrderError::DuplicateClientOrderId {
+                    account,
+                    client_order_id: client_order_id.clone(),
+                });
+            }
+        }
+        self.convert_quote(&mut order)?;
+
+        let unchecked = order.account.as_ref().is_some_and(|a| self.skips_risk_checks(a));
+        for validator in &self.validators {
+            if unchecked && validator.is_risk_check() {
+                continue;
+            }
+            validator.validate(self, &order)?;
+        }
+
+        // Locating borrow reserves shares, so it runs only once every check
+        // passed. The reservation is part of the audited placement.
+        let before_hash = self.state_hash();
+        let located = if unchecked {
+            Quantity::ZERO
+        } else {
+            self.locate_borrow(&order)?
+        };
+        Ok(self.submit_order(order, located, unchecked, before_hash))
+    }
+
+    // The part of place_order after every check has passed, which cannot
+    // fail. `located` is borrow already reserved for the order, and
+    // `before_hash` the state hash from before it was reserved.
+    fn submit_order(
+        &mut self,
+        mut order: Order,
+        located: Quantity,
+        unchecked: bool,
+        before_hash: u64,
+    ) -> Vec<Trade> {
+        let symbol = order.symbol.clone();
+        let account = order.account.clone().unwrap_or_default();
+        // A client id is used up once its order is accepted; rejected ones may be retried
+        if let Some(client_order_id) = &order.client_order_id {
+            self.client_order_ids.entry(account).or_default().insert(client_order_id.clone());
+        }
+
+        if let Some(account) = &order.account {
+            self.account_activity.entry(account.clone()).or_default().orders += 1;
+        }
+
+        if let Some(latency) = order.account.as_ref().and_then(|a| self.order_latency.get(a)) {
+            // In-flight orders wait with the good-after-time orders until they arrive
+            let received_at = self.clock.now() + *latency;
+            order.received_at = Some(received_at);
+            order.not_before = Some(order.not_before.map_or(received_at, |t| t.max(received_at)));
+        }
+
+        let order_id = order.id.clone();
+        let order_book = self.order_books.get_mut(&symbol).unwrap();
+        if !located.is_zero() {
+            // Borrow the order never sells goes back to the pool when it stops working
+            order_book.watch(&order_id);
+            let account = order.account.clone().unwrap_or_default();
+            self.borrow_reserved.insert(order_id.clone(), (account, located));
+        }
+        let trades = order_book.add_order(order);
+        order_book.check_imbalance_halt();
+        self.apply_trades(&trades);
+        self.settle_finished_orders(&symbol);
+        let action = if unchecked {
+            AuditAction::PlaceOrderUnchecked
+        } else {
+            AuditAction::PlaceOrder
+        };
+        self.record_audit(action, &symbol, Some(order_id), before_hash);
+        trades
+    }
+
+    // Settles the market's watched orders that stopped working: connection
+    // bookkeeping forgets them and unsold borrow is returned to the pool
+    fn settle_finished_orders(&mut self, symbol: &str) {
+        let Some(order_book) = self.order_books.get_mut(symbol) else {
+            return;
+        };
+        for (order_id, unfilled) in order_book.take_finished() {
+            // An OCO leg that stops working any other way than by trading
+            // (cancelled, expired, evicted) leaves its sibling on its own
+            if let Some((_, sibling)) = self.oco_links.remove(&order_id) {
+                self.oco_links.remove(&sibling);
+            }
+            if let Some((account, reserved)) = self.borrow_reserved.remove(&order_id) {
+                self.return_borrow(&account, symbol, std::cmp::min(reserved, unfilled));
+            }
+            if let Some(connection_id) = self.order_connections.remove(&order_id) {
+                if let Some(orders) = self.connection_orders.get_mut(&connection_id) {
+                    orders.retain(|(_, id)| *id != order_id);
+                    if orders.is_empty() {
+                        self.connection_orders.remove(&connection_id);
+                    }
+                }
+            }
+        }
+    }
+
+    // Hash of the whole engine: every book's checksum and the accounts'
+    // positions, cash, fees and borrow. Books and accounts keep their digests
+    // current as they change, so this does not walk the resting orders.
+    pub fn state_hash(&self) -> u64 {
+        let mut books = SetDigest::default();
+        for (symbol, order_book) in &self.order_books {
+            books.add((symbol, order_book.checksum()));
+        }
+
+        let mut hasher = DefaultHasher::new();
+        books.0.hash(&mut hasher);
+        self.account_digest.0.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn record_audit(
+        &mut self,
+        action: AuditAction,
+        symbol: &str,
+        order_id: Option<String>,
+        before_hash: u64,
+    ) {
+        let entry = AuditEntry {
+            audit_id: self.audit_log.len() as u64 + 1,
+            action,
+            symbol: symbol.to_string(),
+            order_id,
+            before_hash,
+            after_hash: self.state_hash(),
+            timestamp: self.clock.now(),
+        };
+        self.audit_log.push(entry);
+
+        // OCO siblings of orders that traded in this operation are cancelled
+        // after it, each as its own entry so the hash chain stays intact
+        for (symbol, order_id) in std::mem::take(&mut self.oco_cancels) {
+            self.cancel_oco_sibling(&symbol, &order_id);
+        }
+    }
+
+    pub fn audit_log(&self) -> &[AuditEntry] {
+        &self.audit_log
+    }
+
+    // None removes the limit for the account on that symbol
+    pub fn set_position_limit(
+        &mut self,
+        account: &str,
+        symbol: &str,
+        max_position: Option<Quantity>,
+    ) {
+        let limits = self.position_limits.entry(account.to_string()).or_default();
+        match max_position {
+            Some(max_position) => limits.insert(symbol.to_string(), max_position),
+            None => limits.remove(symbol),
+        };
+    }
+
+    // Exempts an account from risk validators and short-sale locates so it can
+    // seed liquidity without funding. Its orders are audited as unchecked.
+    pub fn set_skip_risk_checks(&mut self, account: &str, skip: bool) {
+        if skip {
+            self.skip_risk_checks.insert(account.to_string());
+        } else {
+            self.skip_risk_checks.remove(account);
+        }
+    }
+
+    pub fn skips_risk_checks(&self, account: &str) -> bool {
+        self.skip_risk_checks.contains(account)
+    }
+
+    pub fn set_fx_rates(&mut self, fx_rates: Arc<dyn FxRates>) {
+        self.fx_rates = Some(fx_rates);
+    }
+
+    // Restates an order quoted in a foreign currency in its book's quote
+    // currency at the current rate, keeping the original prices on the order
+    fn convert_quote(&self, order: &mut Order) -> Result<(), OrderError> {
+        let book_currency = &self.order_books[&order.symbol].config.quote_currency;
+        let Some(currency) = order.quote_currency.take() else {
+            return Ok(());
+        };
+        if &currency == book_currency {
+            return Ok(());
+        }
+
+        let rate = self
+            .fx_rates
+            .as_ref()
+            .and_then(|fx| fx.rate(&currency, book_currency))
+            .ok_or_else(|| OrderError::FxRateUnavailable {
+                from: currency.clone(),
+                to: book_currency.clone(),
+            })?;
+        order.fx_conversion = Some(FxConversion {
+            currency,
+            rate,
+            original_price: order.price,
+            original_stop_price: order.stop_price,
+        });
+        order.price = order.price.map(|p| p * rate);
+        order.stop_price = order.stop_price.map(|p| p * rate);
+        // A converted price seldom lands on the book's grid. Round it so the
+        // order never trades at a worse price, nor triggers sooner, than quoted.
+        if let Some(tick_size) = self.order_books[&order.symbol].config.tick_size {
+            let buy = order.side == Side::Buy;
+            order.price = order.price.map(|p| round_to_tick(p, tick_size, !buy));
+            order.stop_price = order.stop_price.map(|p| round_to_tick(p, tick_size, buy));
+        }
+        Ok(())
+    }
+
+    // Simulated delay between an account submitting an order and the exchange
+    // receiving it. Delayed orders are held until their receipt time, so the
+    // book sequences them by arrival rather than by submission.
+    pub fn set_order_latency(&mut self, account: &str, latency: Option<chrono::Duration>) {
+        match latency {
+            Some(latency) => self.order_latency.insert(account.to_string(), latency),
+            None => self.order_latency.remove(account),
+        };
+    }
+
+    // Short-sale locate: in markets with a borrow pool, the part of a sell
+    // beyond the account's current long position must be borrowed up front.
+    // Shares already promised to the account's working sells don't count as
+    // long. Located borrow is returned to the pool as the account buys to
+    // cover, or when the order stops working before selling it. Returns the
+    // quantity located.
+    fn locate_borrow(&mut self, order: &Order) -> Result<Quantity, OrderError> {
+        let account = match (&order.account, order.side) {
+            (Some(account), Side::Sell) => account,
+            _ => return Ok(Quantity::ZERO),
+        };
+        let order_book = self.order_books.get(&order.symbol).unwrap();
+        let Some(available) = order_book.borrow_available else {
+            return Ok(Quantity::ZERO);
+        };
+
+        // A working sell fills its long part first, so what it still holds
+        // back from the position is its remainder beyond its unsold borrow
+        let pending_long = order_book
+            .working_orders()
+            .filter(|o| o.side == Side::Sell && o.account.as_ref() == Some(account))
+            .fold(Quantity::ZERO, |total, o| {
+                let reserved = self.borrow_reserved.get(&o.id).map_or(Quantity::ZERO, |r| r.1);
+                total + o.quantity.saturating_sub(reserved)
+            });
+        let long_quantity = self.get_position(account, &order.symbol).saturating_sub(pending_long);
+        let short_quantity = order.quantity.saturating_sub(long_quantity);
+        if short_quantity.is_zero() {
+            return Ok(Quantity::ZERO);
+        }
+        if available < short_quantity {
+            return Err(OrderError::NoBorrowAvailable {
+                symbol: order.symbol.clone(),
+                requested: short_quantity,
+                available,
+            });
+        }
+
+        self.take_borrow(account, &order.symbol, short_quantity);
+        Ok(short_quantity)
+    }
+
+    // Moves `quantity`, which the pool must hold, from the pool to the account
+    fn take_borrow(&mut self, account: &str, symbol: &str, quantity: Quantity) {
+        let order_book = self.order_books.get_mut(symbol).unwrap();
+        if let Some(available) = order_book.borrow_available.as_mut() {
+            *available -= quantity;
+        }
+        let borrowed = self
+            .borrowed
+            .entry(account.to_string())
+            .or_default()
+            .entry(symbol.to_string())
+            .or_default();
+        let key = ("borrowed", account, symbol);
+        self.account_digest.replace(key, *borrowed, *borrowed + quantity);
+        *borrowed += quantity;
+    }
+
+    // Brings an account's borrow back in line with what it still needs: its
+    // short position plus the unsold borrow of its working sells. A busted
+    // short sale leaves it holding too much, which goes back to the pool; a
+    // busted cover leaves it short again, and it re-borrows as much as the
+    // pool still has.
+    fn reconcile_borrow(&mut self, account: &str, symbol: &str) {
+        let Some(order_book) = self.order_books.get(symbol) else {
+            return;
+        };
+        let Some(available) = order_book.borrow_available else {
+            return;
+        };
+        let unsold: Quantity = order_book
+            .working_orders()
+            .filter_map(|o| match self.borrow_reserved.get(&o.id) {
+                Some((owner, reserved)) if owner == account => Some((*reserved).min(o.quantity)),
+                _ => None,
+            })
+            .sum();
+        let short = (-self.get_position(account, symbol)).max(Quantity::ZERO);
+        let needed = short + unsold;
+        let borrowed = self
+            .borrowed
+            .get(account)
+            .and_then(|symbols| symbols.get(symbol))
+            .copied()
+            .unwrap_or(Quantity::ZERO);
+        if borrowed > needed {
+            self.return_borrow(account, symbol, borrowed - needed);
+        } else if needed > borrowed {
+            self.take_borrow(account, symbol, (needed - borrowed).min(available));
+        }
+    }
+
+    // Opens a ring of `capacity` trades for a single fast consumer, replacing
+    // any earlier ring. The ring closes when the consumer is dropped. A zero
+    // capacity ring could never hold a trade, so it is refused.
+    pub fn trade_ring(&mut self, capacity: usize) -> Result<TradeRingConsumer, OrderError> {
+        if capacity == 0 {
+            return Err(OrderError::InvalidCapacity(capacity));
+        }
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let dropped = Arc::new(AtomicU64::new(0));
+        self.trade_ring = Some(TradeRingProducer {
+            sender,
+            dropped: dropped.clone(),
+        });
+        Ok(TradeRingConsumer { receiver, dropped })
+    }
+
+    pub fn add_trade_sink(&mut self, sink: Box<dyn TradeSink>) {
+        self.trade_sinks.push(sink);
+    }
+
+    fn publish_to_ring(&mut self, trade: &Trade) {
+        let Some(ring) = &self.trade_ring else {
+            return;
+        };
+        match ring.sender.try_send(trade.clone()) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                ring.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(TrySendError::Disconnected(_)) => self.trade_ring = None,
+        }
+    }
+
+    // Passes deferred prints whose publish time has come to the sinks, in
+    // publish time order
+    fn release_deferred_trades(&mut self) {
+        let now = self.clock.now();
+        let (mut due, waiting): (Vec<Trade>, Vec<Trade>) =
+            std::mem::take(&mut self.deferred_trades)
+                .into_iter()
+                .partition(|t| t.publish_time <= now);
+        self.deferred_trades = waiting;
+        due.sort_by_key(|t| t.publish_time);
+        for trade in &due {
+            self.trade_sinks.iter().for_each(|sink| sink.publish(trade));
+        }
+    }
+
+    fn apply_trades(&mut self, trades: &[Trade]) {
+        self.release_deferred_trades();
+        for trade in trades {
+            self.publish_to_ring(trade);
+            if trade.publish_time <= trade.timestamp {
+                self.trade_sinks.iter().for_each(|sink| sink.publish(trade));
+            } else if !self.trade_sinks.is_empty() {
+                self.deferred_trades.push(trade.clone());
+            }
+            for account in [&trade.buyer_account, &trade.seller_account].into_iter().flatten() {
+                self.account_activity.entry(account.clone()).or_default().trades += 1;
+            }
+
+            let notional = match self.order_books.get(&trade.symbol) {
+                Some(order_book) => order_book.notional(trade.price, trade.quantity),
+                None => trade.price * trade.quantity.to_f64(),
+            };
+
+            if let Some(buyer) = &trade.buyer_account {
+                self.credit_cash(buyer, -notional);
+                self.book_fill(buyer, &trade.symbol, trade.quantity, notional);
+                self.return_borrow(buyer, &trade.symbol, trade.quantity);
+            }
+
+            if let Some(seller) = &trade.seller_account {
+                self.credit_cash(seller, notional);
+                self.book_fill(seller, &trade.symbol, -trade.quantity, notional);
+            }
+
+            self.charge_fees(trade, notional);
+            for order_id in [&trade.buyer_order_id, &trade.seller_order_id] {
+                if let Some((symbol, sibling)) = self.oco_links.remove(order_id) {
+                    self.oco_links.remove(&sibling);
+                    self.oco_cancels.push((symbol, sibling));
+                }
+            }
+        }
+    }
+
+    // Places two linked orders where any fill of one cancels the other, e.g.
+    // a take-profit limit and a protective stop. `order_a` goes in first; if
+    // it trades on arrival or is already gone (an unfilled IOC), `order_b` is
+    // never placed and its trades are empty.
+    pub fn place_oco(
+        &mut self,
+        symbol: &str,
+        order_a: Order,
+        order_b: Order,
+    ) -> Result<(Vec<Trade>, Vec<Trade>), OrderError> {
+        for order in [&order_a, &order_b] {
+            if order.symbol != symbol {
+                return Err(OrderError::SymbolMismatch {
+                    expected: symbol.to_string(),
+                    actual: order.symbol.clone(),
+                });
+            }
+        }
+
+        let (id_a, id_b) = (order_a.id.clone(), order_b.id.clone());
+        let trades_a = self.place_order(order_a)?;
+        let order_book = self.order_books.get_mut(symbol).expect("order was placed");
+        if !trades_a.is_empty() || !order_book.is_working(&id_a) {
+            return Ok((trades_a, Vec::new()));
+        }
+        // Either leg stopping work is reported, so links never outlive the pair
+        order_book.watch(&id_a);
+
+        self.oco_links.insert(id_a.clone(), (symbol.to_string(), id_b.clone()));
+        self.oco_links.insert(id_b.clone(), (symbol.to_string(), id_a.clone()));
+        match self.place_order(order_b) {
+            Ok(trades_b) => {
+                let order_book = self.order_books.get_mut(symbol).expect("order was placed");
+                if order_book.is_working(&id_b) {
+                    order_book.watch(&id_b);
+                } else if let Some((_, sibling)) = self.oco_links.remove(&id_b) {
+                    self.oco_links.remove(&sibling);
+                }
+                Ok((trades_a, trades_b))
+            }
+            Err(e) => {
+                // Without its other leg the first order must not stay working
+                self.oco_links.remove(&id_a);
+                self.oco_links.remove(&id_b);
+                self.cancel_oco_sibling(symbol, &id_a);
+                Err(e)
+            }
+        }
+    }
+
+    // Executes every leg of the spread or none. Each leg is priced by
+    // sweeping its book for the full leg quantity; the spread goes ahead
+    // only if all legs are fillable, the combined net price is within the
+    // limit, every leg order passes validation and borrow is located for
+    // every short leg. Only then does any leg trade, as an IOC limit at its
+    // sweep's worst price, and legs are not re-checked between each other.
+    // Accounts with order latency are refused, since delayed legs would meet
+    // books that have moved, as are legs in batch auction markets, which
+    // would only be queued for the next auction. A maker declining on last look can still leave
+    // a leg short, as on venues that allow it.
+    pub fn place_spread_order(&mut self, spread: SpreadOrder) -> Result<Vec<Trade>, OrderError> {
+        if spread.legs.is_empty() || spread.quantity <= Quantity::ZERO {
+            return Err(OrderError::SpreadRejected("no legs or quantity".to_string()));
+        }
+        if spread.legs.iter().any(|leg| leg.ratio == 0) {
+            return Err(OrderError::SpreadRejected("leg with a zero ratio".to_string()));
+        }
+        let mut symbols = HashSet::new();
+        if !spread.legs.iter().all(|leg| symbols.insert(&leg.symbol)) {
+            // Two legs in one book would be priced against the same liquidity
+            return Err(OrderError::SpreadRejected("more than one leg per market".to_string()));
+        }
+
+        let mut leg_orders = Vec::new();
+        let mut net_cash = 0.0;
+        for leg in &spread.legs {
+            let order_book = self
+                .order_books
+                .get(&leg.symbol)
+                .ok_or_else(|| OrderError::MarketNotFound(leg.symbol.clone()))?;
+            if order_book.config.matching_mode != MatchingMode::Continuous {
+                return Err(OrderError::SpreadRejected(format!(
+                    "{} does not match continuously",
+                    leg.symbol
+                )));
+            }
+            let quantity = spread.quantity.checked_mul(leg.ratio as i64).ok_or_else(|| {
+                OrderError::SpreadRejected(format!("{} leg quantity overflows", leg.symbol))
+            })?;
+            let resting = match leg.side {
+                Side::Buy => &order_book.asks,
+                Side::Sell => &order_book.bids,
+            };
+            let notional = order_book.sweep_notional(resting, quantity);
+            let worst_price = order_book.sweep_limit_price(leg.side, quantity, None);
+            let (Some(notional), Some(worst_price)) = (notional, worst_price) else {
+                return Err(OrderError::SpreadRejected(format!(
+                    "not enough liquidity in {} for {}",
+                    leg.symbol, quantity
+                )));
+            };
+            net_cash += match leg.side {
+                Side::Buy => notional,
+                Side::Sell => -notional,
+            };
+
+            let mut order = Order::new(
+                leg.symbol.clone(),
+                leg.side,
+                OrderType::Limit,
+                quantity,
+                Some(worst_price),
+            )
+            .immediate_or_cancel();
+            order.account = spread.account.clone();
+            leg_orders.push(order);
+        }
+
+        let net_price = net_cash / spread.quantity.to_f64();
+        if net_price > spread.max_net_price {
+            return Err(OrderError::SpreadRejected(format!(
+                "net price {:.2} above limit {:.2}",
+                net_price, spread.max_net_price
+            )));
+        }
+
+        let delayed = spread.account.as_ref().and_then(|a| self.order_latency.get(a));
+        if delayed.is_some_and(|latency| *latency > chrono::Duration::zero()) {
+            return Err(OrderError::SpreadRejected("account has order latency".to_string()));
+        }
+
+        // Check every leg before any trades so a rejection cannot strand one
+        let unchecked = spread.account.as_ref().is_some_and(|a| self.skips_risk_checks(a));
+        for order in &leg_orders {
+            for validator in &self.validators {
+                if !(unchecked && validator.is_risk_check()) {
+                    validator.validate(self, order)?;
+                }
+            }
+        }
+
+        // Borrow located for earlier legs goes back if a later leg finds none
+        let mut before_hash = self.state_hash();
+        let mut located = Vec::new();
+        for order in &leg_orders {
+            let locate = if unchecked {
+                Ok(Quantity::ZERO)
+            } else {
+                self.locate_borrow(order)
+            };
+            match locate {
+                Ok(quantity) => located.push(quantity),
+                Err(e) => {
+                    let account = spread.account.clone().unwrap_or_default();
+                    for (order, quantity) in leg_orders.iter().zip(located) {
+                        self.return_borrow(&account, &order.symbol, quantity);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut trades = Vec::new();
+        for (order, located) in leg_orders.into_iter().zip(located) {
+            trades.extend(self.submit_order(order, located, unchecked, before_hash));
+            before_hash = self.state_hash();
+        }
+        Ok(trades)
+    }
+
+    // Linking the pair was the client's own instruction, so the sibling goes
+    // even if it is protected or has not met the minimum rest time. It is not
+    // a client message and does not count towards the account's cancels.
+    fn cancel_oco_sibling(&mut self, symbol: &str, order_id: &str) {
+        let before_hash = self.state_hash();
+        let Some(order_book) = self.order_books.get_mut(symbol) else {
+            return;
+        };
+        if order_book.cancel_order(order_id).is_none() {
+            // Already gone, e.g. it filled in the same sweep
+            return;
+        }
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::OcoCancel, symbol, Some(order_id.to_string()), before_hash);
+    }
+
+    // Applies a fill of `delta` (positive bought, negative sold) costing
+    // `notional` to the account's position and its average-cost basis. Fills
+    // that add to a position add their cost; fills that reduce it release cost
+    // pro rata, and any part that flips the position opens at the fill price.
+    fn book_fill(&mut self, account: &str, symbol: &str, delta: Quantity, notional: f64) {
+        let position = self
+            .positions
+            .entry(account.to_string())
+            .or_default()
+            .entry(symbol.to_string())
+            .or_default();
+        let cost = self
+            .cost_basis
+            .entry(account.to_string())
+            .or_default()
+            .entry(symbol.to_string())
+            .or_default();
+
+        let key = ("position", account, symbol);
+        self.account_digest.replace(key, *position, *position + delta);
+
+        let signed_notional = if delta < Quantity::ZERO { -notional } else { notional };
+        let adding = position.is_zero() || (*position < Quantity::ZERO) == (delta < Quantity::ZERO);
+        if adding {
+            *cost += signed_notional;
+        } else if delta.abs() <= position.abs() {
+            *cost -= *cost * delta.abs().to_f64() / position.abs().to_f64();
+        } else {
+            let opened = (delta.abs() - position.abs()).to_f64() / delta.abs().to_f64();
+            *cost = signed_notional * opened;
+        }
+
+        *position += delta;
+        if position.is_zero() {
+            *cost = 0.0;
+        }
+    }
+
+    // Auction prints have no aggressor, so both sides pay the taker rate
+    fn charge_fees(&mut self, trade: &Trade, notional: f64) {
+        let participants = [
+            (&trade.buyer_account, trade.aggressor != Some(Side::Sell)),
+            (&trade.seller_account, trade.aggressor != Some(Side::Buy)),
+        ];
+
+        for (account, is_taker) in participants {
+            let account = match account {
+                Some(account) => account,
+                None => continue,
+            };
+
+            if let Some(schedule) = &self.fee_schedule {
+                if let Some(tier) = schedule.tier_for(self.trailing_volume(account)) {
+                    let rate = if is_taker { tier.taker_rate } else { tier.maker_rate };
+                    let fee = notional * rate;
+                    self.credit_cash(account, -fee);
+                    self.add_fees_paid(account, fee);
+                    self.trade_fees
+                        .entry(trade.id.clone())
+                        .or_default()
+                        .push((account.clone(), fee));
+                }
+            }
+
+            let periods = self.fee_volume.entry(account.clone()).or_default();
+            match periods.back_mut() {
+                Some(current) => *current += notional,
+                None => periods.push_back(notional),
+            }
+        }
+    }
+
+    fn credit_cash(&mut self, account: &str, amount: f64) {
+        let cash = self.cash.entry(account.to_string()).or_default();
+        let key = ("cash", account);
+        self.account_digest.replace(key, cash.to_bits(), (*cash + amount).to_bits());
+        *cash += amount;
+    }
+
+    fn add_fees_paid(&mut self, account: &str, fee: f64) {
+        let fees_paid = self.fees_paid.entry(account.to_string()).or_default();
+        let key = ("fees", account);
+        self.account_digest.replace(key, fees_paid.to_bits(), (*fees_paid + fee).to_bits());
+        *fees_paid += fee;
+    }
+
+    pub fn trailing_volume(&self, account: &str) -> f64 {
+        self.fee_volume
+            .get(account)
+            .map_or(0.0, |periods| periods.iter().sum())
+    }
+
+    pub fn get_fees_paid(&self, account: &str) -> f64 {
+        self.fees_paid.get(account).copied().unwrap_or(0.0)
+    }
+
+    pub fn set_fee_schedule(&mut self, schedule: Option<VolumeTierSchedule>) {
+        self.fee_schedule = schedule;
+    }
+
+    // Starts a new fee period, dropping volume that falls outside the lookback.
+    // Fees charged in the closed period are final, even if a trade is busted.
+    pub fn roll_fee_period(&mut self) {
+        self.trade_fees.clear();
+        let lookback = self
+            .fee_schedule
+            .as_ref()
+            .map_or(1, |schedule| schedule.lookback_periods);
+
+        for periods in self.fee_volume.values_mut() {
+            periods.push_back(0.0);
+            while periods.len() > lookback {
+                periods.pop_front();
+            }
+        }
+    }
+
+    fn return_borrow(&mut self, account: &str, symbol: &str, quantity: Quantity) {
+        let borrowed = match self
+            .borrowed
+            .get_mut(account)
+            .and_then(|symbols| symbols.get_mut(symbol))
+        {
+            Some(borrowed) => borrowed,
+            None => return,
+        };
+
+        let returned = std::cmp::min(*borrowed, quantity);
+        let key = ("borrowed", account, symbol);
+        self.account_digest.replace(key, *borrowed, *borrowed - returned);
+        *borrowed -= returned;
+
+        if let Some(order_book) = self.order_books.get_mut(symbol) {
+            if let Some(available) = order_book.borrow_available.as_mut() {
+                *available += returned;
+            }
+        }
+    }
+
+    pub fn get_position(&self, account: &str, symbol: &str) -> Quantity {
+        self.positions
+            .get(account)
+            .and_then(|symbols| symbols.get(symbol))
+            .copied()
+            .unwrap_or(Quantity::ZERO)
+    }
+
+    // One-call risk view of an account across every market it holds
+    pub fn portfolio_risk(&self, account: &str) -> PortfolioRisk {
+        let mut risk = PortfolioRisk {
+            account: account.to_string(),
+            ..PortfolioRisk::default()
+        };
+
+        let mut holdings: Vec<(&String, &Quantity)> = self
+            .positions
+            .get(account)
+            .map(|symbols| symbols.iter().filter(|(_, q)| !q.is_zero()).collect())
+            .unwrap_or_default();
+        holdings.sort();
+
+        for (symbol, &position) in holdings {
+            let order_book = match self.order_books.get(symbol) {
+                Some(order_book) => order_book,
+                None => continue,
+            };
+            let price = order_book.mark_price();
+            let notional = position.to_f64() * price * order_book.config.contract_multiplier;
+            let cost = self
+                .cost_basis
+                .get(account)
+                .and_then(|symbols| symbols.get(symbol))
+                .copied()
+                .unwrap_or(0.0);
+
+            risk.positions.push(SymbolRisk {
+                symbol: symbol.clone(),
+                position,
+                price,
+                notional,
+                unrealized_pnl: notional - cost,
+            });
+        }
+
+        risk.gross_exposure = risk.positions.iter().map(|p| p.notional.abs()).sum();
+        risk.net_exposure = risk.positions.iter().map(|p| p.notional).sum();
+        risk.unrealized_pnl = risk.positions.iter().map(|p| p.unrealized_pnl).sum();
+        if risk.gross_exposure > 0.0 {
+            risk.largest_concentration = risk
+                .positions
+                .iter()
+                .max_by(|a, b| a.notional.abs().partial_cmp(&b.notional.abs()).unwrap())
+                .map(|p| (p.symbol.clone(), p.notional.abs() / risk.gross_exposure));
+        }
+        risk
+    }
+
+    pub fn get_cash_balance(&self, account: &str) -> f64 {
+        self.cash.get(account).copied().unwrap_or(0.0)
+    }
+
+    pub fn get_mark_price(&self, symbol: &str) -> Option<f64> {
+        self.order_books.get(symbol).map(|ob| ob.mark_price())
+    }
+
+    pub fn set_mark_price_method(
+        &mut self,
+        symbol: &str,
+        method: MarkPriceMethod,
+    ) -> Result<(), OrderError> {
+        self.order_books
+            .get_mut(symbol)
+            .map(|ob| ob.set_mark_price_method(method))
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))
+    }
+
+    // Replaces the market's last-look decisions, e.g. with a seeded RandomLastLook
+    pub fn set_last_look(
+        &mut self,
+        symbol: &str,
+        last_look: Box<dyn LastLook>,
+    ) -> Result<(), OrderError> {
+        self.order_books
+            .get_mut(symbol)
+            .map(|ob| ob.set_last_look(last_look))
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))
+    }
+
+    pub fn update_reference(&mut self, symbol: &str, price: f64) -> Result<Vec<Trade>, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let trades = order_book.set_reference_price(price);
+        self.apply_trades(&trades);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::ReferencePrice, symbol, None, before_hash);
+        Ok(trades)
+    }
+
+    // Periodic funding settlement: every open position pays
+    // position * multiplier * mark * rate, so with a positive rate longs pay
+    // shorts and with a negative rate shorts pay longs.
+    pub fn apply_funding(&mut self, symbol: &str, rate: f64) -> Result<(), OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+        let mark_price = order_book.mark_price();
+        let multiplier = order_book.config.contract_multiplier;
+
+        let payments: Vec<(String, f64)> = self
+            .positions
+            .iter()
+            .filter_map(|(account, symbols)| {
+                let position = symbols.get(symbol)?;
+                Some((account.clone(), position.to_f64() * multiplier * mark_price * rate))
+            })
+            .collect();
+        for (account, payment) in payments {
+            self.credit_cash(&account, -payment);
+        }
+        self.record_audit(AuditAction::Funding, symbol, None, before_hash);
+        Ok(())
+    }
+
+    pub fn set_borrow_available(
+        &mut self,
+        symbol: &str,
+        quantity: Option<Quantity>,
+    ) -> Result<(), OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+        order_book.borrow_available = quantity;
+        self.record_audit(AuditAction::BorrowPool, symbol, None, before_hash);
+        Ok(())
+    }
+
+    pub fn order_to_trade_ratio(&self, account: &str) -> f64 {
+        self.account_activity
+            .get(account)
+            .map_or(0.0, |activity| activity.order_to_trade_ratio())
+    }
+
+    pub fn set_order_to_trade_limit(&mut self, limit: Option<OrderToTradeLimit>) {
+        self.order_to_trade_limit = limit;
+    }
+
+    pub fn cancel_order(&mut self, symbol: &str, order_id: &str) -> Result<Order, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+        if order_book.is_protected(order_id) {
+            return Err(OrderError::OrderProtected(order_id.to_string()));
+        }
+        if let Some(remaining) = order_book.rest_time_remaining(order_id) {
+            return Err(OrderError::MinRestTimeNotMet {
+                order_id: order_id.to_string(),
+                remaining,
+            });
+        }
+
+        let cancelled = order_book
+            .cancel_order(order_id)
+            .ok_or_else(|| OrderError::OrderNotFound(order_id.to_string()))?;
+
+        if let Some(account) = &cancelled.account {
+            self.account_activity.entry(account.clone()).or_default().cancels += 1;
+        }
+
+        self.settle_finished_orders(symbol);
+        self.record_audit(
+            AuditAction::CancelOrder,
+            symbol,
+            Some(order_id.to_string()),
+            before_hash,
+        );
+        Ok(cancelled)
+    }
+
+    // Partial cancel: shrinks a resting order without losing its place in
+    // the queue. Returns the quantity left working.
+    pub fn reduce_order(
+        &mut self,
+        symbol: &str,
+        order_id: &str,
+        reduce_by: Quantity,
+    ) -> Result<Quantity, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+        if order_book.is_protected(order_id) {
+            return Err(OrderError::OrderProtected(order_id.to_string()));
+        }
+        if let Some(remaining) = order_book.rest_time_remaining(order_id) {
+            return Err(OrderError::MinRestTimeNotMet {
+                order_id: order_id.to_string(),
+                remaining,
+            });
+        }
+
+        let account = order_book
+            .working_orders()
+            .find(|o| o.id == order_id)
+            .and_then(|o| o.account.clone());
+        let remaining = order_book.reduce_order(order_id, reduce_by)?;
+
+        // The cut comes off the short end first, freeing borrow it no longer needs
+        if let Some((account, reserved)) = self.borrow_reserved.get_mut(order_id) {
+            if *reserved > remaining {
+                let released = *reserved - remaining;
+                *reserved = remaining;
+                let account = account.clone();
+                self.return_borrow(&account, symbol, released);
+            }
+        }
+        if let Some(account) = account {
+            self.account_activity.entry(account).or_default().cancels += 1;
+        }
+
+        self.settle_finished_orders(symbol);
+        self.record_audit(
+            AuditAction::ReduceOrder,
+            symbol,
+            Some(order_id.to_string()),
+            before_hash,
+        );
+        Ok(remaining)
+    }
+
+    // Feed add: rests the order as reported, without matching or risk checks
+    pub fn rest_order(&mut self, order: Order) -> Result<(), OrderError> {
+        let before_hash = self.state_hash();
+        let symbol = order.symbol.clone();
+        let order_id = order.id.clone();
+        self.order_books
+            .get_mut(&symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.clone()))?
+            .rest_order(order)?;
+        self.record_audit(AuditAction::RestOrder, &symbol, Some(order_id), before_hash);
+        Ok(())
+    }
+
+    // Feed execute: fills the referenced resting order directly
+    pub fn execute_order(
+        &mut self,
+        symbol: &str,
+        order_id: &str,
+        quantity: Quantity,
+    ) -> Result<Trade, OrderError> {
+        let before_hash = self.state_hash();
+        let trade = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?
+            .execute_order(order_id, quantity)?;
+        self.apply_trades(std::slice::from_ref(&trade));
+        self.settle_finished_orders(symbol);
+        self.record_audit(
+            AuditAction::ExecuteOrder,
+            symbol,
+            Some(order_id.to_string()),
+            before_hash,
+        );
+        Ok(trade)
+    }
+
+    // Lifts an imbalance halt so the market accepts orders again
+    pub fn resume_market(&mut self, symbol: &str) -> Result<(), OrderError> {
+        let before_hash = self.state_hash();
+        self.order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?
+            .resume();
+        self.record_audit(AuditAction::Resume, symbol, None, before_hash);
+        Ok(())
+    }
+
+    pub fn get_halted_imbalance(&self, symbol: &str) -> Option<Quantity> {
+        self.order_books.get(symbol).and_then(|ob| ob.halted_imbalance())
+    }
+
+    // Cancels every working order in the market that is not protected
+    pub fn cancel_all(&mut self, symbol: &str) -> Result<Vec<Order>, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let cancelled = order_book.cancel_all();
+        for order in &cancelled {
+            if let Some(account) = &order.account {
+                self.account_activity.entry(account.clone()).or_default().cancels += 1;
+            }
+        }
+
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::CancelAll, symbol, None, before_hash);
+        Ok(cancelled)
+    }
+
+    pub fn cancel_level(
+        &mut self,
+        symbol: &str,
+        side: Side,
+        price: f64,
+    ) -> Result<Vec<Order>, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let cancelled = order_book.cancel_level(side, price);
+        for account in cancelled.iter().filter_map(|o| o.account.as_ref()) {
+            self.account_activity.entry(account.clone()).or_default().cancels += 1;
+        }
+
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::CancelLevel, symbol, None, before_hash);
+        Ok(cancelled)
+    }
+
+    // Orders the book dropped to stay within its depth cap. These are exchange
+    // cancels, so they do not count against the owner's order-to-trade ratio.
+    pub fn take_evicted(&mut self, symbol: &str) -> Result<Vec<Order>, OrderError> {
+        self.order_books
+            .get_mut(symbol)
+            .map(|ob| ob.take_evicted())
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))
+    }
+
+    // Places an order on behalf of a client connection. Orders still working
+    // after placement are remembered until they fill or are cancelled, so
+    // they can be pulled if the connection drops.
+    pub fn place_order_for_connection(
+        &mut self,
+        connection_id: u64,
+        order: Order,
+    ) -> Result<Vec<Trade>, OrderError> {
+        let symbol = order.symbol.clone();
+        let order_id = order.id.clone();
+        let trades = self.place_order(order)?;
+
+        let order_book = self.order_books.get_mut(&symbol).unwrap();
+        if order_book.is_working(&order_id) {
+            order_book.watch(&order_id);
+            self.order_connections.insert(order_id.clone(), connection_id);
+            self.connection_orders
+                .entry(connection_id)
+                .or_default()
+                .push((symbol, order_id));
+        }
+
+        Ok(trades)
+    }
+
+    // Cancel-on-disconnect: pulls every order still resting for the connection
+    pub fn disconnect(&mut self, connection_id: u64) -> Vec<Order> {
+        let mut cancelled = Vec::new();
+
+        if let Some(orders) = self.connection_orders.remove(&connection_id) {
+            for (symbol, order_id) in orders {
+                self.order_connections.remove(&order_id);
+                if let Ok(order) = self.cancel_order(&symbol, &order_id) {
+                    cancelled.push(order);
+                }
+            }
+        }
+
+        cancelled
+    }
+
+    pub fn close_market(&mut self, symbol: &str) -> Result<Vec<Trade>, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let trades = order_book.close();
+        self.apply_trades(&trades);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::CloseMarket, symbol, None, before_hash);
+        Ok(trades)
+    }
+
+    // Busts a trade and unwinds its effect on both accounts: position, cost
+    // basis, cash, borrow and, within the fee period it traded in, the fee
+    // charged on it. The notional still counts towards fee tier volume, as
+    // the tier was earned when it traded.
+    pub fn bust_trade(&mut self, symbol: &str, trade_id: &str) -> Result<Trade, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let trade = order_book.bust_trade(trade_id)?;
+        self.deferred_trades.retain(|t| t.id != trade.id);
+
+        for account in [&trade.buyer_account, &trade.seller_account].into_iter().flatten() {
+            let activity = self.account_activity.entry(account.clone()).or_default();
+            activity.trades = activity.trades.saturating_sub(1);
+        }
+        // Booking the opposite fill at the same price undoes the position and,
+        // for the most recent fill, the cost basis exactly
+        let notional = self.order_books[symbol].notional(trade.price, trade.quantity);
+        if let Some(buyer) = &trade.buyer_account {
+            self.credit_cash(buyer, notional);
+            self.book_fill(buyer, &trade.symbol, -trade.quantity, notional);
+        }
+        if let Some(seller) = &trade.seller_account {
+            self.credit_cash(seller, -notional);
+            self.book_fill(seller, &trade.symbol, trade.quantity, notional);
+        }
+        for (account, fee) in self.trade_fees.remove(&trade.id).unwrap_or_default() {
+            self.credit_cash(&account, fee);
+            self.add_fees_paid(&account, -fee);
+        }
+        for account in [&trade.buyer_account, &trade.seller_account].into_iter().flatten() {
+            self.reconcile_borrow(account, symbol);
+        }
+
+        self.record_audit(AuditAction::BustTrade, symbol, None, before_hash);
+        Ok(trade)
+    }
+
+    pub fn roll_session(&mut self, symbol: &str) -> Result<SessionStats, OrderError> {
+        self.order_books
+            .get_mut(symbol)
+            .map(|ob| ob.roll_session())
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))
+    }
+
+    pub fn get_session_stats(&self, symbol: &str) -> Option<SessionStats> {
+        self.order_books.get(symbol).map(|ob| ob.session_stats())
+    }
+
+    pub fn get_volume_profile(&self, symbol: &str, bucket_size: f64) -> Vec<(f64, Quantity)> {
+        self.order_books
+            .get(symbol)
+            .map_or_else(Vec::new, |ob| ob.volume_profile(bucket_size))
+    }
+
+    pub fn get_realized_volatility(&self, symbol: &str, window: usize) -> Option<f64> {
+        self.order_books.get(symbol).and_then(|ob| ob.realized_volatility(window))
+    }
+
+    pub fn get_auction_indicative(&self, symbol: &str) -> Option<AuctionState> {
+        self.order_books.get(symbol).and_then(|ob| ob.auction_indicative())
+    }
+
+    pub fn get_closing_indicative(&self, symbol: &str) -> Option<AuctionState> {
+        self.order_books.get(symbol).and_then(|ob| ob.closing_indicative())
+    }
+
+    // Direct state load of a market's book, as opposed to WAL replay. The
+    // snapshot's trades are history only: positions and cash are untouched.
+    pub fn load_book_snapshot(
+        &mut self,
+        symbol: &str,
+        bids: Vec<Order>,
+        asks: Vec<Order>,
+        trades: Vec<Trade>,
+    ) -> Result<(), OrderError> {
+        let before_hash = self.state_hash();
+        self.order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?
+            .load_snapshot(bids, asks, trades)?;
+
+        // Links to orders from the replaced book no longer mean anything
+        self.oco_links.retain(|_, (linked_symbol, _)| linked_symbol != symbol);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::LoadSnapshot, symbol, None, before_hash);
+        Ok(())
+    }
+
+    pub fn repair_book(&mut self, symbol: &str) -> Result<Vec<Trade>, OrderError> {
+        let before_hash = self.state_hash();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let trades = order_book.uncross();
+        self.apply_trades(&trades);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::RepairBook, symbol, None, before_hash);
+        Ok(trades)
+    }
+
+    pub fn run_auction(&mut self, symbol: &str) -> Result<Vec<Trade>, OrderError> {
+        let before_hash = self.state_hash();
+        let now = self.clock.now();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let trades = order_book.run_auction(now);
+        self.apply_trades(&trades);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::Auction, symbol, None, before_hash);
+        Ok(trades)
+    }
+
+    // Clears every batch whose boundary the engine's clock has passed, in
+    // symbol order, returning the prints per market. Meant to be called on
+    // each tick of the clock that drives frequent batch auctions; orders that
+    // arrive after a boundary but before this runs still join that batch.
+    pub fn run_batches(&mut self) -> Vec<(String, Vec<Trade>)> {
+        let now = self.clock.now();
+        let mut due: Vec<String> = self
+            .order_books
+            .iter()
+            .filter(|(_, ob)| ob.batch_due(now))
+            .map(|(symbol, _)| symbol.clone())
+            .collect();
+        due.sort();
+
+        due.into_iter()
+            .filter_map(|symbol| {
+                let trades = self.run_auction(&symbol).ok()?;
+                Some((symbol, trades))
+            })
+            .collect()
+    }
+
+    // Expires and activates time-driven orders as of the engine's clock
+    pub fn process_time_events(&mut self, symbol: &str) -> Result<TimeEvents, OrderError> {
+        let before_hash = self.state_hash();
+        let now = self.clock.now();
+        let order_book = self
+            .order_books
+            .get_mut(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+
+        let events = order_book.process_time_events(now);
+        self.apply_trades(&events.trades);
+        self.settle_finished_orders(symbol);
+        self.record_audit(AuditAction::TimeEvents, symbol, None, before_hash);
+        Ok(events)
+    }
+
+    pub fn set_session_schedule(
+        &mut self,
+        symbol: &str,
+        schedule: Option<SessionSchedule>,
+    ) -> Result<(), OrderError> {
+        match self.order_books.get_mut(symbol) {
+            Some(order_book) => {
+                order_book.set_session_schedule(schedule);
+                Ok(())
+            }
+            None => Err(OrderError::MarketNotFound(symbol.to_string())),
+        }
+    }
+
+    pub fn is_market_open(&self, symbol: &str, now: DateTime<Utc>) -> Option<bool> {
+        self.order_books.get(symbol).map(|ob| ob.is_open(now))
+    }
+
+    pub fn get_market_data(&self, symbol: &str) -> Option<MarketData> {
+        self.order_books.get(symbol).map(|ob| ob.get_market_data())
+    }
+
+    pub fn get_top_of_book(&self, symbol: &str) -> Option<(Option<f64>, Option<f64>, Option<f64>)> {
+        self.order_books.get(symbol).map(|ob| ob.top_of_book())
+    }
+
+    pub fn round_trip_cost(&self, symbol: &str, quantity: Quantity) -> Option<f64> {
+        self.order_books.get(symbol).and_then(|ob| ob.round_trip_cost(quantity))
+    }
+
+    pub fn memory_report(&self) -> MemoryReport {
+        let mut report = MemoryReport::default();
+        for (symbol, order_book) in &self.order_books {
+            let stats = order_book.memory_stats();
+            report.resting_orders += stats.bid_orders + stats.ask_orders;
+            report.parked_orders += stats.parked_orders;
+            report.trades += stats.trades;
+            report.estimated_bytes += stats.estimated_bytes;
+            report.books.push((symbol.clone(), stats));
+        }
+        report.books.sort_by(|a, b| a.0.cmp(&b.0));
+        report
+    }
+
+    pub fn get_traded_notional(&self, symbol: &str) -> Option<f64> {
+        self.order_books.get(symbol).map(|ob| ob.traded_notional())
+    }
+
+    pub fn get_mid(&self, symbol: &str) -> Option<f64> {
+        self.order_books.get(symbol).and_then(|ob| ob.mid())
+    }
+
+    pub fn get_weighted_mid(&self, symbol: &str) -> Option<f64> {
+        self.order_books.get(symbol).and_then(|ob| ob.weighted_mid())
+    }
+
+    pub fn quantity_to_reach_price(
+        &self,
+        symbol: &str,
+        side: Side,
+        target_price: f64,
+    ) -> Option<Quantity> {
+        self.order_books
+            .get(symbol)
+            .map(|ob| ob.quantity_to_reach_price(side, target_price))
+    }
+
+    pub fn open_orders(&self, account: &str) -> Vec<(String, Order)> {
+        let mut symbols = self.get_symbols();
+        symbols.sort();
+
+        symbols
+            .into_iter()
+            .flat_map(|symbol| {
+                self.order_books[&symbol]
+                    .open_orders(account)
+                    .into_iter()
+                    .map(move |order| (symbol.clone(), order))
+            })
+            .collect()
+    }
+
+    pub fn get_depth(
+        &self,
+        symbol: &str,
+        levels: usize,
+    ) -> Option<(Vec<PriceLevel>, Vec<PriceLevel>)> {
+        self.order_books.get(symbol).map(|ob| ob.depth(levels))
+    }
+
+    pub fn get_depth_curve(&self, symbol: &str, side: Side) -> Option<Vec<PriceLevel>> {
+        self.order_books.get(symbol).map(|ob| ob.depth_curve(side))
+    }
+
+    pub fn get_snapshot_bytes(&self, symbol: &str) -> Result<Vec<u8>, OrderError> {
+        let order_book = self
+            .order_books
+            .get(symbol)
+            .ok_or_else(|| OrderError::MarketNotFound(symbol.to_string()))?;
+        order_book.snapshot_bytes()
+    }
+
+    pub fn get_largest_orders(&self, symbol: &str, side: Side, n: usize) -> Option<Vec<Order>> {
+        self.order_books.get(symbol).map(|ob| ob.largest_orders(side, n))
+    }
+
+    pub fn get_orders(&self, symbol: &str) -> Option<(Vec<Order>, Vec<Order>)> {
+        self.order_books.get(symbol).map(|ob| ob.get_orders())
+    }
+
+    pub fn get_trades(&self, symbol: &str) -> Option<Vec<Trade>> {
+        self.order_books.get(symbol).map(|ob| ob.get_trades())
+    }
+
+    pub fn trades_for_order(&self, symbol: &str, order_id: &str) -> Option<Vec<Trade>> {
+        self.order_books.get(symbol).map(|ob| ob.trades_for_order(order_id))
+    }
+
+    pub fn get_all_trades(&self, symbol: &str) -> Option<Vec<Trade>> {
+        self.order_books.get(symbol).map(|ob| ob.all_trades())
+    }
+
+    pub fn get_symbols(&self) -> Vec<String> {
+        self.order_books.keys().cloned().collect()
+    }
+
+    // Generate mock market data
+    pub fn populate_with_mock_data(&mut self) {
+        self.populate_with_mock_data_from(&mut rand::thread_rng());
+    }
+
+    // Same as populate_with_mock_data with a caller-supplied generator, so a
+    // seeded StdRng gives reproducible books (order ids are still random)
+    pub fn populate_with_mock_data_from<R: Rng + ?Sized>(&mut self, rng: &mut R) {
+        // Create some markets
+        let symbols = ["AAPL", "GOOGL", "MSFT", "AMZN", "TSLA"];
+        let prices = [150.0, 2800.0, 300.0, 3500.0, 750.0];
+
+        let config = BookConfig::default().with_max_order_quantity(Quantity::from_units(10_000));
+
+        for (&symbol, &price) in symbols.iter().zip(prices.iter()) {
+            self.create_market_with_params(symbol, price, config.clone());
+            self.generate_mock_orders(symbol, price, rng);
+        }
+    }
+
+    fn generate_mock_orders<R: Rng + ?Sized>(&mut self, symbol: &str, price: f64, rng: &mut R) {
+        // Generate some buy orders
+        for _ in 0..10 {
+            let price_offset = rng.gen_range(-0.05..0.0);
+            let order_price = price * (1.0 + price_offset);
+            let quantity = Quantity::from_units(rng.gen_range(10..100));
+            
+            let order = Order::new(
+                symbol.to_string(),
+                Side::Buy,
+                OrderType::Limit,
+                quantity,
+                Some(order_price),
+            );
+            
+            let _ = self.place_order(order);
+        }
+        
+        // Generate some sell orders
+        for _ in 0..10 {
+            let price_offset = rng.gen_range(0.0..0.05);
+            let order_price = price * (1.0 + price_offset);
+            let quantity = Quantity::from_units(rng.gen_range(10..100));
+            
+            let order = Order::new(
+                symbol.to_string(),
+                Side::Sell,
+                OrderType::Limit,
+                quantity,
+                Some(order_price),
+            );
+            
+            let _ = self.place_order(order);
+        }
+    }
+}
+
+// ===== SIMULATION =====
+
+// Synthetic order flow around a fair price that follows a geometric random
+// walk. Each tick advances the shared SimClock, moves the fair price, and
+// submits a burst of orders: mostly limits scattered around fair (some of
+// them marketable) plus occasional market orders. Seeding the RNG makes a
+// run replayable.
+pub struct PriceSimulator<R: Rng> {
+    symbol: String,
+    fair_price: f64,
+    volatility: f64, // Standard deviation of the log return per tick
+    orders_per_tick: usize,
+    market_order_prob: f64,
+    tick_interval: chrono::Duration,
+    clock: SimClock,
+    rng: R,
+}
+
+impl<R: Rng> PriceSimulator<R> {
+    // `clock` must be the clock installed on the engine being driven
+    pub fn new(symbol: &str, start_price: f64, volatility: f64, clock: SimClock, rng: R) -> Self {
+        PriceSimulator {
+            symbol: symbol.to_string(),
+            fair_price: start_price,
+            volatility,
+            orders_per_tick: 5,
+            market_order_prob: 0.1,
+            tick_interval: chrono::Duration::seconds(1),
+            clock,
+            rng,
+        }
+    }
+
+    pub fn with_orders_per_tick(mut self, orders_per_tick: usize) -> Self {
+        self.orders_per_tick = orders_per_tick;
+        self
+    }
+
+    pub fn with_tick_interval(mut self, tick_interval: chrono::Duration) -> Self {
+        self.tick_interval = tick_interval;
+        self
+    }
+
+    pub fn fair_price(&self) -> f64 {
+        self.fair_price
+    }
+
+    // Standard normal draw via Box-Muller, since rand alone has no Normal
+    fn standard_normal(&mut self) -> f64 {
+        let u1: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        let u2: f64 = self.rng.gen_range(0.0..1.0);
+        (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+    }
+
+    // Runs one tick and returns the trades it produced. Orders the engine
+    // rejects, e.g. outside the session, are skipped.
+    pub fn step(&mut self, engine: &mut TradingEngine) -> Vec<Trade> {
+        self.clock.advance(self.tick_interval);
+        self.fair_price *= (self.volatility * self.standard_normal()).exp();
+
+        let mut trades = Vec::new();
+        if let Ok(events) = engine.process_time_events(&self.symbol) {
+            trades.extend(events.trades);
+        }
+        let batch_due = engine
+            .order_books
+            .get(&self.symbol)
+            .is_some_and(|ob| ob.batch_due(self.clock.now()));
+        if batch_due {
+            trades.extend(engine.run_auction(&self.symbol).unwrap_or_default());
+        }
+        let tick_size = match engine.order_books.get(&self.symbol) {
+            Some(order_book) => order_book.config.tick_size,
+            None => return trades,
+        };
+
+        for _ in 0..self.orders_per_tick {
+            let side = if self.rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+            let quantity = Quantity::from_units(self.rng.gen_range(1..=20));
+            let order = if self.rng.gen_bool(self.market_order_prob) {
+                Order::new(self.symbol.clone(), side, OrderType::Market, quantity, None)
+            } else {
+                // Mostly passive, with a sliver of the range crossing fair
+                let offset = self.rng.gen_range(-0.01..0.002) * self.fair_price.abs();
+                let mut price = match side {
+                    Side::Buy => self.fair_price + offset,
+                    Side::Sell => self.fair_price - offset,
+                };
+                if let Some(tick_size) = tick_size {
+                    price = (price / tick_size).round() * tick_size;
+                }
+                Order::new(self.symbol.clone(), side, OrderType::Limit, quantity, Some(price))
+            };
+            if let Ok(order_trades) = engine.place_order(order) {
+                trades.extend(order_trades);
+            }
+        }
+        trades
+    }
+
+    pub fn run(&mut self, engine: &mut TradingEngine, ticks: usize) -> Vec<Trade> {
+        (0..ticks).flat_map(|_| self.step(engine)).collect()
+    }
+}
+
+// Simulated market maker that quotes both sides around the mid and skews
+// towards unloading inventory. Each unit of position away from the target
+// shifts both quotes by `skew_per_unit` against it, so a long maker quotes
+// lower (selling more readily) and a short one higher. Beyond the inventory
+// limit, short or long, the side that would add to the position is pulled.
+pub struct InventoryQuoter {
+    account: String,
+    symbol: String,
+    half_spread: f64,
+    quote_size: Quantity,
+    target_inventory: Quantity,
+    skew_per_unit: f64,
+    inventory_limit: Option<Quantity>, // Absolute position past which one side stops quoting
+    live_quotes: Vec<String>,          // Order ids of the quotes placed by the last requote
+}
+
+impl InventoryQuoter {
+    pub fn new(account: &str, symbol: &str, half_spread: f64, quote_size: Quantity) -> Self {
+        InventoryQuoter {
+            account: account.to_string(),
+            symbol: symbol.to_string(),
+            half_spread,
+            quote_size,
+            target_inventory: Quantity::ZERO,
+            skew_per_unit: 0.0,
+            inventory_limit: None,
+            live_quotes: Vec::new(),
+        }
+    }
+
+    pub fn with_target_inventory(mut self, target_inventory: Quantity) -> Self {
+        self.target_inventory = target_inventory;
+        self
+    }
+
+    pub fn with_skew(mut self, skew_per_unit: f64) -> Self {
+        self.skew_per_unit = skew_per_unit;
+        self
+    }
+
+    pub fn with_inventory_limit(mut self, inventory_limit: Quantity) -> Self {
+        self.inventory_limit = Some(inventory_limit);
+        self
+    }
+
+    // Bid and ask the maker would quote now, each None when that side is
+    // pulled. Centres on the mid, or the last price while a side is empty.
+    pub fn quotes(&self, engine: &TradingEngine) -> Option<(Option<f64>, Option<f64>)> {
+        let reference = engine
+            .get_mid(&self.symbol)
+            .or_else(|| engine.get_market_data(&self.symbol).map(|md| md.last_price))?;
+        let position = engine.get_position(&self.account, &self.symbol);
+        let centre = reference - (position - self.target_inventory).to_f64() * self.skew_per_unit;
+
+        let mut bid = centre - self.half_spread;
+        let mut ask = centre + self.half_spread;
+        if let Some(tick_size) = engine.order_books[&self.symbol].config.tick_size {
+            bid = (bid / tick_size).floor() * tick_size;
+            ask = (ask / tick_size).ceil() * tick_size;
+        }
+
+        let limit = self.inventory_limit;
+        let bid = limit.is_none_or(|limit| position < limit).then_some(bid);
+        let ask = limit.is_none_or(|limit| position > -limit).then_some(ask);
+        Some((bid, ask))
+    }
+
+    // Pulls the previous quotes and places fresh ones from the current
+    // position, returning any trades the new quotes made on arrival
+    pub fn requote(&mut self, engine: &mut TradingEngine) -> Vec<Trade> {
+        for order_id in self.live_quotes.drain(..) {
+            engine.cancel_order(&self.symbol, &order_id).ok();
+        }
+        let Some((bid, ask)) = self.quotes(engine) else {
+            return Vec::new();
+        };
+
+        let mut trades = Vec::new();
+        for (side, price) in [(Side::Buy, bid), (Side::Sell, ask)] {
+            let Some(price) = price else {
+                continue;
+            };
+            let order = Order::new(
+                self.symbol.clone(),
+                side,
+                OrderType::Limit,
+                self.quote_size,
+                Some(price),
+            )
+            .with_account(&self.account);
+            let order_id = order.id.clone();
+            if let Ok(order_trades) = engine.place_order(order) {
+                trades.extend(order_trades);
+                self.live_quotes.push(order_id);
+            }
+        }
+        trades
+    }
+}
+
+// ===== FEED REPLAY =====
+
+// Recorded order events in a fixed-width binary layout modelled on ITCH.
+// Integers are big-endian; symbols are ASCII padded with spaces to 8 bytes;
+// quantities and prices are fixed point with eight decimal places, prices
+// signed so negative-price books replay too.
+//
+//   'A' add      symbol[8] order_ref:u64 side:u8 ('B'/'S') quantity:i64 price:i64
+//   'U' modify   symbol[8] order_ref:u64 quantity:i64 price:i64
+//   'D' delete   symbol[8] order_ref:u64
+//   'E' execute  symbol[8] order_ref:u64 quantity:i64
+#[derive(Debug, Clone, PartialEq)]
+pub enum BookEvent {
+    Add { symbol: String, order_ref: u64, side: Side, quantity: Quantity, price: f64 },
+    Modify { symbol: String, order_ref: u64, quantity: Quantity, price: f64 },
+    Delete { symbol: String, order_ref: u64 },
+    Execute { symbol: String, order_ref: u64, quantity: Quantity }, // A resting order was hit
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    Empty,
+    UnknownMessageType(u8),
+    Truncated { message_type: u8, expected: usize, actual: usize },
+    InvalidSide(u8),
+    InvalidSymbol,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "Empty message"),
+            ParseError::UnknownMessageType(t) => write!(f, "Unknown message type 0x{:02x}", t),
+            ParseError::Truncated {
+                message_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Message '{}' needs {} bytes, got {}",
+                *message_type as char, expected, actual
+            ),
+            ParseError::InvalidSide(side) => write!(f, "Invalid side byte 0x{:02x}", side),
+            ParseError::InvalidSymbol => write!(f, "Symbol is not ASCII"),
+        }
+    }
+}
+
+// Total length of a message by its type byte, for framing a recorded stream
+pub fn message_len(message_type: u8) -> Option<usize> {
+    match message_type {
+        b'A' => Some(34),
+        b'U' => Some(33),
+        b'D' => Some(17),
+        b'E' => Some(25),
+        _ => None,
+    }
+}
+
+pub fn parse_message(bytes: &[u8]) -> Result<BookEvent, ParseError> {
+    let &message_type = bytes.first().ok_or(ParseError::Empty)?;
+    let expected = message_len(message_type).ok_or(ParseError::UnknownMessageType(message_type))?;
+    if bytes.len() < expected {
+        return Err(ParseError::Truncated {
+            message_type,
+            expected,
+            actual: bytes.len(),
+        });
+    }
+
+    let symbol = std::str::from_utf8(&bytes[1..9])
+        .ok()
+        .filter(|s| s.is_ascii())
+        .ok_or(ParseError::InvalidSymbol)?
+        .trim_end()
+        .to_string();
+    let u64_at = |at: usize| u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+    let i64_at = |at: usize| i64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+    let price_at = |at: usize| i64_at(at) as f64 / Quantity::SCALE as f64;
+    let order_ref = u64_at(9);
+
+    Ok(match message_type {
+        b'A' => BookEvent::Add {
+            symbol,
+            order_ref,
+            side: match bytes[17] {
+                b'B' => Side::Buy,
+                b'S' => Side::Sell,
+                other => return Err(ParseError::InvalidSide(other)),
+            },
+            quantity: Quantity::from_raw(i64_at(18)),
+            price: price_at(26),
+        },
+        b'U' => BookEvent::Modify {
+            symbol,
+            order_ref,
+            quantity: Quantity::from_raw(i64_at(17)),
+            price: price_at(25),
+        },
+        b'D' => BookEvent::Delete { symbol, order_ref },
+        _ => BookEvent::Execute {
+            symbol,
+            order_ref,
+            quantity: Quantity::from_raw(i64_at(17)),
+        },
+    })
+}
+
+// Aggregated depth at a point in time. The binary form follows the feed's
+// conventions with a variable-length tail of levels, bids then asks:
+//
+//   'S' snapshot symbol[8] timestamp_micros:i64 bid_count:u32 ask_count:u32
+//                (price:i64 quantity:i64) * (bid_count + ask_count)
+//
+// Only symbols of at most 8 ASCII characters fit; others are refused rather
+// than truncated, since they could not be read back.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DepthSnapshot {
+    symbol: String,
+    timestamp: DateTime<Utc>,
+    bids: Vec<PriceLevel>,
+    asks: Vec<PriceLevel>,
+}
+
+impl DepthSnapshot {
+    const HEADER_LEN: usize = 25;
+    const LEVEL_LEN: usize = 16;
+
+    pub fn symbol(&self) -> &str {
+        &self.symbol
+    }
+
+    pub fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    pub fn bids(&self) -> &[PriceLevel] {
+        &self.bids
+    }
+
+    pub fn asks(&self) -> &[PriceLevel] {
+        &self.asks
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, OrderError> {
+        if !self.symbol.is_ascii() || self.symbol.len() > 8 {
+            return Err(OrderError::UnencodableSymbol(self.symbol.clone()));
+        }
+        let levels = self.bids.len() + self.asks.len();
+        let mut bytes = Vec::with_capacity(Self::HEADER_LEN + levels * Self::LEVEL_LEN);
+        bytes.push(b'S');
+        bytes.extend_from_slice(format!("{:<8}", self.symbol).as_bytes());
+        bytes.extend_from_slice(&self.timestamp.timestamp_micros().to_be_bytes());
+        bytes.extend_from_slice(&(self.bids.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&(self.asks.len() as u32).to_be_bytes());
+        for (price, quantity) in self.bids.iter().chain(&self.asks) {
+            let scaled = (price * Quantity::SCALE as f64).round() as i64;
+            bytes.extend_from_slice(&scaled.to_be_bytes());
+            bytes.extend_from_slice(&quantity.raw().to_be_bytes());
+        }
+        Ok(bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ParseError> {
+        let &message_type = bytes.first().ok_or(ParseError::Empty)?;
+        if message_type != b'S' {
+            return Err(ParseError::UnknownMessageType(message_type));
+        }
+        let truncated = |expected: usize| ParseError::Truncated {
+            message_type,
+            expected,
+            actual: bytes.len(),
+        };
+        if bytes.len() < Self::HEADER_LEN {
+            return Err(truncated(Self::HEADER_LEN));
+        }
+
+        let symbol = std::str::from_utf8(&bytes[1..9])
+            .ok()
+            .filter(|s| s.is_ascii())
+            .ok_or(ParseError::InvalidSymbol)?
+            .trim_end()
+            .to_string();
+        let i64_at = |at: usize| i64::from_be_bytes(bytes[at..at + 8].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap());
+        let timestamp = DateTime::from_timestamp_micros(i64_at(9)).unwrap_or_default();
+        let bid_count = u32_at(17) as usize;
+        let ask_count = u32_at(21) as usize;
+
+        let expected = bid_count
+            .saturating_add(ask_count)
+            .saturating_mul(Self::LEVEL_LEN)
+            .saturating_add(Self::HEADER_LEN);
+        if bytes.len() < expected {
+            return Err(truncated(expected));
+        }
+
+        let level_at = |index: usize| {
+            let at = Self::HEADER_LEN + index * Self::LEVEL_LEN;
+            (
+                i64_at(at) as f64 / Quantity::SCALE as f64,
+                Quantity::from_raw(i64_at(at + 8)),
+            )
+        };
+        Ok(DepthSnapshot {
+            symbol,
+            timestamp,
+            bids: (0..bid_count).map(level_at).collect(),
+            asks: (bid_count..bid_count + ask_count).map(level_at).collect(),
+        })
+    }
+}
+
+// A recorded order as it rests in the replayed engine
+#[derive(Debug, Clone)]
+struct ReplayedOrder {
+    symbol: String,
+    order_id: String,
+    remaining: Quantity,
+}
+
+// Drives an engine from recorded events, mapping the feed's order
+// references onto the engine's own order ids
+#[derive(Debug, Default)]
+pub struct BookReplayer {
+    orders: HashMap<u64, ReplayedOrder>,
+}
+
+impl BookReplayer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(
+        &mut self,
+        engine: &mut TradingEngine,
+        event: &BookEvent,
+    ) -> Result<Vec<Trade>, OrderError> {
+        match event {
+            BookEvent::Add {
+                symbol,
+                order_ref,
+                side,
+                quantity,
+                price,
+            } => {
+                self.add(engine, symbol, *order_ref, *side, *quantity, *price)?;
+                Ok(Vec::new())
+            }
+            BookEvent::Modify {
+                symbol,
+                order_ref,
+                quantity,
+                price,
+            } => {
+                // A modify re-queues the order, as a cancel-replace would
+                let side = self.remove(engine, *order_ref)?.side;
+                self.add(engine, symbol, *order_ref, side, *quantity, *price)?;
+                Ok(Vec::new())
+            }
+            BookEvent::Delete { order_ref, .. } => {
+                self.remove(engine, *order_ref)?;
+                Ok(Vec::new())
+            }
+            BookEvent::Execute {
+                symbol,
+                order_ref,
+                quantity,
+            } => {
+                let resting = self.lookup(*order_ref)?;
+                let trade = engine.execute_order(symbol, &resting.order_id, *quantity)?;
+                let remaining = resting.remaining - trade.quantity;
+                if remaining.is_zero() {
+                    self.orders.remove(order_ref);
+                } else if let Some(replayed) = self.orders.get_mut(order_ref) {
+                    replayed.remaining = remaining;
+                }
+                Ok(vec![trade])
+            }
+        }
+    }
+
+    // The feed only reports adds that rest, so they never match on arrival
+    fn add(
+        &mut self,
+        engine: &mut TradingEngine,
+        symbol: &str,
+        order_ref: u64,
+        side: Side,
+        quantity: Quantity,
+        price: f64,
+    ) -> Result<(), OrderError> {
+        let order = Order::new(symbol.to_string(), side, OrderType::Limit, quantity, Some(price));
+        let order_id = order.id.clone();
+        engine.rest_order(order)?;
+        self.orders.insert(
+            order_ref,
+            ReplayedOrder {
+                symbol: symbol.to_string(),
+                order_id,
+                remaining: quantity,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove(&mut self, engine: &mut TradingEngine, order_ref: u64) -> Result<Order, OrderError> {
+        let resting = self.lookup(order_ref)?.clone();
+        let cancelled = engine.cancel_order(&resting.symbol, &resting.order_id)?;
+        self.orders.remove(&order_ref);
+        Ok(cancelled)
+    }
+
+    fn lookup(&self, order_ref: u64) -> Result<&ReplayedOrder, OrderError> {
+        self.orders
+            .get(&order_ref)
+            .ok_or_else(|| OrderError::OrderNotFound(order_ref.to_string()))
+    }
+}
+
+// ===== FEED CONSISTENCY =====
+
+// Where a snapshot feed and its incremental deltas stop agreeing. Delta
+// batch `i` leads from snapshot `i` to snapshot `i + 1`, and errors name
+// the snapshot the batch was leading to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeedError {
+    BatchCount { snapshots: usize, batches: usize },
+    WrongSymbol { snapshot: usize, symbol: String },
+    UnknownOrder { snapshot: usize, order_ref: u64 },
+    DuplicateOrder { snapshot: usize, order_ref: u64 },
+    Overexecuted { snapshot: usize, order_ref: u64 },
+    Diverged { snapshot: usize, side: Side, price: f64, expected: Quantity, actual: Quantity },
+}
+
+impl fmt::Display for FeedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FeedError::BatchCount { snapshots, batches } => write!(
+                f,
+                "{} snapshots need {} delta batches, got {}",
+                snapshots,
+                snapshots.saturating_sub(1),
+                batches
+            ),
+            FeedError::WrongSymbol { snapshot, symbol } => {
+                write!(f, "Snapshot {}: unexpected symbol {}", snapshot, symbol)
+            }
+            FeedError::UnknownOrder {
+                snapshot,
+                order_ref,
+            } => write!(f, "Deltas to snapshot {}: unknown order {}", snapshot, order_ref),
+            FeedError::DuplicateOrder {
+                snapshot,
+                order_ref,
+            } => write!(f, "Deltas to snapshot {}: order {} added twice", snapshot, order_ref),
+            FeedError::Overexecuted {
+                snapshot,
+                order_ref,
+            } => write!(
+                f,
+                "Deltas to snapshot {}: order {} executed beyond its size",
+                snapshot, order_ref
+            ),
+            FeedError::Diverged {
+                snapshot,
+                side,
+                price,
+                expected,
+                actual,
+            } => {
+                let side = match side {
+                    Side::Buy => "bid",
+                    Side::Sell => "ask",
+                };
+                write!(
+                    f,
+                    "Snapshot {} {} at {}: snapshot has {}, deltas give {}",
+                    snapshot, side, price, expected, actual
+                )
+            }
+        }
+    }
+}
+
+impl BookEvent {
+    pub fn symbol(&self) -> &str {
+        match self {
+            BookEvent::Add { symbol, .. }
+            | BookEvent::Modify { symbol, .. }
+            | BookEvent::Delete { symbol, .. }
+            | BookEvent::Execute { symbol, .. } => symbol,
+        }
+    }
+}
+
+// Aggregated levels rebuilt from deltas, keyed by fixed-point price as on
+// the wire so levels compare exactly
+#[derive(Default)]
+struct FeedBook {
+    bids: BTreeMap<i64, Quantity>,
+    asks: BTreeMap<i64, Quantity>,
+    orders: HashMap<u64, RestingRef>,
+}
+
+// Side, price key and remaining quantity of an order added by the deltas
+type RestingRef = (Side, i64, Quantity);
+
+impl FeedBook {
+    fn price_key(price: f64) -> i64 {
+        (price * Quantity::SCALE as f64).round() as i64
+    }
+
+    fn levels(&mut self, side: Side) -> &mut BTreeMap<i64, Quantity> {
+        match side {
+            Side::Buy => &mut self.bids,
+            Side::Sell => &mut self.asks,
+        }
+    }
+
+    // Snapshot levels are known only in aggregate, not by order
+    fn seed(snapshot: &DepthSnapshot) -> Self {
+        let mut book = FeedBook::default();
+        for (side, levels) in [(Side::Buy, snapshot.bids()), (Side::Sell, snapshot.asks())] {
+            for &(price, quantity) in levels {
+                *book.levels(side).entry(Self::price_key(price)).or_default() += quantity;
+            }
+        }
+        book
+    }
+
+    fn rest(&mut self, order_ref: u64, side: Side, key: i64, quantity: Quantity) {
+        *self.levels(side).entry(key).or_default() += quantity;
+        self.orders.insert(order_ref, (side, key, quantity));
+    }
+
+    fn remove(&mut self, snapshot: usize, order_ref: u64) -> Result<RestingRef, FeedError> {
+        let (side, key, quantity) = self
+            .orders
+            .remove(&order_ref)
+            .ok_or(FeedError::UnknownOrder { snapshot, order_ref })?;
+        let levels = self.levels(side);
+        if let Some(level) = levels.get_mut(&key) {
+            *level -= quantity;
+            if level.is_zero() {
+                levels.remove(&key);
+            }
+        }
+        Ok((side, key, quantity))
+    }
+
+    fn apply(&mut self, snapshot: usize, event: &BookEvent) -> Result<(), FeedError> {
+        match event {
+            BookEvent::Add {
+                order_ref,
+                side,
+                quantity,
+                price,
+                ..
+            } => {
+                if self.orders.contains_key(order_ref) {
+                    let order_ref = *order_ref;
+                    return Err(FeedError::DuplicateOrder { snapshot, order_ref });
+                }
+                self.rest(*order_ref, *side, Self::price_key(*price), *quantity);
+            }
+            BookEvent::Modify {
+                order_ref,
+                quantity,
+                price,
+                ..
+            } => {
+                let (side, _, _) = self.remove(snapshot, *order_ref)?;
+                self.rest(*order_ref, side, Self::price_key(*price), *quantity);
+            }
+            BookEvent::Delete { order_ref, .. } => {
+                self.remove(snapshot, *order_ref)?;
+            }
+            BookEvent::Execute {
+                order_ref,
+                quantity,
+                ..
+            } => {
+                let (side, key, remaining) = self.remove(snapshot, *order_ref)?;
+                if *quantity > remaining {
+                    let order_ref = *order_ref;
+                    return Err(FeedError::Overexecuted { snapshot, order_ref });
+                }
+                if remaining > *quantity {
+                    self.rest(*order_ref, side, key, remaining - *quantity);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Reports the first level, best price first, where the two disagree
+    fn compare(&self, index: usize, snapshot: &DepthSnapshot) -> Result<(), FeedError> {
+        let sides = [
+            (Side::Buy, snapshot.bids(), &self.bids),
+            (Side::Sell, snapshot.asks(), &self.asks),
+        ];
+        for (side, levels, rebuilt) in sides {
+            let expected: BTreeMap<i64, Quantity> =
+                levels.iter().map(|&(price, q)| (Self::price_key(price), q)).collect();
+            let mut keys: Vec<i64> = expected.keys().chain(rebuilt.keys()).copied().collect();
+            keys.sort_unstable();
+            keys.dedup();
+            if side == Side::Buy {
+                keys.reverse();
+            }
+
+            for key in keys {
+                let expected = expected.get(&key).copied().unwrap_or_default();
+                let actual = rebuilt.get(&key).copied().unwrap_or_default();
+                if expected != actual {
+                    return Err(FeedError::Diverged {
+                        snapshot: index,
+                        side,
+                        price: key as f64 / Quantity::SCALE as f64,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Checks that applying each batch of deltas to one snapshot's levels gives
+// exactly the next snapshot's levels, as a consumer rebuilding the book
+// from the incremental feed would see it. Snapshots must carry the full
+// visible depth. Orders resting at the first snapshot are only known in
+// aggregate, so the deltas may only modify, delete or execute orders they
+// added themselves.
+pub fn verify_feed_consistency(
+    snapshots: &[DepthSnapshot],
+    deltas: &[Vec<BookEvent>],
+) -> Result<(), FeedError> {
+    if deltas.len() != snapshots.len().saturating_sub(1) {
+        return Err(FeedError::BatchCount {
+            snapshots: snapshots.len(),
+            batches: deltas.len(),
+        });
+    }
+    let Some(first) = snapshots.first() else {
+        return Ok(());
+    };
+
+    let mut book = FeedBook::seed(first);
+    for (i, (batch, snapshot)) in deltas.iter().zip(&snapshots[1..]).enumerate() {
+        let index = i + 1;
+        let wrong = |symbol: &str| FeedError::WrongSymbol {
+            snapshot: index,
+            symbol: symbol.to_string(),
+        };
+        if snapshot.symbol() != first.symbol() {
+            return Err(wrong(snapshot.symbol()));
+        }
+        for event in batch {
+            if event.symbol() != first.symbol() {
+                return Err(wrong(event.symbol()));
+            }
+            book.apply(index, event)?;
+        }
+        book.compare(index, snapshot)?;
+    }
+    Ok(())
+}
+
+// ===== ASYNC API =====
+
+// Async facade for embedding the engine in a tokio application. The engine
+// itself stays synchronous behind an async mutex; each call holds the lock
+// for one engine operation and then publishes what changed to subscribers.
+#[cfg(feature = "tokio")]
+mod async_api {
+    use super::*;
+    use tokio::sync::{broadcast, Mutex};
+
+    #[derive(Debug, Clone)]
+    pub enum MarketUpdate {
+        Trade(Trade),
+        MarketData(MarketData),
+    }
+
+    // Forwards the engine's published prints, so deferred ones reach
+    // subscribers only once their publish time has passed
+    struct UpdateSink(broadcast::Sender<MarketUpdate>);
+
+    impl TradeSink for UpdateSink {
+        fn publish(&self, trade: &Trade) {
+            // No subscribers is not an error
+            let _ = self.0.send(MarketUpdate::Trade(trade.clone()));
+        }
+    }
+
+    #[derive(Clone)]
+    pub struct AsyncTradingEngine {
+        engine: Arc<Mutex<TradingEngine>>,
+        updates: broadcast::Sender<MarketUpdate>,
+    }
+
+    impl AsyncTradingEngine {
+        pub fn new(mut engine: TradingEngine) -> Self {
+            let (updates, _) = broadcast::channel(1024);
+            engine.add_trade_sink(Box::new(UpdateSink(updates.clone())));
+            AsyncTradingEngine {
+                engine: Arc::new(Mutex::new(engine)),
+                updates,
+            }
+        }
+
+        // Shared handle for operations the facade does not wrap
+        pub fn engine(&self) -> Arc<Mutex<TradingEngine>> {
+            self.engine.clone()
+        }
+
+        // Receives every published trade, including deferred prints released by
+        // later engine calls, and market data changes from calls made through
+        // this facade. Slow receivers see RecvError::Lagged rather than blocking.
+        pub fn subscribe(&self) -> broadcast::Receiver<MarketUpdate> {
+            self.updates.subscribe()
+        }
+
+        pub async fn place_order(&self, order: Order) -> Result<Vec<Trade>, OrderError> {
+            let symbol = order.symbol.clone();
+            let mut engine = self.engine.lock().await;
+            let trades = engine.place_order(order)?;
+            let market_data = engine.get_market_data(&symbol);
+            drop(engine);
+
+            if let Some(market_data) = market_data {
+                let _ = self.updates.send(MarketUpdate::MarketData(market_data));
+            }
+            Ok(trades)
+        }
+
+        pub async fn cancel_order(
+            &self,
+            symbol: &str,
+            order_id: &str,
+        ) -> Result<Order, OrderError> {
+            let mut engine = self.engine.lock().await;
+            let cancelled = engine.cancel_order(symbol, order_id)?;
+            let market_data = engine.get_market_data(symbol);
+            drop(engine);
+
+            if let Some(market_data) = market_data {
+                let _ = self.updates.send(MarketUpdate::MarketData(market_data));
+            }
+            Ok(cancelled)
+        }
+
+        // Also releases deferred prints that have come due to subscribers
+        pub async fn process_time_events(&self, symbol: &str) -> Result<TimeEvents, OrderError> {
+            let mut engine = self.engine.lock().await;
+            let events = engine.process_time_events(symbol)?;
+            let market_data = engine.get_market_data(symbol);
+            drop(engine);
+
+            if let Some(market_data) = market_data {
+                let _ = self.updates.send(MarketUpdate::MarketData(market_data));
+            }
+            Ok(events)
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+pub use async_api::{AsyncTradingEngine, MarketUpdate};
+
+// ===== ARROW EXPORT =====
+
+// Columnar snapshots of a book for analytics tools such as Polars or
+// DataFusion. Timestamps are microseconds since the epoch in UTC.
+#[cfg(feature = "arrow")]
+mod arrow_export {
+    use super::*;
+    use arrow_array::{
+        ArrayRef, BooleanArray, Decimal128Array, Float64Array, RecordBatch, StringArray,
+        TimestampMicrosecondArray, UInt64Array,
+    };
+    use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+    use std::sync::Arc;
+
+    fn timestamp_type() -> DataType {
+        DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+    }
+
+    fn timestamps<'a>(times: impl Iterator<Item = &'a DateTime<Utc>>) -> ArrayRef {
+        let micros: Vec<i64> = times.map(|t| t.timestamp_micros()).collect();
+        Arc::new(TimestampMicrosecondArray::from(micros).with_timezone("UTC"))
+    }
+
+    // Quantities keep their exact fixed-point value as an 8-place decimal
+    const QUANTITY_PRECISION: u8 = 18;
+    const QUANTITY_SCALE: i8 = 8;
+
+    fn quantity_type() -> DataType {
+        DataType::Decimal128(QUANTITY_PRECISION, QUANTITY_SCALE)
+    }
+
+    fn quantities(values: impl Iterator<Item = Quantity>) -> Result<ArrayRef, ArrowError> {
+        let array = values
+            .map(|q| q.raw() as i128)
+            .collect::<Decimal128Array>()
+            .with_precision_and_scale(QUANTITY_PRECISION, QUANTITY_SCALE)?;
+        Ok(Arc::new(array))
+    }
+
+    fn strings<'a>(values: impl Iterator<Item = Option<&'a str>>) -> ArrayRef {
+        Arc::new(values.collect::<StringArray>())
+    }
+
+    impl OrderBook {
+        // Resting orders, bids then asks, each in queue order. Hidden orders
+        // are included and flagged rather than dropped.
+        pub fn to_arrow_batch(&self) -> Result<RecordBatch, ArrowError> {
+            let orders: Vec<&Order> = self.bids.iter().chain(self.asks.iter()).collect();
+
+            let schema = Schema::new(vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new("symbol", DataType::Utf8, false),
+                Field::new("side", DataType::Utf8, false),
+                Field::new("order_type", DataType::Utf8, false),
+                Field::new("quantity", quantity_type(), false),
+                Field::new("price", DataType::Float64, true),
+                Field::new("account", DataType::Utf8, true),
+                Field::new("sequence", DataType::UInt64, false),
+                Field::new("hidden", DataType::Boolean, false),
+                Field::new("improved_book", DataType::Boolean, false),
+                Field::new("timestamp", timestamp_type(), false),
+            ]);
+
+            let sides: Vec<String> = orders.iter().map(|o| o.side.to_string()).collect();
+            let types: Vec<String> = orders.iter().map(|o| o.order_type.to_string()).collect();
+            let columns: Vec<ArrayRef> = vec![
+                strings(orders.iter().map(|o| Some(o.id.as_str()))),
+                strings(orders.iter().map(|o| Some(o.symbol.as_str()))),
+                strings(sides.iter().map(|s| Some(s.as_str()))),
+                strings(types.iter().map(|t| Some(t.as_str()))),
+                quantities(orders.iter().map(|o| o.quantity))?,
+                Arc::new(orders.iter().map(|o| o.price).collect::<Float64Array>()),
+                strings(orders.iter().map(|o| o.account.as_deref())),
+                Arc::new(orders.iter().map(|o| o.sequence).collect::<UInt64Array>()),
+                Arc::new(orders.iter().map(|o| Some(o.hidden)).collect::<BooleanArray>()),
+                Arc::new(orders.iter().map(|o| Some(o.improved_book)).collect::<BooleanArray>()),
+                timestamps(orders.iter().map(|o| &o.timestamp)),
+            ];
+
+            RecordBatch::try_new(Arc::new(schema), columns)
+        }
+
+        // Every trade in execution order, including ones not yet published
+        pub fn trades_to_arrow_batch(&self) -> Result<RecordBatch, ArrowError> {
+            let trades = &self.trades;
+
+            let schema = Schema::new(vec![
+                Field::new("id", DataType::Utf8, false),
+                Field::new("symbol", DataType::Utf8, false),
+                Field::new("buyer_order_id", DataType::Utf8, false),
+                Field::new("seller_order_id", DataType::Utf8, false),
+                Field::new("buyer_account", DataType::Utf8, true),
+                Field::new("seller_account", DataType::Utf8, true),
+                Field::new("aggressor", DataType::Utf8, true),
+                Field::new("quantity", quantity_type(), false),
+                Field::new("price", DataType::Float64, false),
+                Field::new("timestamp", timestamp_type(), false),
+                Field::new("publish_time", timestamp_type(), false),
+                Field::new("tick_direction", DataType::Utf8, false),
+            ]);
+
+            let aggressors: Vec<Option<String>> =
+                trades.iter().map(|t| t.aggressor.map(|side| side.to_string())).collect();
+            let ticks: Vec<String> = trades.iter().map(|t| t.tick_direction.to_string()).collect();
+            let columns: Vec<ArrayRef> = vec![
+                strings(trades.iter().map(|t| Some(t.id.as_str()))),
+                strings(trades.iter().map(|t| Some(t.symbol.as_str()))),
+                strings(trades.iter().map(|t| Some(t.buyer_order_id.as_str()))),
+                strings(trades.iter().map(|t| Some(t.seller_order_id.as_str()))),
+                strings(trades.iter().map(|t| t.buyer_account.as_deref())),
+                strings(trades.iter().map(|t| t.seller_account.as_deref())),
+                strings(aggressors.iter().map(|a| a.as_deref())),
+                quantities(trades.iter().map(|t| t.quantity))?,
+                Arc::new(trades.iter().map(|t| t.price).collect::<Float64Array>()),
+                timestamps(trades.iter().map(|t| &t.timestamp)),
+                timestamps(trades.iter().map(|t| &t.publish_time)),
+                strings(ticks.iter().map(|t| Some(t.as_str()))),
+            ];
+
+            RecordBatch::try_new(Arc::new(schema), columns)
+        }
+    }
+}
+
+// ===== JSON SCHEMA =====
+
+// JSON Schemas for the types API clients exchange, so non-Rust clients can
+// validate payloads or generate bindings. Quantities are decimal strings,
+// matching the gRPC wire format.
+#[cfg(feature = "json-schema")]
+mod json_schema {
+    use super::*;
+    use schemars::{json_schema, JsonSchema, Schema, SchemaGenerator};
+    use std::borrow::Cow;
+
+    impl JsonSchema for Quantity {
+        fn schema_name() -> Cow<'static, str> {
+            "Quantity".into()
+        }
+
+        fn json_schema(_: &mut SchemaGenerator) -> Schema {
+            json_schema!({
+                "type": "string",
+                "pattern": "^-?[0-9]+(\\.[0-9]{1,8})?$",
+                "description": "Decimal with up to eight places, e.g. \"0.5\""
+            })
+        }
+    }
+
+    pub fn json_schemas() -> HashMap<&'static str, serde_json::Value> {
+        HashMap::from([
+            ("Order", schemars::schema_for!(Order).to_value()),
+            ("Trade", schemars::schema_for!(Trade).to_value()),
+            ("OrderOutcome", schemars::schema_for!(OrderOutcome).to_value()),
+        ])
+    }
+}
+
+#[cfg(feature = "json-schema")]
+pub use json_schema::json_schemas;
+
+// ===== GRPC SERVICE =====
+
+#[cfg(feature = "grpc")]
+mod grpc {
+    use super::*;
+    use std::net::SocketAddr;
+    use std::pin::Pin;
+    use std::sync::Arc;
+    use tokio::sync::{broadcast, Mutex};
+    use tokio_stream::wrappers::BroadcastStream;
+    use tokio_stream::{Stream, StreamExt};
+    use tonic::{Request, Response, Status};
+
+    pub mod proto {
+        tonic::include_proto!("trading");
+    }
+
+    use proto::trading_server::{Trading, TradingServer};
+
+    // The engine is synchronous, so every RPC takes the async lock for the
+    // duration of a single engine call. The service registers a trade sink,
+    // so every trade the engine makes is fanned out to StreamTrades
+    // subscribers, not just those of PlaceOrder.
+    pub struct TradingService {
+        engine: Arc<Mutex<TradingEngine>>,
+        trades: broadcast::Sender<Trade>,
+    }
+
+    impl TradingService {
+        pub async fn new(engine: Arc<Mutex<TradingEngine>>) -> Self {
+            let (trades, _) = broadcast::channel(1024);
+            engine.lock().await.add_trade_sink(Box::new(trades.clone()));
+            TradingService { engine, trades }
+        }
+    }
+
+    // No subscribers is not an error
+    impl TradeSink for broadcast::Sender<Trade> {
+        fn publish(&self, trade: &Trade) {
+            let _ = self.send(trade.clone());
+        }
+    }
+
+    impl From<&Trade> for proto::Trade {
+        fn from(trade: &Trade) -> Self {
+            proto::Trade {
+                id: trade.id.clone(),
+                symbol: trade.symbol.clone(),
+                buyer_order_id: trade.buyer_order_id.clone(),
+                seller_order_id: trade.seller_order_id.clone(),
+                quantity: trade.quantity.to_string(),
+                price: trade.price,
+                timestamp_micros: trade.timestamp.timestamp_micros(),
+            }
+        }
+    }
+
+    impl From<OrderError> for Status {
+        fn from(error: OrderError) -> Self {
+            let message = error.to_string();
+            match error {
+                OrderError::MarketNotFound(_)
+                | OrderError::OrderNotFound(_)
+                | OrderError::TradeNotFound(_)
+                | OrderError::VenueNotFound(_) => Status::not_found(message),
+                OrderError::Throttled { .. } => Status::resource_exhausted(message),
+                OrderError::DuplicateClientOrderId { .. } => Status::already_exists(message),
+                OrderError::MarketClosed(_)
+                | OrderError::TradeThrough { .. }
+                | OrderError::NoBorrowAvailable { .. }
+                | OrderError::PositionLimitExceeded { .. }
+                | OrderError::FxRateUnavailable { .. }
+                | OrderError::OrderProtected(_)
+                | OrderError::MarketHalted { .. }
+                | OrderError::MinRestTimeNotMet { .. }
+                | OrderError::UnencodableSymbol(_)
+                | OrderError::SpreadRejected(_) => Status::failed_precondition(message),
+                OrderError::SymbolMismatch { .. }
+                | OrderError::InvalidQuantity { .. }
+                | OrderError::QuantityTooLarge { .. }
+                | OrderError::OffTickPrice { .. }
+                | OrderError::InvalidPrice(_)
+                | OrderError::InvalidTickSize(_)
+                | OrderError::InvalidCapacity(_)
+                | OrderError::InvalidSnapshot(_) => Status::invalid_argument(message),
+            }
+        }
+    }
+
+    fn to_levels(levels: Vec<PriceLevel>) -> Vec<proto::PriceLevel> {
+        levels
+            .into_iter()
+            .map(|(price, quantity)| proto::PriceLevel {
+                price,
+                quantity: quantity.to_string(),
+            })
+            .collect()
+    }
+
+    type TradeStream = Pin<Box<dyn Stream<Item = Result<proto::Trade, Status>> + Send>>;
+
+    #[tonic::async_trait]
+    impl Trading for TradingService {
+        async fn place_order(
+            &self,
+            request: Request<proto::PlaceOrderRequest>,
+        ) -> Result<Response<proto::PlaceOrderResponse>, Status> {
+            let request = request.into_inner();
+
+            let side = match proto::Side::try_from(request.side) {
+                Ok(proto::Side::Buy) => Side::Buy,
+                Ok(proto::Side::Sell) => Side::Sell,
+                Err(_) => return Err(Status::invalid_argument("Unknown side")),
+            };
+            let (order_type, price) = match proto::OrderType::try_from(request.order_type) {
+                Ok(proto::OrderType::Limit) => match request.price {
+                    Some(price) => (OrderType::Limit, Some(price)),
+                    None => return Err(Status::invalid_argument("Limit orders need a price")),
+                },
+                Ok(proto::OrderType::Market) => (OrderType::Market, None),
+                Err(_) => return Err(Status::invalid_argument("Unknown order type")),
+            };
+            // Sign and step are checked by the engine
+            let quantity: Quantity = request.quantity.parse().map_err(Status::invalid_argument)?;
+
+            let mut order = Order::new(request.symbol, side, order_type, quantity, price);
+            if let Some(account) = &request.account {
+                order = order.with_account(account);
+            }
+            if request.immediate_or_cancel {
+                order = order.immediate_or_cancel();
+            }
+            let order_id = order.id.clone();
+
+            let trades = self.engine.lock().await.place_order(order)?;
+
+            Ok(Response::new(proto::PlaceOrderResponse {
+                order_id,
+                trades: trades.iter().map(proto::Trade::from).collect(),
+            }))
+        }
+
+        async fn cancel_order(
+            &self,
+            request: Request<proto::CancelOrderRequest>,
+        ) -> Result<Response<proto::CancelOrderResponse>, Status> {
+            let request = request.into_inner();
+            let cancelled = self
+                .engine
+                .lock()
+                .await
+                .cancel_order(&request.symbol, &request.order_id)?;
+
+            Ok(Response::new(proto::CancelOrderResponse {
+                order_id: cancelled.id,
+                remaining_quantity: cancelled.quantity.to_string(),
+            }))
+        }
+
+        type StreamTradesStream = TradeStream;
+
+        async fn stream_trades(
+            &self,
+            request: Request<proto::StreamTradesRequest>,
+        ) -> Result<Response<Self::StreamTradesStream>, Status> {
+            let symbol = request.into_inner().symbol;
+
+            // Lagging subscribers silently skip the trades they missed
+            let stream = BroadcastStream::new(self.trades.subscribe()).filter_map(move |trade| {
+                match trade {
+                    Ok(trade) if symbol.is_empty() || trade.symbol == symbol => {
+                        Some(Ok(proto::Trade::from(&trade)))
+                    }
+                    _ => None,
+                }
+            });
+
+            Ok(Response::new(Box::pin(stream)))
+        }
+
+        async fn get_depth(
+            &self,
+            request: Request<proto::GetDepthRequest>,
+        ) -> Result<Response<proto::GetDepthResponse>, Status> {
+            let request = request.into_inner();
+            let (bids, asks) = self
+                .engine
+                .lock()
+                .await
+                .get_depth(&request.symbol, request.levels as usize)
+                .ok_or_else(|| Status::from(OrderError::MarketNotFound(request.symbol.clone())))?;
+
+            Ok(Response::new(proto::GetDepthResponse {
+                symbol: request.symbol,
+                bids: to_levels(bids),
+                asks: to_levels(asks),
+            }))
+        }
+    }
+
+    pub async fn serve(
+        engine: TradingEngine,
+        addr: SocketAddr,
+    ) -> Result<(), tonic::transport::Error> {
+        let service = TradingService::new(Arc::new(Mutex::new(engine))).await;
+        tonic::transport::Server::builder()
+            .add_service(TradingServer::new(service))
+            .serve(addr)
+            .await
+    }
+}
+
+// ===== CLI =====
+
+// Quotes older than this are flagged in the market overview
+const STALE_QUOTE_SECS: i64 = 60;
+
+// How the CLI renders numbers. Prices are shown to `precision` places;
+// quantities keep their exact decimals. Only presentation changes: the core
+// types' Display impls stay locale-neutral.
+#[derive(Debug, Clone, Copy)]
+struct NumberFormat {
+    thousands_separator: Option<char>,
+    decimal_separator: char,
+    precision: usize,
+}
+
+impl NumberFormat {
+    const US: NumberFormat = NumberFormat {
+        thousands_separator: None,
+        decimal_separator: '.',
+        precision: 2,
+    };
+    const EUROPEAN: NumberFormat = NumberFormat {
+        thousands_separator: Some('.'),
+        decimal_separator: ',',
+        precision: 2,
+    };
+
+    // "us" or "eu", as accepted by --locale
+    fn from_locale(locale: &str) -> Option<Self> {
+        match locale.to_lowercase().as_str() {
+            "us" => Some(Self::US),
+            "eu" => Some(Self::EUROPEAN),
+            _ => None,
+        }
+    }
+
+    fn price(&self, price: f64) -> String {
+        self.localize(&format!("{:.*}", self.precision, price))
+    }
+
+    fn quantity(&self, quantity: Quantity) -> String {
+        self.localize(&quantity.to_string())
+    }
+
+    // Rewrites a plain decimal such as "-1234.5" with this format's separators
+    fn localize(&self, plain: &str) -> String {
+        let (sign, unsigned) = match plain.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", plain),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+
+        let mut text = sign.to_string();
+        for (idx, digit) in integer.chars().enumerate() {
+            if idx > 0 && (integer.len() - idx) % 3 == 0 {
+                text.extend(self.thousands_separator);
+            }
+            text.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            text.push(self.decimal_separator);
+            text.push_str(fraction);
+        }
+        text
+    }
+}
+
+fn format_market_data(market_data: &MarketData, nf: &NumberFormat) -> String {
+    format!(
+        "{}: Bid: {} x {}, Ask: {} x {}, Last: {}, Mark: {}",
+        market_data.symbol,
+        nf.price(market_data.bid),
+        nf.quantity(market_data.bid_size),
+        nf.price(market_data.ask),
+        nf.quantity(market_data.ask_size),
+        nf.price(market_data.last_price),
+        nf.price(market_data.mark_price)
+    )
+}
+
+fn format_order(order: &Order, nf: &NumberFormat) -> String {
+    format!(
+        "Order[{}]: {} {} {} x {} @ {}",
+        order.id,
+        order.symbol,
+        order.side,
+        order.order_type,
+        nf.quantity(order.quantity),
+        order.price.map_or("MARKET".to_string(), |p| nf.price(p))
+    )
+}
+
+fn format_trade(trade: &Trade, nf: &NumberFormat) -> String {
+    format!(
+        "Trade[{}]: {} x {} @ {} (Buy: {}, Sell: {})",
+        trade.id,
+        trade.symbol,
+        nf.quantity(trade.quantity),
+        nf.price(trade.price),
+        trade.buyer_order_id,
+        trade.seller_order_id
+    )
+}
+
+fn print_menu() {
+    println!("\n=== TRADING SYSTEM CLI ===");
+    println!("1. View available markets");
+    println!("2. View market data");
+    println!("3. View order book");
+    println!("4. View recent trades");
+    println!("5. Place limit order");
+    println!("6. Place market order");
+    println!("7. Generate more mock data");
+    println!("8. Exit");
+    print!("Select an option: ");
+    io::stdout().flush().unwrap();
+}
+
+fn read_line() -> String {
+    let mut input = String::new();
+    io::stdin().read_line(&mut input).expect("Failed to read input");
+    input.trim().to_string()
+}
+
+fn view_markets(engine: &TradingEngine, nf: &NumberFormat) {
+    let max_age = chrono::Duration::seconds(STALE_QUOTE_SECS);
+    println!("\n=== AVAILABLE MARKETS ===");
+    for symbol in engine.get_symbols() {
+        if let Some(market_data) = engine.get_market_data(&symbol) {
+            println!("{}", format_market_data(&market_data, nf));
+            if market_data.is_stale(engine.now(), max_age) {
+                let age = market_data.age(engine.now()).num_seconds();
+                println!("  WARNING: quote is stale, last updated {}s ago", age);
+            }
+        } else {
+            println!("{}: No market data available", symbol);
+        }
+    }
+}
+
+fn view_market_data(engine: &TradingEngine, nf: &NumberFormat) {
+    print!("Enter symbol: ");
+    io::stdout().flush().unwrap();
+    let symbol = read_line();
+    
+    if let Some(market_data) = engine.get_market_data(&symbol) {
+        println!("\n=== MARKET DATA FOR {} ===", symbol);
+        println!("{}", format_market_data(&market_data, nf));
+        match market_data.spread_bps() {
+            Some(bps) => println!("Spread: {} bps", nf.price(bps)),
+            None => println!("Spread: n/a"),
+        }
+    } else {
+        println!("Market {} not found", symbol);
+    }
+}
+
+fn view_order_book(engine: &TradingEngine, nf: &NumberFormat) {
+    print!("Enter symbol: ");
+    io::stdout().flush().unwrap();
+    let symbol = read_line();
+    
+    if let Some((bids, asks)) = engine.get_orders(&symbol) {
+        println!("\n=== ORDER BOOK FOR {} ===", symbol);
+        
+        println!("BIDS:");
+        for bid in bids {
+            println!("  {}", format_order(&bid, nf));
+        }
+        
+        println!("ASKS:");
+        for ask in asks {
+            println!("  {}", format_order(&ask, nf));
+        }
+    } else {
+        println!("Market {} not found", symbol);
+    }
+}
+
+fn view_trades(engine: &TradingEngine, nf: &NumberFormat) {
+    print!("Enter symbol: ");
+    io::stdout().flush().unwrap();
+    let symbol = read_line();
+    
+    print!("Show time and sales tape? (y/n): ");
+    io::stdout().flush().unwrap();
+    let tape = read_line().eq_ignore_ascii_case("y");
+    
+    if let Some(trades) = engine.get_trades(&symbol) {
+        println!("\n=== RECENT TRADES FOR {} ===", symbol);
+        
+        if trades.is_empty() {
+            println!("No trades yet");
+        } else if tape {
+            print_tape(&trades, nf);
+        } else {
+            for trade in trades.iter().rev().take(10) {
+                println!("{}", format_trade(trade, nf));
+            }
+        }
+    } else {
+        println!("Market {} not found", symbol);
+    }
+}
+
+// Time and sales: newest prints last, with the aggressor side and the
+// session's running volume up to and including each print
+fn print_tape(trades: &[Trade], nf: &NumberFormat) {
+    let mut cumulative = Quantity::ZERO;
+    let running: Vec<Quantity> = trades
+        .iter()
+        .map(|trade| {
+            cumulative += trade.quantity;
+            cumulative
+        })
+        .collect();
+    
+    println!("{:<15} {:>4} {:>14} {:>12} {:>16}", "TIME", "SIDE", "QTY", "PRICE", "CUM VOL");
+    let start = trades.len().saturating_sub(10);
+    for (trade, volume) in trades[start..].iter().zip(&running[start..]) {
+        let side = trade.aggressor.map_or("-".to_string(), |side| side.to_string());
+        println!(
+            "{:<15} {:>4} {:>14} {:>12} {:>16}",
+            trade.timestamp.format("%H:%M:%S%.6f"),
+            side,
+            nf.quantity(trade.quantity),
+            nf.price(trade.price),
+            nf.quantity(*volume)
+        );
+    }
+}
+
+fn place_limit_order(engine: &mut TradingEngine, nf: &NumberFormat) {
+    print!("Enter symbol: ");
+    io::stdout().flush().unwrap();
+    let symbol = read_line();
+    
+    print!("Side (buy/sell): ");
+    io::stdout().flush().unwrap();
+    let side_input = read_line().to_lowercase();
+    let side = if side_input == "buy" { Side::Buy } else { Side::Sell };
+    
+    print!("Quantity: ");
+    io::stdout().flush().unwrap();
+    let quantity: Quantity = read_line().parse().unwrap_or(Quantity::ZERO);
+    
+    print!("Limit price: ");
+    io::stdout().flush().unwrap();
+    // Zero and negative limits are valid for instruments that trade below zero
+    let price: Option<f64> = read_line().parse().ok();
+    
+    if quantity <= Quantity::ZERO || price.is_none() {
+        println!("Invalid quantity or price");
+        return;
+    }
+    
+    let order = Order::new(
+        symbol,
+        side,
+        OrderType::Limit,
+        quantity,
+        price,
+    );
+    
+    match engine.place_order(order.clone()) {
+        Ok(trades) => {
+            println!("Order placed: {}", format_order(&order, nf));
+            if !trades.is_empty() {
+                println!("Trades executed:");
+                for trade in trades {
+                    println!("  {}", format_trade(&trade, nf));
+                }
+            }
+        }
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+fn place_market_order(engine: &mut TradingEngine, nf: &NumberFormat) {
+    print!("Enter symbol: ");
+    io::stdout().flush().unwrap();
+    let symbol = read_line();
+    
+    print!("Side (buy/sell): ");
+    io::stdout().flush().unwrap();
+    let side_input = read_line().to_lowercase();
+    let side = if side_input == "buy" { Side::Buy } else { Side::Sell };
+    
+    print!("Quantity: ");
+    io::stdout().flush().unwrap();
+    let quantity: Quantity = read_line().parse().unwrap_or(Quantity::ZERO);
+    
+    if quantity <= Quantity::ZERO {
+        println!("Invalid quantity");
+        return;
+    }
+    
+    let order = Order::new(
+        symbol,
+        side,
+        OrderType::Market,
+        quantity,
+        None,
+    );
+    
+    match engine.place_order(order.clone()) {
+        Ok(trades) => {
+            println!("Order placed: {}", format_order(&order, nf));
+            if !trades.is_empty() {
+                println!("Trades executed:");
+                for trade in trades {
+                    println!("  {}", format_trade(&trade, nf));
+                }
+            } else {
+                println!("No trades executed. No matching orders in the book.");
+            }
+        }
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+fn generate_more_mock_data(engine: &mut TradingEngine) {
+    for symbol in engine.get_symbols() {
+        if let Some(market_data) = engine.get_market_data(&symbol) {
+            engine.generate_mock_orders(&symbol, market_data.last_price, &mut rand::thread_rng());
+        }
+    }
+    println!("Generated additional mock orders for all markets");
+}
+
+// Runs a scenario file, one command per line; blank lines and lines
+// starting with '#' are skipped. Engine rejections are printed and the
+// script carries on, but a line that does not parse stops it.
+//   create SYMBOL PRICE
+//   buy|sell SYMBOL QTY [PRICE [CLIENT_ID]]   (no price sends a market order)
+//   cancel ID                                 (order id or client order id)
+//   print SYMBOL
+fn run_script(engine: &mut TradingEngine, path: &str, nf: &NumberFormat) -> Result<(), String> {
+    let script = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    for (idx, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        println!("> {}", line);
+        run_script_line(engine, line, nf).map_err(|e| format!("line {}: {}", idx + 1, e))?;
+    }
+    Ok(())
+}
+
+fn run_script_line(
+    engine: &mut TradingEngine,
+    line: &str,
+    nf: &NumberFormat,
+) -> Result<(), String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let price = |word: &str| word.parse::<f64>().map_err(|_| format!("invalid price '{}'", word));
+
+    match words.as_slice() {
+        ["create", symbol, initial_price] => {
+            engine.create_market(symbol, price(initial_price)?);
+            println!("Created market {}", symbol);
+        }
+        [side @ ("buy" | "sell"), symbol, quantity, rest @ ..] if rest.len() <= 2 => {
+            let side = if *side == "buy" { Side::Buy } else { Side::Sell };
+            let quantity: Quantity = quantity.parse()?;
+            let (order_type, limit) = match rest.first() {
+                Some(limit) => (OrderType::Limit, Some(price(limit)?)),
+                None => (OrderType::Market, None),
+            };
+            let order = match rest.get(1) {
+                Some(client_order_id) => Order::new_with_client_id(
+                    symbol.to_string(),
+                    side,
+                    order_type,
+                    quantity,
+                    limit,
+                    client_order_id,
+                ),
+                None => Order::new(symbol.to_string(), side, order_type, quantity, limit),
+            };
+
+            match engine.place_order(order.clone()) {
+                Ok(trades) => {
+                    println!("Order placed: {}", format_order(&order, nf));
+                    for trade in trades {
+                        println!("  {}", format_trade(&trade, nf));
+                    }
+                }
+                Err(e) => println!("Error: {}", e),
+            }
+        }
+        ["cancel", id] => {
+            let found = engine.get_symbols().into_iter().find_map(|symbol| {
+                let (bids, asks) = engine.get_orders(&symbol)?;
+                bids.into_iter()
+                    .chain(asks)
+                    .find(|o| o.id == *id || o.client_order_id.as_deref() == Some(*id))
+                    .map(|o| (symbol, o.id))
+            });
+            match found {
+                Some((symbol, order_id)) => match engine.cancel_order(&symbol, &order_id) {
+                    Ok(order) => println!("Cancelled: {}", format_order(&order, nf)),
+                    Err(e) => println!("Error: {}", e),
+                },
+                None => println!("Error: {}", OrderError::OrderNotFound(id.to_string())),
+            }
+        }
+        ["print", symbol] => match (engine.get_market_data(symbol), engine.get_orders(symbol)) {
+            (Some(market_data), Some((bids, asks))) => {
+                println!("{}", format_market_data(&market_data, nf));
+                for order in bids.iter().chain(&asks) {
+                    println!("  {}", format_order(order, nf));
+                }
+                for trade in engine.get_trades(symbol).unwrap_or_default() {
+                    println!("  {}", format_trade(&trade, nf));
+                }
+            }
+            _ => println!("Error: {}", OrderError::MarketNotFound(symbol.to_string())),
+        },
+        _ => return Err(format!("unrecognised command '{}'", line)),
+    }
+    Ok(())
+}
+
+// Bad command-line input: report it and exit with the usual usage status
+fn usage_error(message: &str) -> ! {
+    eprintln!("Usage error: {}", message);
+    std::process::exit(2);
+}
+
+// `--locale <us|eu>` picks the number format for CLI output
+fn locale_arg(args: &[String]) -> Result<NumberFormat, String> {
+    match args.iter().position(|arg| arg == "--locale") {
+        Some(pos) => args
+            .get(pos + 1)
+            .and_then(|locale| NumberFormat::from_locale(locale))
+            .ok_or_else(|| "--locale needs one of: us, eu".to_string()),
+        None => Ok(NumberFormat::US),
+    }
+}
+
+// `--script <path>` names a scenario file to run instead of the CLI
+fn script_arg(args: &[String]) -> Result<Option<&str>, String> {
+    match args.iter().position(|arg| arg == "--script") {
+        Some(pos) => args
+            .get(pos + 1)
+            .map(|path| Some(path.as_str()))
+            .ok_or_else(|| "--script needs a file path".to_string()),
+        None => Ok(None),
+    }
+}
+
+fn main() {
+    let mut engine = TradingEngine::new();
+    engine.populate_with_mock_data();
+
+    // `--grpc <addr>` serves the engine over gRPC instead of running the CLI
+    #[cfg(feature = "grpc")]
+    {
+        let args: Vec<String> = std::env::args().collect();
+        if let Some(pos) = args.iter().position(|arg| arg == "--grpc") {
+            let addr = args
+                .get(pos + 1)
+                .and_then(|addr| addr.parse().ok())
+                .expect("--grpc needs a socket address, e.g. 127.0.0.1:50051");
+            println!("Serving gRPC on {}", addr);
+            let runtime = tokio::runtime::Runtime::new().expect("Failed to start tokio runtime");
+            if let Err(e) = runtime.block_on(grpc::serve(engine, addr)) {
+                println!("gRPC server error: {}", e);
+            }
+            return;
+        }
+    }
+
+    let args: Vec<String> = std::env::args().collect();
+    let nf = locale_arg(&args).unwrap_or_else(|e| usage_error(&e));
+
+    // `--script <path>` runs a scenario file against an empty engine and exits
+    if let Some(path) = script_arg(&args).unwrap_or_else(|e| usage_error(&e)) {
+        if let Err(e) = run_script(&mut TradingEngine::new(), path, &nf) {
+            println!("Script error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+    
+    loop {
+        print_menu();
+        let choice = read_line();
+        
+        match choice.as_str() {
+            "1" => view_markets(&engine, &nf),
+            "2" => view_market_data(&engine, &nf),
+            "3" => view_order_book(&engine, &nf),
+            "4" => view_trades(&engine, &nf),
+            "5" => place_limit_order(&mut engine, &nf),
+            "6" => place_market_order(&mut engine, &nf),
+            "7" => generate_more_mock_data(&mut engine),
+            "8" => {
+                println!("Exiting...");
+                break;
+            }
+            _ => println!("Invalid option"),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn utc(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(year, month, day, hour, minute, 0).unwrap()
+    }
+
+    fn qty(units: i64) -> Quantity {
+        Quantity::from_units(units)
+    }
+
+    fn limit(symbol: &str, side: Side, quantity: i64, price: f64) -> Order {
+        Order::new(symbol.to_string(), side, OrderType::Limit, qty(quantity), Some(price))
+    }
+
+    // Engine on a SimClock, with the handle to drive it
+    fn sim_engine(start: DateTime<Utc>) -> (TradingEngine, SimClock) {
+        let clock = SimClock::new(start);
+        let mut engine = TradingEngine::new();
+        engine.set_clock(Arc::new(clock.clone()));
+        (engine, clock)
+    }
+
+    fn nyse_hours() -> SessionSchedule {
+        let open = NaiveTime::from_hms_opt(9, 30, 0).unwrap();
+        let close = NaiveTime::from_hms_opt(16, 0, 0).unwrap();
+        SessionSchedule::new(chrono_tz::America::New_York, open, close)
+    }
+
+    #[test]
+    fn session_open_shifts_an_hour_earlier_in_utc_after_spring_forward() {
+        let schedule = nyse_hours();
+        // Friday 8 March 2024 is EST (UTC-5); Monday 11 March is EDT (UTC-4)
+        assert!(!schedule.is_open(utc(2024, 3, 8, 13, 45)));
+        assert!(schedule.is_open(utc(2024, 3, 8, 14, 30)));
+        assert!(schedule.is_open(utc(2024, 3, 11, 13, 45)));
+        assert!(!schedule.is_open(utc(2024, 3, 11, 13, 29)));
+    }
+
+    #[test]
+    fn session_close_shifts_an_hour_later_in_utc_after_fall_back() {
+        let schedule = nyse_hours();
+        // Friday 1 November 2024 is EDT; Monday 4 November is EST
+        assert!(!schedule.is_open(utc(2024, 11, 1, 20, 30)));
+        assert!(schedule.is_open(utc(2024, 11, 4, 20, 30)));
+        assert!(!schedule.is_open(utc(2024, 11, 4, 21, 0)));
+    }
+
+    #[test]
+    fn session_is_closed_on_non_trading_days() {
+        let schedule = nyse_hours();
+        assert!(!schedule.is_open(utc(2024, 3, 9, 15, 0)));
+        let weekend = nyse_hours().with_trading_days(vec![Weekday::Sat]);
+        assert!(weekend.is_open(utc(2024, 3, 9, 15, 0)));
+    }
+
+    #[test]
+    fn engine_rejects_orders_outside_the_session_and_opens_on_schedule() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 13, 0));
+        engine.create_market("AAPL", 100.0);
+        engine.set_session_schedule("AAPL", Some(nyse_hours())).unwrap();
+
+        let rejected = engine.place_order(limit("AAPL", Side::Buy, 10, 99.0));
+        assert_eq!(rejected.unwrap_err(), OrderError::MarketClosed("AAPL".to_string()));
+
+        clock.set(utc(2024, 3, 11, 13, 30));
+        assert_eq!(engine.is_market_open("AAPL", engine.now()), Some(true));
+        assert!(engine.place_order(limit("AAPL", Side::Buy, 10, 99.0)).is_ok());
+    }
+
+    #[test]
+    fn seeded_mock_data_is_reproducible() {
+        let depth_after_seed = |seed: u64| {
+            let mut engine = TradingEngine::new();
+            engine.populate_with_mock_data_from(&mut StdRng::seed_from_u64(seed));
+            let mut symbols = engine.get_symbols();
+            symbols.sort();
+            symbols
+                .into_iter()
+                .map(|symbol| engine.get_depth(&symbol, usize::MAX).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(depth_after_seed(7), depth_after_seed(7));
+        assert_ne!(depth_after_seed(7), depth_after_seed(8));
+    }
+
+    #[test]
+    fn mock_data_can_be_generated_on_another_thread() {
+        let handle = std::thread::spawn(|| {
+            let mut engine = TradingEngine::new();
+            engine.populate_with_mock_data_from(&mut StdRng::seed_from_u64(1));
+            engine
+        });
+        let engine = handle.join().unwrap();
+        assert_eq!(engine.get_symbols().len(), 5);
+    }
+
+    // Two venues for AAPL: ARCA offers at 100, BATS at 101
+    fn two_venues() -> VenueGroup {
+        let mut group = VenueGroup::new("AAPL");
+        group.add_venue("ARCA", 100.0);
+        group.add_venue("BATS", 100.0);
+        group.place_order("ARCA", limit("AAPL", Side::Sell, 10, 100.0)).unwrap();
+        group.place_order("BATS", limit("AAPL", Side::Sell, 10, 101.0)).unwrap();
+        group
+    }
+
+    #[test]
+    fn venue_order_that_would_trade_through_is_rejected() {
+        let mut group = two_venues();
+        let result = group.place_order("BATS", limit("AAPL", Side::Buy, 5, 101.0));
+        assert_eq!(
+            result.unwrap_err(),
+            OrderError::TradeThrough {
+                venue: "ARCA".to_string(),
+                price: 100.0
+            }
+        );
+        assert_eq!(group.venue("BATS").unwrap().best_ask(), Some(101.0));
+    }
+
+    #[test]
+    fn routed_order_goes_to_the_venue_with_the_better_price() {
+        let mut group = two_venues();
+        let (venue, trades) = group.route_order(limit("AAPL", Side::Buy, 5, 101.0)).unwrap();
+        assert_eq!(venue, "ARCA");
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].price, 100.0);
+    }
+
+    #[test]
+    fn venue_orders_go_through_the_venue_engine() {
+        let mut group = two_venues();
+        let fractional = Order::new(
+            "AAPL".to_string(),
+            Side::Buy,
+            OrderType::Limit,
+            Quantity::from_raw(Quantity::SCALE / 2),
+            Some(100.0),
+        );
+        assert!(matches!(
+            group.place_order("ARCA", fractional),
+            Err(OrderError::InvalidQuantity { .. })
+        ));
+
+        let buy = limit("AAPL", Side::Buy, 4, 100.0).with_account("alice");
+        group.place_order("ARCA", buy).unwrap();
+        let arca = group.venue_engine("ARCA").unwrap();
+        assert_eq!(arca.get_position("alice", "AAPL"), qty(4));
+        assert_eq!(arca.get_cash_balance("alice"), -400.0);
+        assert_eq!(arca.audit_log().last().unwrap().action, AuditAction::PlaceOrder);
+    }
+
+    #[test]
+    fn microprice_leans_towards_the_thinner_side() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 20, 99.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 10, 99.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 10, 101.0)).unwrap();
+
+        assert_eq!(engine.get_mid("AAPL"), Some(100.0));
+        // (99 * 10 + 101 * 30) / 40: thin asks pull the fair value up
+        assert_eq!(engine.get_weighted_mid("AAPL"), Some(100.5));
+    }
+
+    #[test]
+    fn mid_is_none_while_a_side_is_empty() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 10, 99.0));
+        assert_eq!(book.mid(), None);
+        assert_eq!(book.weighted_mid(), None);
+    }
+
+    #[test]
+    fn taker_skips_a_maker_that_always_rejects_on_last_look() {
+        let mut book = OrderBook::new("EURUSD".to_string(), 1.1);
+        let picky = limit("EURUSD", Side::Sell, 10, 1.1).with_last_look(1.0);
+        let picky_id = picky.id.clone();
+        book.add_order(picky);
+        let plain = limit("EURUSD", Side::Sell, 10, 1.1);
+        let plain_id = plain.id.clone();
+        book.add_order(plain);
+
+        let trades = book.add_order(limit("EURUSD", Side::Buy, 5, 1.1));
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].seller_order_id, plain_id);
+        assert_eq!(book.asks.front().unwrap().id, picky_id);
+        assert_eq!(book.asks.front().unwrap().quantity, qty(10));
+    }
+
+    // Declines the first `remaining` fills offered, then accepts everything
+    #[derive(Debug)]
+    struct RejectFirst {
+        remaining: usize,
+    }
+
+    impl LastLook for RejectFirst {
+        fn rejects(&mut self, _maker: &Order, _reject_prob: f64) -> bool {
+            let reject = self.remaining > 0;
+            self.remaining = self.remaining.saturating_sub(1);
+            reject
+        }
+    }
+
+    #[test]
+    fn injected_last_look_decides_instead_of_the_probability() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("EURUSD", 1.1);
+        engine.set_last_look("EURUSD", Box::new(RejectFirst { remaining: 1 })).unwrap();
+        let maker = limit("EURUSD", Side::Sell, 10, 1.1).with_last_look(0.0);
+        engine.place_order(maker).unwrap();
+
+        let first = engine.place_order(limit("EURUSD", Side::Buy, 5, 1.1).immediate_or_cancel());
+        assert!(first.unwrap().is_empty());
+        let second = engine.place_order(limit("EURUSD", Side::Buy, 5, 1.1));
+        assert_eq!(second.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn seeded_last_look_replays_the_same_fills() {
+        let fills = |seed: u64| {
+            let mut book = OrderBook::new("EURUSD".to_string(), 1.1);
+            book.set_last_look(Box::new(RandomLastLook::new(seed)));
+            for _ in 0..20 {
+                book.add_order(limit("EURUSD", Side::Sell, 1, 1.1).with_last_look(0.5));
+            }
+            book.add_order(limit("EURUSD", Side::Buy, 20, 1.1).immediate_or_cancel());
+            // Sequences of the makers that declined
+            book.asks.iter().map(|o| o.sequence).collect::<Vec<_>>()
+        };
+        assert_eq!(fills(42), fills(42));
+    }
+
+    // The VecDeque layout the order arena replaced, kept as the reference
+    // the arena must match fill for fill and as the benchmark baseline
+    struct DequeSide {
+        side: Side,
+        orders: VecDeque<Order>,
+    }
+
+    impl DequeSide {
+        fn insert(&mut self, order: Order, priority_model: PriorityModel) {
+            let price = order.price.unwrap();
+            let idx = self
+                .orders
+                .iter()
+                .position(|resting| {
+                    let resting_price = resting.price.unwrap();
+                    let better = match self.side {
+                        Side::Buy => resting_price > price,
+                        Side::Sell => resting_price < price,
+                    };
+                    let queued_ahead =
+                        resting_price == price && !priority_model.ranks_ahead(&order, resting);
+                    !better && !queued_ahead
+                })
+                .unwrap_or(self.orders.len());
+            self.orders.insert(idx, order);
+        }
+
+        fn cancel(&mut self, order_id: &str) -> Option<Order> {
+            let idx = self.orders.iter().position(|o| o.id == order_id)?;
+            self.orders.remove(idx)
+        }
+    }
+
+    type Fill = (String, String, Quantity, f64);
+
+    struct DequeBook {
+        bids: DequeSide,
+        asks: DequeSide,
+        priority_model: PriorityModel,
+        allocation: Arc<dyn AllocationPolicy>,
+    }
+
+    impl DequeBook {
+        fn new(priority_model: PriorityModel, algorithm: MatchingAlgorithm) -> Self {
+            DequeBook {
+                bids: DequeSide { side: Side::Buy, orders: VecDeque::new() },
+                asks: DequeSide { side: Side::Sell, orders: VecDeque::new() },
+                priority_model,
+                allocation: algorithm.policy(),
+            }
+        }
+
+        // Continuous matching as the VecDeque book did it
+        fn add(&mut self, mut order: Order) -> Vec<Fill> {
+            let mut fills = Vec::new();
+            let mut start = 0;
+            while order.quantity > Quantity::ZERO {
+                let resting = match order.side {
+                    Side::Buy => &mut self.asks.orders,
+                    Side::Sell => &mut self.bids.orders,
+                };
+                let Some(level_price) = resting.get(start).and_then(|o| o.price) else {
+                    break;
+                };
+                if !OrderBook::crosses(&order, level_price, None) {
+                    break;
+                }
+                let level_len = resting
+                    .iter()
+                    .skip(start)
+                    .take_while(|o| o.price == Some(level_price))
+                    .count();
+                let level: Vec<&Order> = resting.range(start..start + level_len).collect();
+                for (order_id, quantity) in self.allocation.allocate(&level, order.quantity) {
+                    let level_range = start..start + level_len;
+                    let i = level_range.into_iter().find(|&i| resting[i].id == order_id).unwrap();
+                    let (buyer, seller) = match order.side {
+                        Side::Buy => (order.id.clone(), order_id),
+                        Side::Sell => (order_id, order.id.clone()),
+                    };
+                    fills.push((buyer, seller, quantity, level_price));
+                    resting[i].quantity -= quantity;
+                    order.quantity -= quantity;
+                }
+                let mut removed = 0;
+                for i in (start..start + level_len).rev() {
+                    if resting[i].quantity.is_zero() {
+                        resting.remove(i);
+                        removed += 1;
+                    }
+                }
+                start += level_len - removed;
+            }
+            if order.quantity > Quantity::ZERO && order.rests() {
+                match order.side {
+                    Side::Buy => self.bids.insert(order, self.priority_model),
+                    Side::Sell => self.asks.insert(order, self.priority_model),
+                }
+            }
+            fills
+        }
+
+        fn cancel(&mut self, order_id: &str) -> Option<Order> {
+            self.bids.cancel(order_id).or_else(|| self.asks.cancel(order_id))
+        }
+
+        fn reduce(&mut self, order_id: &str, reduce_by: Quantity) -> Option<Quantity> {
+            let orders = self.bids.orders.iter_mut().chain(self.asks.orders.iter_mut());
+            let order = orders.into_iter().find(|o| o.id == order_id)?;
+            if reduce_by >= order.quantity {
+                self.cancel(order_id);
+                return Some(Quantity::ZERO);
+            }
+            // Pro-rata fills can leave a fraction, which only a full cancel clears
+            let remaining = order.quantity - reduce_by;
+            if !remaining.is_multiple_of(Quantity::from_units(1)) {
+                return None;
+            }
+            order.quantity = remaining;
+            Some(remaining)
+        }
+    }
+
+    fn side_contents<'a>(orders: impl Iterator<Item = &'a Order>) -> Vec<(String, Quantity)> {
+        orders.map(|o| (o.id.clone(), o.quantity)).collect()
+    }
+
+    // Random adds, market sweeps, cancels and reductions against both
+    // layouts, comparing every fill and both queues after each step
+    fn assert_arena_matches_deque(
+        priority_model: PriorityModel,
+        algorithm: MatchingAlgorithm,
+        seed: u64,
+    ) {
+        let config = BookConfig::default()
+            .with_priority_model(priority_model)
+            .with_matching_algorithm(algorithm);
+        let mut book = OrderBook::with_config("SIM".to_string(), 100.0, config);
+        let mut reference = DequeBook::new(priority_model, algorithm);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut ids: Vec<String> = Vec::new();
+
+        for step in 0..3000 {
+            let roll = rng.gen_range(0..10);
+            if roll < 3 && !ids.is_empty() {
+                let id = ids.swap_remove(rng.gen_range(0..ids.len()));
+                let cancelled = book.cancel_order(&id).map(|o| o.id);
+                assert_eq!(cancelled, reference.cancel(&id).map(|o| o.id), "step {}", step);
+            } else if roll == 3 && !ids.is_empty() {
+                let id = ids[rng.gen_range(0..ids.len())].clone();
+                let reduce_by = qty(rng.gen_range(1..6));
+                let expected = reference.reduce(&id, reduce_by);
+                assert_eq!(book.reduce_order(&id, reduce_by).ok(), expected, "step {}", step);
+            } else {
+                let side = if rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+                let quantity = qty(rng.gen_range(1..20));
+                let mut order = if roll == 9 {
+                    Order::new("SIM".to_string(), side, OrderType::Market, quantity, None)
+                } else {
+                    let price = 97.5 + rng.gen_range(0..10) as f64 * 0.5;
+                    Order::new("SIM".to_string(), side, OrderType::Limit, quantity, Some(price))
+                };
+                if rng.gen_bool(0.2) {
+                    order = order.hidden();
+                }
+                ids.push(order.id.clone());
+                let expected = reference.add(order.clone());
+                let fills: Vec<Fill> = book
+                    .add_order(order)
+                    .into_iter()
+                    .map(|t| (t.buyer_order_id, t.seller_order_id, t.quantity, t.price))
+                    .collect();
+                assert_eq!(fills, expected, "step {}", step);
+            }
+
+            assert_eq!(
+                side_contents(book.bids.iter()),
+                side_contents(reference.bids.orders.iter()),
+                "bids after step {}",
+                step
+            );
+            assert_eq!(
+                side_contents(book.asks.iter()),
+                side_contents(reference.asks.orders.iter()),
+                "asks after step {}",
+                step
+            );
+            book.check_invariants().unwrap();
+        }
+    }
+
+    #[test]
+    fn order_arena_matches_the_vecdeque_book_under_time_priority() {
+        for seed in 0..4 {
+            assert_arena_matches_deque(PriorityModel::TimeOnly, MatchingAlgorithm::Fifo, seed);
+        }
+    }
+
+    #[test]
+    fn order_arena_matches_the_vecdeque_book_under_other_priority_models() {
+        for seed in 0..2 {
+            for model in [PriorityModel::DisplayedBeforeHidden, PriorityModel::SizePriority] {
+                assert_arena_matches_deque(model, MatchingAlgorithm::Fifo, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn order_arena_matches_the_vecdeque_book_under_pro_rata() {
+        for seed in 0..2 {
+            assert_arena_matches_deque(PriorityModel::TimeOnly, MatchingAlgorithm::ProRata, seed);
+        }
+    }
+
+    #[test]
+    fn order_arena_reuses_freed_slots_and_drops_empty_levels() {
+        let mut side = OrderSide::new(Side::Sell);
+        let first = limit("SIM", Side::Sell, 5, 101.0);
+        let second = limit("SIM", Side::Sell, 5, 100.0);
+        let (first_id, second_id) = (first.id.clone(), second.id.clone());
+        side.insert(first, PriorityModel::TimeOnly);
+        side.insert(second, PriorityModel::TimeOnly);
+        assert_eq!(side.best_price(), Some(100.0));
+
+        assert_eq!(side.fill_front(qty(5)).map(|o| o.id), Some(second_id));
+        assert_eq!(side.best_price(), Some(101.0));
+        assert_eq!(side.next_level(Some(100.0)), Some(101.0));
+        assert!(side.level_slots(100.0).is_empty());
+
+        side.insert(limit("SIM", Side::Sell, 3, 99.0), PriorityModel::TimeOnly);
+        assert_eq!(side.slots.len(), 2);
+        assert_eq!(side.remove(&first_id).map(|o| o.quantity), Some(qty(5)));
+        assert_eq!(side.len(), 1);
+        assert_eq!(side.pop_back().map(|o| o.quantity), Some(qty(3)));
+        assert!(side.levels.is_empty() && side.index.is_empty());
+    }
+
+    // Deep book built and then cancelled in random order, arena against the
+    // VecDeque baseline. Run with
+    // `cargo test --release -- --ignored --nocapture order_side_throughput`.
+    #[test]
+    #[ignore]
+    fn order_side_throughput_against_vecdeque() {
+        const ORDERS: usize = 20_000;
+        let orders: Vec<Order> = (0..ORDERS)
+            .map(|i| limit("SIM", Side::Buy, 1 + (i % 7) as i64, 90.0 + (i % 200) as f64 * 0.05))
+            .collect();
+        let mut cancel_order: Vec<usize> = (0..ORDERS).collect();
+        cancel_order.shuffle(&mut StdRng::seed_from_u64(7));
+
+        let started = Instant::now();
+        let mut arena = OrderSide::new(Side::Buy);
+        for order in &orders {
+            arena.insert(order.clone(), PriorityModel::TimeOnly);
+        }
+        for &i in &cancel_order {
+            arena.remove(&orders[i].id);
+        }
+        let arena_time = started.elapsed();
+
+        let started = Instant::now();
+        let mut deque = DequeSide { side: Side::Buy, orders: VecDeque::new() };
+        for order in &orders {
+            deque.insert(order.clone(), PriorityModel::TimeOnly);
+        }
+        for &i in &cancel_order {
+            deque.cancel(&orders[i].id);
+        }
+        let deque_time = started.elapsed();
+
+        println!(
+            "{} inserts and cancels: arena {:?}, VecDeque {:?} ({:.1}x)",
+            ORDERS,
+            arena_time,
+            deque_time,
+            deque_time.as_secs_f64() / arena_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn disconnect_cancels_the_connections_resting_orders_and_empties_the_book() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order_for_connection(7, limit("AAPL", Side::Buy, 10, 99.0)).unwrap();
+        engine.place_order_for_connection(7, limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+        let stop = Order::new_stop("AAPL".to_string(), Side::Sell, qty(5), 95.0);
+        engine.place_order_for_connection(7, stop).unwrap();
+        engine.place_order_for_connection(8, limit("AAPL", Side::Buy, 3, 98.0)).unwrap();
+
+        let cancelled = engine.disconnect(7);
+        assert_eq!(cancelled.len(), 3);
+        let book = &engine.order_books["AAPL"];
+        let (bids, asks) = book.get_orders();
+        assert_eq!(bids.len(), 1, "only the other connection's bid is left");
+        assert!(asks.is_empty() && book.get_pending_stops().is_empty());
+        assert!(engine.disconnect(7).is_empty());
+    }
+
+    #[test]
+    fn connection_forgets_orders_once_they_fill_or_are_cancelled() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+
+        // Filled on arrival: never tracked
+        engine.place_order_for_connection(7, limit("AAPL", Side::Buy, 5, 101.0)).unwrap();
+        assert!(!engine.connection_orders.contains_key(&7));
+
+        let filled_later = limit("AAPL", Side::Sell, 4, 102.0);
+        let cancelled = limit("AAPL", Side::Buy, 4, 98.0);
+        let kept = limit("AAPL", Side::Buy, 4, 97.0);
+        let (filled_id, cancelled_id, kept_id) =
+            (filled_later.id.clone(), cancelled.id.clone(), kept.id.clone());
+        for order in [filled_later, cancelled, kept] {
+            engine.place_order_for_connection(7, order).unwrap();
+        }
+        assert_eq!(engine.connection_orders[&7].len(), 3);
+
+        engine.place_order(limit("AAPL", Side::Buy, 4, 102.0)).unwrap();
+        engine.cancel_order("AAPL", &cancelled_id).unwrap();
+        let tracked: Vec<&String> = engine.connection_orders[&7].iter().map(|(_, id)| id).collect();
+        assert_eq!(tracked, vec![&kept_id]);
+        assert!(!engine.order_connections.contains_key(&filled_id));
+
+        assert_eq!(engine.disconnect(7).into_iter().map(|o| o.id).collect::<Vec<_>>(), [kept_id]);
+        assert!(engine.order_connections.is_empty());
+    }
+
+    #[test]
+    fn order_to_trade_ratio_counts_placements_and_cancels_per_trade() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        assert_eq!(engine.order_to_trade_ratio("churn"), 0.0);
+
+        // Ten quotes placed and pulled without a trade: twenty messages
+        for _ in 0..10 {
+            let order = limit("AAPL", Side::Buy, 1, 95.0).with_account("churn");
+            let order_id = order.id.clone();
+            engine.place_order(order).unwrap();
+            engine.cancel_order("AAPL", &order_id).unwrap();
+        }
+        assert_eq!(engine.order_to_trade_ratio("churn"), 20.0);
+
+        engine.place_order(limit("AAPL", Side::Sell, 1, 101.0).with_account("maker")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 1, 101.0).with_account("churn")).unwrap();
+        assert_eq!(engine.order_to_trade_ratio("churn"), 21.0);
+        assert_eq!(engine.order_to_trade_ratio("maker"), 1.0);
+    }
+
+    #[test]
+    fn accounts_over_the_order_to_trade_limit_are_throttled() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_order_to_trade_limit(Some(OrderToTradeLimit::new(5.0, 8)));
+
+        // Below the message floor the ratio is not enforced
+        for _ in 0..7 {
+            engine.place_order(limit("AAPL", Side::Buy, 1, 95.0).with_account("churn")).unwrap();
+        }
+        engine.place_order(limit("AAPL", Side::Buy, 1, 95.0).with_account("churn")).unwrap();
+
+        let rejected = engine.place_order(limit("AAPL", Side::Buy, 1, 95.0).with_account("churn"));
+        assert_eq!(
+            rejected.unwrap_err(),
+            OrderError::Throttled { account: "churn".to_string(), ratio: 8.0, max_ratio: 5.0 }
+        );
+        // Other accounts are unaffected
+        assert!(engine.place_order(limit("AAPL", Side::Buy, 1, 95.0).with_account("calm")).is_ok());
+    }
+
+    fn borrow_pool(engine: &TradingEngine, symbol: &str) -> Option<Quantity> {
+        engine.order_books[symbol].borrow_available
+    }
+
+    #[test]
+    fn short_sale_locates_borrow_and_covering_returns_it() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(qty(100))).unwrap();
+
+        engine.place_order(limit("AAPL", Side::Buy, 30, 100.0).with_account("B")).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 30, 100.0).with_account("S")).unwrap();
+        assert_eq!(engine.get_position("S", "AAPL"), qty(-30));
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(70)));
+
+        // B is long, so its sell needs no locate; S buying it back covers
+        engine.place_order(limit("AAPL", Side::Sell, 30, 101.0).with_account("B")).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(70)));
+        engine.place_order(limit("AAPL", Side::Buy, 30, 101.0).with_account("S")).unwrap();
+        assert_eq!(engine.get_position("S", "AAPL"), Quantity::ZERO);
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(100)));
+    }
+
+    #[test]
+    fn short_sale_beyond_the_borrow_pool_is_rejected() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(qty(10))).unwrap();
+
+        let rejected = engine.place_order(limit("AAPL", Side::Sell, 20, 100.0).with_account("S"));
+        assert_eq!(
+            rejected.unwrap_err(),
+            OrderError::NoBorrowAvailable {
+                symbol: "AAPL".to_string(),
+                requested: qty(20),
+                available: qty(10),
+            }
+        );
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(10)));
+    }
+
+    #[test]
+    fn unsold_borrow_returns_when_the_order_is_reduced_or_cancelled() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(qty(100))).unwrap();
+
+        let sell = limit("AAPL", Side::Sell, 40, 105.0).with_account("S");
+        let sell_id = sell.id.clone();
+        engine.place_order(sell).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(60)));
+
+        engine.reduce_order("AAPL", &sell_id, qty(10)).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(70)));
+        engine.cancel_order("AAPL", &sell_id).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(100)));
+
+        engine.place_order(limit("AAPL", Side::Sell, 25, 105.0).with_account("S")).unwrap();
+        engine.cancel_all("AAPL").unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(100)));
+        assert!(engine.borrow_reserved.is_empty());
+    }
+
+    #[test]
+    fn long_position_promised_to_working_sells_is_not_counted_again() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(qty(100))).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 50, 100.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 50, 100.0).with_account("S")).unwrap();
+        assert_eq!(engine.get_position("S", "AAPL"), qty(50));
+
+        // The first sell uses the whole long position; the second is a short sale
+        engine.place_order(limit("AAPL", Side::Sell, 50, 105.0).with_account("S")).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(100)));
+        engine.place_order(limit("AAPL", Side::Sell, 20, 106.0).with_account("S")).unwrap();
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(80)));
+    }
+
+    #[test]
+    fn cancel_level_pulls_only_the_orders_at_that_price() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for (quantity, price) in [(10, 99.0), (20, 99.0), (5, 98.0)] {
+            book.add_order(limit("AAPL", Side::Buy, quantity, price));
+        }
+        book.add_order(limit("AAPL", Side::Sell, 15, 101.0));
+
+        let cancelled = book.cancel_level(Side::Buy, 99.0);
+        let mut quantities: Vec<Quantity> = cancelled.iter().map(|o| o.quantity).collect();
+        quantities.sort();
+        assert_eq!(quantities, vec![qty(10), qty(20)]);
+
+        let (bids, asks) = book.depth(5);
+        assert_eq!(bids, vec![(98.0, qty(5))]);
+        assert_eq!(asks, vec![(101.0, qty(15))]);
+        assert_eq!(book.get_market_data().bid, 98.0);
+        assert!(book.cancel_level(Side::Buy, 97.0).is_empty());
+    }
+
+    // Engine where account "L" is long and "S" short 10 AAPL, traded at 100
+    fn long_and_short_at_100() -> TradingEngine {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 100.0).with_account("S")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 10, 100.0).with_account("L")).unwrap();
+        engine
+    }
+
+    #[test]
+    fn positive_funding_has_longs_pay_shorts() {
+        let mut engine = long_and_short_at_100();
+        let (long_cash, short_cash) = (engine.get_cash_balance("L"), engine.get_cash_balance("S"));
+        let mark = engine.get_mark_price("AAPL").unwrap();
+
+        engine.apply_funding("AAPL", 0.001).unwrap();
+        let payment = 10.0 * mark * 0.001;
+        assert!((engine.get_cash_balance("L") - (long_cash - payment)).abs() < 1e-9);
+        assert!((engine.get_cash_balance("S") - (short_cash + payment)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn negative_funding_has_shorts_pay_longs() {
+        let mut engine = long_and_short_at_100();
+        let (long_cash, short_cash) = (engine.get_cash_balance("L"), engine.get_cash_balance("S"));
+        let mark = engine.get_mark_price("AAPL").unwrap();
+
+        engine.apply_funding("AAPL", -0.002).unwrap();
+        let payment = 10.0 * mark * 0.002;
+        assert!((engine.get_cash_balance("L") - (long_cash + payment)).abs() < 1e-9);
+        assert!((engine.get_cash_balance("S") - (short_cash - payment)).abs() < 1e-9);
+        assert!(engine.apply_funding("MSFT", 0.001).is_err());
+    }
+
+    #[test]
+    fn routed_sell_goes_to_the_best_bid_and_unmatched_orders_to_the_primary() {
+        let mut group = two_venues();
+        group.place_order("ARCA", limit("AAPL", Side::Buy, 10, 98.0)).unwrap();
+        group.place_order("BATS", limit("AAPL", Side::Buy, 10, 99.0)).unwrap();
+
+        let (venue, trades) = group.route_order(limit("AAPL", Side::Sell, 4, 98.0)).unwrap();
+        assert_eq!(venue, "BATS");
+        assert_eq!(trades[0].price, 99.0);
+
+        let mut empty = VenueGroup::new("AAPL");
+        empty.add_venue("ARCA", 100.0);
+        empty.add_venue("BATS", 100.0);
+        let (venue, trades) = empty.route_order(limit("AAPL", Side::Buy, 5, 99.0)).unwrap();
+        assert_eq!(venue, "ARCA");
+        assert!(trades.is_empty());
+        assert_eq!(empty.venue("ARCA").unwrap().best_bid(), Some(99.0));
+    }
+
+    #[test]
+    fn top_of_book_agrees_with_market_data_through_matches_and_cancels() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let agrees = |book: &OrderBook| {
+            let data = book.get_market_data();
+            assert_eq!(book.top_of_book(), (Some(data.bid), Some(data.ask), Some(data.last_price)));
+        };
+
+        book.add_order(limit("AAPL", Side::Buy, 10, 99.0));
+        let inside_bid = limit("AAPL", Side::Buy, 5, 99.5);
+        let inside_bid_id = inside_bid.id.clone();
+        book.add_order(inside_bid);
+        book.add_order(limit("AAPL", Side::Sell, 10, 101.0));
+        agrees(&book);
+
+        book.add_order(limit("AAPL", Side::Sell, 3, 99.5));
+        assert_eq!(book.top_of_book().2, Some(99.5));
+        agrees(&book);
+
+        book.cancel_order(&inside_bid_id);
+        assert_eq!(book.top_of_book().0, Some(99.0));
+        agrees(&book);
+
+        book.add_order(limit("AAPL", Side::Buy, 4, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 2, 100.5));
+        assert_eq!(book.top_of_book().1, Some(100.5));
+        agrees(&book);
+    }
+
+    fn market_on_close(side: Side, quantity: i64) -> Order {
+        Order::new("AAPL".to_string(), side, OrderType::MarketOnClose, qty(quantity), None)
+    }
+
+    #[test]
+    fn closing_auction_crosses_moc_orders_and_sweeps_the_imbalance_at_one_price() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 10, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 15, 102.0));
+        assert!(book.add_order(market_on_close(Side::Buy, 30)).is_empty());
+        assert!(book.add_order(market_on_close(Side::Sell, 10)).is_empty());
+
+        // The 20 lot imbalance reaches the 102 level, so everything prints there
+        let trades = book.close();
+        let quantities: Vec<Quantity> = trades.iter().map(|t| t.quantity).collect();
+        assert_eq!(quantities, vec![qty(10), qty(10), qty(10)]);
+        assert!(trades.iter().all(|t| t.price == 102.0));
+        assert_eq!(book.closing_price(), Some(102.0));
+        assert_eq!(book.get_market_data().last_price, 102.0);
+        assert_eq!(book.depth(5).1, vec![(102.0, qty(5))]);
+    }
+
+    #[test]
+    fn closing_auction_without_limit_liquidity_cancels_the_imbalance_at_the_last_price() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(market_on_close(Side::Sell, 8));
+        book.add_order(market_on_close(Side::Buy, 5));
+
+        let trades = book.close();
+        assert_eq!(trades.len(), 1);
+        assert_eq!((trades[0].quantity, trades[0].price), (qty(5), 100.0));
+        assert_eq!(book.closing_price(), Some(100.0));
+        assert!(book.close().is_empty());
+    }
+
+    #[test]
+    fn stops_triggered_together_activate_by_stop_price_then_sequence() {
+        let run = || {
+            let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+            for price in [101.0, 102.0, 103.0] {
+                book.add_order(limit("AAPL", Side::Sell, 10, price));
+            }
+            book.add_order(limit("AAPL", Side::Sell, 100, 110.0));
+
+            let mut stop_ids = Vec::new();
+            for (quantity, stop_price) in [(1, 103.0), (2, 101.0), (3, 102.0), (4, 102.0)] {
+                let stop =
+                    Order::new_stop("AAPL".to_string(), Side::Buy, qty(quantity), stop_price);
+                stop_ids.push(stop.id.clone());
+                assert!(book.add_order(stop).is_empty());
+            }
+
+            let sweep =
+                Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(30), None);
+            let trades = book.add_order(sweep);
+            let activated: Vec<usize> = trades[3..]
+                .iter()
+                .map(|t| stop_ids.iter().position(|id| *id == t.buyer_order_id).unwrap())
+                .collect();
+            let prints: Vec<(f64, Quantity)> =
+                trades.iter().map(|t| (t.price, t.quantity)).collect();
+            (activated, prints)
+        };
+
+        let (activated, prints) = run();
+        // 101 first, then the two 102 stops in the order they were accepted, then 103
+        assert_eq!(activated, vec![1, 2, 3, 0]);
+        assert_eq!(prints[..3], [(101.0, qty(10)), (102.0, qty(10)), (103.0, qty(10))]);
+        assert_eq!(run(), (activated, prints));
+    }
+
+    #[test]
+    fn taker_moves_to_the_better_tier_once_trailing_volume_crosses_it() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 10.0);
+        let schedule = VolumeTierSchedule::new(vec![
+            VolumeTier::new(2_000.0, 0.001, 0.0015),
+            VolumeTier::new(0.0, 0.002, 0.003),
+        ])
+        .with_lookback_periods(2);
+        engine.set_fee_schedule(Some(schedule));
+        engine.place_order(limit("AAPL", Side::Sell, 1_000, 10.0).with_account("M")).unwrap();
+
+        let mut fees = Vec::new();
+        let mut buy_100 = |engine: &mut TradingEngine| {
+            let before = engine.get_fees_paid("T");
+            engine.place_order(limit("AAPL", Side::Buy, 100, 10.0).with_account("T")).unwrap();
+            fees.push(engine.get_fees_paid("T") - before);
+        };
+        buy_100(&mut engine);
+        buy_100(&mut engine);
+        buy_100(&mut engine);
+        // Volume outside the lookback no longer counts towards the tier
+        engine.roll_fee_period();
+        buy_100(&mut engine);
+        engine.roll_fee_period();
+        engine.roll_fee_period();
+        buy_100(&mut engine);
+
+        let expected = [3.0, 3.0, 1.5, 1.5, 3.0];
+        for (fee, expected) in fees.iter().zip(expected) {
+            assert!((fee - expected).abs() < 1e-9, "{:?}", fees);
+        }
+        assert_eq!(engine.trailing_volume("T"), 1_000.0);
+    }
+
+    #[test]
+    fn quantity_to_reach_price_sums_the_levels_better_than_the_target() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for (quantity, price) in [(10, 101.0), (20, 102.0), (5, 102.0), (30, 103.0)] {
+            book.add_order(limit("AAPL", Side::Sell, quantity, price));
+        }
+        for (quantity, price) in [(8, 99.0), (12, 98.0)] {
+            book.add_order(limit("AAPL", Side::Buy, quantity, price));
+        }
+
+        assert_eq!(book.quantity_to_reach_price(Side::Buy, 100.0), Quantity::ZERO);
+        assert_eq!(book.quantity_to_reach_price(Side::Buy, 102.0), qty(10));
+        assert_eq!(book.quantity_to_reach_price(Side::Buy, 103.0), qty(35));
+        assert_eq!(book.quantity_to_reach_price(Side::Buy, 110.0), qty(65));
+        assert_eq!(book.quantity_to_reach_price(Side::Sell, 98.0), qty(8));
+        assert_eq!(book.quantity_to_reach_price(Side::Sell, 90.0), qty(20));
+    }
+
+    #[test]
+    fn open_orders_lists_an_accounts_working_orders_across_symbols() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 300.0);
+
+        let keep = limit("MSFT", Side::Sell, 5, 310.0).with_account("alice");
+        let cancel = limit("AAPL", Side::Buy, 10, 95.0).with_account("alice");
+        let (keep_id, cancel_id) = (keep.id.clone(), cancel.id.clone());
+        engine.place_order(keep).unwrap();
+        engine.place_order(cancel).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 3, 96.0).with_account("alice")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 7, 97.0).with_account("bob")).unwrap();
+        engine.cancel_order("AAPL", &cancel_id).unwrap();
+
+        let open = engine.open_orders("alice");
+        let summary: Vec<(&str, Quantity)> =
+            open.iter().map(|(symbol, order)| (symbol.as_str(), order.quantity)).collect();
+        assert_eq!(summary, vec![("AAPL", qty(3)), ("MSFT", qty(5))]);
+        assert_eq!(open[1].1.id, keep_id);
+        assert!(engine.open_orders("carol").is_empty());
+    }
+
+    #[test]
+    fn market_sweep_returns_trades_best_price_first_in_recorded_order() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for (quantity, price) in [(5, 103.0), (5, 101.0), (5, 102.0), (5, 101.0)] {
+            book.add_order(limit("AAPL", Side::Sell, quantity, price));
+        }
+        for (quantity, price) in [(5, 97.0), (5, 99.0), (5, 98.0)] {
+            book.add_order(limit("AAPL", Side::Buy, quantity, price));
+        }
+
+        let buy = Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(18), None);
+        let trades = book.add_order(buy);
+        let prices: Vec<f64> = trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![101.0, 101.0, 102.0, 103.0]);
+
+        let sell = Order::new("AAPL".to_string(), Side::Sell, OrderType::Market, qty(12), None);
+        let trades = book.add_order(sell);
+        let prices: Vec<f64> = trades.iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![99.0, 98.0, 97.0]);
+
+        let recorded: Vec<f64> = book.get_trades().iter().map(|t| t.price).collect();
+        assert_eq!(recorded, vec![101.0, 101.0, 102.0, 103.0, 99.0, 98.0, 97.0]);
+    }
+
+    fn assert_audit_chain(entries: &[AuditEntry]) {
+        for (i, pair) in entries.windows(2).enumerate() {
+            assert_eq!(pair[1].audit_id, pair[0].audit_id + 1);
+            assert_eq!(pair[0].after_hash, pair[1].before_hash, "break after entry {}", i + 1);
+        }
+    }
+
+    #[test]
+    fn audit_entries_chain_their_state_hashes() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 300.0);
+
+        let resting = limit("AAPL", Side::Sell, 10, 101.0).with_account("S");
+        let resting_id = resting.id.clone();
+        engine.place_order(resting).unwrap();
+        engine.place_order(limit("MSFT", Side::Buy, 5, 299.0).with_account("B")).unwrap();
+        let trades = engine.place_order(limit("AAPL", Side::Buy, 4, 101.0).with_account("B"));
+        engine.reduce_order("AAPL", &resting_id, qty(2)).unwrap();
+        engine.bust_trade("AAPL", &trades.unwrap()[0].id).unwrap();
+        engine.cancel_all("MSFT").unwrap();
+
+        // Account-level changes outside the books are chained too, including
+        // the borrow a short sale locates before it is placed
+        engine.place_order(limit("AAPL", Side::Buy, 2, 101.0).with_account("B")).unwrap();
+        engine.apply_funding("AAPL", 0.001).unwrap();
+        engine.set_borrow_available("MSFT", Some(qty(50))).unwrap();
+        engine.place_order(limit("MSFT", Side::Sell, 5, 310.0).with_account("S")).unwrap();
+        assert_eq!(engine.order_books["MSFT"].borrow_available, Some(qty(45)));
+
+        let log = engine.audit_log();
+        assert_eq!(log.len(), 12);
+        assert_eq!(log[9].action, AuditAction::Funding);
+        assert_eq!(log[10].action, AuditAction::BorrowPool);
+        assert_eq!(log[0].audit_id, 1);
+        assert!(log.iter().all(|entry| entry.before_hash != entry.after_hash));
+        assert_audit_chain(log);
+        assert_eq!(log.last().unwrap().after_hash, engine.state_hash());
+    }
+
+    #[test]
+    fn state_hash_covers_account_state_outside_the_books() {
+        let mut engine = long_and_short_at_100();
+        let before = engine.state_hash();
+        engine.apply_funding("AAPL", 0.001).unwrap();
+        assert_ne!(engine.state_hash(), before);
+
+        // Funding straight back restores every balance and so the hash
+        engine.apply_funding("AAPL", -0.001).unwrap();
+        assert_eq!(engine.state_hash(), before);
+    }
+
+    #[test]
+    fn book_digests_kept_as_orders_change_match_a_fresh_digest() {
+        let fresh = |side: &OrderSide| {
+            let mut digest = SetDigest::default();
+            side.iter().for_each(|order| digest.add(order.digest_entry()));
+            digest
+        };
+
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let mut ids = Vec::new();
+        for (side, quantity, price) in
+            [(Side::Buy, 10, 99.0), (Side::Buy, 5, 98.0), (Side::Sell, 8, 101.0)]
+        {
+            let order = limit("AAPL", side, quantity, price);
+            ids.push(order.id.clone());
+            book.add_order(order);
+        }
+        book.add_order(limit("AAPL", Side::Sell, 4, 99.0));
+        book.reduce_order(&ids[2], qty(3)).unwrap();
+        book.cancel_order(&ids[1]);
+        book.add_order(Order::new_stop("AAPL".to_string(), Side::Buy, qty(2), 105.0));
+
+        assert_eq!(book.bids.digest, fresh(&book.bids));
+        assert_eq!(book.asks.digest, fresh(&book.asks));
+        assert_ne!(book.bids.digest, SetDigest::default());
+        let checksum = book.checksum();
+        book.add_order(limit("AAPL", Side::Buy, 1, 90.0));
+        assert_ne!(book.checksum(), checksum);
+    }
+
+    #[test]
+    fn external_reference_price_triggers_a_resting_stop() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 103.0).with_account("M")).unwrap();
+        let stop = Order::new_stop("AAPL".to_string(), Side::Buy, qty(4), 102.0).with_account("T");
+        let stop_id = stop.id.clone();
+        assert!(engine.place_order(stop).unwrap().is_empty());
+
+        assert!(engine.update_reference("AAPL", 101.5).unwrap().is_empty());
+        assert_eq!(engine.get_mark_price("AAPL"), Some(101.5));
+
+        let trades = engine.update_reference("AAPL", 102.0).unwrap();
+        assert_eq!(trades.len(), 1);
+        assert_eq!(trades[0].buyer_order_id, stop_id);
+        assert_eq!((trades[0].price, trades[0].quantity), (103.0, qty(4)));
+        assert_eq!(engine.get_position("T", "AAPL"), qty(4));
+        assert_eq!(engine.audit_log().last().unwrap().action, AuditAction::ReferencePrice);
+    }
+
+    #[test]
+    fn hidden_orders_trade_but_never_set_the_quote() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 10, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 6, 99.0).hidden());
+        book.add_order(limit("AAPL", Side::Buy, 20, 99.5).hidden());
+        book.add_order(limit("AAPL", Side::Sell, 30, 101.0));
+
+        assert_eq!(book.best_bid(), Some(99.0));
+        assert_eq!(book.top_of_book().0, Some(99.0));
+        assert_eq!(book.mid(), Some(100.0));
+        let data = book.get_market_data();
+        assert_eq!((data.bid, data.bid_size), (99.0, qty(10)));
+        // Displayed sizes 10 and 30 weight the microprice
+        assert_eq!(book.weighted_mid(), Some((99.0 * 30.0 + 101.0 * 10.0) / 40.0));
+        assert!(!book.is_locked_or_crossed());
+
+        let trades = book.add_order(limit("AAPL", Side::Sell, 5, 99.5));
+        assert_eq!((trades[0].price, trades[0].quantity), (99.5, qty(5)));
+        assert_eq!(book.get_market_data().bid, 99.0);
+    }
+
+    #[test]
+    fn size_priority_keeps_a_partly_filled_order_in_place() {
+        let config = BookConfig::default().with_priority_model(PriorityModel::SizePriority);
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        let small = limit("AAPL", Side::Buy, 30, 100.0);
+        let large = limit("AAPL", Side::Buy, 50, 100.0);
+        let (small_id, large_id) = (small.id.clone(), large.id.clone());
+        book.add_order(small);
+        book.add_order(large);
+
+        // The large order ranks first on arrival and stays first once it is smaller
+        let first = book.add_order(limit("AAPL", Side::Sell, 25, 100.0));
+        assert_eq!(first[0].buyer_order_id, large_id);
+        let second = book.add_order(limit("AAPL", Side::Sell, 30, 100.0));
+        let fills: Vec<(&str, Quantity)> =
+            second.iter().map(|t| (t.buyer_order_id.as_str(), t.quantity)).collect();
+        assert_eq!(fills, vec![(large_id.as_str(), qty(25)), (small_id.as_str(), qty(5))]);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn grpc_client_places_an_order_and_streams_every_engine_trade() {
+        use grpc::proto;
+        use grpc::proto::trading_client::TradingClient;
+        use grpc::proto::trading_server::TradingServer;
+        use tokio_stream::wrappers::TcpListenerStream;
+
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 101.0)).unwrap();
+        let stop = Order::new_stop("AAPL".to_string(), Side::Buy, qty(2), 102.0);
+        let stop_id = stop.id.clone();
+        engine.place_order(stop).unwrap();
+        let engine = Arc::new(tokio::sync::Mutex::new(engine));
+        let service = grpc::TradingService::new(engine.clone()).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TradingServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let mut client = TradingClient::connect(format!("http://{}", addr)).await.unwrap();
+        let request = proto::StreamTradesRequest {
+            symbol: "AAPL".to_string(),
+        };
+        let mut trades = client.stream_trades(request).await.unwrap().into_inner();
+
+        let placed = client
+            .place_order(proto::PlaceOrderRequest {
+                symbol: "AAPL".to_string(),
+                side: proto::Side::Buy as i32,
+                order_type: proto::OrderType::Limit as i32,
+                quantity: "4".to_string(),
+                price: Some(101.0),
+                account: None,
+                immediate_or_cancel: false,
+            })
+            .await
+            .unwrap()
+            .into_inner();
+        let streamed = trades.message().await.unwrap().unwrap();
+        assert_eq!(streamed.buyer_order_id, placed.order_id);
+        assert_eq!(streamed, placed.trades[0]);
+
+        // A stop set off by a reference price trades outside any RPC
+        engine.lock().await.update_reference("AAPL", 102.0).unwrap();
+        let streamed = trades.message().await.unwrap().unwrap();
+        assert_eq!(streamed.buyer_order_id, stop_id);
+        assert_eq!((streamed.price, streamed.quantity.parse()), (101.0, Ok(qty(2))));
+    }
+
+    // Rests orders without matching them, as a matcher bug might
+    fn rest_unmatched(book: &mut OrderBook, mut order: Order) {
+        order.sequence = book.next_sequence;
+        book.next_sequence += 1;
+        book.insert_resting(order);
+    }
+
+    #[test]
+    fn crossed_book_is_repaired_by_trading_the_overlap() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        rest_unmatched(&mut book, limit("AAPL", Side::Buy, 10, 101.0));
+        rest_unmatched(&mut book, limit("AAPL", Side::Sell, 4, 100.0));
+        rest_unmatched(&mut book, limit("AAPL", Side::Sell, 8, 100.5));
+        rest_unmatched(&mut book, limit("AAPL", Side::Buy, 3, 99.0));
+        assert!(book.is_locked_or_crossed());
+        assert!(book.check_invariants().is_err());
+
+        // The bid arrived first, so both fills print at its price with the asks aggressing
+        let trades = book.uncross();
+        let fills: Vec<(f64, Quantity, Option<Side>)> =
+            trades.iter().map(|t| (t.price, t.quantity, t.aggressor)).collect();
+        assert_eq!(
+            fills,
+            vec![(101.0, qty(4), Some(Side::Sell)), (101.0, qty(6), Some(Side::Sell))]
+        );
+        assert!(!book.is_locked_or_crossed());
+        assert!(book.check_invariants().is_ok());
+        assert_eq!(book.depth(5), (vec![(99.0, qty(3))], vec![(100.5, qty(2))]));
+        assert!(book.uncross().is_empty());
+    }
+
+    #[test]
+    fn deferred_print_stays_off_the_tape_last_price_and_stats_until_published() {
+        let clock = SimClock::new(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_deferred_publication(qty(100), chrono::Duration::minutes(15));
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.set_clock(Arc::new(clock.clone()));
+
+        book.add_order(limit("AAPL", Side::Sell, 10, 100.5));
+        book.add_order(limit("AAPL", Side::Buy, 10, 100.5));
+        book.add_order(limit("AAPL", Side::Sell, 500, 102.0));
+        let block = book.add_order(limit("AAPL", Side::Buy, 500, 102.0));
+        assert_eq!(block[0].publish_time, utc(2024, 3, 11, 14, 15));
+
+        // Positions see the block at once; the public view does not
+        assert_eq!(book.get_trades().len(), 1);
+        assert_eq!(book.get_market_data().last_price, 100.5);
+        assert_eq!(book.top_of_book().2, Some(100.5));
+        let stats = book.session_stats();
+        assert_eq!((stats.trade_count(), stats.volume(), stats.high()), (1, qty(10), Some(100.5)));
+
+        // A later small print ticks against the tape, not the hidden block
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+        let small = book.add_order(limit("AAPL", Side::Buy, 5, 101.0));
+        assert_eq!(small[0].tick_direction, TickDirection::Uptick);
+
+        clock.set(utc(2024, 3, 11, 14, 15));
+        assert_eq!(book.get_trades().len(), 3);
+        assert_eq!(book.public_trades(utc(2024, 3, 11, 14, 14)).len(), 2);
+        // The block is out but older than the small print, which stays the last sale
+        assert_eq!(book.get_market_data().last_price, 101.0);
+        let stats = book.session_stats();
+        assert_eq!((stats.trade_count(), stats.volume(), stats.high()), (3, qty(515), Some(102.0)));
+    }
+
+    #[test]
+    fn contract_multiplier_scales_notional_cash_and_pnl_but_not_prices() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let futures = BookConfig::default().with_contract_multiplier(100.0);
+        engine.create_market_with_params("ES", 100.0, futures);
+
+        for symbol in ["AAPL", "ES"] {
+            engine.place_order(limit(symbol, Side::Sell, 2, 100.0).with_account("M")).unwrap();
+            engine.place_order(limit(symbol, Side::Buy, 2, 100.0).with_account("A")).unwrap();
+            engine.update_reference(symbol, 105.0).unwrap();
+        }
+
+        let books = &engine.order_books;
+        assert_eq!(books["AAPL"].traded_notional(), 200.0);
+        assert_eq!(books["ES"].traded_notional(), 20_000.0);
+        assert_eq!(books["ES"].session_stats().vwap(), Some(100.0));
+        assert_eq!(engine.get_cash_balance("A"), -200.0 - 20_000.0);
+
+        let risk = engine.portfolio_risk("A");
+        let pnl: Vec<(&str, f64)> =
+            risk.positions().iter().map(|p| (p.symbol(), p.unrealized_pnl)).collect();
+        assert_eq!(pnl, vec![("AAPL", 10.0), ("ES", 1_000.0)]);
+        assert_eq!(risk.gross_exposure(), 210.0 + 21_000.0);
+    }
+
+    #[test]
+    fn pro_rata_remainder_goes_to_the_largest_then_earliest_order() {
+        let order = |raw: i64, sequence: u64| {
+            let mut order = limit("AAPL", Side::Sell, 1, 100.0);
+            order.quantity = Quantity::from_raw(raw);
+            order.sequence = sequence;
+            order
+        };
+        let allocate = |level: &[Order], incoming: i64| -> Vec<i64> {
+            let level: Vec<&Order> = level.iter().collect();
+            let allocations = ProRataPolicy.allocate(&level, Quantity::from_raw(incoming));
+            level
+                .iter()
+                .map(|o| allocations.iter().find(|(id, _)| *id == o.id).map_or(0, |a| a.1.raw()))
+                .collect()
+        };
+
+        // 7 units over 3:5:2 split 2, 3 and 1, leaving one unit for the largest
+        let unequal = [order(3, 1), order(5, 2), order(2, 3)];
+        assert_eq!(allocate(&unequal, 7), vec![2, 4, 1]);
+
+        // Between equal sizes the earlier order wins it, whatever the level order
+        let tied = [order(2, 3), order(4, 2), order(4, 1)];
+        assert_eq!(allocate(&tied, 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn position_limit_counts_open_orders_on_the_same_side() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_position_limit("A", "AAPL", Some(qty(100)));
+
+        engine.place_order(limit("AAPL", Side::Buy, 60, 99.0).with_account("A")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 30, 98.0).with_account("A")).unwrap();
+        let rejected = engine.place_order(limit("AAPL", Side::Buy, 20, 97.0).with_account("A"));
+        assert_eq!(
+            rejected.unwrap_err(),
+            OrderError::PositionLimitExceeded {
+                account: "A".to_string(),
+                symbol: "AAPL".to_string(),
+                exposure: qty(110),
+                max_position: qty(100),
+            }
+        );
+
+        // Sells are measured from the position on their own, and other accounts are unaffected
+        engine.place_order(limit("AAPL", Side::Sell, 100, 105.0).with_account("A")).unwrap();
+        assert!(engine.place_order(limit("AAPL", Side::Sell, 1, 106.0).with_account("A")).is_err());
+        assert!(engine.place_order(limit("AAPL", Side::Buy, 500, 97.0).with_account("B")).is_ok());
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn arrow_batches_hold_the_resting_orders_and_trades() {
+        use arrow_array::{Array, Decimal128Array, Float64Array, StringArray};
+
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 10, 99.0).with_account("A"));
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 7, 100.5).hidden());
+        book.add_order(limit("AAPL", Side::Buy, 2, 100.5));
+
+        let orders = book.to_arrow_batch().unwrap();
+        assert_eq!(orders.num_rows(), 3);
+        let column = |name: &str| orders.column_by_name(name).unwrap().clone();
+        let prices = column("price");
+        let prices = prices.as_any().downcast_ref::<Float64Array>().unwrap();
+        assert_eq!(prices.values().to_vec(), vec![99.0, 100.5, 101.0]);
+        let accounts = column("account");
+        let accounts = accounts.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(accounts.value(0), "A");
+        assert!(accounts.is_null(1));
+        let quantities = column("quantity");
+        let quantities = quantities.as_any().downcast_ref::<Decimal128Array>().unwrap();
+        assert_eq!(quantities.value(1), qty(5).raw() as i128);
+
+        let trades = book.trades_to_arrow_batch().unwrap();
+        assert_eq!(trades.num_rows(), 1);
+        let aggressor = trades.column_by_name("aggressor").unwrap();
+        let aggressor = aggressor.as_any().downcast_ref::<StringArray>().unwrap();
+        assert_eq!(aggressor.value(0), Side::Buy.to_string());
+    }
+
+    #[test]
+    fn market_order_remainder_is_cancelled_and_never_rests() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+        book.add_order(limit("AAPL", Side::Buy, 3, 99.0));
+
+        let buy = Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(8), None);
+        let trades = book.add_order(buy);
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<Quantity>(), qty(5));
+        let sell = Order::new("AAPL".to_string(), Side::Sell, OrderType::Market, qty(9), None);
+        assert_eq!(book.add_order(sell).len(), 1);
+
+        // Nothing rests from either order, so both sides are empty and unpriced orders absent
+        let (bids, asks) = book.get_orders();
+        assert!(bids.is_empty() && asks.is_empty());
+        assert!(book.working_orders().all(|o| o.price.is_some_and(f64::is_finite)));
+        assert!(book.check_invariants().is_ok());
+
+        // Into an empty book a market order simply goes away
+        let buy = Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(1), None);
+        assert!(book.add_order(buy).is_empty());
+        assert_eq!(book.working_orders().count(), 0);
+    }
+
+    #[test]
+    fn order_timestamps_and_gtd_expiry_follow_the_sim_clock() {
+        let start = utc(2020, 1, 2, 15, 0);
+        let (mut engine, clock) = sim_engine(start);
+        engine.create_market("AAPL", 100.0);
+
+        let expiry = start + chrono::Duration::minutes(30);
+        let gtd = limit("AAPL", Side::Buy, 10, 99.0).good_till(expiry);
+        engine.place_order(gtd).unwrap();
+        let (bids, _) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids[0].timestamp, start);
+
+        // Wall-clock time is years past the expiry, but only simulated time counts
+        assert!(engine.process_time_events("AAPL").unwrap().expired().is_empty());
+        clock.advance(chrono::Duration::minutes(29));
+        assert!(engine.process_time_events("AAPL").unwrap().expired().is_empty());
+        clock.advance(chrono::Duration::minutes(1));
+        let events = engine.process_time_events("AAPL").unwrap();
+        assert_eq!(events.expired().len(), 1);
+        assert!(engine.get_orders("AAPL").unwrap().0.is_empty());
+
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 5, 101.0)).unwrap();
+        let trades = engine.get_trades("AAPL").unwrap();
+        assert_eq!(trades[0].timestamp, expiry);
+    }
+
+    #[test]
+    fn quantity_arithmetic_saturates_and_checked_variants_reject_overflow() {
+        assert!("92233720369".parse::<Quantity>().is_err());
+        let big: Quantity = "92233720000".parse().unwrap();
+        assert_eq!(big + big, Quantity::MAX);
+        assert_eq!(-big - big, Quantity::from_raw(i64::MIN));
+        assert_eq!(big.checked_add(big), None);
+        assert_eq!(big.checked_sub(-big), None);
+        assert_eq!(big.checked_mul(2), None);
+        assert_eq!(Quantity::checked_from_units(i64::MAX), None);
+        assert_eq!(Quantity::from_units(i64::MAX), Quantity::MAX);
+        assert_eq!([big, big, -big].into_iter().sum::<Quantity>(), Quantity::MAX - big);
+    }
+
+    #[test]
+    fn half_unit_fills_leave_exact_remainders() {
+        let half: Quantity = "0.5".parse().unwrap();
+        let config = BookConfig::default().with_quantity_step(half);
+        let mut book = OrderBook::with_config("BTC".to_string(), 100.0, config);
+        let ask = Order::new("BTC".to_string(), Side::Sell, OrderType::Limit, qty(2), Some(100.0));
+        book.add_order(ask);
+
+        let buy = Order::new("BTC".to_string(), Side::Buy, OrderType::Limit, half, Some(100.0));
+        let trades = book.add_order(buy);
+        assert_eq!(trades[0].quantity, half);
+        let buy = Order::new("BTC".to_string(), Side::Buy, OrderType::Limit, half, Some(100.0));
+        book.add_order(buy);
+
+        let (_, asks) = book.get_orders();
+        assert_eq!(asks[0].quantity, qty(1));
+        assert_eq!(asks[0].quantity.to_string(), "1");
+        assert_eq!(book.trades.iter().map(|t| t.quantity).sum::<Quantity>(), qty(1));
+    }
+
+    #[test]
+    fn good_after_time_order_enters_the_book_only_at_activation() {
+        let start = utc(2024, 3, 11, 14, 0);
+        let (mut engine, clock) = sim_engine(start);
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 5, 100.0)).unwrap();
+
+        let activation = start + chrono::Duration::minutes(5);
+        let buy = limit("AAPL", Side::Buy, 8, 100.0).good_after(activation);
+        assert!(engine.place_order(buy).unwrap().is_empty());
+        assert!(engine.get_orders("AAPL").unwrap().0.is_empty());
+
+        clock.advance(chrono::Duration::minutes(4));
+        assert!(engine.process_time_events("AAPL").unwrap().trades().is_empty());
+        assert!(engine.get_trades("AAPL").unwrap().is_empty());
+
+        clock.advance(chrono::Duration::minutes(1));
+        let events = engine.process_time_events("AAPL").unwrap();
+        assert_eq!(events.trades().len(), 1);
+        assert_eq!(events.trades()[0].timestamp, activation);
+        let (bids, _) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids[0].quantity, qty(3));
+    }
+
+    #[test]
+    fn side_cap_evicts_the_order_furthest_from_the_touch() {
+        let config = BookConfig::default().with_max_orders_per_side(3);
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        for price in [99.0, 97.0, 98.0] {
+            book.add_order(limit("AAPL", Side::Buy, 1, price));
+        }
+        assert!(book.take_evicted().is_empty());
+
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.5));
+        let evicted = book.take_evicted();
+        assert_eq!(evicted.len(), 1);
+        assert_eq!(evicted[0].price, Some(97.0));
+        let prices: Vec<_> = book.get_orders().0.iter().map(|o| o.price.unwrap()).collect();
+        assert_eq!(prices, vec![99.5, 99.0, 98.0]);
+
+        // A newcomer worse than everything resting is the one that goes
+        book.add_order(limit("AAPL", Side::Buy, 1, 90.0));
+        assert_eq!(book.take_evicted()[0].price, Some(90.0));
+        // At an equal price the later arrival is the one behind in time
+        let late = limit("AAPL", Side::Buy, 1, 98.0);
+        let late_id = late.id.clone();
+        book.add_order(late);
+        assert_eq!(book.take_evicted()[0].id, late_id);
+        assert_eq!(book.get_orders().0.len(), 3);
+    }
+
+    #[test]
+    fn batch_auction_clears_accumulated_orders_at_one_price() {
+        let clock = SimClock::new(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)));
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.set_clock(Arc::new(clock.clone()));
+
+        assert!(book.add_order(limit("AAPL", Side::Buy, 10, 101.0)).is_empty());
+        assert!(book.add_order(limit("AAPL", Side::Buy, 5, 100.0)).is_empty());
+        assert!(book.add_order(limit("AAPL", Side::Sell, 8, 99.0)).is_empty());
+        assert!(book.add_order(limit("AAPL", Side::Sell, 6, 100.0)).is_empty());
+        assert!(book.run_auction(clock.now()).is_empty());
+
+        clock.advance(chrono::Duration::seconds(1));
+        let trades = book.run_auction(clock.now());
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<Quantity>(), qty(14));
+        assert!(trades.iter().all(|t| t.price == 100.0));
+        let (bids, asks) = book.get_orders();
+        assert_eq!((bids.len(), asks.len()), (1, 0));
+        assert_eq!(bids[0].quantity, qty(1));
+    }
+
+    #[test]
+    fn non_finite_prices_never_reach_the_book() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for price in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            assert!(book.add_order(limit("AAPL", Side::Buy, 1, price)).is_empty());
+        }
+        let stop = Order::new_stop("AAPL".to_string(), Side::Sell, qty(1), f64::NAN);
+        book.add_order(stop);
+        assert_eq!(book.working_orders().count(), 0);
+        assert!(book.get_orders().0.is_empty());
+
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let nan = limit("AAPL", Side::Buy, 1, f64::NAN);
+        assert!(matches!(engine.place_order(nan), Err(OrderError::InvalidPrice(_))));
+    }
+
+    #[test]
+    fn busting_a_trade_restores_positions_volume_and_last_price() {
+        let mut engine = long_and_short_at_100();
+        let cash = (engine.get_cash_balance("L"), engine.get_cash_balance("S"));
+        engine.place_order(limit("AAPL", Side::Sell, 5, 102.0).with_account("S")).unwrap();
+        let trades = engine.place_order(limit("AAPL", Side::Buy, 5, 102.0).with_account("L"));
+        let bad = trades.unwrap().remove(0);
+        assert_eq!(engine.get_position("L", "AAPL"), qty(15));
+
+        let busted = engine.bust_trade("AAPL", &bad.id).unwrap();
+        assert_eq!(busted.id, bad.id);
+        assert_eq!(engine.get_position("L", "AAPL"), qty(10));
+        assert_eq!(engine.get_position("S", "AAPL"), -qty(10));
+        assert_eq!((engine.get_cash_balance("L"), engine.get_cash_balance("S")), cash);
+        let stats = engine.get_session_stats("AAPL").unwrap();
+        assert_eq!((stats.trade_count(), stats.volume()), (1, qty(10)));
+        assert_eq!(stats.high(), Some(100.0));
+        assert_eq!(engine.get_market_data("AAPL").unwrap().last_price, 100.0);
+        assert!(matches!(
+            engine.bust_trade("AAPL", &bad.id),
+            Err(OrderError::TradeNotFound(_))
+        ));
+    }
+
+    // Asks of 3@100, 4@101 and 5@102, then the given order
+    fn sweep_with(order: Order) -> (OrderBook, Vec<Trade>) {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 3, 100.0));
+        book.add_order(limit("AAPL", Side::Sell, 4, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 5, 102.0));
+        let trades = book.add_order(order);
+        (book, trades)
+    }
+
+    #[test]
+    fn marketable_limit_sweeps_to_its_limit_then_rests_the_remainder() {
+        let (book, trades) = sweep_with(limit("AAPL", Side::Buy, 10, 101.0));
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<Quantity>(), qty(7));
+        let (bids, asks) = book.get_orders();
+        assert_eq!(bids.len(), 1);
+        assert_eq!((bids[0].price, bids[0].quantity), (Some(101.0), qty(3)));
+        assert_eq!(asks[0].price, Some(102.0));
+    }
+
+    #[test]
+    fn immediate_or_cancel_limit_drops_the_remainder() {
+        let order = limit("AAPL", Side::Buy, 10, 101.0).immediate_or_cancel();
+        let (book, trades) = sweep_with(order);
+        assert_eq!(trades.iter().map(|t| t.quantity).sum::<Quantity>(), qty(7));
+        assert!(trades.iter().all(|t| t.price <= 101.0));
+        assert!(book.get_orders().0.is_empty());
+    }
+
+    struct RejectOddQuantity;
+
+    impl OrderValidator for RejectOddQuantity {
+        fn validate(&self, _engine: &TradingEngine, order: &Order) -> Result<(), OrderError> {
+            if order.quantity.raw() / Quantity::SCALE % 2 == 1 {
+                return Err(OrderError::InvalidQuantity { quantity: order.quantity, step: qty(2) });
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn custom_validator_runs_before_matching() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.add_validator(Box::new(RejectOddQuantity));
+        engine.place_order(limit("AAPL", Side::Sell, 4, 100.0)).unwrap();
+
+        let odd = engine.place_order(limit("AAPL", Side::Buy, 3, 100.0));
+        assert!(matches!(odd, Err(OrderError::InvalidQuantity { .. })));
+        assert!(engine.get_trades("AAPL").unwrap().is_empty());
+        assert_eq!(engine.place_order(limit("AAPL", Side::Buy, 2, 100.0)).unwrap().len(), 1);
+
+        // Built-in checks still run alongside it
+        let nan = limit("AAPL", Side::Buy, 2, f64::NAN);
+        assert!(matches!(engine.place_order(nan), Err(OrderError::InvalidPrice(_))));
+    }
+
+    #[test]
+    fn portfolio_risk_sums_exposure_across_symbols() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 50.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 100.0).with_account("MM")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 10, 100.0).with_account("P")).unwrap();
+        engine.place_order(limit("MSFT", Side::Buy, 4, 50.0).with_account("MM")).unwrap();
+        engine.place_order(limit("MSFT", Side::Sell, 4, 50.0).with_account("P")).unwrap();
+
+        let risk = engine.portfolio_risk("P");
+        assert_eq!(risk.positions().len(), 2);
+        assert_eq!(risk.positions()[1].position(), -qty(4));
+        assert!((risk.gross_exposure() - 1200.0).abs() < 1e-9);
+        assert!((risk.net_exposure() - 800.0).abs() < 1e-9);
+        assert!(risk.unrealized_pnl().abs() < 1e-9);
+        let (symbol, share) = risk.largest_concentration().unwrap();
+        assert_eq!(symbol, "AAPL");
+        assert!((share - 1000.0 / 1200.0).abs() < 1e-9);
+        assert!(engine.portfolio_risk("nobody").largest_concentration().is_none());
+    }
+
+    #[test]
+    fn rolling_a_session_archives_its_stats_and_starts_fresh() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+        book.add_order(limit("AAPL", Side::Buy, 2, 101.0));
+        book.add_order(limit("AAPL", Side::Buy, 3, 101.0));
+        book.add_order(limit("AAPL", Side::Buy, 4, 99.0));
+
+        let archived = book.roll_session();
+        assert_eq!((archived.trade_count(), archived.volume()), (2, qty(5)));
+        assert_eq!(archived.high(), Some(101.0));
+        assert!(archived.ended_at().is_some());
+        assert_eq!(book.session_history().len(), 1);
+
+        let live = book.session_stats();
+        assert_eq!((live.trade_count(), live.volume()), (0, Quantity::ZERO));
+        assert_eq!((live.high(), live.vwap()), (None, None));
+        // GTC orders carry over untouched
+        assert_eq!(book.get_orders().0[0].quantity, qty(4));
+
+        book.add_order(limit("AAPL", Side::Sell, 1, 99.0));
+        assert_eq!(book.session_stats().low(), Some(99.0));
+        assert_eq!(book.session_history()[0].low(), Some(101.0));
+    }
+
+    #[test]
+    fn round_trip_cost_walks_both_sides_of_a_wide_book() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 2, 102.0));
+        book.add_order(limit("AAPL", Side::Sell, 5, 103.0));
+        book.add_order(limit("AAPL", Side::Buy, 3, 98.0));
+        book.add_order(limit("AAPL", Side::Buy, 5, 96.0));
+
+        // One unit only touches the top of each side
+        assert_eq!(book.round_trip_cost(qty(1)), Some(102.0 - 98.0));
+        // Four units: 2@102 + 2@103 bought, 3@98 + 1@96 sold
+        assert_eq!(book.round_trip_cost(qty(4)), Some(410.0 - 390.0));
+        assert_eq!(book.round_trip_cost(qty(7)), Some(719.0 - 678.0));
+        // The bids hold 8 but the asks only 7
+        assert_eq!(book.round_trip_cost(qty(8)), None);
+    }
+
+    #[test]
+    fn mid_mark_price_ignores_an_off_mid_trade() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_mark_price_method("AAPL", MarkPriceMethod::Mid).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 5, 99.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 1, 104.0).with_account("S")).unwrap();
+        // Lifts the ask at 101 and trades one unit at 104, well off the mid
+        engine.place_order(limit("AAPL", Side::Buy, 6, 104.0).with_account("B")).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+
+        assert_eq!(engine.get_market_data("AAPL").unwrap().last_price, 104.0);
+        assert_eq!(engine.get_mark_price("AAPL"), Some(100.0));
+        assert_eq!(engine.get_market_data("AAPL").unwrap().mark_price, 100.0);
+        let risk = engine.portfolio_risk("S");
+        assert_eq!(risk.positions()[0].price(), 100.0);
+        assert!((risk.unrealized_pnl() - (-100.0 + 104.0)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn trades_are_tagged_with_tick_direction() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for price in [100.0, 101.0, 101.0, 99.5, 100.0] {
+            book.add_order(limit("AAPL", Side::Sell, 1, price));
+            book.add_order(limit("AAPL", Side::Buy, 1, price));
+        }
+        let directions: Vec<_> = book.trades.iter().map(|t| t.tick_direction).collect();
+        assert_eq!(
+            directions,
+            vec![
+                TickDirection::Zero,
+                TickDirection::Uptick,
+                TickDirection::Zero,
+                TickDirection::Downtick,
+                TickDirection::Uptick,
+            ]
+        );
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_engine_broadcasts_trades_from_awaited_orders() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let engine = AsyncTradingEngine::new(engine);
+        let mut updates = engine.subscribe();
+
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).await.unwrap();
+        assert!(matches!(updates.recv().await.unwrap(), MarketUpdate::MarketData(_)));
+
+        let trades = engine.place_order(limit("AAPL", Side::Buy, 3, 101.0)).await.unwrap();
+        match updates.recv().await.unwrap() {
+            MarketUpdate::Trade(trade) => assert_eq!(trade.id, trades[0].id),
+            other => panic!("expected a trade, got {:?}", other),
+        }
+        match updates.recv().await.unwrap() {
+            MarketUpdate::MarketData(market_data) => assert_eq!(market_data.last_price, 101.0),
+            other => panic!("expected market data, got {:?}", other),
+        }
+        assert_eq!(engine.engine().lock().await.get_trades("AAPL").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn tick_size_must_be_positive_and_finite() {
+        for tick_size in [0.0, -0.01, f64::NAN, f64::INFINITY] {
+            let config = BookConfig::default().with_tick_size(tick_size, TradeRounding::Nearest);
+            assert!(matches!(config, Err(OrderError::InvalidTickSize(_))));
+        }
+        assert!(BookConfig::default().with_tick_size(0.01, TradeRounding::Nearest).is_ok());
+    }
+
+    #[test]
+    fn off_tick_execution_prices_round_per_policy() {
+        let print = |rounding: TradeRounding| {
+            let config = BookConfig::default().with_tick_size(0.01, rounding).unwrap();
+            let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+            // Inserted below the engine's tick validation, as an off-grid fill price would be
+            book.add_order(limit("AAPL", Side::Sell, 1, 100.013));
+            book.add_order(limit("AAPL", Side::Buy, 1, 100.02))[0].price
+        };
+        assert!((print(TradeRounding::Nearest) - 100.01).abs() < 1e-9);
+        assert!((print(TradeRounding::TowardMaker) - 100.02).abs() < 1e-9);
+        assert!((print(TradeRounding::TowardTaker) - 100.01).abs() < 1e-9);
+        // Without an aggressor every policy rounds to nearest
+        assert!((TradeRounding::TowardMaker.apply(100.016, 0.01, None) - 100.02).abs() < 1e-9);
+        assert!((TradeRounding::TowardTaker.apply(100.004, 0.01, None) - 100.0).abs() < 1e-9);
+    }
+
+    // Trades from one session of three crosses, on a SimClock starting at `start`
+    fn recorded_session(start: DateTime<Utc>, last_price: f64) -> Vec<Trade> {
+        let (mut engine, clock) = sim_engine(start);
+        engine.create_market("AAPL", 100.0);
+        for price in [100.0, 101.0, last_price] {
+            clock.advance(chrono::Duration::seconds(1));
+            engine.place_order(limit("AAPL", Side::Sell, 2, price).with_account("S")).unwrap();
+            engine.place_order(limit("AAPL", Side::Buy, 2, price).with_account("B")).unwrap();
+        }
+        engine.get_trades("AAPL").unwrap()
+    }
+
+    #[test]
+    fn trade_stream_comparison_reports_the_first_divergence() {
+        let recorded = recorded_session(utc(2024, 3, 11, 14, 0), 102.0);
+        // A later run under different ids and clock times still matches
+        let replayed = recorded_session(utc(2024, 3, 12, 14, 0), 102.0);
+        assert!(compare_trade_streams(&recorded, &replayed).is_none());
+        assert_eq!(compare_trade_streams_timed(&recorded, &replayed).unwrap().index(), 0);
+
+        let changed = recorded_session(utc(2024, 3, 11, 14, 0), 102.5);
+        let divergence = compare_trade_streams_timed(&recorded, &changed).unwrap();
+        assert_eq!(divergence.index(), 2);
+        assert_eq!(divergence.expected().unwrap().price, 102.0);
+        assert_eq!(divergence.actual().unwrap().price, 102.5);
+
+        let divergence = compare_trade_streams(&recorded, &recorded[..2]).unwrap();
+        assert_eq!(divergence.index(), 2);
+        assert!(divergence.actual().is_none());
+        assert!(divergence.to_string().contains("end of stream"));
+    }
+
+    #[test]
+    fn top_of_book_sizes_aggregate_the_best_level() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 3, 99.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 4, 99.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 9, 98.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+
+        let market_data = engine.get_market_data("AAPL").unwrap();
+        assert_eq!((market_data.bid_size, market_data.ask_size), (qty(7), qty(5)));
+        assert!(market_data.to_string().contains("Bid: 99.00 x 7, Ask: 101.00 x 5"));
+
+        engine.place_order(limit("AAPL", Side::Sell, 5, 99.0)).unwrap();
+        let market_data = engine.get_market_data("AAPL").unwrap();
+        assert_eq!((market_data.bid, market_data.bid_size), (99.0, qty(2)));
+    }
+
+    #[test]
+    fn max_order_quantity_rejects_above_the_cap_only() {
+        let mut engine = TradingEngine::new();
+        let config = BookConfig::default().with_max_order_quantity(qty(100));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        engine.place_order(limit("AAPL", Side::Sell, 100, 100.0)).unwrap();
+
+        let over = engine.place_order(limit("AAPL", Side::Sell, 101, 100.0));
+        assert!(matches!(
+            over,
+            Err(OrderError::QuantityTooLarge { quantity, max_quantity, .. })
+                if quantity == qty(101) && max_quantity == qty(100)
+        ));
+        let market = |units| {
+            Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(units), None)
+        };
+        let over = engine.place_order(market(101));
+        assert!(matches!(over, Err(OrderError::QuantityTooLarge { .. })));
+        assert_eq!(engine.place_order(market(100)).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn delayed_orders_match_in_receipt_time_order() {
+        let start = utc(2024, 3, 11, 14, 0);
+        let (mut engine, clock) = sim_engine(start);
+        engine.create_market("AAPL", 100.0);
+        engine.set_order_latency("SLOW", Some(chrono::Duration::milliseconds(50)));
+        engine.set_order_latency("FAST", Some(chrono::Duration::milliseconds(5)));
+        engine.place_order(limit("AAPL", Side::Sell, 5, 100.0).with_account("MM")).unwrap();
+
+        // SLOW submits first but FAST reaches the exchange first
+        let slow = limit("AAPL", Side::Buy, 5, 100.0).with_account("SLOW");
+        assert!(engine.place_order(slow).unwrap().is_empty());
+        clock.advance(chrono::Duration::milliseconds(1));
+        let fast = limit("AAPL", Side::Buy, 5, 100.0).with_account("FAST");
+        assert!(engine.place_order(fast).unwrap().is_empty());
+
+        clock.advance(chrono::Duration::milliseconds(10));
+        let events = engine.process_time_events("AAPL").unwrap();
+        assert_eq!(events.trades().len(), 1);
+        assert_eq!(events.trades()[0].buyer_account.as_deref(), Some("FAST"));
+
+        clock.advance(chrono::Duration::milliseconds(50));
+        assert!(engine.process_time_events("AAPL").unwrap().trades().is_empty());
+        let (bids, _) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids[0].account.as_deref(), Some("SLOW"));
+        assert_eq!(bids[0].received_at, Some(start + chrono::Duration::milliseconds(50)));
+    }
+
+    #[test]
+    fn euro_quoted_order_rests_at_the_converted_dollar_price() {
+        let mut engine = TradingEngine::new();
+        let usd = BookConfig::default().with_quote_currency("USD");
+        engine.create_market_with_params("AAPL", 100.0, usd);
+        let eur = BookConfig::default().with_quote_currency("EUR");
+        engine.create_market_with_params("SAP", 80.0, eur);
+        engine.set_fx_rates(Arc::new(StaticFxRates::new().with_rate("EUR", "USD", 1.25)));
+
+        engine.place_order(limit("AAPL", Side::Buy, 3, 80.0).quoted_in("EUR")).unwrap();
+        let (bids, _) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids[0].price, Some(100.0));
+        let conversion = bids[0].fx_conversion.as_ref().unwrap();
+        assert_eq!((conversion.currency.as_str(), conversion.rate), ("EUR", 1.25));
+        assert_eq!(conversion.original_price, Some(80.0));
+
+        // The pair also converts the other way, and an unknown one is refused
+        engine.place_order(limit("SAP", Side::Sell, 1, 125.0).quoted_in("USD")).unwrap();
+        assert_eq!(engine.get_orders("SAP").unwrap().1[0].price, Some(100.0));
+        let yen = engine.place_order(limit("AAPL", Side::Sell, 1, 15000.0).quoted_in("JPY"));
+        assert!(matches!(yen, Err(OrderError::FxRateUnavailable { .. })));
+    }
+
+    #[test]
+    fn trades_record_the_aggressor_side() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 2, 101.0));
+        let lifted = book.add_order(limit("AAPL", Side::Buy, 2, 101.0));
+        book.add_order(limit("AAPL", Side::Buy, 2, 99.0));
+        let hit = book.add_order(limit("AAPL", Side::Sell, 2, 99.0));
+
+        assert_eq!(lifted[0].aggressor, Some(Side::Buy));
+        assert_eq!(hit[0].aggressor, Some(Side::Sell));
+    }
+
+    #[test]
+    fn traded_notional_is_exact_over_many_small_trades() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        assert_eq!(engine.get_traded_notional("AAPL"), Some(0.0));
+        // 0.1 and 0.2 do not sum exactly in f64, so naive accumulation drifts
+        for _ in 0..1000 {
+            engine.place_order(limit("AAPL", Side::Sell, 3, 0.1)).unwrap();
+            engine.place_order(limit("AAPL", Side::Buy, 3, 0.1)).unwrap();
+            engine.place_order(limit("AAPL", Side::Sell, 1, 0.2)).unwrap();
+            engine.place_order(limit("AAPL", Side::Buy, 1, 0.2)).unwrap();
+        }
+        assert_eq!(engine.get_traded_notional("AAPL"), Some(500.0));
+        assert_eq!(engine.get_traded_notional("MSFT"), None);
+    }
+
+    #[test]
+    fn limit_orders_match_at_negative_prices() {
+        let mut book = OrderBook::new("CL".to_string(), -5.0);
+        book.add_order(limit("CL", Side::Sell, 4, -3.0));
+        book.add_order(limit("CL", Side::Sell, 4, -2.0));
+        // A buy at -2.5 only reaches the cheaper offer
+        let trades = book.add_order(limit("CL", Side::Buy, 6, -2.5));
+        assert_eq!(trades.len(), 1);
+        assert_eq!((trades[0].price, trades[0].quantity), (-3.0, qty(4)));
+        let (bids, asks) = book.get_orders();
+        assert_eq!(bids[0].price, Some(-2.5));
+        assert_eq!(asks[0].price, Some(-2.0));
+        assert!(book.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn market_orders_trade_through_a_negative_price_book() {
+        let mut book = OrderBook::new("CL".to_string(), -5.0);
+        book.add_order(limit("CL", Side::Buy, 2, -1.0));
+        book.add_order(limit("CL", Side::Buy, 2, -4.0));
+        let sell = Order::new("CL".to_string(), Side::Sell, OrderType::Market, qty(4), None);
+        let prices: Vec<f64> = book.add_order(sell).iter().map(|t| t.price).collect();
+        assert_eq!(prices, vec![-1.0, -4.0]);
+
+        book.add_order(limit("CL", Side::Sell, 3, -0.5));
+        let buy = Order::new("CL".to_string(), Side::Buy, OrderType::Market, qty(3), None);
+        assert_eq!(book.add_order(buy)[0].price, -0.5);
+        assert!(book.get_orders().0.is_empty());
+    }
+
+    // Feed message header: type, space-padded symbol and order reference
+    fn feed_message(message_type: u8, order_ref: u64, tail: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![message_type];
+        bytes.extend_from_slice(b"AAPL    ");
+        bytes.extend_from_slice(&order_ref.to_be_bytes());
+        for field in tail {
+            bytes.extend_from_slice(field);
+        }
+        bytes
+    }
+
+    fn fixed(value: f64) -> [u8; 8] {
+        ((value * Quantity::SCALE as f64).round() as i64).to_be_bytes()
+    }
+
+    #[test]
+    fn feed_messages_parse_from_hand_built_bytes() {
+        let add = feed_message(b'A', 7, &[b"S", &fixed(10.0), &fixed(-1.5)]);
+        let modify = feed_message(b'U', 7, &[&fixed(4.0), &fixed(101.25)]);
+        let delete = feed_message(b'D', 7, &[]);
+        let execute = feed_message(b'E', 7, &[&fixed(0.5)]);
+        let symbol = "AAPL".to_string();
+
+        assert_eq!(
+            parse_message(&add),
+            Ok(BookEvent::Add {
+                symbol: symbol.clone(),
+                order_ref: 7,
+                side: Side::Sell,
+                quantity: qty(10),
+                price: -1.5
+            })
+        );
+        assert_eq!(
+            parse_message(&modify),
+            Ok(BookEvent::Modify {
+                symbol: symbol.clone(),
+                order_ref: 7,
+                quantity: qty(4),
+                price: 101.25
+            })
+        );
+        assert_eq!(
+            parse_message(&delete),
+            Ok(BookEvent::Delete { symbol: symbol.clone(), order_ref: 7 })
+        );
+        let half = "0.5".parse().unwrap();
+        assert_eq!(
+            parse_message(&execute),
+            Ok(BookEvent::Execute { symbol, order_ref: 7, quantity: half })
+        );
+
+        assert_eq!(parse_message(&[]), Err(ParseError::Empty));
+        assert_eq!(parse_message(b"Z"), Err(ParseError::UnknownMessageType(b'Z')));
+        assert!(matches!(parse_message(&add[..20]), Err(ParseError::Truncated { .. })));
+        let bad_side = feed_message(b'A', 7, &[b"X", &fixed(1.0), &fixed(1.0)]);
+        assert_eq!(parse_message(&bad_side), Err(ParseError::InvalidSide(b'X')));
+    }
+
+    #[test]
+    fn replayed_execute_fills_only_the_referenced_order() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let mut replayer = BookReplayer::new();
+        let mut replay = |engine: &mut TradingEngine, bytes: Vec<u8>| {
+            replayer.apply(engine, &parse_message(&bytes).unwrap()).unwrap()
+        };
+
+        // Two asks at one price, with ref 2 behind ref 1 in the queue
+        replay(&mut engine, feed_message(b'A', 1, &[b"S", &fixed(5.0), &fixed(100.0)]));
+        replay(&mut engine, feed_message(b'A', 2, &[b"S", &fixed(5.0), &fixed(100.0)]));
+        let trades = replay(&mut engine, feed_message(b'E', 2, &[&fixed(3.0)]));
+        assert_eq!(trades.len(), 1);
+        assert_eq!((trades[0].price, trades[0].quantity), (100.0, qty(3)));
+        let (_, asks) = engine.get_orders("AAPL").unwrap();
+        let sizes: Vec<Quantity> = asks.iter().map(|o| o.quantity).collect();
+        assert_eq!(sizes, vec![qty(5), qty(2)]);
+        assert_eq!(trades[0].seller_order_id, asks[1].id);
+
+        // A crossing add rests as reported instead of trading
+        let cross = replay(&mut engine, feed_message(b'A', 3, &[b"B", &fixed(1.0), &fixed(100.0)]));
+        assert!(cross.is_empty());
+        assert_eq!(engine.get_trades("AAPL").unwrap().len(), 1);
+
+        replay(&mut engine, feed_message(b'E', 2, &[&fixed(2.0)]));
+        replay(&mut engine, feed_message(b'U', 1, &[&fixed(4.0), &fixed(101.0)]));
+        replay(&mut engine, feed_message(b'D', 3, &[]));
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert!(bids.is_empty());
+        assert_eq!((asks.len(), asks[0].price, asks[0].quantity), (1, Some(101.0), qty(4)));
+        let delete = BookEvent::Delete {
+            symbol: "AAPL".to_string(),
+            order_ref: 2,
+        };
+        let gone = replayer.apply(&mut engine, &delete);
+        assert!(matches!(gone, Err(OrderError::OrderNotFound(_))));
+    }
+
+    #[test]
+    fn privileged_account_skips_risk_checks_with_an_audit_trail() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(Quantity::ZERO)).unwrap();
+        for account in ["LP", "USER"] {
+            engine.set_position_limit(account, "AAPL", Some(qty(10)));
+        }
+        engine.set_skip_risk_checks("LP", true);
+
+        // Neither account holds shares or has borrow, and both orders break the limit
+        let user = engine.place_order(limit("AAPL", Side::Sell, 50, 101.0).with_account("USER"));
+        assert!(matches!(user, Err(OrderError::PositionLimitExceeded { .. })));
+        engine.place_order(limit("AAPL", Side::Sell, 50, 101.0).with_account("LP")).unwrap();
+
+        let entry = engine.audit_log().last().unwrap();
+        assert!(entry.to_string().contains("PLACE_UNCHECKED"));
+        assert_eq!(engine.get_orders("AAPL").unwrap().1[0].account.as_deref(), Some("LP"));
+
+        engine.set_skip_risk_checks("LP", false);
+        let again = engine.place_order(limit("AAPL", Side::Sell, 50, 101.0).with_account("LP"));
+        assert!(again.is_err());
+    }
+
+    #[test]
+    fn memory_stats_count_orders_and_trades() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 50.0);
+        for price in [97.0, 98.0, 99.0] {
+            engine.place_order(limit("AAPL", Side::Buy, 1, price)).unwrap();
+        }
+        engine.place_order(limit("AAPL", Side::Sell, 2, 101.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 1, 99.0)).unwrap();
+        engine.place_order(Order::new_stop("AAPL".to_string(), Side::Sell, qty(1), 90.0)).unwrap();
+        engine.place_order(limit("MSFT", Side::Sell, 1, 50.0)).unwrap();
+
+        let report = engine.memory_report();
+        let (symbol, aapl) = &report.books[0];
+        assert_eq!(symbol, "AAPL");
+        assert_eq!((aapl.bid_orders, aapl.ask_orders, aapl.parked_orders), (2, 1, 1));
+        assert_eq!((aapl.trades, aapl.resting_orders()), (1, 3));
+        assert!(aapl.estimated_bytes > 0);
+        assert_eq!((report.resting_orders, report.parked_orders, report.trades), (4, 1, 1));
+        let total: usize = report.books.iter().map(|(_, stats)| stats.estimated_bytes).sum();
+        assert_eq!(report.estimated_bytes, total);
+    }
+
+    #[test]
+    fn effective_and_realized_spread_of_a_known_trade() {
+        let clock = SimClock::new(utc(2024, 3, 11, 14, 0));
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.set_clock(Arc::new(clock.clone()));
+        book.add_order(limit("AAPL", Side::Buy, 2, 99.0));
+        book.add_order(limit("AAPL", Side::Sell, 2, 101.0));
+
+        let trade = book.add_order(limit("AAPL", Side::Buy, 1, 101.0)).remove(0);
+        assert_eq!(trade.mid_at_trade, 100.0);
+        assert_eq!(book.effective_spread(&trade), 2.0);
+
+        let interval = chrono::Duration::seconds(10);
+        assert_eq!(book.realized_spread(&trade, interval), None);
+        clock.advance(chrono::Duration::seconds(5));
+        book.add_order(limit("AAPL", Side::Buy, 1, 100.5));
+        clock.advance(chrono::Duration::seconds(5));
+        // The mid moved up to 100.75, so the seller kept 0.5 of the 2.0 spread
+        assert_eq!(book.realized_spread(&trade, interval), Some(0.5));
+    }
+
+    // Take-profit sell at 105 and protective stop at 95, returning their ids
+    fn bracket(engine: &mut TradingEngine, take_profit: Order) -> (String, String) {
+        let stop = Order::new_stop("AAPL".to_string(), Side::Sell, qty(2), 95.0);
+        let ids = (take_profit.id.clone(), stop.id.clone());
+        let (a, b) = engine.place_oco("AAPL", take_profit, stop).unwrap();
+        assert!(a.is_empty() && b.is_empty());
+        ids
+    }
+
+    fn is_working(engine: &TradingEngine, order_id: &str) -> bool {
+        engine.order_books["AAPL"].is_working(order_id)
+    }
+
+    #[test]
+    fn filling_the_limit_leg_cancels_the_stop_leg() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let (limit_id, stop_id) = bracket(&mut engine, limit("AAPL", Side::Sell, 2, 105.0));
+
+        // A partial fill is enough
+        engine.place_order(limit("AAPL", Side::Buy, 1, 105.0)).unwrap();
+        assert!(is_working(&engine, &limit_id));
+        assert!(!is_working(&engine, &stop_id));
+        assert!(engine.oco_links.is_empty());
+        let last = engine.audit_log().last().unwrap();
+        assert_eq!(last.action, AuditAction::OcoCancel);
+        assert_eq!(last.order_id.as_deref(), Some(stop_id.as_str()));
+        assert_audit_chain(engine.audit_log());
+    }
+
+    #[test]
+    fn triggering_the_stop_leg_cancels_the_limit_leg() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 5, 94.0)).unwrap();
+        let (limit_id, stop_id) = bracket(&mut engine, limit("AAPL", Side::Sell, 2, 105.0));
+
+        engine.place_order(limit("AAPL", Side::Sell, 1, 94.0)).unwrap();
+        let trades = engine.get_trades("AAPL").unwrap();
+        assert!(trades.iter().any(|t| t.seller_order_id == stop_id));
+        assert!(!is_working(&engine, &limit_id));
+        assert!(engine.oco_links.is_empty());
+        assert_audit_chain(engine.audit_log());
+    }
+
+    #[test]
+    fn oco_cancel_overrides_protection_and_minimum_rest_time() {
+        let mut engine = TradingEngine::new();
+        let config = BookConfig::default().with_min_rest_time(chrono::Duration::minutes(5));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        let stop = Order::new_stop("AAPL".to_string(), Side::Sell, qty(2), 95.0).protected();
+        let stop_id = stop.id.clone();
+        let take_profit = limit("AAPL", Side::Sell, 2, 105.0).with_account("A");
+        engine.place_oco("AAPL", take_profit, stop).unwrap();
+        assert!(engine.cancel_order("AAPL", &stop_id).is_err());
+
+        engine.place_order(limit("AAPL", Side::Buy, 2, 105.0)).unwrap();
+        assert!(!is_working(&engine, &stop_id));
+        // The automatic cancel is not the account's own message
+        assert_eq!(engine.account_activity["A"].cancels, 0);
+    }
+
+    #[test]
+    fn expired_leg_unlinks_and_leaves_its_sibling_working() {
+        let start = utc(2024, 3, 11, 14, 0);
+        let (mut engine, clock) = sim_engine(start);
+        engine.create_market("AAPL", 100.0);
+        let take_profit = limit("AAPL", Side::Sell, 2, 105.0)
+            .good_till(start + chrono::Duration::minutes(1));
+        let (limit_id, stop_id) = bracket(&mut engine, take_profit);
+        assert_eq!(engine.oco_links.len(), 2);
+
+        clock.advance(chrono::Duration::minutes(1));
+        engine.process_time_events("AAPL").unwrap();
+        assert!(!is_working(&engine, &limit_id));
+        assert!(is_working(&engine, &stop_id));
+        assert!(engine.oco_links.is_empty());
+    }
+
+    #[test]
+    fn market_data_goes_stale_on_the_injected_clock() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 50.0);
+        engine.place_order(limit("AAPL", Side::Buy, 1, 99.0)).unwrap();
+
+        clock.advance(chrono::Duration::seconds(90));
+        engine.place_order(limit("MSFT", Side::Buy, 1, 49.0)).unwrap();
+        let now = engine.now();
+        let max_age = chrono::Duration::seconds(60);
+        let aapl = engine.get_market_data("AAPL").unwrap();
+        let msft = engine.get_market_data("MSFT").unwrap();
+        assert_eq!(aapl.age(now), chrono::Duration::seconds(90));
+        assert!(aapl.is_stale(now, max_age));
+        assert!(!msft.is_stale(now, max_age));
+        // Exactly at the limit still counts as fresh
+        assert!(!aapl.is_stale(now, chrono::Duration::seconds(90)));
+    }
+
+    #[test]
+    fn market_data_renders_under_us_and_european_locales() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("BTC", 1234.5);
+        engine.place_order(limit("BTC", Side::Buy, 1500, 1234.5)).unwrap();
+        engine.place_order(limit("BTC", Side::Sell, 2, 1236.25)).unwrap();
+        let market_data = engine.get_market_data("BTC").unwrap();
+
+        assert_eq!(
+            format_market_data(&market_data, &NumberFormat::US),
+            "BTC: Bid: 1234.50 x 1500, Ask: 1236.25 x 2, Last: 1234.50, Mark: 1235.38"
+        );
+        assert_eq!(
+            format_market_data(&market_data, &NumberFormat::EUROPEAN),
+            "BTC: Bid: 1.234,50 x 1.500, Ask: 1.236,25 x 2, Last: 1.234,50, Mark: 1.235,38"
+        );
+    }
+
+    #[test]
+    fn locale_flag_is_parsed_without_panicking() {
+        let args = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let nf = locale_arg(&args(&["engine", "--locale", "EU"])).unwrap();
+        assert_eq!(nf.decimal_separator, ',');
+        assert_eq!(locale_arg(&args(&["engine"])).unwrap().decimal_separator, '.');
+        assert!(locale_arg(&args(&["engine", "--locale", "fr"])).is_err());
+        assert!(locale_arg(&args(&["engine", "--locale"])).is_err());
+    }
+
+    #[test]
+    fn only_the_order_setting_a_new_best_is_flagged() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 1, 98.0));
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.5));
+        book.add_order(limit("AAPL", Side::Sell, 1, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 1, 100.5));
+
+        let (bids, asks) = book.get_orders();
+        let flags: Vec<(f64, bool)> =
+            bids.iter().map(|o| (o.price.unwrap(), o.improved_book())).collect();
+        assert_eq!(flags, vec![(99.5, true), (99.0, true), (99.0, false), (98.0, false)]);
+        assert!(asks.iter().all(|o| o.improved_book()));
+    }
+
+    #[test]
+    fn crossing_limit_fills_at_maker_or_taker_price_per_config() {
+        let fill_price = |fill_price_side: FillPriceSide| {
+            let config = BookConfig::default().with_fill_price_side(fill_price_side);
+            let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+            book.add_order(limit("AAPL", Side::Sell, 1, 100.0));
+            book.add_order(limit("AAPL", Side::Buy, 1, 101.0))[0].price
+        };
+        assert_eq!(fill_price(FillPriceSide::MakerPrice), 100.0);
+        assert_eq!(fill_price(FillPriceSide::TakerPrice), 101.0);
+        assert_eq!(FillPriceSide::default(), FillPriceSide::MakerPrice);
+    }
+
+    #[test]
+    fn trade_ring_delivers_every_trade_under_capacity_and_counts_overflow() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        assert_eq!(engine.trade_ring(0).err(), Some(OrderError::InvalidCapacity(0)));
+
+        let ring = engine.trade_ring(4).unwrap();
+        let mut expected = Vec::new();
+        for _ in 0..4 {
+            engine.place_order(limit("AAPL", Side::Sell, 1, 100.0)).unwrap();
+            expected.extend(engine.place_order(limit("AAPL", Side::Buy, 1, 100.0)).unwrap());
+        }
+        let ids = |trades: &[Trade]| trades.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&ring.drain()), ids(&expected));
+        assert_eq!(ring.dropped(), 0);
+
+        for _ in 0..5 {
+            engine.place_order(limit("AAPL", Side::Sell, 1, 100.0)).unwrap();
+            engine.place_order(limit("AAPL", Side::Buy, 1, 100.0)).unwrap();
+        }
+        assert_eq!(ring.drain().len(), 4);
+        assert_eq!(ring.dropped(), 1);
+        assert!(ring.try_recv().is_none());
+    }
+
+    #[test]
+    fn spread_in_basis_points_is_comparable_across_price_levels() {
+        let quoted = |symbol: &str, bid: f64, ask: f64| {
+            let mut book = OrderBook::new(symbol.to_string(), (bid + ask) / 2.0);
+            book.add_order(limit(symbol, Side::Buy, 1, bid));
+            book.add_order(limit(symbol, Side::Sell, 1, ask));
+            book.get_market_data().spread_bps()
+        };
+        let penny = quoted("F", 9.999, 10.001).unwrap();
+        let share = quoted("BRK", 499_950.0, 500_050.0).unwrap();
+        assert!((penny - 2.0).abs() < 1e-9);
+        assert!((share - 2.0).abs() < 1e-9);
+
+        // No mid to normalise by
+        assert_eq!(quoted("CL", -2.0, -1.0), None);
+        let mut one_sided = OrderBook::new("AAPL".to_string(), 100.0);
+        one_sided.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        assert_eq!(one_sided.get_market_data().spread_bps(), None);
+    }
+
+    #[test]
+    fn converted_prices_are_rounded_onto_the_tick_grid_passively() {
+        let mut engine = TradingEngine::new();
+        engine.create_market_auto_tick("AAPL", 150.0);
+        engine.set_fx_rates(Arc::new(StaticFxRates::new().with_rate("EUR", "USD", 1.0837)));
+
+        // 99.99 EUR is 108.359163 USD, between the 0.01 ticks
+        engine.place_order(limit("AAPL", Side::Buy, 1, 99.99).quoted_in("EUR")).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 1, 149.99).quoted_in("EUR")).unwrap();
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert!((bids[0].price.unwrap() - 108.35).abs() < 1e-9);
+        assert!((asks[0].price.unwrap() - 162.55).abs() < 1e-9);
+
+        let stop = Order::new_stop("AAPL".to_string(), Side::Sell, qty(1), 99.99);
+        engine.place_order(stop.quoted_in("EUR")).unwrap();
+        let stops = engine.order_books["AAPL"].get_pending_stops();
+        assert!((stops[0].stop_price.unwrap() - 108.35).abs() < 1e-9);
+    }
+
+    #[test]
+    fn auto_tick_follows_price_magnitude_and_validates_orders() {
+        assert_eq!(auto_tick_size(2500.0), 0.1);
+        assert_eq!(auto_tick_size(150.0), 0.01);
+        assert_eq!(auto_tick_size(1.0), 0.01);
+        assert_eq!(auto_tick_size(0.5), 0.0001);
+
+        let mut engine = TradingEngine::new();
+        engine.create_market_auto_tick("BRK", 2500.0);
+        engine.create_market_auto_tick("PENNY", 0.5);
+        assert!(engine.place_order(limit("BRK", Side::Buy, 1, 2499.9)).is_ok());
+        let off_tick = engine.place_order(limit("BRK", Side::Buy, 1, 2499.95));
+        assert!(matches!(off_tick, Err(OrderError::OffTickPrice { .. })));
+        assert!(engine.place_order(limit("PENNY", Side::Buy, 1, 0.4999)).is_ok());
+        let off_tick = engine.place_order(limit("PENNY", Side::Buy, 1, 0.49995));
+        assert!(matches!(off_tick, Err(OrderError::OffTickPrice { .. })));
+    }
+
+    #[test]
+    fn price_simulator_trades_without_crossing_and_replays_from_its_seed() {
+        let run = |seed: u64| {
+            let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+            engine.create_market_auto_tick("AAPL", 100.0);
+            let rng = StdRng::seed_from_u64(seed);
+            let mut simulator = PriceSimulator::new("AAPL", 100.0, 0.002, clock, rng);
+            let mut prints = Vec::new();
+            for _ in 0..200 {
+                let trades = simulator.step(&mut engine);
+                prints.extend(trades.iter().map(|t| (t.price, t.quantity)));
+                let order_book = &engine.order_books["AAPL"];
+                assert!(order_book.check_invariants().is_ok());
+                let (bids, asks) = order_book.get_orders();
+                if let (Some(bid), Some(ask)) = (bids.first(), asks.first()) {
+                    assert!(bid.price < ask.price);
+                }
+            }
+            prints
+        };
+        let prints = run(7);
+        assert!(!prints.is_empty());
+        assert_eq!(prints, run(7));
+        assert_ne!(prints, run(8));
+    }
+
+    #[test]
+    fn protected_orders_survive_cancel_all_and_refuse_cancellation() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let quote = limit("AAPL", Side::Buy, 5, 99.0).protected();
+        let quote_id = quote.id.clone();
+        engine.place_order(quote).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 5, 98.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+
+        let cancelled = engine.cancel_all("AAPL").unwrap();
+        assert_eq!(cancelled.len(), 2);
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids.iter().map(|o| o.id.clone()).collect::<Vec<_>>(), vec![quote_id.clone()]);
+        assert!(asks.is_empty());
+
+        let refused = engine.cancel_order("AAPL", &quote_id);
+        assert_eq!(refused.unwrap_err(), OrderError::OrderProtected(quote_id.clone()));
+        let refused = engine.reduce_order("AAPL", &quote_id, qty(1));
+        assert_eq!(refused.unwrap_err(), OrderError::OrderProtected(quote_id));
+    }
+
+    #[test]
+    fn idempotent_retry_replays_accepted_trades_per_account() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 100.0)).unwrap();
+
+        let buy = || limit("AAPL", Side::Buy, 2, 100.0).with_account("A");
+        let key = || Some("retry-1".to_string());
+        let first = engine.place_order_idempotent(buy(), key()).unwrap();
+        let retry = engine.place_order_idempotent(buy(), key()).unwrap();
+        assert_eq!(first.len(), 1);
+        assert_eq!(retry[0].id, first[0].id);
+        assert_eq!(engine.get_orders("AAPL").unwrap().1[0].quantity, qty(8));
+
+        // Another account's identical key is its own submission
+        let other = limit("AAPL", Side::Buy, 2, 100.0).with_account("B");
+        let other = engine.place_order_idempotent(other, key()).unwrap();
+        assert_ne!(other[0].id, first[0].id);
+        assert_eq!(engine.get_orders("AAPL").unwrap().1[0].quantity, qty(6));
+    }
+
+    #[test]
+    fn idempotent_retry_after_a_rejection_executes() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 13, 0));
+        engine.create_market("AAPL", 100.0);
+        engine.set_session_schedule("AAPL", Some(nyse_hours())).unwrap();
+        let order = || limit("AAPL", Side::Buy, 1, 99.0).with_account("A");
+
+        let closed = engine.place_order_idempotent(order(), Some("k".to_string()));
+        assert_eq!(closed.unwrap_err(), OrderError::MarketClosed("AAPL".to_string()));
+        clock.set(utc(2024, 3, 11, 13, 30));
+        assert!(engine.place_order_idempotent(order(), Some("k".to_string())).is_ok());
+        assert_eq!(engine.get_orders("AAPL").unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn idempotency_cache_evicts_the_least_recently_used_key() {
+        let mut cache = IdempotencyCache::new(2);
+        let key = |k: &str| (Some("A".to_string()), k.to_string());
+        cache.insert(key("a"), Vec::new());
+        cache.insert(key("b"), Vec::new());
+        for _ in 0..10 {
+            assert!(cache.get(&key("a")).is_some());
+        }
+        cache.insert(key("c"), Vec::new());
+        assert!(cache.get(&key("b")).is_none());
+        assert!(cache.get(&key("a")).is_some());
+        assert!(cache.get(&key("c")).is_some());
+        // Repeated uses are compacted rather than piling up
+        assert!(cache.recency.len() <= 4);
+    }
+
+    #[test]
+    fn market_order_stops_at_the_collar_and_cancels_the_rest() {
+        let config = BookConfig::default().with_market_order_collar(0.05);
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.add_order(limit("AAPL", Side::Sell, 2, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 2, 104.0));
+        book.add_order(limit("AAPL", Side::Sell, 5, 107.0));
+
+        let buy = Order::new("AAPL".to_string(), Side::Buy, OrderType::Market, qty(10), None);
+        let fills: Vec<_> = book.add_order(buy).iter().map(|t| (t.price, t.quantity)).collect();
+        assert_eq!(fills, vec![(101.0, qty(2)), (104.0, qty(2))]);
+        let (bids, asks) = book.get_orders();
+        assert!(bids.is_empty());
+        assert_eq!((asks.len(), asks[0].price, asks[0].quantity), (1, Some(107.0), qty(5)));
+    }
+
+    #[test]
+    fn fair_value_tilts_toward_the_deeper_side() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        assert_eq!(book.fair_value(2), None);
+        book.add_order(limit("AAPL", Side::Buy, 10, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 10, 98.0));
+        // One-sided: the bids' VWAP
+        assert!((book.fair_value(2).unwrap() - 98.5).abs() < 1e-9);
+
+        book.add_order(limit("AAPL", Side::Sell, 1, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 1, 102.0));
+        let fair = book.fair_value(2).unwrap();
+        assert!((fair - 2173.0 / 22.0).abs() < 1e-9);
+        assert!(fair < book.mid().unwrap());
+        assert!((book.fair_value(1).unwrap() - 1091.0 / 11.0).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "json-schema")]
+    #[test]
+    fn order_schema_lists_its_fields_and_types() {
+        let schemas = json_schema::json_schemas();
+        assert!(schemas.contains_key("Trade") && schemas.contains_key("OrderOutcome"));
+        let order = &schemas["Order"];
+        let properties = &order["properties"];
+        assert_eq!(properties["id"]["type"], "string");
+        assert_eq!(properties["symbol"]["type"], "string");
+        assert_eq!(properties["quantity"]["$ref"], "#/$defs/Quantity");
+        assert_eq!(order["$defs"]["Quantity"]["type"], "string");
+        assert_eq!(properties["price"]["type"], serde_json::json!(["number", "null"]));
+        assert_eq!(properties["side"]["$ref"], "#/$defs/Side");
+        assert_eq!(order["$defs"]["Side"]["enum"], serde_json::json!(["Buy", "Sell"]));
+    }
+
+    #[test]
+    fn one_sided_book_trips_the_imbalance_halt_until_resumed() {
+        let mut engine = TradingEngine::new();
+        let config = BookConfig::default().with_imbalance_halt(qty(100));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 101.0)).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 60, 99.0)).unwrap();
+        assert_eq!(engine.get_halted_imbalance("AAPL"), None);
+        let tipping = limit("AAPL", Side::Buy, 60, 98.0);
+        let tipping_id = tipping.id.clone();
+        engine.place_order(tipping).unwrap();
+        assert_eq!(engine.get_halted_imbalance("AAPL"), Some(qty(110)));
+
+        let halted = engine.place_order(limit("AAPL", Side::Sell, 60, 102.0));
+        let expected = OrderError::MarketHalted { symbol: "AAPL".to_string(), imbalance: qty(110) };
+        assert_eq!(halted.unwrap_err(), expected);
+        // Balancing the book does not lift the halt on its own
+        engine.cancel_order("AAPL", &tipping_id).unwrap();
+        assert_eq!(engine.get_halted_imbalance("AAPL"), Some(qty(110)));
+
+        engine.resume_market("AAPL").unwrap();
+        assert!(engine.place_order(limit("AAPL", Side::Sell, 10, 102.0)).is_ok());
+    }
+
+    #[test]
+    fn reducing_keeps_priority_and_clamps_to_a_full_cancel() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let first = limit("AAPL", Side::Sell, 10, 101.0);
+        let second = limit("AAPL", Side::Sell, 10, 101.0);
+        let (first_id, second_id) = (first.id.clone(), second.id.clone());
+        book.add_order(first);
+        book.add_order(second);
+
+        assert_eq!(book.reduce_order(&first_id, qty(6)), Ok(qty(4)));
+        assert_eq!(book.get_market_data().ask_size, qty(14));
+        let buy = book.add_order(limit("AAPL", Side::Buy, 4, 101.0));
+        assert_eq!(buy[0].seller_order_id, first_id);
+
+        assert_eq!(book.reduce_order(&second_id, qty(50)), Ok(Quantity::ZERO));
+        assert!(book.get_orders().1.is_empty());
+    }
+
+    #[test]
+    fn reduce_refuses_non_positive_cuts_and_off_step_remainders() {
+        let half: Quantity = "0.5".parse().unwrap();
+        let config = BookConfig::default().with_quantity_step(half);
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        let order = limit("AAPL", Side::Buy, 10, 99.0);
+        let id = order.id.clone();
+        book.add_order(order);
+
+        for reduce_by in [Quantity::ZERO, qty(-3), "0.25".parse().unwrap()] {
+            let refused = book.reduce_order(&id, reduce_by);
+            assert!(matches!(refused, Err(OrderError::InvalidQuantity { .. })));
+        }
+        assert_eq!(book.get_orders().0[0].quantity, qty(10));
+        assert_eq!(book.reduce_order(&id, half), Ok("9.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn valid_snapshot_replaces_the_book() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 7, 95.0)).unwrap();
+
+        let bids = vec![limit("AAPL", Side::Buy, 3, 99.0), limit("AAPL", Side::Buy, 2, 98.0)];
+        let asks = vec![limit("AAPL", Side::Sell, 4, 101.0)];
+        let bid_ids: Vec<String> = bids.iter().map(|o| o.id.clone()).collect();
+        engine.load_book_snapshot("AAPL", bids, asks, Vec::new()).unwrap();
+
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids.iter().map(|o| o.id.clone()).collect::<Vec<_>>(), bid_ids);
+        assert_eq!((asks.len(), asks[0].quantity), (1, qty(4)));
+        let trades = engine.place_order(limit("AAPL", Side::Sell, 3, 99.0)).unwrap();
+        assert_eq!(trades[0].buyer_order_id, bid_ids[0]);
+    }
+
+    #[test]
+    fn crossed_or_unsorted_snapshot_is_rejected_and_the_book_kept() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 7, 95.0)).unwrap();
+
+        let crossed = engine.load_book_snapshot(
+            "AAPL",
+            vec![limit("AAPL", Side::Buy, 1, 102.0)],
+            vec![limit("AAPL", Side::Sell, 1, 101.0)],
+            Vec::new(),
+        );
+        assert!(matches!(crossed, Err(OrderError::InvalidSnapshot(_))));
+        let unsorted = engine.load_book_snapshot(
+            "AAPL",
+            vec![limit("AAPL", Side::Buy, 1, 98.0), limit("AAPL", Side::Buy, 1, 99.0)],
+            Vec::new(),
+            Vec::new(),
+        );
+        assert!(matches!(unsorted, Err(OrderError::InvalidSnapshot(_))));
+
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert_eq!((bids.len(), bids[0].price, asks.len()), (1, Some(95.0), 0));
+    }
+
+    #[test]
+    fn batch_indication_updates_as_auction_orders_arrive() {
+        let config = BookConfig::default()
+            .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)));
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.add_order(limit("AAPL", Side::Buy, 10, 101.0));
+        assert_eq!(book.auction_indicative(), None);
+
+        book.add_order(limit("AAPL", Side::Sell, 4, 99.0));
+        let state = book.auction_indicative().unwrap();
+        assert_eq!((state.matched_quantity, state.imbalance), (qty(4), qty(6)));
+        // Every price from 99 to 101 clears 4, so the last price is kept
+        assert_eq!(state.price, 100.0);
+
+        book.add_order(limit("AAPL", Side::Sell, 10, 100.0));
+        let state = book.auction_indicative().unwrap();
+        assert_eq!((state.price, state.matched_quantity), (100.0, qty(10)));
+        assert_eq!(state.imbalance, qty(-4));
+    }
+
+    #[test]
+    fn closing_indication_matches_what_close_then_does() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Sell, 3, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 5, 102.0));
+        book.add_order(market_on_close(Side::Buy, 10));
+        book.add_order(market_on_close(Side::Sell, 4));
+        let state = book.closing_indicative().unwrap();
+        assert_eq!(state.price, 102.0);
+        assert_eq!((state.matched_quantity, state.imbalance), (qty(10), qty(0)));
+
+        // More buying than the asks can absorb leaves an unfilled buy surplus
+        book.add_order(market_on_close(Side::Buy, 10));
+        let state = book.closing_indicative().unwrap();
+        assert_eq!(state.price, 102.0);
+        assert_eq!((state.matched_quantity, state.imbalance), (qty(12), qty(8)));
+
+        let trades = book.close();
+        let executed: Quantity = trades.iter().map(|t| t.quantity).sum();
+        assert_eq!(executed, state.matched_quantity);
+        assert!(trades.iter().all(|t| t.price == state.price));
+        assert_eq!(book.closing_indicative(), None);
+    }
+
+    #[test]
+    fn cancel_waits_for_the_minimum_rest_time() {
+        let start = utc(2024, 3, 11, 14, 0);
+        let (mut engine, clock) = sim_engine(start);
+        let config = BookConfig::default().with_min_rest_time(chrono::Duration::seconds(2));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        let order = limit("AAPL", Side::Buy, 5, 99.0);
+        let id = order.id.clone();
+        engine.place_order(order).unwrap();
+
+        clock.advance(chrono::Duration::milliseconds(500));
+        let early = engine.cancel_order("AAPL", &id).unwrap_err();
+        let remaining = chrono::Duration::milliseconds(1500);
+        assert_eq!(early, OrderError::MinRestTimeNotMet { order_id: id.clone(), remaining });
+        assert!(is_working(&engine, &id));
+
+        clock.advance(remaining);
+        assert_eq!(engine.cancel_order("AAPL", &id).unwrap().id, id);
+    }
+
+    #[test]
+    fn bust_refunds_fees_within_the_fee_period_only() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 10.0);
+        let schedule = VolumeTierSchedule::new(vec![VolumeTier::new(0.0, 0.001, 0.002)]);
+        engine.set_fee_schedule(Some(schedule));
+        let mut trade = || {
+            engine.place_order(limit("AAPL", Side::Sell, 100, 10.0).with_account("M")).unwrap();
+            let buy = limit("AAPL", Side::Buy, 100, 10.0).with_account("T");
+            engine.place_order(buy).unwrap().remove(0)
+        };
+        let first = trade();
+        let second = trade();
+        assert_eq!(engine.get_fees_paid("T"), 4.0);
+
+        engine.bust_trade("AAPL", &first.id).unwrap();
+        assert_eq!(engine.get_fees_paid("T"), 2.0);
+        assert_eq!(engine.get_cash_balance("T"), -1_002.0);
+        // Once the period rolls the fee is final, and nothing is kept for it
+        engine.roll_fee_period();
+        assert!(engine.trade_fees.is_empty());
+        engine.bust_trade("AAPL", &second.id).unwrap();
+        assert_eq!(engine.get_fees_paid("T"), 2.0);
+        assert_eq!(engine.get_cash_balance("T"), -2.0);
+        // Volume stays: the tier was earned when the trades happened
+        assert_eq!(engine.trailing_volume("T"), 2_000.0);
+    }
+
+    #[test]
+    fn bust_returns_or_reborrows_the_borrow_the_trade_moved() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.set_borrow_available("AAPL", Some(qty(10))).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 4, 100.0).with_account("S")).unwrap();
+        let buy = limit("AAPL", Side::Buy, 4, 100.0).with_account("L");
+        let short_sale = engine.place_order(buy).unwrap().remove(0);
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(6)));
+
+        // A busted short sale no longer needs its borrow
+        engine.bust_trade("AAPL", &short_sale.id).unwrap();
+        assert_eq!(engine.get_position("S", "AAPL"), Quantity::ZERO);
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(10)));
+
+        // A busted cover leaves the seller short again, borrowing once more
+        engine.place_order(limit("AAPL", Side::Sell, 4, 100.0).with_account("S")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 4, 100.0).with_account("L")).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 4, 100.0).with_account("L")).unwrap();
+        let buy = limit("AAPL", Side::Buy, 4, 100.0).with_account("S");
+        let cover = engine.place_order(buy).unwrap().remove(0);
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(10)));
+        engine.bust_trade("AAPL", &cover.id).unwrap();
+        assert_eq!(engine.get_position("S", "AAPL"), -qty(4));
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(6)));
+        assert_eq!(engine.borrowed["S"]["AAPL"], qty(4));
+    }
+
+    // The naive scan StopIndex replaces: every pending stop is checked
+    fn scan_triggered(stops: &mut Vec<Order>, low: f64, high: f64) -> Vec<Order> {
+        let fires = |o: &Order| match o.side {
+            Side::Buy => o.stop_price.unwrap() <= high,
+            Side::Sell => o.stop_price.unwrap() >= low,
+        };
+        let (mut triggered, pending): (Vec<Order>, Vec<Order>) =
+            stops.drain(..).partition(|o| fires(o));
+        *stops = pending;
+        triggered.sort_by(|a, b| {
+            let (pa, pb) = (a.stop_price.unwrap(), b.stop_price.unwrap());
+            let by_price = match a.side {
+                Side::Buy => pa.total_cmp(&pb),
+                Side::Sell => pb.total_cmp(&pa),
+            };
+            let sells_last = (a.side == Side::Sell).cmp(&(b.side == Side::Sell));
+            sells_last.then(by_price).then(a.sequence.cmp(&b.sequence))
+        });
+        triggered
+    }
+
+    fn random_stop(rng: &mut StdRng, sequence: u64) -> Order {
+        let side = if rng.gen_bool(0.5) { Side::Buy } else { Side::Sell };
+        let stop_price = 90.0 + rng.gen_range(0..40) as f64 * 0.5;
+        let mut stop = Order::new_stop("SIM".to_string(), side, qty(1), stop_price);
+        stop.sequence = sequence;
+        stop
+    }
+
+    #[test]
+    fn stop_index_fires_exactly_what_a_full_scan_fires() {
+        for seed in 0..20 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut index = StopIndex::default();
+            let mut reference: Vec<Order> = Vec::new();
+            let mut price = 100.0;
+            for sequence in 0..2000 {
+                match rng.gen_range(0..10) {
+                    0..=5 => {
+                        let stop = random_stop(&mut rng, sequence);
+                        reference.push(stop.clone());
+                        index.push(stop);
+                    }
+                    6 if !reference.is_empty() => {
+                        let id = reference.swap_remove(rng.gen_range(0..reference.len())).id;
+                        assert_eq!(index.remove(&id).map(|o| o.id), Some(id));
+                    }
+                    _ => {
+                        let next = price + rng.gen_range(-3..=3) as f64 * 0.5;
+                        let (low, high) = (f64::min(price, next), f64::max(price, next));
+                        price = next;
+                        let ids = |stops: Vec<Order>| stops.into_iter().map(|o| o.id).collect();
+                        let fired: Vec<String> = ids(index.take_triggered(low, high));
+                        let expected: Vec<String> = ids(scan_triggered(&mut reference, low, high));
+                        assert_eq!(fired, expected, "seed {} step {}", seed, sequence);
+                    }
+                }
+                assert_eq!(index.orders.len(), reference.len());
+            }
+            assert!(index.buys.len() + index.sells.len() <= 2 * index.orders.len().max(1));
+        }
+    }
+
+    // Timing comparison with the full scan; run with
+    // `cargo test --release -- --ignored --nocapture stop_index_throughput`.
+    #[test]
+    #[ignore]
+    fn stop_index_throughput_against_scan() {
+        const STOPS: u64 = 20_000;
+        const MOVES: usize = 2_000;
+        let mut rng = StdRng::seed_from_u64(7);
+        let stops: Vec<Order> = (0..STOPS).map(|seq| random_stop(&mut rng, seq)).collect();
+        // Small moves around 100 that only fire a few stops each
+        let moves: Vec<(f64, f64)> =
+            (0..MOVES).map(|i| (99.9 - (i % 5) as f64 * 0.01, 100.1)).collect();
+
+        let started = Instant::now();
+        let mut index = StopIndex::default();
+        for stop in &stops {
+            index.push(stop.clone());
+        }
+        let mut fired = 0;
+        for &(low, high) in &moves {
+            fired += index.take_triggered(low, high).len();
+        }
+        let index_time = started.elapsed();
+
+        let started = Instant::now();
+        let mut scan = stops.clone();
+        let mut scan_fired = 0;
+        for &(low, high) in &moves {
+            scan_fired += scan_triggered(&mut scan, low, high).len();
+        }
+        let scan_time = started.elapsed();
+
+        assert_eq!(fired, scan_fired);
+        println!(
+            "{} stops, {} price moves: heaps {:?}, scan {:?} ({:.1}x)",
+            STOPS,
+            MOVES,
+            index_time,
+            scan_time,
+            scan_time.as_secs_f64() / index_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn volume_profile_buckets_trades_and_finds_the_point_of_control() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        for (quantity, price) in [(2, 99.2), (10, 100.0), (5, 100.4), (3, 101.0), (1, 100.9)] {
+            book.add_order(limit("AAPL", Side::Sell, quantity, price));
+            book.add_order(limit("AAPL", Side::Buy, quantity, price));
+        }
+        // 100.0 opens its bucket and 101.0, the top of the range, opens its own
+        let profile = book.volume_profile(0.5);
+        let expected = [(99.0, qty(2)), (100.0, qty(15)), (100.5, qty(1)), (101.0, qty(3))];
+        assert_eq!(profile, expected);
+        assert_eq!(book.point_of_control(0.5), Some(100.0));
+        assert_eq!(book.point_of_control(1.0), Some(100.0));
+        assert_eq!(book.volume_profile(1.0)[1], (100.0, qty(16)));
+        assert!(book.volume_profile(0.0).is_empty());
+    }
+
+    #[test]
+    fn client_order_id_is_accepted_once_per_account() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 13, 0));
+        engine.create_market("AAPL", 100.0);
+        engine.set_session_schedule("AAPL", Some(nyse_hours())).unwrap();
+        let order = |client_order_id: &str, account: &str| {
+            Order::new_with_client_id(
+                "AAPL".to_string(),
+                Side::Buy,
+                OrderType::Limit,
+                qty(1),
+                Some(99.0),
+                client_order_id,
+            )
+            .with_account(account)
+        };
+
+        // A rejected submission does not use up its id
+        assert!(engine.place_order(order("c-1", "A")).is_err());
+        clock.set(utc(2024, 3, 11, 13, 30));
+        assert!(engine.place_order(order("c-1", "A")).is_ok());
+
+        let duplicate = engine.place_order(order("c-1", "A"));
+        let expected = OrderError::DuplicateClientOrderId {
+            account: "A".to_string(),
+            client_order_id: "c-1".to_string(),
+        };
+        assert_eq!(duplicate.unwrap_err(), expected);
+        assert!(engine.place_order(order("c-2", "A")).is_ok());
+        assert!(engine.place_order(order("c-1", "B")).is_ok());
+        assert_eq!(engine.get_orders("AAPL").unwrap().0.len(), 3);
+    }
+
+    #[test]
+    fn script_flag_needs_a_path() {
+        let args = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        let with_path = args(&["engine", "--script", "demo.txt"]);
+        assert_eq!(script_arg(&with_path), Ok(Some("demo.txt")));
+        assert_eq!(script_arg(&args(&["engine"])), Ok(None));
+        assert!(script_arg(&args(&["engine", "--script"])).is_err());
+    }
+
+    // Writes `script` to a fresh file and runs it against an empty engine
+    fn run_script_text(script: &str) -> (TradingEngine, Result<(), String>) {
+        let path = std::env::temp_dir().join(format!("scenario-{}.txt", Uuid::new_v4()));
+        std::fs::write(&path, script).unwrap();
+        let mut engine = TradingEngine::new();
+        let result = run_script(&mut engine, path.to_str().unwrap(), &NumberFormat::US);
+        std::fs::remove_file(&path).unwrap();
+        (engine, result)
+    }
+
+    #[test]
+    fn script_sets_up_a_book_and_trades() {
+        let (engine, result) = run_script_text(
+            "# demo\n\
+             create AAPL 100\n\
+             sell AAPL 10 101 ask-1\n\
+             sell AAPL 5 102\n\
+             buy AAPL 4 99 bid-1\n\
+             buy AAPL 3 101\n\
+             cancel bid-1\n\
+             buy AAPL 2\n\
+             print AAPL\n",
+        );
+        assert_eq!(result, Ok(()));
+        let trades = engine.get_trades("AAPL").unwrap();
+        let prints: Vec<_> = trades.iter().map(|t| (t.price, t.quantity)).collect();
+        assert_eq!(prints, vec![(101.0, qty(3)), (101.0, qty(2))]);
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert!(bids.is_empty());
+        let asks: Vec<_> = asks.iter().map(|o| (o.price, o.quantity)).collect();
+        assert_eq!(asks, vec![(Some(101.0), qty(5)), (Some(102.0), qty(5))]);
+    }
+
+    #[test]
+    fn script_parse_error_reports_its_line() {
+        let (engine, result) = run_script_text("create AAPL 100\n\nbuy AAPL 1 cheap\nprint AAPL\n");
+        assert_eq!(result, Err("line 3: invalid price 'cheap'".to_string()));
+        assert!(engine.get_orders("AAPL").unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn realized_volatility_of_a_known_price_series() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        assert_eq!(book.realized_volatility(10), None);
+        for price in [100.0, 200.0, 100.0, 200.0] {
+            book.add_order(limit("AAPL", Side::Sell, 1, price));
+            book.add_order(limit("AAPL", Side::Buy, 1, price));
+        }
+        let ln2 = 2f64.ln();
+        // Log returns ln2, -ln2, ln2 about a mean of ln2 / 3
+        let full = book.realized_volatility(10).unwrap();
+        assert!((full - 2.0 * 2f64.sqrt() / 3.0 * ln2).abs() < 1e-12);
+        assert!((book.realized_volatility(3).unwrap() - ln2).abs() < 1e-12);
+        assert_eq!(book.realized_volatility(1), None);
+        let annual = book.annualized_volatility(3, 252.0).unwrap();
+        assert!((annual - ln2 * 252f64.sqrt()).abs() < 1e-12);
+    }
+
+    // Offers every order it is shown twice the incoming quantity, twice over,
+    // after a negative share and one for an order that is not on the level
+    #[derive(Debug)]
+    struct Greedy;
+
+    impl AllocationPolicy for Greedy {
+        fn allocate(&self, level: &[&Order], incoming: Quantity) -> Vec<(String, Quantity)> {
+            let mut allocations = vec![
+                ("unknown".to_string(), incoming),
+                (level[0].id.clone(), -incoming),
+            ];
+            for order in level.iter().chain(level) {
+                allocations.push((order.id.clone(), incoming + incoming));
+            }
+            allocations
+        }
+    }
+
+    #[test]
+    fn over_allocation_is_capped_at_both_sides_remaining_quantity() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.set_allocation_policy(Arc::new(Greedy));
+        book.add_order(limit("AAPL", Side::Sell, 3, 100.0));
+        book.add_order(limit("AAPL", Side::Sell, 4, 100.0));
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+
+        let trades = book.add_order(limit("AAPL", Side::Buy, 9, 101.0));
+        let fills: Vec<_> = trades.iter().map(|t| (t.price, t.quantity)).collect();
+        assert_eq!(fills, vec![(100.0, qty(3)), (100.0, qty(4)), (101.0, qty(2))]);
+        let (bids, asks) = book.get_orders();
+        assert!(bids.is_empty());
+        assert_eq!(asks[0].quantity, qty(3));
+        assert!(book.check_invariants().is_ok());
+    }
+
+    #[test]
+    fn fifo_and_pro_rata_split_the_same_level_differently() {
+        let level = [
+            limit("AAPL", Side::Sell, 2, 100.0),
+            limit("AAPL", Side::Sell, 6, 100.0),
+            limit("AAPL", Side::Sell, 2, 100.0),
+        ];
+        let level: Vec<&Order> = level.iter().collect();
+        let shares = |allocations: Vec<(String, Quantity)>| -> Vec<Quantity> {
+            allocations.into_iter().map(|(_, quantity)| quantity).collect()
+        };
+        assert_eq!(shares(FifoPolicy.allocate(&level, qty(5))), vec![qty(2), qty(3)]);
+        assert_eq!(shares(ProRataPolicy.allocate(&level, qty(5))), vec![qty(1), qty(3), qty(1)]);
+        // More than the level holds fills it exactly under either policy
+        let total = |allocations: Vec<(String, Quantity)>| -> Quantity {
+            allocations.into_iter().map(|(_, quantity)| quantity).sum()
+        };
+        assert_eq!(total(FifoPolicy.allocate(&level, qty(50))), qty(10));
+        assert_eq!(total(ProRataPolicy.allocate(&level, qty(50))), qty(10));
+    }
+
+    #[test]
+    fn largest_orders_rank_by_size_across_levels_and_skip_hidden() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let orders = [
+            limit("AAPL", Side::Buy, 5, 99.0),
+            limit("AAPL", Side::Buy, 20, 97.0),
+            limit("AAPL", Side::Buy, 8, 98.0),
+            limit("AAPL", Side::Buy, 50, 98.0).hidden(),
+            limit("AAPL", Side::Buy, 8, 96.0),
+        ];
+        let ids: Vec<String> = orders.iter().map(|o| o.id.clone()).collect();
+        for order in orders {
+            book.add_order(order);
+        }
+
+        let largest: Vec<String> =
+            book.largest_orders(Side::Buy, 3).into_iter().map(|o| o.id).collect();
+        // The two 8 lots keep book priority: 98 ahead of 96
+        assert_eq!(largest, vec![ids[1].clone(), ids[2].clone(), ids[4].clone()]);
+        assert_eq!(book.largest_orders(Side::Buy, 10).len(), 4);
+        assert!(book.largest_orders(Side::Sell, 3).is_empty());
+    }
+
+    #[test]
+    fn long_inventory_shifts_the_makers_quotes_down() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 20, 99.0).with_account("X")).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 30, 101.0).with_account("X")).unwrap();
+        let mut quoter = InventoryQuoter::new("MM", "AAPL", 0.5, qty(1)).with_skew(0.1);
+        assert_eq!(quoter.quotes(&engine), Some((Some(99.5), Some(100.5))));
+
+        engine.place_order(limit("AAPL", Side::Buy, 10, 101.0).with_account("MM")).unwrap();
+        let (bid, ask) = quoter.quotes(&engine).unwrap();
+        assert!((bid.unwrap() - 98.5).abs() < 1e-9 && (ask.unwrap() - 99.5).abs() < 1e-9);
+
+        // At the inventory limit the bid, which would add to the long, is pulled
+        quoter = quoter.with_inventory_limit(qty(10));
+        assert!(quoter.quotes(&engine).unwrap().0.is_none());
+        assert!(quoter.requote(&mut engine).is_empty());
+        let (bids, asks) = engine.get_orders("AAPL").unwrap();
+        assert!(bids.iter().all(|o| o.account.as_deref() == Some("X")));
+        assert_eq!((asks[0].account.as_deref(), asks[0].price), (Some("MM"), Some(99.5)));
+    }
+
+    #[test]
+    fn order_tags_ride_through_to_every_fill() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let maker = limit("AAPL", Side::Sell, 10, 100.0)
+            .with_tag("strategy", "mm-1")
+            .with_tag("desk", "ny")
+            .with_tag("strategy", "mm-2");
+        assert_eq!(maker.tag("strategy"), Some("mm-2"));
+        engine.place_order(maker).unwrap();
+
+        for _ in 0..2 {
+            let taker = limit("AAPL", Side::Buy, 4, 100.0).with_tag("strategy", "twap");
+            let trades = engine.place_order(taker).unwrap();
+            assert_eq!(trades[0].buyer_tags, vec![("strategy".to_string(), "twap".to_string())]);
+            let seller_tags: Vec<(&str, &str)> =
+                trades[0].seller_tags.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            assert_eq!(seller_tags, vec![("desk", "ny"), ("strategy", "mm-2")]);
+        }
+        let (_, asks) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(asks[0].tag("desk"), Some("ny"));
+    }
+
+    #[test]
+    fn engine_runs_each_due_batch_on_its_clock() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+        let batched = || {
+            BookConfig::default()
+                .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)))
+        };
+        engine.create_market_with_params("MSFT", 300.0, batched());
+        engine.create_market_with_params("AAPL", 100.0, batched());
+        for (symbol, price) in [("MSFT", 300.0), ("AAPL", 100.0)] {
+            let buy = limit(symbol, Side::Buy, 5, price + 1.0);
+            assert!(engine.place_order(buy).unwrap().is_empty());
+            let sell = limit(symbol, Side::Sell, 5, price - 1.0);
+            assert!(engine.place_order(sell).unwrap().is_empty());
+        }
+        // Crossing orders wait for the boundary rather than matching continuously
+        assert!(engine.run_batches().is_empty());
+        assert!(engine.get_trades("AAPL").unwrap().is_empty());
+
+        clock.advance(chrono::Duration::seconds(1));
+        let batches = engine.run_batches();
+        let symbols: Vec<&str> = batches.iter().map(|(symbol, _)| symbol.as_str()).collect();
+        assert_eq!(symbols, vec!["AAPL", "MSFT"]);
+        for ((_, trades), price) in batches.iter().zip([100.0, 300.0]) {
+            let prints: Vec<_> = trades.iter().map(|t| (t.price, t.quantity)).collect();
+            assert_eq!(prints, [(price, qty(5))]);
+        }
+        assert!(engine.run_batches().is_empty());
+    }
+
+    #[test]
+    fn monotonic_stamps_order_acceptance_and_execution() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        let (bids, _) = book.get_orders();
+        assert!(bids[0].sequence < bids[1].sequence);
+        assert!(bids[0].accepted_nanos() < bids[1].accepted_nanos());
+
+        let trades = book.add_order(limit("AAPL", Side::Sell, 1, 99.0));
+        assert_eq!(trades[0].buyer_order_id, bids[0].id);
+        assert!(trades[0].executed_nanos() > bids[1].accepted_nanos());
+    }
+
+    // Two asks of 5 at 100 that fill at most `cap` per turn
+    fn capped_asks(book: &mut OrderBook, cap: i64) -> (String, String) {
+        let first = limit("AAPL", Side::Sell, 5, 100.0).with_max_fill_per_interaction(qty(cap));
+        let second = limit("AAPL", Side::Sell, 5, 100.0).with_max_fill_per_interaction(qty(cap));
+        let ids = (first.id.clone(), second.id.clone());
+        book.add_order(first);
+        book.add_order(second);
+        ids
+    }
+
+    fn fills_by_seller(trades: &[Trade]) -> Vec<(String, Quantity)> {
+        trades.iter().map(|t| (t.seller_order_id.clone(), t.quantity)).collect()
+    }
+
+    #[test]
+    fn capped_resting_orders_take_turns_against_one_incoming_order() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let (a, b) = capped_asks(&mut book, 2);
+        let trades = book.add_order(limit("AAPL", Side::Buy, 7, 100.0));
+        let expected = vec![
+            (a.clone(), qty(2)),
+            (b.clone(), qty(2)),
+            (a.clone(), qty(2)),
+            (b.clone(), qty(1)),
+        ];
+        assert_eq!(fills_by_seller(&trades), expected);
+        let (_, asks) = book.get_orders();
+        assert_eq!(asks.iter().map(|o| o.quantity).collect::<Vec<_>>(), vec![qty(1), qty(2)]);
+    }
+
+    #[test]
+    fn pro_rata_holds_a_capped_order_to_its_cap_per_round() {
+        let config = BookConfig::default().with_matching_algorithm(MatchingAlgorithm::ProRata);
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        let capped = limit("AAPL", Side::Sell, 30, 100.0).with_max_fill_per_interaction(qty(5));
+        let open = limit("AAPL", Side::Sell, 10, 100.0);
+        let (a, b) = (capped.id.clone(), open.id.clone());
+        book.add_order(capped);
+        book.add_order(open);
+
+        // Uncapped the split would be 15/5; the cap's excess goes to the other
+        // order and the capped one fills again only once that is used up
+        let trades = book.add_order(limit("AAPL", Side::Buy, 20, 100.0));
+        let expected = vec![(a.clone(), qty(5)), (b, qty(10)), (a, qty(5))];
+        assert_eq!(fills_by_seller(&trades), expected);
+    }
+
+    #[test]
+    fn batch_auction_gives_capped_orders_turns_within_a_level() {
+        let clock = SimClock::new(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)));
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.set_clock(Arc::new(clock.clone()));
+        let (a, b) = capped_asks(&mut book, 2);
+        book.add_order(limit("AAPL", Side::Buy, 6, 100.0));
+
+        clock.advance(chrono::Duration::seconds(1));
+        let trades = book.run_auction(clock.now());
+        let expected = vec![(a.clone(), qty(2)), (b, qty(2)), (a, qty(2))];
+        assert_eq!(fills_by_seller(&trades), expected);
+        let (_, asks) = book.get_orders();
+        assert_eq!(asks.iter().map(|o| o.quantity).collect::<Vec<_>>(), vec![qty(1), qty(3)]);
+    }
+
+    #[test]
+    fn close_gives_capped_limits_turns_against_the_imbalance() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let (a, b) = capped_asks(&mut book, 2);
+        book.add_order(market_on_close(Side::Buy, 6));
+        let trades = book.close();
+        let expected = vec![(a.clone(), qty(2)), (b, qty(2)), (a, qty(2))];
+        assert_eq!(fills_by_seller(&trades), expected);
+        assert!(trades.iter().all(|t| t.price == 100.0));
+        let (_, asks) = book.get_orders();
+        assert_eq!(asks.iter().map(|o| o.quantity).collect::<Vec<_>>(), vec![qty(1), qty(3)]);
+    }
+
+    // AAPL offered and MSFT bid, 10 each, by a market maker
+    fn spread_markets() -> TradingEngine {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.create_market("MSFT", 90.0);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 100.0).with_account("MM")).unwrap();
+        engine.place_order(limit("AAPL", Side::Buy, 10, 99.0).with_account("MM")).unwrap();
+        engine.place_order(limit("MSFT", Side::Buy, 10, 90.0).with_account("MM")).unwrap();
+        engine
+    }
+
+    fn resting_quantity(engine: &TradingEngine, symbol: &str) -> Quantity {
+        let (bids, asks) = engine.get_orders(symbol).unwrap();
+        bids.iter().chain(&asks).map(|o| o.quantity).sum()
+    }
+
+    #[test]
+    fn spread_trades_both_legs_only_within_its_net_price() {
+        let mut engine = spread_markets();
+        let spread = |max_net_price| {
+            SpreadOrder::new(qty(5), max_net_price)
+                .with_leg("AAPL", Side::Buy, 1)
+                .with_leg("MSFT", Side::Sell, 1)
+                .with_account("T")
+        };
+
+        // Buying at 100 and selling at 90 costs 10 a unit
+        let rejected = engine.place_spread_order(spread(8.0));
+        assert!(matches!(rejected, Err(OrderError::SpreadRejected(_))));
+        assert_eq!(engine.get_position("T", "AAPL"), Quantity::ZERO);
+
+        let trades = engine.place_spread_order(spread(12.0)).unwrap();
+        assert_eq!(trades.len(), 2);
+        assert_eq!(engine.get_position("T", "AAPL"), qty(5));
+        assert_eq!(engine.get_position("T", "MSFT"), qty(-5));
+    }
+
+    #[test]
+    fn spread_leg_without_borrow_stops_every_leg() {
+        let mut engine = spread_markets();
+        engine.set_borrow_available("AAPL", Some(qty(100))).unwrap();
+        engine.set_borrow_available("MSFT", Some(qty(2))).unwrap();
+        let spread = SpreadOrder::new(qty(5), 0.0)
+            .with_leg("AAPL", Side::Sell, 1)
+            .with_leg("MSFT", Side::Sell, 1)
+            .with_account("T");
+
+        let rejected = engine.place_spread_order(spread);
+        assert!(matches!(rejected, Err(OrderError::NoBorrowAvailable { .. })));
+        // The AAPL leg neither traded nor kept the borrow it located
+        assert_eq!(borrow_pool(&engine, "AAPL"), Some(qty(100)));
+        assert_eq!(resting_quantity(&engine, "AAPL"), qty(20));
+        assert_eq!(resting_quantity(&engine, "MSFT"), qty(10));
+    }
+
+    #[test]
+    fn spread_is_refused_for_delayed_accounts_and_oversized_legs() {
+        let mut engine = spread_markets();
+        engine.set_order_latency("T", Some(chrono::Duration::milliseconds(5)));
+        let spread = SpreadOrder::new(qty(5), 12.0)
+            .with_leg("AAPL", Side::Buy, 1)
+            .with_leg("MSFT", Side::Sell, 1);
+        let delayed = engine.place_spread_order(spread.clone().with_account("T"));
+        assert!(matches!(delayed, Err(OrderError::SpreadRejected(_))));
+        assert_eq!(resting_quantity(&engine, "AAPL"), qty(20));
+
+        let huge = SpreadOrder::new(Quantity::from_raw(i64::MAX / 2), f64::MAX)
+            .with_leg("AAPL", Side::Buy, 3);
+        let overflow = engine.place_spread_order(huge);
+        assert!(matches!(overflow, Err(OrderError::SpreadRejected(_))));
+    }
+
+    // Keeps each event as "message field=value ..." so tests can look for it
+    #[cfg(feature = "tracing")]
+    struct CapturedEvents(Arc<std::sync::Mutex<Vec<String>>>);
+
+    #[cfg(feature = "tracing")]
+    impl tracing::Subscriber for CapturedEvents {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, _: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            struct Fields(String);
+            impl tracing::field::Visit for Fields {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+                    match field.name() {
+                        "message" => self.0.insert_str(0, &format!("{:?}", value)),
+                        name => self.0.push_str(&format!(" {}={:?}", name, value)),
+                    }
+                }
+            }
+            let mut fields = Fields(String::new());
+            event.record(&mut fields);
+            self.0.lock().unwrap().push(fields.0);
+        }
+
+        fn enter(&self, _: &tracing::span::Id) {}
+
+        fn exit(&self, _: &tracing::span::Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn matching_order_emits_accepted_and_trade_events() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subscriber = CapturedEvents(events.clone());
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        let buy = limit("AAPL", Side::Buy, 5, 100.0);
+        let buy_id = buy.id.clone();
+        tracing::subscriber::with_default(subscriber, || {
+            book.add_order(limit("AAPL", Side::Sell, 5, 100.0));
+            book.add_order(buy);
+        });
+
+        let events = events.lock().unwrap();
+        let accepted = events.iter().filter(|e| e.starts_with("order accepted")).count();
+        assert_eq!(accepted, 2);
+        let trade = events.iter().find(|e| e.starts_with("trade ")).unwrap();
+        assert!(trade.contains("symbol=AAPL"));
+        assert!(trade.contains(&format!("buyer_order_id={}", buy_id)));
+        assert!(trade.contains("price=100.0"));
+    }
+
+    fn snapshot_book(symbol: &str, levels: usize) -> OrderBook {
+        let mut book = OrderBook::new(symbol.to_string(), 100.0);
+        book.set_clock(Arc::new(SimClock::new(utc(2024, 3, 11, 14, 30))));
+        // Prices on a cent grid, computed so each is the nearest double to the cent
+        for i in 0..levels as i64 {
+            book.add_order(limit(symbol, Side::Buy, 1 + i, (9999 - i) as f64 / 100.0));
+            book.add_order(limit(symbol, Side::Sell, 2 + i, (10001 + i) as f64 / 100.0));
+        }
+        book
+    }
+
+    #[test]
+    fn depth_snapshot_round_trips_through_bytes() {
+        let book = snapshot_book("AAPL", 3);
+        let snapshot = book.depth_snapshot(usize::MAX);
+        let decoded = DepthSnapshot::from_bytes(&book.snapshot_bytes().unwrap()).unwrap();
+        assert_eq!(decoded, snapshot);
+        assert_eq!(decoded.symbol(), "AAPL");
+        assert_eq!(decoded.timestamp(), utc(2024, 3, 11, 14, 30));
+        assert_eq!(decoded.bids()[0], (99.99, qty(1)));
+        assert_eq!(decoded.asks().len(), 3);
+
+        // An empty book and a symbol using all 8 bytes survive too
+        let empty = snapshot_book("BRK.B123", 0).depth_snapshot(10);
+        assert_eq!(DepthSnapshot::from_bytes(&empty.to_bytes().unwrap()).unwrap(), empty);
+    }
+
+    #[test]
+    fn depth_snapshot_refuses_symbols_the_layout_cannot_hold() {
+        for symbol in ["TOOLONGSYM", "ÄPFEL"] {
+            let book = snapshot_book(symbol, 1);
+            let expected = OrderError::UnencodableSymbol(symbol.to_string());
+            assert_eq!(book.snapshot_bytes(), Err(expected));
+        }
+        let engine = TradingEngine::new();
+        let missing = engine.get_snapshot_bytes("AAPL");
+        assert_eq!(missing, Err(OrderError::MarketNotFound("AAPL".to_string())));
+    }
+
+    // The same snapshot as JSON, with quantities as decimal strings
+    fn depth_snapshot_json(snapshot: &DepthSnapshot) -> String {
+        let levels = |levels: &[PriceLevel]| -> Vec<serde_json::Value> {
+            levels.iter().map(|(p, q)| serde_json::json!([p, q.to_string()])).collect()
+        };
+        serde_json::json!({
+            "symbol": snapshot.symbol(),
+            "timestamp_micros": snapshot.timestamp().timestamp_micros(),
+            "bids": levels(snapshot.bids()),
+            "asks": levels(snapshot.asks()),
+        })
+        .to_string()
+    }
+
+    fn depth_snapshot_from_json(text: &str) -> DepthSnapshot {
+        let value: serde_json::Value = serde_json::from_str(text).unwrap();
+        let levels = |side: &str| -> Vec<PriceLevel> {
+            let levels = value[side].as_array().unwrap();
+            let level = |l: &serde_json::Value| {
+                (l[0].as_f64().unwrap(), l[1].as_str().unwrap().parse().unwrap())
+            };
+            levels.iter().map(level).collect()
+        };
+        DepthSnapshot {
+            symbol: value["symbol"].as_str().unwrap().to_string(),
+            timestamp: DateTime::from_timestamp_micros(value["timestamp_micros"].as_i64().unwrap())
+                .unwrap(),
+            bids: levels("bids"),
+            asks: levels("asks"),
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn depth_snapshot_bytes_against_json() {
+        const ROUNDS: usize = 2_000;
+        let snapshot = snapshot_book("AAPL", 200).depth_snapshot(usize::MAX);
+
+        let started = Instant::now();
+        let mut binary_len = 0;
+        for _ in 0..ROUNDS {
+            let bytes = snapshot.to_bytes().unwrap();
+            binary_len = bytes.len();
+            assert_eq!(DepthSnapshot::from_bytes(&bytes).unwrap().bids.len(), 200);
+        }
+        let binary_time = started.elapsed();
+
+        let started = Instant::now();
+        let mut json_len = 0;
+        for _ in 0..ROUNDS {
+            let text = depth_snapshot_json(&snapshot);
+            json_len = text.len();
+            assert_eq!(depth_snapshot_from_json(&text).bids.len(), 200);
+        }
+        let json_time = started.elapsed();
+
+        assert_eq!(depth_snapshot_from_json(&depth_snapshot_json(&snapshot)), snapshot);
+        println!(
+            "400 levels x {}: binary {} bytes in {:?}, JSON {} bytes in {:?} ({:.1}x)",
+            ROUNDS,
+            binary_len,
+            binary_time,
+            json_len,
+            json_time,
+            json_time.as_secs_f64() / binary_time.as_secs_f64()
+        );
+    }
+
+    #[test]
+    fn astronomically_priced_limit_buy_stays_a_limit_order() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Sell, 3, 100.0)).unwrap();
+
+        // A market order would cancel its unfilled 2; the limit rests at its price
+        let trades = engine.place_order(limit("AAPL", Side::Buy, 5, 1e12)).unwrap();
+        assert_eq!((trades[0].price, trades[0].quantity), (100.0, qty(3)));
+        let (bids, _) = engine.get_orders("AAPL").unwrap();
+        assert_eq!(bids.len(), 1);
+        assert_eq!((bids[0].order_type, bids[0].price), (OrderType::Limit, Some(1e12)));
+        assert_eq!(bids[0].quantity, qty(2));
+    }
+
+    #[test]
+    fn zero_limit_price_is_legal_unless_the_book_rejects_it() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("SPRD", 0.0);
+        let config = BookConfig::default().with_zero_price_rejected();
+        engine.create_market_with_params("FEED", 10.0, config);
+
+        assert!(engine.place_order(limit("SPRD", Side::Sell, 1, 0.0)).is_ok());
+        assert_eq!(engine.get_orders("SPRD").unwrap().1[0].price, Some(0.0));
+        let rejected = engine.place_order(limit("FEED", Side::Sell, 1, 0.0));
+        assert_eq!(rejected.err(), Some(OrderError::InvalidPrice(0.0)));
+        let nan = engine.place_order(limit("SPRD", Side::Buy, 1, f64::NAN));
+        assert!(matches!(nan, Err(OrderError::InvalidPrice(p)) if p.is_nan()));
+    }
+
+    #[test]
+    fn depth_curve_accumulates_visible_quantity_away_from_the_touch() {
+        let mut book = OrderBook::new("AAPL".to_string(), 100.0);
+        book.add_order(limit("AAPL", Side::Buy, 2, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 3, 98.0));
+        book.add_order(limit("AAPL", Side::Buy, 1, 99.0));
+        book.add_order(limit("AAPL", Side::Buy, 4, 97.0).hidden());
+        book.add_order(limit("AAPL", Side::Sell, 5, 101.0));
+        book.add_order(limit("AAPL", Side::Sell, 2, 102.0));
+
+        let bids = book.depth_curve(Side::Buy);
+        assert_eq!(bids, vec![(99.0, qty(3)), (98.0, qty(6))]);
+        let asks = book.depth_curve(Side::Sell);
+        assert_eq!(asks, vec![(101.0, qty(5)), (102.0, qty(7))]);
+        for curve in [bids, asks] {
+            assert!(curve.windows(2).all(|w| w[0].1 <= w[1].1));
+        }
+        assert!(OrderBook::new("AAPL".to_string(), 100.0).depth_curve(Side::Buy).is_empty());
+    }
+
+    #[test]
+    fn trades_for_order_returns_every_fill_of_a_multi_fill_order() {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        let sell = limit("AAPL", Side::Sell, 10, 100.0);
+        let sell_id = sell.id.clone();
+        engine.place_order(sell).unwrap();
+        engine.place_order(limit("AAPL", Side::Sell, 5, 101.0)).unwrap();
+
+        let mut fills = Vec::new();
+        for quantity in [3, 4, 5] {
+            fills.extend(engine.place_order(limit("AAPL", Side::Buy, quantity, 101.0)).unwrap());
+        }
+        // The last buy also takes 2 from the 101 offer, which is not the sell's fill
+        let ids = |trades: &[Trade]| trades.iter().map(|t| t.id.clone()).collect::<Vec<_>>();
+        let own: Vec<Trade> =
+            fills.iter().filter(|t| t.seller_order_id == sell_id).cloned().collect();
+        let history = engine.trades_for_order("AAPL", &sell_id).unwrap();
+        assert_eq!(ids(&history), ids(&own));
+        assert_eq!(history.iter().map(|t| t.quantity).sum::<Quantity>(), qty(10));
+
+        // Busting a fill drops it from the history
+        engine.bust_trade("AAPL", &own[1].id).unwrap();
+        let history = engine.trades_for_order("AAPL", &sell_id).unwrap();
+        assert_eq!(ids(&history), vec![own[0].id.clone(), own[2].id.clone()]);
+        assert!(engine.trades_for_order("AAPL", "unknown").unwrap().is_empty());
+        assert!(engine.trades_for_order("MSFT", &sell_id).is_none());
+    }
+
+    #[test]
+    fn fee_tiers_sort_without_panicking_on_a_nan_threshold() {
+        let schedule = VolumeTierSchedule::new(vec![
+            VolumeTier::new(1_000.0, 0.001, 0.002),
+            VolumeTier::new(f64::NAN, 0.0, 0.0),
+            VolumeTier::new(0.0, 0.002, 0.003),
+        ]);
+        // A NaN threshold sorts last and is never reached
+        assert_eq!(schedule.tier_for(500.0).unwrap().taker_rate, 0.003);
+        assert_eq!(schedule.tier_for(1e12).unwrap().taker_rate, 0.002);
+    }
+
+    #[cfg(feature = "grpc")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn grpc_streams_a_deferred_large_trade_once_its_delay_has_passed() {
+        use grpc::proto;
+        use grpc::proto::trading_client::TradingClient;
+        use grpc::proto::trading_server::TradingServer;
+        use tokio_stream::wrappers::TcpListenerStream;
+
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_deferred_publication(qty(100), chrono::Duration::minutes(15));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        engine.place_order(limit("AAPL", Side::Sell, 500, 100.0)).unwrap();
+        let engine = Arc::new(tokio::sync::Mutex::new(engine));
+        let service = grpc::TradingService::new(engine.clone()).await;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(TradingServer::new(service))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let mut client = TradingClient::connect(format!("http://{}", addr)).await.unwrap();
+        let request = proto::StreamTradesRequest {
+            symbol: "AAPL".to_string(),
+        };
+        let mut trades = client.stream_trades(request).await.unwrap().into_inner();
+
+        let large = limit("AAPL", Side::Buy, 200, 100.0);
+        let large_id = large.id.clone();
+        engine.lock().await.place_order(large).unwrap();
+        let wait = std::time::Duration::from_millis(200);
+        assert!(tokio::time::timeout(wait, trades.message()).await.is_err());
+
+        // Still held a minute short of the delay, then released by time events
+        clock.advance(chrono::Duration::minutes(14));
+        engine.lock().await.process_time_events("AAPL").unwrap();
+        assert!(tokio::time::timeout(wait, trades.message()).await.is_err());
+        clock.advance(chrono::Duration::minutes(1));
+        engine.lock().await.process_time_events("AAPL").unwrap();
+        let streamed = trades.message().await.unwrap().unwrap();
+        assert_eq!(streamed.buyer_order_id, large_id);
+        assert_eq!(streamed.quantity.parse(), Ok(qty(200)));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test]
+    async fn async_engine_broadcasts_a_deferred_trade_after_its_delay() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_deferred_publication(qty(100), chrono::Duration::minutes(15));
+        engine.create_market_with_params("AAPL", 100.0, config);
+        let engine = AsyncTradingEngine::new(engine);
+        let mut updates = engine.subscribe();
+
+        engine.place_order(limit("AAPL", Side::Sell, 500, 100.0)).await.unwrap();
+        let trades = engine.place_order(limit("AAPL", Side::Buy, 200, 100.0)).await.unwrap();
+        assert_eq!(trades.len(), 1);
+        assert!(matches!(updates.recv().await.unwrap(), MarketUpdate::MarketData(_)));
+        assert!(matches!(updates.recv().await.unwrap(), MarketUpdate::MarketData(_)));
+        assert!(updates.try_recv().is_err());
+
+        clock.advance(chrono::Duration::minutes(15));
+        engine.process_time_events("AAPL").await.unwrap();
+        match updates.recv().await.unwrap() {
+            MarketUpdate::Trade(trade) => assert_eq!(trade.id, trades[0].id),
+            other => panic!("expected the deferred trade, got {:?}", other),
+        }
+        assert!(matches!(updates.recv().await.unwrap(), MarketUpdate::MarketData(_)));
+    }
+
+    #[test]
+    fn batch_auction_keeps_the_improved_book_flags_of_resting_orders() {
+        let clock = SimClock::new(utc(2024, 3, 11, 14, 0));
+        let config = BookConfig::default()
+            .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)));
+        let mut book = OrderBook::with_config("AAPL".to_string(), 100.0, config);
+        book.set_clock(Arc::new(clock.clone()));
+        let flags = |book: &OrderBook, side: Side| -> Vec<(f64, bool)> {
+            let (bids, asks) = book.get_orders();
+            let orders = if side == Side::Buy { bids } else { asks };
+            orders.iter().map(|o| (o.price.unwrap(), o.improved_book())).collect()
+        };
+        let auction = |book: &mut OrderBook, orders: Vec<Order>| {
+            for order in orders {
+                book.add_order(order);
+            }
+            clock.advance(chrono::Duration::seconds(1));
+            book.run_auction(clock.now())
+        };
+
+        auction(
+            &mut book,
+            vec![limit("AAPL", Side::Buy, 1, 98.0), limit("AAPL", Side::Sell, 1, 101.0)],
+        );
+        auction(
+            &mut book,
+            vec![limit("AAPL", Side::Buy, 1, 99.0), limit("AAPL", Side::Buy, 1, 99.0)],
+        );
+        assert_eq!(flags(&book, Side::Buy), vec![(99.0, true), (99.0, false), (98.0, true)]);
+        assert_eq!(flags(&book, Side::Sell), vec![(101.0, true)]);
+
+        // The 98 bid set the best when it arrived and is re-queued behind both
+        // 99s by the next auction, which must not re-derive its flag
+        let trades = auction(
+            &mut book,
+            vec![limit("AAPL", Side::Sell, 1, 102.0), limit("AAPL", Side::Sell, 1, 99.0)],
+        );
+        assert_eq!(trades.len(), 1);
+        assert_eq!(flags(&book, Side::Buy), vec![(99.0, false), (98.0, true)]);
+        assert_eq!(flags(&book, Side::Sell), vec![(101.0, true), (102.0, false)]);
+    }
+
+    #[test]
+    fn spread_with_a_leg_in_a_batch_auction_market_is_rejected() {
+        let (mut engine, clock) = sim_engine(utc(2024, 3, 11, 14, 0));
+        engine.create_market("AAPL", 100.0);
+        let config = BookConfig::default()
+            .with_matching_mode(MatchingMode::BatchAuction(chrono::Duration::seconds(1)));
+        engine.create_market_with_params("MSFT", 90.0, config);
+        engine.place_order(limit("AAPL", Side::Sell, 10, 100.0).with_account("MM")).unwrap();
+        engine.place_order(limit("MSFT", Side::Buy, 10, 90.0).with_account("MM")).unwrap();
+        clock.advance(chrono::Duration::seconds(1));
+        engine.run_auction("MSFT").unwrap();
+        assert_eq!(resting_quantity(&engine, "MSFT"), qty(10));
+
+        let spread = SpreadOrder::new(qty(5), 20.0)
+            .with_leg("AAPL", Side::Buy, 1)
+            .with_leg("MSFT", Side::Sell, 1)
+            .with_account("T");
+        let result = engine.place_spread_order(spread);
+        assert!(matches!(result, Err(OrderError::SpreadRejected(_))));
+        assert!(engine.get_trades("AAPL").unwrap().is_empty());
+        assert_eq!(resting_quantity(&engine, "AAPL"), qty(10));
+        assert!(engine.order_books["MSFT"].batch_orders.is_empty());
+    }
+
+    #[test]
+    fn spread_net_price_counts_each_leg_contract_multiplier() {
+        let mut engine = TradingEngine::new();
+        let config = BookConfig::default().with_contract_multiplier(50.0);
+        engine.create_market_with_params("ES", 5000.0, config);
+        engine.create_market("SPY", 500.0);
+        engine.place_order(limit("ES", Side::Sell, 1, 5000.0).with_account("MM")).unwrap();
+        engine.place_order(limit("SPY", Side::Buy, 500, 499.0).with_account("MM")).unwrap();
+        let spread = |max_net_price| {
+            SpreadOrder::new(qty(1), max_net_price)
+                .with_leg("ES", Side::Buy, 1)
+                .with_leg("SPY", Side::Sell, 500)
+                .with_account("T")
+        };
+
+        // 250,000 for the future against 249,500 for the shares
+        assert!(engine.place_spread_order(spread(10.0)).is_err());
+        assert_eq!(engine.place_spread_order(spread(500.0)).unwrap().len(), 2);
+    }
+
+    // Full-depth snapshots taken between batches of feed events replayed
+    // into an engine, which already holds a bid before the feed starts
+    fn recorded_feed() -> (Vec<DepthSnapshot>, Vec<Vec<BookEvent>>) {
+        let mut engine = TradingEngine::new();
+        engine.create_market("AAPL", 100.0);
+        engine.place_order(limit("AAPL", Side::Buy, 4, 99.0)).unwrap();
+        let add = |order_ref, side, quantity, price| BookEvent::Add {
+            symbol: "AAPL".to_string(),
+            order_ref,
+            side,
+            quantity: qty(quantity),
+            price,
+        };
+        let deltas = vec![
+            vec![
+                add(1, Side::Buy, 5, 99.0),
+                add(2, Side::Sell, 3, 101.0),
+                add(3, Side::Sell, 2, 101.5),
+            ],
+            vec![
+                BookEvent::Execute { symbol: "AAPL".to_string(), order_ref: 2, quantity: qty(1) },
+                BookEvent::Modify {
+                    symbol: "AAPL".to_string(),
+                    order_ref: 1,
+                    quantity: qty(6),
+                    price: 99.5,
+                },
+            ],
+            vec![
+                BookEvent::Delete { symbol: "AAPL".to_string(), order_ref: 3 },
+                BookEvent::Execute { symbol: "AAPL".to_string(), order_ref: 2, quantity: qty(2) },
+            ],
+        ];
+
+        let mut replayer = BookReplayer::new();
+        let mut snapshots = vec![engine.order_books["AAPL"].depth_snapshot(usize::MAX)];
+        for batch in &deltas {
+            for event in batch {
+                replayer.apply(&mut engine, event).unwrap();
+            }
+            snapshots.push(engine.order_books["AAPL"].depth_snapshot(usize::MAX));
+        }
+        (snapshots, deltas)
+    }
+
+    #[test]
+    fn recorded_snapshots_and_deltas_are_consistent() {
+        let (snapshots, deltas) = recorded_feed();
+        assert_eq!(snapshots[2].bids(), &[(99.5, qty(6)), (99.0, qty(4))]);
+        assert_eq!(verify_feed_consistency(&snapshots, &deltas), Ok(()));
+        assert_eq!(verify_feed_consistency(&[], &[]), Ok(()));
+        assert_eq!(
+            verify_feed_consistency(&snapshots, &deltas[1..]),
+            Err(FeedError::BatchCount { snapshots: 4, batches: 2 })
+        );
+    }
+
+    #[test]
+    fn corrupted_delta_is_reported_at_the_snapshot_it_breaks() {
+        let (snapshots, mut deltas) = recorded_feed();
+        deltas[1][0] =
+            BookEvent::Execute { symbol: "AAPL".to_string(), order_ref: 2, quantity: qty(2) };
+
+        let error = verify_feed_consistency(&snapshots, &deltas).unwrap_err();
+        assert_eq!(
+            error,
+            FeedError::Diverged {
+                snapshot: 2,
+                side: Side::Sell,
+                price: 101.0,
+                expected: qty(2),
+                actual: qty(1),
+            }
+        );
+        assert_eq!(error.to_string(), "Snapshot 2 ask at 101: snapshot has 2, deltas give 1");
+
+        deltas[1][0] =
+            BookEvent::Execute { symbol: "AAPL".to_string(), order_ref: 2, quantity: qty(4) };
+        assert_eq!(
+            verify_feed_consistency(&snapshots, &deltas),
+            Err(FeedError::Overexecuted { snapshot: 2, order_ref: 2 })
+        );
+        deltas[1][0] = BookEvent::Delete { symbol: "AAPL".to_string(), order_ref: 9 };
+        assert_eq!(
+            verify_feed_consistency(&snapshots, &deltas),
+            Err(FeedError::UnknownOrder { snapshot: 2, order_ref: 9 })
+        );
+    }
+}